@@ -1,50 +1,659 @@
+use fizzle::{
+	monitor::MonitorUptime,
+	retry::Backoff,
+	routing::DataCategory,
+	smartplugs::{
+		baseline::TodayBaseline, breaker::CircuitBreakerConfig, diagnostics::DiagnosticsSampling,
+		downsample::DownsampleConfig, topic::TemplateTopicScheme, DeviceTimezone, DuplicatePolicy,
+		TimestampStrategy,
+	},
+	tariff::Tariff,
+};
+use influxdb::{AcceptancePolicy, LineProtocolSink, RotatingFiles};
 use serde::Deserialize;
+use std::{
+	collections::BTreeMap,
+	fs::File,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+};
 use url::Url;
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Config {
 	pub mqtt: MqttConfig,
 	pub influxdb: InfluxConfig,
 	pub display: Option<DisplayConfig>,
+	pub tariff: Option<Tariff>,
+	pub query_api: Option<QueryApiConfig>,
+
+	#[serde(default)]
+	pub meter: MeterConfig,
+
+	#[serde(default)]
+	pub health: HealthConfig,
+
+	#[serde(default)]
+	pub smartplugs: SmartPlugsConfig,
+
+	#[serde(default)]
+	pub tasmota: TasmotaConfig,
+
+	/// How to report how long fizzle has been monitoring each source.
+	#[serde(default)]
+	pub monitor_uptime: MonitorUptime,
+
+	/// Named groups of smart plug device names whose readings are summed.
+	#[serde(default)]
+	pub groups: BTreeMap<String, Vec<String>>,
+}
+
+impl Config {
+	/// Reads a YAML or JSON configuration file, by extension.
+	pub fn load<T: AsRef<Path>>(path: T) -> anyhow::Result<Self> {
+		let path = path.as_ref();
+		let config_file = File::open(path)?;
+		let config = match path.extension().and_then(|s| s.to_str()) {
+			Some("yaml") | Some("yml") => serde_yaml::from_reader(config_file)?,
+			Some("json") => serde_json::from_reader(config_file)?,
+			None | Some(_) => anyhow::bail!("unknown config file extension"),
+		};
+		Self::validate(&config)?;
+		Ok(config)
+	}
+
+	/// Rejects settings which parse but cannot work together.
+	pub fn validate(&self) -> anyhow::Result<()> {
+		self.smartplugs.validate()?;
+		if let Some(display) = &self.display {
+			display.validate()?;
+		}
+		Ok(())
+	}
+
+	/// Compares against a newly loaded configuration, sorting the sections
+	/// which differ by whether they can be applied while running.
+	pub fn changes(&self, new: &Config) -> ConfigChanges {
+		let mut changes = ConfigChanges::default();
+
+		let mut check = |name, differs, hot| {
+			if differs {
+				if hot {
+					changes.applied.push(name);
+				} else {
+					changes.restart_required.push(name);
+				}
+			}
+		};
+
+		check("tariff", self.tariff != new.tariff, true);
+		check("groups", self.groups != new.groups, true);
+		check("mqtt", self.mqtt != new.mqtt, false);
+		check("influxdb", self.influxdb != new.influxdb, false);
+		check("display", self.display != new.display, false);
+		check("query_api", self.query_api != new.query_api, false);
+		check("meter", self.meter != new.meter, false);
+		check("health", self.health != new.health, false);
+		check("smartplugs", self.smartplugs != new.smartplugs, false);
+		check("tasmota", self.tasmota != new.tasmota, false);
+		check(
+			"monitor_uptime",
+			self.monitor_uptime != new.monitor_uptime,
+			false,
+		);
+
+		changes
+	}
+}
+
+/// Configuration sections changed by a reload.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigChanges {
+	/// Sections which take effect immediately.
+	pub applied: Vec<&'static str>,
+	/// Sections which are ignored until fizzle is restarted.
+	pub restart_required: Vec<&'static str>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct MeterConfig {
+	/// Seconds without an impulse after which zero power is written, and
+	/// repeated at the same interval until impulses resume.
+	pub zero_power_after_secs: Option<u64>,
+
+	/// Seconds over which an impulse repeating the count and clock of an
+	/// earlier one is ignored as a duplicate.
+	pub dedup_window_secs: Option<u64>,
+
+	/// Timestamp impulses by each meter's clock, anchored to machine time
+	/// at its first impulse, unless it drifts from machine time by more than
+	/// this many milliseconds. Machine time is always used if unset.
+	pub device_clock_grace_ms: Option<u64>,
+
+	/// Impulse meters to read. Defaults to a single meter on
+	/// `meter-reader/impulse/raw`.
+	#[serde(default)]
+	pub meters: Vec<ImpulseMeterConfig>,
+}
+
+impl MeterConfig {
+	/// Returns the configured meters, or the default meter if none are.
+	pub fn meters(&self) -> Vec<ImpulseMeterConfig> {
+		if self.meters.is_empty() {
+			vec![ImpulseMeterConfig::default()]
+		} else {
+			self.meters.clone()
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ImpulseMeterConfig {
+	/// Topic the meter reader publishes impulses to.
+	pub topic: String,
+
+	/// Value of the `device` tag written with the meter's readings.
+	pub device: String,
+
+	/// The meter constant: impulses per kWh, printed on the meter.
+	#[serde(default = "default_impulses_per_kwh")]
+	pub impulses_per_kwh: u32,
+}
+
+fn default_impulses_per_kwh() -> u32 {
+	1000
+}
+
+impl Default for ImpulseMeterConfig {
+	fn default() -> Self {
+		Self {
+			topic: String::from("meter-reader/impulse/raw"),
+			device: String::from("garage/meter"),
+			impulses_per_kwh: default_impulses_per_kwh(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct HealthConfig {
+	/// Seconds between writes of fizzle's own health counters. Disabled if
+	/// unset.
+	pub interval_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct SmartPlugsConfig {
+	/// How telemetry is timestamped. `server_assigned` is refused, as writes
+	/// are batched and InfluxDB would give every point in a batch the same
+	/// time, so readings from one device would overwrite each other.
+	#[serde(default)]
+	pub timestamp_strategy: TimestampStrategy,
+
+	/// How to handle readings with the same timestamp as one already
+	/// received.
+	#[serde(default)]
+	pub duplicate_policy: DuplicatePolicy,
+
+	/// Timezone device clocks are set to: `local`, the default, `UTC` or an
+	/// offset such as `+10:00`.
+	#[serde(default)]
+	pub device_timezone: DeviceTimezone,
+
+	/// When to pause writes for a device whose points InfluxDB keeps
+	/// rejecting.
+	#[serde(default)]
+	pub circuit_breaker: CircuitBreakerConfig,
+
+	/// Derive apparent power and power factor for devices which do not
+	/// report them.
+	#[serde(default)]
+	pub derive_power: bool,
+
+	/// Most devices to adopt from their telemetry. Unlimited by default.
+	pub max_devices: Option<usize>,
+
+	/// Write sensor telemetry without waiting for matching state telemetry,
+	/// for devices which only report energy. State fields are omitted.
+	#[serde(default)]
+	pub sensor_only: bool,
+
+	/// Ask newly seen devices for their `Status 0` description.
+	#[serde(default)]
+	pub discovery: bool,
+
+	/// Write device diagnostics, such as MQTT reconnection counts and WiFi
+	/// signal strength, with each telemetry point.
+	#[serde(default)]
+	pub diagnostics: bool,
+
+	/// Also write energy in kWh, as `energy_kwh`, alongside `energy` in Wh.
+	#[serde(default)]
+	pub energy_kwh: bool,
+
+	/// Write diagnostics only with every Nth telemetry point, or at most
+	/// once per interval, per device. Power and energy are unaffected.
+	#[serde(default)]
+	pub diagnostics_sampling: DiagnosticsSampling,
+
+	/// Write a `reboot` event when a device announces it has booted.
+	#[serde(default)]
+	pub reboot_events: bool,
+
+	/// Decimal places to round named float fields to, such as
+	/// `current = 3`, to keep line protocol short.
+	#[serde(default)]
+	pub float_precision: BTreeMap<String, u32>,
+
+	/// File in which each device's cumulative cost is kept across restarts.
+	pub cost_state_path: Option<PathBuf>,
+
+	/// Write one aggregated telemetry point per device per interval rather
+	/// than every reading.
+	pub downsample: Option<DownsampleConfig>,
+
+	/// Seconds after which sensor or state telemetry still waiting for its
+	/// other half is dropped. Kept until matched if unset.
+	pub stale_telemetry_secs: Option<u64>,
+
+	/// Seconds a device's LWT must stand before an online/offline change is
+	/// reported, to ride out brief WiFi drops. Reported immediately if unset.
+	pub lwt_debounce_secs: Option<u64>,
+
+	/// How each device's `energy_today` is baselined on the day it is first
+	/// seen: `first_observation`, `midnight` or `query`. See
+	/// [`TodayBaseline`] for the accuracy of each.
+	#[serde(default)]
+	pub today_baseline: TodayBaseline,
+}
+
+impl SmartPlugsConfig {
+	/// Rejects a timestamp strategy the batched writer cannot honour.
+	pub fn validate(&self) -> anyhow::Result<()> {
+		if self.timestamp_strategy == TimestampStrategy::ServerAssigned {
+			anyhow::bail!(
+				"smartplugs.timestamp_strategy 'server_assigned' is not supported: writes are \
+				 batched, so InfluxDB would give every point in a batch the same time"
+			);
+		}
+		Ok(())
+	}
+}
+
+/// Where Tasmota devices publish, for devices whose topic or full topic
+/// setting differs from the default `tasmota/%prefix%/%topic%/` layout.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TasmotaConfig {
+	/// Topic templates for each message, with `%topic%` standing for the
+	/// device name.
+	#[serde(default)]
+	pub topics: TemplateTopicScheme,
+
+	/// Filters subscribed to for device telemetry.
+	#[serde(default = "default_telemetry_filters")]
+	pub subscribe: Vec<String>,
+
+	/// Filters subscribed to for `Status` responses when discovery is
+	/// enabled.
+	#[serde(default = "default_status_filters")]
+	pub status_subscribe: Vec<String>,
+}
+
+impl Default for TasmotaConfig {
+	fn default() -> Self {
+		Self {
+			topics: Default::default(),
+			subscribe: default_telemetry_filters(),
+			status_subscribe: default_status_filters(),
+		}
+	}
+}
+
+fn default_telemetry_filters() -> Vec<String> {
+	vec![String::from("tasmota/tele/#")]
 }
 
-#[derive(Debug, Deserialize)]
+fn default_status_filters() -> Vec<String> {
+	vec![
+		String::from("tasmota/stat/+/+/STATUS"),
+		String::from("tasmota/stat/+/+/STATUS0"),
+	]
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct MqttConfig {
 	pub host: String,
 	pub port: Option<u16>,
 
 	#[serde(default)]
 	pub tls: bool,
+
+	/// Client identifier presented to the broker. Defaults to one derived
+	/// from the hostname so it is stable across restarts and reconnects.
+	pub client_id: Option<String>,
+
+	/// Request a clean session on connect. Disabled by default so the broker
+	/// keeps our subscriptions and queued QoS 1/2 messages across reconnects.
+	#[serde(default)]
+	pub clean_session: bool,
+
+	/// How to retry the initial connection while the broker is unreachable,
+	/// such as when it is still starting up.
+	#[serde(default)]
+	pub startup_retry: Backoff,
+
+	/// How many messages each subscription buffers before the broker
+	/// connection waits for them to be handled.
+	#[serde(default)]
+	pub subscribe_buffers: SubscribeBuffers,
+}
+
+/// Channel sizes for each subscription. Bursty topics need more room,
+/// while quiet ones can make do with less.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct SubscribeBuffers {
+	/// Tasmota telemetry, from every smart plug.
+	pub telemetry: usize,
+	/// Tasmota `Status` responses, when discovery is enabled.
+	pub status: usize,
+	/// Control commands.
+	pub control: usize,
+	/// Impulse meter readings.
+	pub impulses: usize,
+	/// Impulse meter readings shown on the display.
+	pub display: usize,
+}
+
+impl Default for SubscribeBuffers {
+	fn default() -> Self {
+		Self {
+			telemetry: 64,
+			status: 8,
+			control: 8,
+			impulses: 8,
+			display: 8,
+		}
+	}
 }
 
-#[derive(Debug, Deserialize)]
+impl MqttConfig {
+	/// Returns the client identifier to use for every connection attempt.
+	pub fn client_id(&self) -> String {
+		if let Some(client_id) = &self.client_id {
+			return client_id.clone();
+		}
+
+		let hostname = std::env::var("HOSTNAME")
+			.ok()
+			.or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+			.map(|hostname| hostname.trim().to_string())
+			.filter(|hostname| !hostname.is_empty());
+
+		match hostname {
+			Some(hostname) => format!("fizzle-{hostname}"),
+			None => String::from("fizzle"),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct InfluxConfig {
 	pub host: Url,
 	pub bucket: String,
 	pub token: String,
 	pub org: String,
 	pub read_only: bool,
+
+	/// In read-only mode, write line protocol to rotating files in this
+	/// directory instead of printing it.
+	pub read_only_files: Option<RotatingFiles>,
+
+	/// Path to a PEM-encoded CA certificate to trust for the InfluxDB host.
+	pub ca_certificate: Option<PathBuf>,
+
+	/// Disable TLS certificate verification. Only for lab use.
+	#[serde(default)]
+	pub insecure_skip_verify: bool,
+
+	/// Maximum number of batches per second written while catching up on a
+	/// backlog.
+	pub max_flush_rate: Option<f64>,
+
+	/// Submit points strictly in the order they were written, even while
+	/// retrying after a failure, at the cost of throughput.
+	#[serde(default)]
+	pub strict_order: bool,
+
+	/// Directory in which buffered writes are logged until InfluxDB accepts
+	/// them, so they are written after a crash or restart. Each bucket
+	/// written to keeps its log in a subdirectory named after it.
+	pub wal_directory: Option<PathBuf>,
+
+	/// Sync each logged write to disk, so it also survives power loss.
+	#[serde(default)]
+	pub wal_fsync: bool,
+
+	/// Directory in which line protocol InfluxDB refuses is kept, for later
+	/// inspection or replay, rather than dropped. Each bucket written to
+	/// appends to `{bucket}.lp`.
+	pub dead_letter_directory: Option<PathBuf>,
+
+	/// Drop points older than each bucket's retention period when replaying
+	/// the write-ahead log, rather than sending points InfluxDB would
+	/// discard.
+	#[serde(default)]
+	pub respect_retention: bool,
+
+	/// Compress write request bodies with gzip. Defaults to true.
+	pub gzip: Option<bool>,
+
+	/// Smallest write request body, in bytes, which is compressed. Defaults
+	/// to 1024.
+	pub gzip_min_bytes: Option<usize>,
+
+	/// Maximum time, in seconds, a Flux query may take. Defaults to 30.
+	pub query_timeout_secs: Option<u64>,
+
+	/// Send line protocol to this socket, such as a Telegraf listener,
+	/// instead of the InfluxDB write API. Queries still use `host`.
+	/// Timestamps are in milliseconds, so set the listener's precision to
+	/// match.
+	pub sink: Option<LineProtocolSink>,
+
+	/// Additional sockets which receive a copy of every write. A failing
+	/// mirror does not hold up the others.
+	#[serde(default)]
+	pub mirrors: Vec<LineProtocolSink>,
+
+	/// Whether a write must be accepted by every destination, or just one,
+	/// when mirrors are configured.
+	#[serde(default)]
+	pub acceptance: AcceptancePolicy,
+
+	/// Buckets for categories of data which should not be written to
+	/// `bucket`. Each must already exist.
+	#[serde(default)]
+	pub buckets: BTreeMap<DataCategory, String>,
+}
+
+/// Local HTTP endpoint for running ad-hoc Flux queries.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct QueryApiConfig {
+	pub bind: SocketAddr,
+
+	/// Bearer token required on every request.
+	pub token: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct DisplayConfig {
 	pub topic: String,
+
+	/// Retain flag for every display publish, unless overridden below.
 	#[serde(default)]
 	pub retain: bool,
 
+	/// Retain flag for the live page.
+	pub page_retain: Option<bool>,
+
+	/// Retain flag for status messages, such as the shutdown page.
+	pub status_retain: Option<bool>,
+
+	/// Retain flag for button output, unless set on the button itself.
+	pub button_retain: Option<bool>,
+
 	pub meter_topic: String,
 	pub meter_device: String,
 
 	#[serde(default = "Vec::new")]
 	pub buttons: Vec<DisplayButtonConfig>,
+
+	/// How to retry a failed publish, such as during a broker reconnect.
+	/// Defaults to three attempts over a few hundred milliseconds, after
+	/// which the message is dropped.
+	#[serde(default = "default_publish_retry")]
+	pub publish_retry: Backoff,
+
+	/// Shown before yesterday's cost. A single character, so the line fits
+	/// the display.
+	#[serde(default = "default_currency_symbol")]
+	pub currency_symbol: char,
+}
+
+fn default_currency_symbol() -> char {
+	'$'
+}
+
+fn default_publish_retry() -> Backoff {
+	Backoff {
+		initial_delay_ms: 100,
+		max_delay_ms: 1_000,
+		max_attempts: Some(3),
+	}
 }
 
-#[derive(Clone, Debug, Deserialize)]
+impl DisplayConfig {
+	/// Rejects a publish retry which never gives up: a page stuck retrying
+	/// would hold up every reading after it.
+	pub fn validate(&self) -> anyhow::Result<()> {
+		if self.publish_retry.max_attempts.is_none() {
+			anyhow::bail!("display.publish_retry.max_attempts must be set");
+		}
+		Ok(())
+	}
+
+	pub fn page_retain(&self) -> bool {
+		self.page_retain.unwrap_or(self.retain)
+	}
+
+	pub fn status_retain(&self) -> bool {
+		self.status_retain.unwrap_or(self.retain)
+	}
+
+	/// Buttons are not retained unless configured, regardless of `retain`.
+	pub fn button_retain(&self, button: &DisplayButtonConfig) -> bool {
+		button.retain.or(self.button_retain).unwrap_or(false)
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct DisplayButtonConfig {
 	pub topic: String,
 	pub output_topic: String,
 	pub output_payload: Option<String>,
+	pub retain: Option<bool>,
+}
 
-	#[serde(default)]
-	pub retain: bool,
+#[cfg(test)]
+mod tests {
+	use super::{DisplayConfig, MqttConfig, SmartPlugsConfig, SubscribeBuffers};
+
+	fn mqtt_config(client_id: Option<&str>) -> MqttConfig {
+		MqttConfig {
+			host: String::from("localhost"),
+			port: None,
+			tls: false,
+			client_id: client_id.map(String::from),
+			clean_session: false,
+			startup_retry: Default::default(),
+			subscribe_buffers: Default::default(),
+		}
+	}
+
+	#[test]
+	fn configured_client_id_is_used() {
+		let config = mqtt_config(Some("meter-agent"));
+		assert_eq!(config.client_id(), "meter-agent");
+	}
+
+	#[test]
+	fn generated_client_id_is_stable() {
+		let config = mqtt_config(None);
+		let first = config.client_id();
+		assert!(first.starts_with("fizzle"));
+		assert_eq!(config.client_id(), first);
+	}
+
+	#[test]
+	fn subscribe_buffers_default_to_previous_sizes() {
+		let config: MqttConfig = serde_yaml::from_str("host: localhost\n").unwrap();
+		assert_eq!(config.subscribe_buffers, SubscribeBuffers::default());
+		assert_eq!(config.subscribe_buffers.telemetry, 64);
+
+		let config: MqttConfig = serde_yaml::from_str(
+			"host: localhost\nsubscribe_buffers:\n  telemetry: 512\n  control: 1\n",
+		)
+		.unwrap();
+		assert_eq!(
+			config.subscribe_buffers,
+			SubscribeBuffers {
+				telemetry: 512,
+				control: 1,
+				..Default::default()
+			}
+		);
+	}
+
+	#[test]
+	fn server_assigned_timestamps_are_refused() {
+		let config: SmartPlugsConfig =
+			serde_yaml::from_str("timestamp_strategy: server_assigned\n").unwrap();
+		assert!(config.validate().is_err());
+
+		let config: SmartPlugsConfig =
+			serde_yaml::from_str("timestamp_strategy: prefer_machine\n").unwrap();
+		assert!(config.validate().is_ok());
+	}
+
+	#[test]
+	fn shutdown_uses_status_retain() {
+		let config: DisplayConfig = serde_yaml::from_str(
+			"topic: display\nmeter_topic: meter\nmeter_device: garage/meter\nretain: true\nstatus_retain: false\n",
+		)
+		.unwrap();
+		assert!(config.page_retain());
+		assert!(!config.status_retain());
+
+		let config: DisplayConfig = serde_yaml::from_str(
+			"topic: display\nmeter_topic: meter\nmeter_device: garage/meter\nstatus_retain: true\n",
+		)
+		.unwrap();
+		assert!(!config.page_retain());
+		assert!(config.status_retain());
+	}
+
+	#[test]
+	fn unbounded_publish_retry_is_refused() {
+		let config: DisplayConfig = serde_yaml::from_str(
+			"topic: display\nmeter_topic: meter\nmeter_device: garage/meter\npublish_retry:\n  initial_delay_ms: 100\n",
+		)
+		.unwrap();
+		assert!(config.validate().is_err());
+
+		let config: DisplayConfig = serde_yaml::from_str(
+			"topic: display\nmeter_topic: meter\nmeter_device: garage/meter\n",
+		)
+		.unwrap();
+		assert!(config.validate().is_ok());
+	}
 }
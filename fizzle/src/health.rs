@@ -0,0 +1,277 @@
+use influxdb::{LineBuilder, Status};
+use std::{
+	collections::BTreeMap,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Mutex,
+	},
+	time::Instant,
+};
+use tokio::sync::watch;
+
+/// Most topics whose message counts are tracked at once. The least recently
+/// seen topic is forgotten to make room for a new one.
+const MAX_TRACKED_TOPICS: usize = 256;
+
+/// Number of the busiest topics written with each health point.
+const TOP_TOPICS: usize = 10;
+
+/// Counters describing fizzle's own health, shared between the tasks which
+/// update them and the task which periodically writes them.
+#[derive(Debug)]
+pub struct HealthStats {
+	started: Instant,
+	messages_processed: AtomicU64,
+	write_failures: AtomicU64,
+	pending: Mutex<Vec<watch::Receiver<Status>>>,
+	topics: Mutex<TopicCounts>,
+}
+
+/// Message counts for recently seen topics.
+#[derive(Debug)]
+struct TopicCounts {
+	counts: BTreeMap<String, TopicCount>,
+	/// Incremented with every message, to order topics by when last seen.
+	sequence: u64,
+	window_started: Instant,
+}
+
+#[derive(Debug, Default)]
+struct TopicCount {
+	/// Messages since the counts were last written.
+	window: u64,
+	last_seen: u64,
+}
+
+impl TopicCounts {
+	fn increment(&mut self, topic: &str) {
+		self.sequence += 1;
+		if let Some(count) = self.counts.get_mut(topic) {
+			count.window += 1;
+			count.last_seen = self.sequence;
+			return;
+		}
+
+		if self.counts.len() >= MAX_TRACKED_TOPICS {
+			let least_recent = self
+				.counts
+				.iter()
+				.min_by_key(|(_, count)| count.last_seen)
+				.map(|(topic, _)| topic.clone());
+			if let Some(least_recent) = least_recent {
+				self.counts.remove(&least_recent);
+			}
+		}
+		self.counts.insert(
+			topic.to_string(),
+			TopicCount {
+				window: 1,
+				last_seen: self.sequence,
+			},
+		);
+	}
+}
+
+impl Default for HealthStats {
+	fn default() -> Self {
+		Self {
+			started: Instant::now(),
+			messages_processed: AtomicU64::new(0),
+			write_failures: AtomicU64::new(0),
+			pending: Mutex::new(Vec::new()),
+			topics: Mutex::new(TopicCounts {
+				counts: BTreeMap::new(),
+				sequence: 0,
+				window_started: Instant::now(),
+			}),
+		}
+	}
+}
+
+impl HealthStats {
+	/// Counts a received MQTT message.
+	pub fn message_processed(&self) {
+		self.messages_processed.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Counts a received MQTT message, and the messages received on `topic`.
+	pub fn message_received(&self, topic: &str) {
+		self.message_processed();
+		self.topics.lock().unwrap().increment(topic);
+	}
+
+	/// Tracks the outcome of a write, counting it as a failure if InfluxDB
+	/// rejects it. Writes already settled are counted and forgotten first,
+	/// so the tracked writes are bounded by those still queued.
+	pub fn track(&self, status: watch::Receiver<Status>) {
+		let mut pending = self.pending.lock().unwrap();
+		self.settle_pending(&mut pending);
+		pending.push(status);
+	}
+
+	pub fn messages_processed(&self) -> u64 {
+		self.messages_processed.load(Ordering::Relaxed)
+	}
+
+	/// Returns the number of tracked writes rejected so far.
+	pub fn write_failures(&self) -> u64 {
+		self.settle();
+		self.write_failures.load(Ordering::Relaxed)
+	}
+
+	/// Writes the current counters as a `fizzle` point. `buffer_depth` is the
+	/// number of writes waiting in the write client's queue.
+	///
+	/// The busiest topics since the previous write follow, each as a point
+	/// tagged `reason=topic` with its message count and rate per second.
+	pub fn write_line_protocol_with(
+		&self,
+		buffer_depth: usize,
+	) -> impl FnOnce(LineBuilder) -> LineBuilder {
+		let write_failures = self.write_failures();
+		let messages_processed = self.messages_processed();
+		let uptime = self.started.elapsed().as_secs();
+		let busiest = self.take_busiest_topics();
+
+		move |builder| {
+			let mut builder = builder
+				.measurement("fizzle")
+				.tag("reason", "health")
+				.field("buffer_depth", buffer_depth as u64)
+				.field("messages_processed", messages_processed)
+				.field("uptime", uptime)
+				.field("write_failures", write_failures)
+				.close_line();
+			for (topic, messages, rate) in busiest {
+				builder = builder
+					.measurement("fizzle")
+					.tag("reason", "topic")
+					.tag("topic", &topic)
+					.field("messages", messages)
+					.field("rate", rate)
+					.close_line();
+			}
+			builder
+		}
+	}
+
+	/// Returns the busiest topics since the last call, with their message
+	/// counts and rates, and starts a new window.
+	fn take_busiest_topics(&self) -> Vec<(String, u64, f64)> {
+		let mut topics = self.topics.lock().unwrap();
+		let elapsed = topics.window_started.elapsed().as_secs_f64();
+		topics.window_started = Instant::now();
+
+		let mut busiest: Vec<_> = topics
+			.counts
+			.iter_mut()
+			.filter(|(_, count)| count.window > 0)
+			.map(|(topic, count)| {
+				let messages = std::mem::take(&mut count.window);
+				(topic.clone(), messages)
+			})
+			.collect();
+		busiest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+		busiest.truncate(TOP_TOPICS);
+
+		busiest
+			.into_iter()
+			.map(|(topic, messages)| {
+				let rate = if elapsed > 0.0 {
+					messages as f64 / elapsed
+				} else {
+					0.0
+				};
+				(topic, messages, rate)
+			})
+			.collect()
+	}
+
+	fn settle(&self) {
+		let mut pending = self.pending.lock().unwrap();
+		self.settle_pending(&mut pending);
+	}
+
+	fn settle_pending(&self, pending: &mut Vec<watch::Receiver<Status>>) {
+		pending.retain(|status| match *status.borrow() {
+			Status::Accepted | Status::Deferred => false,
+			Status::Rejected => {
+				self.write_failures.fetch_add(1, Ordering::Relaxed);
+				false
+			}
+			// Still queued, unless the write task has gone away.
+			Status::Init | Status::Buffered => status.has_changed().is_ok(),
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{HealthStats, MAX_TRACKED_TOPICS};
+	use influxdb::Status;
+	use tokio::sync::watch;
+
+	#[test]
+	fn settled_writes_are_not_kept() {
+		let health = HealthStats::default();
+		for status in [Status::Accepted, Status::Rejected, Status::Accepted] {
+			let (tx, rx) = watch::channel(Status::Buffered);
+			health.track(rx);
+			tx.send_replace(status);
+		}
+		let (_queued, rx) = watch::channel(Status::Buffered);
+		health.track(rx);
+
+		// Only the last, still queued, write is waiting to be counted.
+		assert_eq!(health.pending.lock().unwrap().len(), 1);
+		assert_eq!(health.write_failures(), 1);
+	}
+
+	#[test]
+	fn topic_counts_are_tracked() {
+		let health = HealthStats::default();
+		for topic in [
+			"tasmota/tele/kitchen/kettle/SENSOR",
+			"tasmota/tele/kitchen/kettle/STATE",
+			"tasmota/tele/kitchen/kettle/SENSOR",
+			"meter-reader/impulse/raw",
+			"tasmota/tele/kitchen/kettle/SENSOR",
+		] {
+			health.message_received(topic);
+		}
+
+		assert_eq!(health.messages_processed(), 5);
+		let busiest = |health: &HealthStats| -> Vec<(String, u64)> {
+			health
+				.take_busiest_topics()
+				.into_iter()
+				.map(|(topic, messages, _)| (topic, messages))
+				.collect()
+		};
+		assert_eq!(
+			busiest(&health),
+			vec![
+				(String::from("tasmota/tele/kitchen/kettle/SENSOR"), 3),
+				(String::from("meter-reader/impulse/raw"), 1),
+				(String::from("tasmota/tele/kitchen/kettle/STATE"), 1),
+			]
+		);
+
+		// The least recently seen topic makes way for new ones.
+		for index in 0..MAX_TRACKED_TOPICS {
+			health.message_received(&format!("topic/{index}"));
+			health.message_received("tasmota/tele/kitchen/kettle/SENSOR");
+		}
+		assert_eq!(
+			health.topics.lock().unwrap().counts.len(),
+			MAX_TRACKED_TOPICS
+		);
+		assert_eq!(
+			busiest(&health)[0],
+			(
+				String::from("tasmota/tele/kitchen/kettle/SENSOR"),
+				MAX_TRACKED_TOPICS as u64
+			)
+		);
+	}
+}
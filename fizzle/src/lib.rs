@@ -1,2 +1,8 @@
+pub mod capture;
+pub mod health;
+pub mod monitor;
+pub mod retry;
+pub mod routing;
 pub mod smartplugs;
+pub mod tariff;
 pub mod util;
@@ -3,24 +3,60 @@ mod tasks;
 
 use clap::Parser;
 use config::Config;
-use fizzle::smartplugs::{topic::HomeTasmotaTopicScheme, SmartPlugSwarm};
-use influxdb::{util::stdout_buffered_client, Client as InfluxDbClient, Precision};
+use fizzle::{
+	capture::{self, Recorder},
+	health::HealthStats,
+	routing::{self, DataCategory},
+	smartplugs::{
+		baseline::{self, TodayBaseline},
+		topic::TemplateTopicScheme,
+		SmartPlugSwarm, CONTROL_TOPIC_FILTER,
+	},
+	tariff,
+	util::{local_offset_at, message_span},
+};
+use influxdb::{
+	buffered, immediate, util::stdout_buffered_client, Client as InfluxDbClient, MeasurementRouter,
+	MultiSink, Precision,
+};
 use mqtt::{
 	clients::tokio::{tcp_client, Options},
-	FilterBuf,
+	QoS,
 };
 use std::{
-	fs::File,
+	collections::BTreeMap,
 	path::{Path, PathBuf},
 	sync::Arc,
+	time::Duration,
+};
+use time::{util::local_offset::Soundness, OffsetDateTime};
+use tokio::{
+	sync::{mpsc, watch},
+	task::JoinHandle,
+	time::interval,
 };
-use time::util::local_offset::Soundness;
-use tokio::sync::watch;
+use tracing::Instrument;
+
+/// How often unmatched smart plug telemetry is checked for staleness.
+const STALE_SWEEP_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How often cumulative costs are saved, besides at shutdown, bounding what
+/// a crash loses.
+const COST_SAVE_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Parser)]
 pub struct Arguments {
 	#[clap(env = "FIZZLE_CONFIG_PATH")]
 	config: PathBuf,
+
+	/// Append incoming smart plug messages to this capture file.
+	#[clap(long)]
+	record: Option<PathBuf>,
+
+	/// Replay a capture file through the smart plug handlers, printing the
+	/// resulting line protocol instead of writing it, then exit.
+	#[clap(long, conflicts_with = "record")]
+	replay: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -36,23 +72,150 @@ async fn main() -> anyhow::Result<()> {
 	let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
 	// Read the configuration file
-	let config = load_config(arguments.config)?;
+	let config = Arc::new(Config::load(&arguments.config)?);
+	if let Some(path) = &arguments.replay {
+		return replay(path, &config).await;
+	}
+
+	let (config_tx, mut config_rx) = watch::channel(Arc::clone(&config));
+
+	// Reload the runtime-changeable parts of the configuration on SIGHUP.
+	#[cfg(unix)]
+	let reload_task =
+		tasks::reload::create_task(arguments.config.clone(), config_tx, shutdown_rx.clone())?;
+	#[cfg(not(unix))]
+	drop(config_tx);
 
 	// Setup the InfluxDB client.
-	let influxdb_client =
-		InfluxDbClient::new(config.influxdb.host.clone(), &config.influxdb.token)?;
-	let query_client = influxdb_client.query_client().org(&config.influxdb.org);
+	let mut influxdb_client_builder =
+		InfluxDbClient::builder(config.influxdb.host.clone(), &config.influxdb.token)
+			.danger_accept_invalid_certs(config.influxdb.insecure_skip_verify);
+	if let Some(path) = &config.influxdb.ca_certificate {
+		influxdb_client_builder = influxdb_client_builder.ca_certificate(path);
+	}
+	let influxdb_client = influxdb_client_builder.build()?;
+	let mut query_client = influxdb_client.query_client().org(&config.influxdb.org);
+	if let Some(secs) = config.influxdb.query_timeout_secs {
+		query_client = query_client.timeout(Duration::from_secs(secs));
+	}
+	tracing::info!("querying InfluxDB at '{}'", query_client.url());
+
+	// A write task which stops by itself, such as after InfluxDB refuses the
+	// token, cannot recover. Shut down and exit with an error, so whatever
+	// supervises fizzle can alert and restart it with the replaced token.
+	let (write_failed_tx, mut write_failed_rx) = mpsc::channel(1);
+	let supervise = |task: JoinHandle<anyhow::Result<()>>| {
+		let write_failed = write_failed_tx.clone();
+		tokio::spawn(async move {
+			let result = task.await?;
+			if let Err(error) = &result {
+				let _ = write_failed.try_send(error.to_string());
+			}
+			result
+		})
+	};
 	//
-	let (write_client, influxdb_task) = if !config.influxdb.read_only {
-		influxdb_client
-			.write_to_bucket(&config.influxdb.bucket)
-			.org(&config.influxdb.org)
-			.precision(Precision::Milliseconds)
-			.build()
-			.buffered(shutdown_rx.clone())
+	let (write_client, influxdb_task) = if config.influxdb.read_only {
+		match config.influxdb.read_only_files.clone() {
+			Some(files) => files.buffered(shutdown_rx.clone()),
+			None => stdout_buffered_client(),
+		}
+	} else if let Some(sink) = config.influxdb.sink.clone() {
+		sink.buffered(shutdown_rx.clone())
 	} else {
-		stdout_buffered_client()
+		// Fail fast, rather than buffering writes for a host we cannot reach.
+		let version = influxdb_client.ping().await.map_err(|error| {
+			anyhow::anyhow!(
+				"InfluxDB at '{}' is not reachable: {error}",
+				influxdb_client.host()
+			)
+		})?;
+		tracing::info!(
+			"connected to InfluxDB {} at '{}'",
+			version.as_deref().unwrap_or("(unknown version)"),
+			influxdb_client.host()
+		);
+
+		let write_to_bucket = |bucket: &str, max_point_age: Option<Duration>| {
+			let client = influxdb_client
+				.write_to_bucket(bucket)
+				.org(&config.influxdb.org)
+				.precision(Precision::Milliseconds)
+				.gzip(config.influxdb.gzip.unwrap_or(true))
+				.gzip_min_len(
+					config
+						.influxdb
+						.gzip_min_bytes
+						.unwrap_or(immediate::DEFAULT_GZIP_MIN_LEN),
+				)
+				.build();
+			tracing::info!("writing to InfluxDB at '{}'", client.write_url());
+			let (client, task) = client.buffered_with(
+				shutdown_rx.clone(),
+				buffered::Options {
+					max_flush_rate: config.influxdb.max_flush_rate,
+					strict_order: config.influxdb.strict_order,
+					wal_directory: config
+						.influxdb
+						.wal_directory
+						.as_ref()
+						.map(|directory| directory.join(bucket)),
+					wal_fsync: config.influxdb.wal_fsync,
+					dead_letter_path: config
+						.influxdb
+						.dead_letter_directory
+						.as_ref()
+						.map(|directory| directory.join(format!("{bucket}.lp"))),
+					max_point_age,
+					..Default::default()
+				},
+			);
+			(client, supervise(task))
+		};
+
+		let max_point_age =
+			retention_limit(&influxdb_client, &config, &config.influxdb.bucket).await;
+		let (client, task) = write_to_bucket(&config.influxdb.bucket, max_point_age);
+		if config.influxdb.buckets.is_empty() {
+			(client, task)
+		} else {
+			// Route each category of data to its bucket, sharing a client
+			// between categories with the same bucket.
+			let mut categories: BTreeMap<&str, Vec<DataCategory>> = BTreeMap::new();
+			for (category, bucket) in &config.influxdb.buckets {
+				categories
+					.entry(bucket.as_str())
+					.or_default()
+					.push(*category);
+			}
+
+			let mut router = MeasurementRouter::new(client, task);
+			for (bucket, categories) in categories {
+				if !influxdb_client
+					.bucket_exists(&config.influxdb.org, bucket)
+					.await?
+				{
+					anyhow::bail!("InfluxDB bucket '{bucket}' does not exist");
+				}
+				let max_point_age = retention_limit(&influxdb_client, &config, bucket).await;
+				let (client, task) = write_to_bucket(bucket, max_point_age);
+				router = routing::with_categories(router, &categories, client, task);
+			}
+			router.buffered(shutdown_rx.clone())
+		}
 	};
+	let (write_client, influxdb_task) =
+		if config.influxdb.read_only || config.influxdb.mirrors.is_empty() {
+			(write_client, influxdb_task)
+		} else {
+			let mut multi_sink =
+				MultiSink::new(config.influxdb.acceptance).with_sink(write_client, influxdb_task);
+			for mirror in config.influxdb.mirrors.iter().cloned() {
+				let (client, task) = mirror.buffered(shutdown_rx.clone());
+				multi_sink = multi_sink.with_sink(client, task);
+			}
+			multi_sink.buffered(shutdown_rx.clone())
+		};
 
 	write_client
 		.write_with(|builder| {
@@ -64,48 +227,207 @@ async fn main() -> anyhow::Result<()> {
 		})
 		.await?;
 
-	// Spawn a task to handle incoming MQTT messages
+	// Periodically write fizzle's own health, unless read-only.
 	//
-	let options = Options {
+	let health = Arc::new(HealthStats::default());
+	let health_task = match config.health.interval_secs {
+		Some(secs) if !config.influxdb.read_only => Some(tasks::health::create_task(
+			write_client.clone(),
+			Arc::clone(&health),
+			Duration::from_secs(secs),
+			shutdown_rx.clone(),
+		)),
+		_ => None,
+	};
+
+	// Connect to the MQTT broker, waiting for it if it is not up yet.
+	//
+	let options = || Options {
 		host: config.mqtt.host.clone(),
 		port: config
 			.mqtt
 			.port
 			.unwrap_or_else(|| if config.mqtt.tls { 8883 } else { 1883 }),
 		tls: config.mqtt.tls,
+		client_id: config.mqtt.client_id(),
+		clean_session: config.mqtt.clean_session,
 		..Default::default()
 	};
-	let (mqtt_client, handle) = tcp_client(options);
+	let buffers = config.mqtt.subscribe_buffers;
+	let telemetry_filters: Vec<&str> = config
+		.tasmota
+		.subscribe
+		.iter()
+		.map(String::as_str)
+		.collect();
+	let (mqtt_client, handle, mut tasmota_rx) = config
+		.mqtt
+		.startup_retry
+		.retry("connecting to the MQTT broker", || {
+			let options = options();
+			let filters = telemetry_filters.clone();
+			async move {
+				let (client, handle) = tcp_client(options);
+				match client
+					.subscribe(filters.as_slice(), buffers.telemetry)
+					.await
+				{
+					Ok(tasmota_rx) => Ok((client, handle, tasmota_rx)),
+					Err(error) => {
+						// Stop the failed client before trying again with the same client_id.
+						handle.abort();
+						Err(error)
+					}
+				}
+			}
+		})
+		.await?;
 
 	// Spawn the smart-meter task.
 	//
 	let smart_meter_task = tokio::spawn(tasks::smart_meter::smart_meter_task(
 		mqtt_client.clone(),
 		write_client.clone(),
-		FilterBuf::new("meter-reader/impulse/raw")?,
+		config.meter.clone(),
+		config.monitor_uptime,
+		buffers.impulses,
+		Arc::clone(&health),
 	));
 
 	// Spawn a task to drive the character display device
 	//
 	let display_task = tasks::display::create_task(
 		mqtt_client.clone(),
-		query_client,
+		query_client.clone(),
+		config_rx.clone(),
+		shutdown_rx.clone(),
+	);
+
+	// Spawn a task to serve ad-hoc queries, if configured.
+	//
+	let query_api_task = tasks::query_api::create_task(
+		query_client.clone(),
 		Arc::clone(&config),
 		shutdown_rx.clone(),
 	);
 
 	// Create the smart plug swarm!
-	let mut tasmota_rx = mqtt_client.subscribe("tasmota/tele/#", 64).await?;
-	let mut swarm: SmartPlugSwarm<HomeTasmotaTopicScheme> =
-		SmartPlugSwarm::new(write_client.clone());
+	let mut swarm = build_swarm(write_client.clone(), &config);
+	if health_task.is_some() {
+		swarm = swarm.with_health(Arc::clone(&health));
+	}
+	if let Some(path) = &config.smartplugs.cost_state_path {
+		swarm = swarm.with_costs(tariff::load_costs(path)?);
+	}
+	if config.smartplugs.today_baseline == TodayBaseline::Query {
+		let now = OffsetDateTime::now_utc();
+		let today = now.to_offset(local_offset_at(now)).date();
+		let bucket = config
+			.influxdb
+			.buckets
+			.get(&DataCategory::Telemetry)
+			.unwrap_or(&config.influxdb.bucket);
+		let seeded = match yesterday::day_bounds(today) {
+			Ok((day_start, _)) => {
+				baseline::fetch_energy_today(&query_client, bucket, day_start).await
+			}
+			Err(error) => Err(error),
+		};
+		match seeded {
+			Ok(energy_today) => {
+				tracing::info!("resuming today's energy for {} devices", energy_today.len());
+				swarm.seed_energy_today(today, energy_today);
+			}
+			Err(error) => tracing::warn!(
+				"failed to fetch today's energy, counting from the first reading: {error:?}"
+			),
+		}
+	}
+	let mut status_rx = if config.smartplugs.discovery {
+		let filters: Vec<&str> = config
+			.tasmota
+			.status_subscribe
+			.iter()
+			.map(String::as_str)
+			.collect();
+		Some(
+			mqtt_client
+				.subscribe(filters.as_slice(), buffers.status)
+				.await?,
+		)
+	} else {
+		None
+	};
+	let mut control_rx = mqtt_client
+		.subscribe(CONTROL_TOPIC_FILTER, buffers.control)
+		.await?;
+	let mut recorder = arguments
+		.record
+		.as_deref()
+		.map(Recorder::open)
+		.transpose()?;
 
+	let mut stale_sweep = interval(STALE_SWEEP_INTERVAL);
+	let mut cost_save = interval(COST_SAVE_INTERVAL);
+	let mut write_failed = None;
+	let downsample = config.smartplugs.downsample;
+	let mut downsample_flush = interval(Duration::from_secs(
+		downsample.map_or(60, |downsample| downsample.interval_secs.max(1)),
+	));
 	loop {
 		tokio::select! {
 			Some(message) = tasmota_rx.recv() => {
-				let Err(error) = swarm.handle_telemetry(message).await else {
-					continue
-				};
-				tracing::error!("error handling telemetry: {error:?}");
+				health.message_received(message.topic.as_str());
+				if let Some(recorder) = &mut recorder {
+					let now = OffsetDateTime::now_utc();
+					if let Err(error) = recorder.record(now, message.topic.as_str(), &message.payload) {
+						tracing::warn!("failed to record message: {error:?}");
+					}
+				}
+
+				let span = message_span(message.topic.as_str());
+				if let Err(error) = swarm.handle_telemetry(message).instrument(span).await {
+					tracing::error!("error handling telemetry: {error:?}");
+				}
+				for (topic, payload) in swarm.take_discovery_commands() {
+					// Discovery is retried on the next poll, so a lost request
+					// must not skip the graceful shutdown.
+					if let Err(error) = mqtt_client.publish(topic.as_str(), payload, QoS::AtMostOnce, false).await {
+						tracing::warn!("failed to publish discovery request to '{topic}': {error:?}");
+					}
+				}
+			}
+			Some(message) = async { status_rx.as_mut()?.recv().await } => {
+				health.message_received(message.topic.as_str());
+				let span = message_span(message.topic.as_str());
+				if let Err(error) = swarm.handle_telemetry(message).instrument(span).await {
+					tracing::error!("error handling status response: {error:?}");
+				}
+			}
+			Some(message) = control_rx.recv() => {
+				swarm.handle_control(message.topic.as_str());
+			}
+			_ = stale_sweep.tick() => {
+				swarm.sweep_stale();
+			}
+			_ = cost_save.tick(), if config.smartplugs.cost_state_path.is_some() => {
+				save_costs(&config, &swarm);
+			}
+			_ = downsample_flush.tick(), if downsample.is_some() => {
+				if let Err(error) = swarm.flush_stale_downsampled().await {
+					tracing::error!("failed to write downsampled telemetry: {error:?}");
+				}
+			}
+			Ok(()) = config_rx.changed() => {
+				let config = config_rx.borrow();
+				swarm.set_groups(config.groups.clone());
+				swarm.set_tariff(config.tariff.clone());
+			}
+			Some(error) = write_failed_rx.recv() => {
+				tracing::error!("writes to InfluxDB stopped ({error}), shutting down");
+				shutdown_tx.send(true)?;
+				write_failed = Some(error);
+				break
 			}
 			_ = tokio::signal::ctrl_c() => {
 				tracing::debug!("received ctrl-c, closing");
@@ -115,6 +437,10 @@ async fn main() -> anyhow::Result<()> {
 		}
 	}
 
+	if let Err(error) = swarm.flush_downsampled().await {
+		tracing::error!("failed to write downsampled telemetry: {error:?}");
+	}
+	save_costs(&config, &swarm);
 	drop(swarm);
 	drop(write_client);
 
@@ -123,19 +449,105 @@ async fn main() -> anyhow::Result<()> {
 
 	influxdb_task.await??;
 	display_task.await??;
+	query_api_task.await??;
 	smart_meter_task.await??;
+	if let Some(health_task) = health_task {
+		health_task.await??;
+	}
+	#[cfg(unix)]
+	reload_task.await??;
 
-	Ok(())
+	match write_failed {
+		Some(error) => Err(anyhow::anyhow!("writes to InfluxDB stopped: {error}")),
+		None => Ok(()),
+	}
 }
 
-fn load_config<T: AsRef<Path>>(path: T) -> anyhow::Result<Arc<Config>> {
-	let path = path.as_ref();
-	let config_file = File::open(path)?;
-	let config = match path.extension().and_then(|s| s.to_str()) {
-		Some("yaml") | Some("yml") => serde_yaml::from_reader(config_file)?,
-		Some("json") => serde_json::from_reader(config_file)?,
-		None | Some(_) => panic!("unknown config file extension"),
-	};
-	let config = Arc::new(config);
-	Ok(config)
+fn build_swarm(
+	write_client: buffered::Client,
+	config: &Config,
+) -> SmartPlugSwarm<TemplateTopicScheme> {
+	SmartPlugSwarm::with_topic_scheme(write_client, config.tasmota.topics.clone())
+		.with_timestamp_strategy(config.smartplugs.timestamp_strategy)
+		.with_duplicate_policy(config.smartplugs.duplicate_policy)
+		.with_device_timezone(config.smartplugs.device_timezone)
+		.with_max_devices(config.smartplugs.max_devices)
+		.with_circuit_breaker(config.smartplugs.circuit_breaker)
+		.with_derived_power(config.smartplugs.derive_power)
+		.with_sensor_only(config.smartplugs.sensor_only)
+		.with_discovery(config.smartplugs.discovery)
+		.with_diagnostics(config.smartplugs.diagnostics)
+		.with_energy_kwh(config.smartplugs.energy_kwh)
+		.with_diagnostics_sampling(config.smartplugs.diagnostics_sampling)
+		.with_reboot_events(config.smartplugs.reboot_events)
+		.with_float_precision(config.smartplugs.float_precision.clone())
+		.with_downsample(config.smartplugs.downsample)
+		.with_stale_after(
+			config
+				.smartplugs
+				.stale_telemetry_secs
+				.map(|secs| time::Duration::seconds(secs as i64)),
+		)
+		.with_lwt_debounce(
+			config
+				.smartplugs
+				.lwt_debounce_secs
+				.map(|secs| time::Duration::seconds(secs as i64)),
+		)
+		.with_today_baseline(config.smartplugs.today_baseline)
+		.with_monitor_uptime(config.monitor_uptime)
+		.with_groups(config.groups.clone())
+		.with_tariff(config.tariff.clone())
+}
+
+/// Saves the swarm's cumulative costs, if configured to.
+fn save_costs(config: &Config, swarm: &SmartPlugSwarm<TemplateTopicScheme>) {
+	if let Some(path) = &config.smartplugs.cost_state_path {
+		if let Err(error) = tariff::save_costs(path, swarm.costs()) {
+			tracing::error!("failed to save cumulative costs: {error:?}");
+		}
+	}
+}
+
+/// Returns the age beyond which points replayed for `bucket` are dropped:
+/// its retention period, if configured to respect it.
+async fn retention_limit(
+	influxdb_client: &InfluxDbClient,
+	config: &Config,
+	bucket: &str,
+) -> Option<Duration> {
+	if !config.influxdb.respect_retention {
+		return None;
+	}
+	match influxdb_client
+		.bucket_retention(&config.influxdb.org, bucket)
+		.await
+	{
+		Ok(retention) => {
+			tracing::info!("retention period of InfluxDB bucket '{bucket}' is {retention:?}");
+			retention
+		}
+		Err(error) => {
+			tracing::warn!(
+				"failed to read the retention period of InfluxDB bucket '{bucket}': {error:?}"
+			);
+			None
+		}
+	}
+}
+
+/// Replays a capture through a swarm which prints its line protocol.
+async fn replay(path: &Path, config: &Config) -> anyhow::Result<()> {
+	let messages = capture::read_capture(path)?;
+	let (write_client, write_task) = stdout_buffered_client();
+
+	let mut swarm = build_swarm(write_client, config);
+	let count = capture::replay(&mut swarm, messages).await;
+	tracing::info!("replayed {count} messages from {}", path.display());
+	if let Err(error) = swarm.flush_downsampled().await {
+		tracing::error!("failed to write downsampled telemetry: {error:?}");
+	}
+
+	drop(swarm);
+	write_task.await?
 }
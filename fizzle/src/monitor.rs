@@ -0,0 +1,75 @@
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+/// How fizzle reports how long it has been monitoring a source.
+///
+/// Each source (the impulse meter, or a smart plug) is stamped with the time
+/// fizzle first observed it. Restarting fizzle restamps every source.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MonitorUptime {
+	/// Write `monitor_uptime`: whole seconds since the source was first
+	/// observed. Drops to zero whenever fizzle restarts.
+	#[default]
+	Uptime,
+	/// Write `monitor_start`: the Unix timestamp, in seconds, at which the
+	/// source was first observed. Constant for the life of the process.
+	StartTime,
+	/// Write neither field.
+	Omit,
+}
+
+impl MonitorUptime {
+	/// Returns the field key and value to write, if any, for a source first
+	/// observed at `started`.
+	pub fn field(&self, started: OffsetDateTime) -> Option<(&'static str, u64)> {
+		self.field_at(started, OffsetDateTime::now_utc())
+	}
+
+	pub fn field_at(
+		&self,
+		started: OffsetDateTime,
+		now: OffsetDateTime,
+	) -> Option<(&'static str, u64)> {
+		match self {
+			Self::Uptime => {
+				let uptime = (now - started).whole_seconds().max(0) as u64;
+				Some(("monitor_uptime", uptime))
+			}
+			Self::StartTime => Some(("monitor_start", started.unix_timestamp().max(0) as u64)),
+			Self::Omit => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::MonitorUptime;
+	use time::macros::datetime;
+
+	#[test]
+	fn uptime() {
+		let started = datetime!(2023-10-04 12:00 UTC);
+		let now = datetime!(2023-10-04 12:01:30 UTC);
+		assert_eq!(
+			MonitorUptime::Uptime.field_at(started, now),
+			Some(("monitor_uptime", 90))
+		);
+	}
+
+	#[test]
+	fn start_time() {
+		let started = datetime!(2023-10-04 12:00 UTC);
+		let now = datetime!(2023-10-04 12:01:30 UTC);
+		assert_eq!(
+			MonitorUptime::StartTime.field_at(started, now),
+			Some(("monitor_start", 1_696_420_800))
+		);
+	}
+
+	#[test]
+	fn omit() {
+		let started = datetime!(2023-10-04 12:00 UTC);
+		assert_eq!(MonitorUptime::Omit.field_at(started, started), None);
+	}
+}
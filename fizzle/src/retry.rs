@@ -0,0 +1,109 @@
+use serde::Deserialize;
+use std::{fmt, future::Future, time::Duration};
+
+/// How to retry an operation which fails at startup, such as connecting to a
+/// broker which has not started yet.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+pub struct Backoff {
+	/// Milliseconds to wait after the first failure. Each later wait is
+	/// twice the previous one, up to `max_delay_ms`.
+	#[serde(default = "default_initial_delay_ms")]
+	pub initial_delay_ms: u64,
+
+	#[serde(default = "default_max_delay_ms")]
+	pub max_delay_ms: u64,
+
+	/// Attempts to make before giving up. `None` retries indefinitely.
+	pub max_attempts: Option<u32>,
+}
+
+fn default_initial_delay_ms() -> u64 {
+	500
+}
+
+fn default_max_delay_ms() -> u64 {
+	30_000
+}
+
+impl Default for Backoff {
+	fn default() -> Self {
+		Self {
+			initial_delay_ms: default_initial_delay_ms(),
+			max_delay_ms: default_max_delay_ms(),
+			max_attempts: None,
+		}
+	}
+}
+
+impl Backoff {
+	/// Runs `operation` until it succeeds, waiting between failed attempts.
+	/// Returns the last error once `max_attempts` have failed.
+	pub async fn retry<T, E, F, Fut>(&self, what: &str, mut operation: F) -> Result<T, E>
+	where
+		E: fmt::Debug,
+		F: FnMut() -> Fut,
+		Fut: Future<Output = Result<T, E>>,
+	{
+		let mut delay = Duration::from_millis(self.initial_delay_ms);
+		let max_delay = Duration::from_millis(self.max_delay_ms);
+		let mut attempt = 1;
+		loop {
+			match operation().await {
+				Ok(value) => return Ok(value),
+				Err(error) if self.max_attempts.is_some_and(|max| attempt >= max) => {
+					tracing::error!("{what} failed after {attempt} attempts: {error:?}");
+					return Err(error);
+				}
+				Err(error) => {
+					tracing::warn!(
+						"{what} failed (attempt {attempt}), retrying in {delay:?}: {error:?}"
+					);
+				}
+			}
+
+			tokio::time::sleep(delay).await;
+			delay = (delay * 2).min(max_delay);
+			attempt += 1;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Backoff;
+
+	#[tokio::test]
+	async fn failures_are_retried_until_success() {
+		let backoff = Backoff {
+			initial_delay_ms: 1,
+			max_delay_ms: 4,
+			max_attempts: Some(5),
+		};
+
+		let mut attempts = 0;
+		let result = backoff
+			.retry("connecting", || {
+				attempts += 1;
+				let attempt = attempts;
+				async move {
+					if attempt < 3 {
+						Err("connection refused")
+					} else {
+						Ok(attempt)
+					}
+				}
+			})
+			.await;
+		assert_eq!(result, Ok(3));
+
+		let mut attempts = 0;
+		let result: Result<(), _> = backoff
+			.retry("connecting", || {
+				attempts += 1;
+				async { Err("connection refused") }
+			})
+			.await;
+		assert_eq!(result, Err("connection refused"));
+		assert_eq!(attempts, 5);
+	}
+}
@@ -0,0 +1,104 @@
+use influxdb::{buffered, MeasurementRouter};
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+
+/// Kinds of data fizzle writes, for sending each to its own bucket.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum DataCategory {
+	/// Impulse meter readings.
+	Impulse,
+	/// Smart plug and group readings.
+	Telemetry,
+	/// fizzle's own startup and health points.
+	Diagnostics,
+	/// Discrete events, such as relay state changes and reboots.
+	Events,
+}
+
+impl DataCategory {
+	/// Returns the measurements written for the category.
+	pub fn measurements(self) -> &'static [&'static str] {
+		match self {
+			Self::Impulse => &["impulse"],
+			Self::Telemetry => &["telemetry", "group_telemetry"],
+			Self::Diagnostics => &["fizzle"],
+			Self::Events => &["state_change", "reboot"],
+		}
+	}
+}
+
+/// Routes the measurements of `categories` to `client`.
+pub fn with_categories(
+	router: MeasurementRouter,
+	categories: &[DataCategory],
+	client: buffered::Client,
+	task: JoinHandle<anyhow::Result<()>>,
+) -> MeasurementRouter {
+	let measurements = categories
+		.iter()
+		.flat_map(|category| category.measurements().iter().copied());
+	router.with_route(measurements, client, task)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{with_categories, DataCategory};
+	use influxdb::{util::channel_buffered_client, MeasurementRouter};
+	use tokio::sync::watch;
+
+	#[tokio::test]
+	async fn categories_route_to_their_buckets() {
+		let idle = || tokio::spawn(async { Ok(()) });
+		let (default, mut default_rx) = channel_buffered_client(4);
+		let mut router = MeasurementRouter::new(default, idle());
+
+		let categories = [
+			DataCategory::Impulse,
+			DataCategory::Telemetry,
+			DataCategory::Diagnostics,
+			DataCategory::Events,
+		];
+		let mut receivers = Vec::new();
+		for category in categories {
+			let (client, rx) = channel_buffered_client(4);
+			router = with_categories(router, &[category], client, idle());
+			receivers.push(rx);
+		}
+
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let (client, _handle) = router.buffered(shutdown_rx);
+		client
+			.write_raw(
+				concat!(
+					"impulse,device=garage/meter energy=1i\n",
+					"telemetry,device=kitchen/kettle power=120i\n",
+					"group_telemetry,group=kitchen power=120i\n",
+					"fizzle,reason=started pid=1u\n",
+					"state_change,device=kitchen/kettle from=\"off\",to=\"on\"\n",
+					"unrouted f=1i\n",
+				)
+				.into(),
+			)
+			.await
+			.unwrap();
+
+		let mut received = Vec::new();
+		for rx in &mut receivers {
+			let (buffer, _) = rx.recv().await.unwrap();
+			received.push(String::from_utf8(buffer.to_vec()).unwrap());
+		}
+		assert_eq!(
+			received,
+			vec![
+				"impulse,device=garage/meter energy=1i\n",
+				"telemetry,device=kitchen/kettle power=120i\ngroup_telemetry,group=kitchen power=120i\n",
+				"fizzle,reason=started pid=1u\n",
+				"state_change,device=kitchen/kettle from=\"off\",to=\"on\"\n",
+			]
+		);
+
+		let (buffer, _) = default_rx.recv().await.unwrap();
+		assert_eq!(&buffer[..], b"unrouted f=1i\n");
+	}
+}
@@ -0,0 +1,141 @@
+use influxdb::query::{flux::Expr, Flux, QueryClient};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use time::OffsetDateTime;
+
+/// How a device's `energy_today` is baselined on the day fizzle first sees
+/// it, or sees it again after a restart.
+///
+/// Energy used that day before the first reading cannot be known from the
+/// device's lifetime counter alone, so each choice trades accuracy against
+/// availability:
+///
+/// - `first_observation` counts from the first reading, undercounting the
+///   day by whatever was used before it.
+/// - `midnight` writes no `energy_today` until the first local midnight has
+///   passed, after which every day is counted in full.
+/// - `query` resumes from the last `energy_today` written today, so only the
+///   energy used while fizzle was not running is missed. Devices without a
+///   point today fall back to `first_observation`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TodayBaseline {
+	#[default]
+	FirstObservation,
+	Midnight,
+	Query,
+}
+
+/// The last `energy_today` of each device since `params.dayStart`.
+///
+/// A device's points are split across tables by its other tags, such as
+/// `ssid`, so they are regrouped by device, and sorted, before the last is
+/// taken.
+fn query() -> Flux {
+	Flux::from_bucket(Expr::param("bucket"))
+		.range_from(Expr::time_param("dayStart"))
+		.filter_measurement("telemetry")
+		.filter_field("energy_today")
+		.group(&["device"])
+		.sort(&["_time"])
+		.last()
+}
+
+#[derive(Deserialize)]
+struct Record {
+	device: String,
+	#[serde(rename = "_value")]
+	value: i64,
+}
+
+/// Fetches the last `energy_today`, in Wh, written for each device in
+/// `bucket` since `day_start`.
+pub async fn fetch_energy_today(
+	client: &QueryClient,
+	bucket: &str,
+	day_start: OffsetDateTime,
+) -> anyhow::Result<BTreeMap<String, i64>> {
+	let records: Vec<Record> = client
+		.query_as(
+			query().build(),
+			[("bucket", bucket.into()), ("dayStart", day_start.into())],
+		)
+		.await?;
+	Ok(records
+		.into_iter()
+		.map(|record| (record.device, record.value))
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::fetch_energy_today;
+	use time::macros::datetime;
+	use tokio::{
+		io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+		net::TcpListener,
+	};
+
+	const CSV: &str = "#datatype,string,long,dateTime:RFC3339,long,string\r\n\
+		#group,false,false,false,false,true\r\n\
+		#default,_result,,,,\r\n\
+		,result,table,_time,_value,device\r\n\
+		,,0,2023-10-04T11:59:00Z,750,kitchen/kettle\r\n\
+		,,1,2023-10-04T11:58:00Z,120,kitchen/toaster\r\n";
+
+	/// Accepts a single query, answering it with canned CSV, and resolves to
+	/// the request body.
+	async fn mock_influxdb() -> (String, tokio::task::JoinHandle<String>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let handle = tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			let mut reader = BufReader::new(stream);
+			let mut content_length = 0;
+			loop {
+				let mut line = String::new();
+				reader.read_line(&mut line).await.unwrap();
+				let line = line.trim_end();
+				if line.is_empty() {
+					break;
+				}
+				if let Some((name, value)) = line.split_once(':') {
+					if name.eq_ignore_ascii_case("content-length") {
+						content_length = value.trim().parse().unwrap();
+					}
+				}
+			}
+			let mut body = vec![0; content_length];
+			reader.read_exact(&mut body).await.unwrap();
+
+			let response = format!(
+				"HTTP/1.1 200 OK\r\nContent-Type: text/csv; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{CSV}",
+				CSV.len()
+			);
+			reader
+				.into_inner()
+				.write_all(response.as_bytes())
+				.await
+				.unwrap();
+			String::from_utf8(body).unwrap()
+		});
+		(format!("http://{address}"), handle)
+	}
+
+	#[tokio::test]
+	async fn energy_today_is_fetched_per_device() {
+		let (url, server) = mock_influxdb().await;
+		let client = influxdb::Client::new(url, "token").unwrap().query_client();
+
+		let energy_today = fetch_energy_today(&client, "fizzle", datetime!(2023-10-04 00:00 UTC))
+			.await
+			.unwrap();
+		assert_eq!(energy_today.len(), 2);
+		assert_eq!(energy_today["kitchen/kettle"], 750);
+		assert_eq!(energy_today["kitchen/toaster"], 120);
+
+		let request = server.await.unwrap();
+		assert!(request.contains(r#"group(columns: [\"device\"])"#));
+		assert!(request.contains(r#"\"bucket\":\"fizzle\""#));
+	}
+}
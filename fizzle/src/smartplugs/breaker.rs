@@ -0,0 +1,130 @@
+use influxdb::Status;
+use serde::Deserialize;
+use std::{
+	collections::VecDeque,
+	time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+/// Settings for the per-device write circuit breaker.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+	/// Consecutive rejected writes after which a device's writes are paused.
+	pub threshold: u32,
+	/// Seconds to pause a device's writes before trying again.
+	pub cooldown_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+	fn default() -> Self {
+		Self {
+			threshold: 5,
+			cooldown_secs: 300,
+		}
+	}
+}
+
+/// Pauses writes for a device whose points InfluxDB keeps rejecting, so they
+/// stop costing a retry of every batch they land in.
+///
+/// Write outcomes arrive asynchronously, so they are settled each time the
+/// device next reports.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+	config: CircuitBreakerConfig,
+	pending: VecDeque<watch::Receiver<Status>>,
+	failures: u32,
+	open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+	pub fn new(config: CircuitBreakerConfig) -> Self {
+		Self {
+			config,
+			pending: VecDeque::new(),
+			failures: 0,
+			open_until: None,
+		}
+	}
+
+	/// Tracks the outcome of a write for the device.
+	pub fn track(&mut self, status: watch::Receiver<Status>) {
+		self.pending.push_back(status);
+	}
+
+	/// Returns true if the device's points may be written at `now`.
+	pub fn allow(&mut self, name: &str, now: Instant) -> bool {
+		self.settle(name, now);
+
+		match self.open_until {
+			Some(until) if now < until => false,
+			Some(_) => {
+				tracing::info!("resuming writes for device '{name}' after cooldown");
+				self.open_until = None;
+				self.failures = 0;
+				true
+			}
+			None => true,
+		}
+	}
+
+	/// Returns true while the device's writes are paused.
+	pub fn is_open(&self) -> bool {
+		self.open_until.is_some()
+	}
+
+	fn settle(&mut self, name: &str, now: Instant) {
+		while let Some(status) = self.pending.front() {
+			let outcome = status.borrow().clone();
+			match outcome {
+				Status::Accepted => self.failures = 0,
+				Status::Rejected => self.failures += 1,
+				// Still queued, unless the write task has gone away.
+				Status::Init | Status::Buffered if status.has_changed().is_ok() => break,
+				Status::Init | Status::Buffered | Status::Deferred => {}
+			}
+			self.pending.pop_front();
+
+			if self.config.threshold > 0
+				&& self.failures >= self.config.threshold
+				&& self.open_until.is_none()
+			{
+				tracing::warn!(
+					"InfluxDB rejected {} consecutive writes for device '{name}', pausing its writes for {}s",
+					self.failures,
+					self.config.cooldown_secs
+				);
+				self.open_until = Some(now + Duration::from_secs(self.config.cooldown_secs));
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{CircuitBreaker, CircuitBreakerConfig};
+	use influxdb::Status;
+	use std::time::{Duration, Instant};
+	use tokio::sync::watch;
+
+	#[test]
+	fn recovers_after_cooldown() {
+		let mut breaker = CircuitBreaker::new(CircuitBreakerConfig {
+			threshold: 1,
+			cooldown_secs: 60,
+		});
+
+		let (tx, rx) = watch::channel(Status::Buffered);
+		breaker.track(rx);
+		let now = Instant::now();
+		assert!(breaker.allow("test", now));
+
+		tx.send_replace(Status::Rejected);
+		assert!(!breaker.allow("test", now));
+		assert!(breaker.is_open());
+		assert!(!breaker.allow("test", now + Duration::from_secs(59)));
+		assert!(breaker.allow("test", now + Duration::from_secs(60)));
+		assert!(!breaker.is_open());
+	}
+}
@@ -0,0 +1,98 @@
+use serde::Deserialize;
+use time::{Duration, OffsetDateTime};
+
+/// How often each device's diagnostic fields are written. They change
+/// slowly, so need not be written with every telemetry point.
+///
+/// With both limits set, diagnostics are written when either is reached.
+/// With neither, they are written with every point.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct DiagnosticsSampling {
+	/// Write diagnostics with every Nth telemetry point.
+	pub every: Option<u32>,
+	/// Write diagnostics at most once per this many seconds.
+	pub interval_secs: Option<u64>,
+}
+
+/// Decides which of a device's telemetry points carry diagnostics.
+#[derive(Debug)]
+pub struct DiagnosticsSampler {
+	sampling: DiagnosticsSampling,
+	/// Points since diagnostics were last written.
+	skipped: u32,
+	last_written: Option<OffsetDateTime>,
+}
+
+impl DiagnosticsSampler {
+	pub fn new(sampling: DiagnosticsSampling) -> Self {
+		Self {
+			sampling,
+			skipped: 0,
+			last_written: None,
+		}
+	}
+
+	/// Returns true if the point at `at` should carry diagnostics. The first
+	/// point always does.
+	pub fn sample(&mut self, at: OffsetDateTime) -> bool {
+		let due = match (self.sampling.every, self.sampling.interval_secs) {
+			(None, None) => true,
+			(every, interval_secs) => {
+				let count_due = every.is_some_and(|every| self.skipped + 1 >= every);
+				let time_due = match (interval_secs, self.last_written) {
+					(Some(secs), Some(last)) => at - last >= Duration::seconds(secs as i64),
+					_ => false,
+				};
+				self.last_written.is_none() || count_due || time_due
+			}
+		};
+
+		if due {
+			self.skipped = 0;
+			self.last_written = Some(at);
+		} else {
+			self.skipped += 1;
+		}
+		due
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{DiagnosticsSampler, DiagnosticsSampling};
+	use time::{macros::datetime, Duration};
+
+	#[test]
+	fn sampled_by_count_or_interval() {
+		let start = datetime!(2023-10-04 12:00 UTC);
+		let samples = |sampling| {
+			let mut sampler = DiagnosticsSampler::new(sampling);
+			(0..7)
+				.map(|n| sampler.sample(start + Duration::seconds(10 * n)))
+				.collect::<Vec<_>>()
+		};
+
+		assert_eq!(samples(DiagnosticsSampling::default()), vec![true; 7]);
+		assert_eq!(
+			samples(DiagnosticsSampling {
+				every: Some(3),
+				interval_secs: None,
+			}),
+			vec![true, false, false, true, false, false, true]
+		);
+		assert_eq!(
+			samples(DiagnosticsSampling {
+				every: None,
+				interval_secs: Some(25),
+			}),
+			vec![true, false, false, true, false, false, true]
+		);
+		assert_eq!(
+			samples(DiagnosticsSampling {
+				every: Some(2),
+				interval_secs: Some(60),
+			}),
+			vec![true, false, true, false, true, false, true]
+		);
+	}
+}
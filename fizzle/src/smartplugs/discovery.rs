@@ -0,0 +1,136 @@
+use super::topic::TopicGenerator;
+use std::collections::BTreeMap;
+use tasmota::Status0;
+use time::{Duration, OffsetDateTime};
+
+/// How long to wait for a `Status` response before asking again.
+const RETRY_AFTER: Duration = Duration::seconds(60);
+
+/// Number of `Status` requests sent before giving up on a device.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// What a device reported about itself in its `Status` response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+	pub device_name: String,
+	pub friendly_name: Option<String>,
+	pub module: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum DiscoveryState {
+	Pending {
+		attempts: u32,
+		requested: OffsetDateTime,
+	},
+	Discovered(DeviceInfo),
+	Unresponsive,
+}
+
+/// Asks newly seen devices to describe themselves with `Status 0`, and
+/// records their responses.
+///
+/// The registry only queues commands; the caller publishes them, see
+/// [`DiscoveryRegistry::take_commands`].
+#[derive(Debug, Default)]
+pub struct DiscoveryRegistry {
+	devices: BTreeMap<String, DiscoveryState>,
+	commands: Vec<(String, String)>,
+}
+
+impl DiscoveryRegistry {
+	/// Requests a description of `name` if it has not been asked yet, or asks
+	/// again if an earlier request went unanswered.
+	pub fn poll<G: TopicGenerator>(&mut self, topics: &G, name: &str, now: OffsetDateTime) {
+		let attempts = match self.devices.get(name) {
+			None => 1,
+			Some(DiscoveryState::Pending {
+				attempts,
+				requested,
+			}) if now - *requested >= RETRY_AFTER => attempts + 1,
+			Some(_) => return,
+		};
+
+		if attempts > MAX_ATTEMPTS {
+			tracing::warn!(
+				"device '{name}' did not respond to {MAX_ATTEMPTS} status requests, giving up"
+			);
+			self.devices
+				.insert(name.to_string(), DiscoveryState::Unresponsive);
+			return;
+		}
+
+		self.devices.insert(
+			name.to_string(),
+			DiscoveryState::Pending {
+				attempts,
+				requested: now,
+			},
+		);
+		self.commands
+			.push((topics.status_command_topic(name), String::from("0")));
+	}
+
+	/// Records a device's `Status` response.
+	pub fn update(&mut self, name: &str, status: Status0) {
+		let status = status.status;
+		let info = DeviceInfo {
+			device_name: status.device_name,
+			friendly_name: status.friendly_name.into_iter().next(),
+			module: status.module,
+		};
+		tracing::info!("discovered device '{name}': {info:?}");
+		self.devices
+			.insert(name.to_string(), DiscoveryState::Discovered(info));
+	}
+
+	/// Returns what the device reported about itself, if it has responded.
+	pub fn device_info(&self, name: &str) -> Option<&DeviceInfo> {
+		match self.devices.get(name) {
+			Some(DiscoveryState::Discovered(info)) => Some(info),
+			_ => None,
+		}
+	}
+
+	/// Removes and returns the queued `(topic, payload)` commands to publish.
+	pub fn take_commands(&mut self) -> Vec<(String, String)> {
+		std::mem::take(&mut self.commands)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{DiscoveryRegistry, MAX_ATTEMPTS};
+	use crate::smartplugs::topic::HomeTasmotaTopicScheme;
+	use time::{macros::datetime, Duration};
+
+	#[test]
+	fn unresponsive_device_is_abandoned() {
+		let mut registry = DiscoveryRegistry::default();
+		let mut now = datetime!(2023-10-04 12:00 UTC);
+
+		for _ in 0..MAX_ATTEMPTS {
+			registry.poll(&HomeTasmotaTopicScheme, "kitchen/kettle", now);
+			assert_eq!(registry.take_commands().len(), 1);
+
+			// Not yet time to ask again.
+			registry.poll(
+				&HomeTasmotaTopicScheme,
+				"kitchen/kettle",
+				now + Duration::seconds(1),
+			);
+			assert!(registry.take_commands().is_empty());
+
+			now += Duration::seconds(60);
+		}
+
+		registry.poll(&HomeTasmotaTopicScheme, "kitchen/kettle", now);
+		registry.poll(
+			&HomeTasmotaTopicScheme,
+			"kitchen/kettle",
+			now + Duration::hours(1),
+		);
+		assert!(registry.take_commands().is_empty());
+		assert_eq!(registry.device_info("kitchen/kettle"), None);
+	}
+}
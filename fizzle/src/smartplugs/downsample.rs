@@ -0,0 +1,234 @@
+use super::smartplug::Telemetry;
+use serde::Deserialize;
+
+/// How instantaneous readings within an interval are combined.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+	/// The last reading in the interval.
+	#[default]
+	Last,
+	/// The mean of the readings in the interval.
+	Mean,
+	/// The largest reading in the interval.
+	Max,
+}
+
+/// Settings for writing at most one telemetry point per device per interval.
+///
+/// Each point is timestamped at the end of its interval, as InfluxDB's
+/// `aggregateWindow` does by default, so that its counters, such as energy,
+/// which are as of the interval's last reading, are never later than its
+/// timestamp.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+pub struct DownsampleConfig {
+	/// Length of each interval in seconds. Intervals are aligned to the Unix
+	/// epoch, so a 60 second interval starts on each minute.
+	pub interval_secs: u64,
+	/// How current, power, voltage and the other instantaneous readings are
+	/// combined. Counters, such as energy, always take the last reading.
+	#[serde(default)]
+	pub aggregation: Aggregation,
+}
+
+impl DownsampleConfig {
+	fn interval_ms(&self) -> i64 {
+		self.interval_secs.max(1) as i64 * 1000
+	}
+}
+
+/// Collapses a device's readings into one point per interval.
+///
+/// An interval's point is only known once a reading from a later interval
+/// arrives, so [`Downsampler::push`] returns the previous interval's point.
+/// Call [`Downsampler::flush_stale`] periodically to take the interval of a
+/// device which has stopped reporting, and [`Downsampler::flush`] to take the
+/// interval still open, such as on shutdown.
+#[derive(Debug)]
+pub struct Downsampler {
+	config: DownsampleConfig,
+	pending: Option<Bucket>,
+	/// End of the last interval whose point was returned, in milliseconds.
+	closed_until: Option<i64>,
+}
+
+impl Downsampler {
+	pub fn new(config: DownsampleConfig) -> Self {
+		Self {
+			config,
+			pending: None,
+			closed_until: None,
+		}
+	}
+
+	/// Adds a reading, returning the aggregated point for the previous
+	/// interval if this reading starts a new one.
+	///
+	/// Readings without a timestamp are returned immediately, since their
+	/// interval is not known. Readings for an interval already returned are
+	/// dropped rather than overwriting its point.
+	pub fn push(&mut self, telemetry: Telemetry) -> Option<Telemetry> {
+		let Some(timestamp) = telemetry.timestamp else {
+			return Some(telemetry);
+		};
+		let interval_ms = self.config.interval_ms();
+		let start = timestamp - timestamp.rem_euclid(interval_ms);
+
+		let closed = self.closed_until.is_some_and(|until| start < until);
+		match &mut self.pending {
+			Some(bucket) if bucket.start == start => {
+				bucket.add(telemetry);
+				None
+			}
+			Some(bucket) if start < bucket.start => {
+				tracing::debug!(
+					"dropping late reading for device '{}' at {timestamp}",
+					telemetry.name
+				);
+				None
+			}
+			_ if closed => {
+				tracing::debug!(
+					"dropping late reading for device '{}' at {timestamp}",
+					telemetry.name
+				);
+				None
+			}
+			_ => {
+				let previous = self.pending.replace(Bucket::new(start, telemetry));
+				previous.map(|bucket| self.finish(bucket))
+			}
+		}
+	}
+
+	/// Takes the aggregated point for the interval still open, if any.
+	pub fn flush(&mut self) -> Option<Telemetry> {
+		self.pending.take().map(|bucket| self.finish(bucket))
+	}
+
+	/// Takes the aggregated point for the interval still open if it ended at
+	/// least a whole interval before `now`, in milliseconds. The extra
+	/// interval leaves time for readings delivered late.
+	pub fn flush_stale(&mut self, now: i64) -> Option<Telemetry> {
+		let interval_ms = self.config.interval_ms();
+		let stale = self
+			.pending
+			.as_ref()
+			.is_some_and(|bucket| bucket.start + 2 * interval_ms <= now);
+		if stale {
+			self.flush()
+		} else {
+			None
+		}
+	}
+
+	fn finish(&mut self, bucket: Bucket) -> Telemetry {
+		let end = bucket.start + self.config.interval_ms();
+		self.closed_until = Some(end);
+		bucket.finish(end, self.config.aggregation)
+	}
+}
+
+/// Running aggregates of one field within an interval.
+#[derive(Clone, Copy, Debug)]
+struct Aggregate {
+	sum: f64,
+	max: f64,
+	last: f64,
+	count: u32,
+}
+
+impl Aggregate {
+	fn new(value: f64) -> Self {
+		Self {
+			sum: value,
+			max: value,
+			last: value,
+			count: 1,
+		}
+	}
+
+	fn add(&mut self, value: f64) {
+		self.sum += value;
+		self.max = self.max.max(value);
+		self.last = value;
+		self.count += 1;
+	}
+
+	fn value(&self, aggregation: Aggregation) -> f64 {
+		match aggregation {
+			Aggregation::Last => self.last,
+			Aggregation::Mean => self.sum / self.count as f64,
+			Aggregation::Max => self.max,
+		}
+	}
+}
+
+fn add_optional(aggregate: &mut Option<Aggregate>, value: Option<f64>) {
+	match (aggregate.as_mut(), value) {
+		(Some(aggregate), Some(value)) => aggregate.add(value),
+		(None, Some(value)) => *aggregate = Some(Aggregate::new(value)),
+		(_, None) => {}
+	}
+}
+
+/// The readings received so far for one interval.
+#[derive(Debug)]
+struct Bucket {
+	/// Start of the interval, in milliseconds.
+	start: i64,
+	current: Aggregate,
+	power: Aggregate,
+	voltage: Aggregate,
+	apparent_power: Option<Aggregate>,
+	power_factor: Option<Aggregate>,
+	reactive_power: Option<Aggregate>,
+	latest: Telemetry,
+}
+
+impl Bucket {
+	fn new(start: i64, telemetry: Telemetry) -> Self {
+		Self {
+			start,
+			current: Aggregate::new(telemetry.current),
+			power: Aggregate::new(telemetry.power as f64),
+			voltage: Aggregate::new(telemetry.voltage as f64),
+			apparent_power: telemetry.apparent_power.map(|v| Aggregate::new(v as f64)),
+			power_factor: telemetry.power_factor.map(Aggregate::new),
+			reactive_power: telemetry.reactive_power.map(|v| Aggregate::new(v as f64)),
+			latest: telemetry,
+		}
+	}
+
+	fn add(&mut self, telemetry: Telemetry) {
+		self.current.add(telemetry.current);
+		self.power.add(telemetry.power as f64);
+		self.voltage.add(telemetry.voltage as f64);
+		add_optional(
+			&mut self.apparent_power,
+			telemetry.apparent_power.map(|v| v as f64),
+		);
+		add_optional(&mut self.power_factor, telemetry.power_factor);
+		add_optional(
+			&mut self.reactive_power,
+			telemetry.reactive_power.map(|v| v as f64),
+		);
+		self.latest = telemetry;
+	}
+
+	/// Returns the interval's point, timestamped at `end`, the end of the
+	/// interval.
+	fn finish(self, end: i64, aggregation: Aggregation) -> Telemetry {
+		let integer = |aggregate: Aggregate| aggregate.value(aggregation).round() as i64;
+		Telemetry {
+			current: self.current.value(aggregation),
+			power: integer(self.power),
+			voltage: integer(self.voltage),
+			apparent_power: self.apparent_power.map(integer),
+			power_factor: self.power_factor.map(|a| a.value(aggregation)),
+			reactive_power: self.reactive_power.map(integer),
+			timestamp: Some(end),
+			..self.latest
+		}
+	}
+}
@@ -1,74 +1,612 @@
+pub mod baseline;
+pub mod breaker;
+pub mod diagnostics;
+pub mod discovery;
+pub mod downsample;
 mod smartplug;
+pub mod timestamp;
 pub mod topic;
 
-use self::topic::{TelemetryType, TopicGenerator};
-use crate::util::{bytes_to_string, parse_json_payload};
-use influxdb::buffered;
+use self::{
+	baseline::TodayBaseline,
+	breaker::{CircuitBreaker, CircuitBreakerConfig},
+	diagnostics::{DiagnosticsSampler, DiagnosticsSampling},
+	discovery::{DeviceInfo, DiscoveryRegistry},
+	downsample::{DownsampleConfig, Downsampler},
+	smartplug::Telemetry,
+	topic::{TelemetryType, TopicGenerator},
+};
+use crate::{
+	health::HealthStats,
+	monitor::MonitorUptime,
+	tariff::{CumulativeCost, Tariff},
+	util::{bytes_to_string, datetime_from_millis, millis_from_datetime, parse_json_bytes},
+};
+use bytes::Bytes;
+use influxdb::{buffered, OptionalField};
 use mqtt::clients::tokio::Message;
 pub use smartplug::SmartPlug;
-use std::{collections::BTreeMap, error, fmt};
-use tasmota::{sns::StatusSNS, PowerState, StatusSTS};
+use std::{collections::BTreeMap, error, fmt, sync::Arc, time::Instant};
+use tasmota::{sns::StatusSNS, Info1, PowerState, Status0, StatusSTS};
+use time::{Date, OffsetDateTime};
+pub use timestamp::{DeviceTimezone, DuplicatePolicy, TimestampStrategy};
+
+/// Topic filter for commands sent to fizzle itself.
+pub const CONTROL_TOPIC_FILTER: &str = "fizzle/cmnd/#";
 
 #[derive(Debug)]
 pub struct SmartPlugSwarm<G: TopicGenerator> {
 	writer: buffered::Client,
+	topics: G,
 	smartplugs: BTreeMap<String, SmartPlug<G>>,
+	max_devices: Option<usize>,
 	telemetry_map: BTreeMap<String, String>,
+	timestamp_strategy: TimestampStrategy,
+	duplicate_policy: DuplicatePolicy,
+	device_timezone: DeviceTimezone,
+	monitor_uptime: MonitorUptime,
+	groups: BTreeMap<String, Vec<String>>,
+	latest: BTreeMap<String, LatestReading>,
+	breaker_config: CircuitBreakerConfig,
+	breakers: BTreeMap<String, CircuitBreaker>,
+	derive_power: bool,
+	sensor_only: bool,
+	diagnostics: bool,
+	energy_kwh: bool,
+	diagnostics_sampling: DiagnosticsSampling,
+	diagnostics_samplers: BTreeMap<String, DiagnosticsSampler>,
+	reboot_events: bool,
+	float_precision: BTreeMap<String, u32>,
+	tariff: Option<Tariff>,
+	costs: BTreeMap<String, CumulativeCost>,
+	downsample: Option<DownsampleConfig>,
+	downsamplers: BTreeMap<String, Downsampler>,
+	stale_after: Option<time::Duration>,
+	lwt_debounce: Option<time::Duration>,
+	today_baseline: TodayBaseline,
+	today_seeds: BTreeMap<String, (Date, i64)>,
+	clock: Option<OffsetDateTime>,
+	discovery: Option<DiscoveryRegistry>,
+	health: Option<Arc<HealthStats>>,
 }
 
-impl<G: TopicGenerator + fmt::Debug> SmartPlugSwarm<G> {
+/// The most recent power and energy written for a smart plug.
+#[derive(Clone, Copy, Debug)]
+struct LatestReading {
+	power: i64,
+	energy: i64,
+}
+
+/// Summed readings across the members of a group.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GroupTotals {
+	pub power: i64,
+	pub energy: i64,
+	pub online: i64,
+}
+
+impl<G: TopicGenerator + Default + fmt::Debug> SmartPlugSwarm<G> {
 	pub fn new(writer: buffered::Client) -> Self {
+		Self::with_topic_scheme(writer, G::default())
+	}
+}
+
+impl<G: TopicGenerator + fmt::Debug> SmartPlugSwarm<G> {
+	/// Creates a swarm whose device topics follow `topics`.
+	pub fn with_topic_scheme(writer: buffered::Client, topics: G) -> Self {
 		Self {
 			writer,
+			topics,
 			smartplugs: BTreeMap::new(),
+			max_devices: None,
 			telemetry_map: BTreeMap::new(),
+			timestamp_strategy: Default::default(),
+			duplicate_policy: Default::default(),
+			device_timezone: Default::default(),
+			monitor_uptime: Default::default(),
+			groups: BTreeMap::new(),
+			latest: BTreeMap::new(),
+			breaker_config: Default::default(),
+			breakers: BTreeMap::new(),
+			derive_power: false,
+			sensor_only: false,
+			diagnostics: false,
+			energy_kwh: false,
+			diagnostics_sampling: Default::default(),
+			diagnostics_samplers: BTreeMap::new(),
+			reboot_events: false,
+			float_precision: BTreeMap::new(),
+			tariff: None,
+			costs: BTreeMap::new(),
+			downsample: None,
+			downsamplers: BTreeMap::new(),
+			stale_after: None,
+			lwt_debounce: None,
+			today_baseline: TodayBaseline::FirstObservation,
+			today_seeds: BTreeMap::new(),
+			clock: None,
+			discovery: None,
+			health: None,
 		}
 	}
 
+	/// Enables asking each newly seen device to describe itself with
+	/// `Status 0`. The commands are queued for the caller to publish; see
+	/// [`SmartPlugSwarm::take_discovery_commands`].
+	pub fn with_discovery(self, enabled: bool) -> Self {
+		let mut s = self;
+		s.discovery = enabled.then(DiscoveryRegistry::default);
+		s
+	}
+
+	/// Counts rejected telemetry writes in `health`.
+	pub fn with_health(self, health: Arc<HealthStats>) -> Self {
+		let mut s = self;
+		s.health = Some(health);
+		s
+	}
+
+	/// Removes and returns the queued `(topic, payload)` discovery commands.
+	pub fn take_discovery_commands(&mut self) -> Vec<(String, String)> {
+		self.discovery
+			.as_mut()
+			.map(DiscoveryRegistry::take_commands)
+			.unwrap_or_default()
+	}
+
+	/// Returns what a device reported about itself, if discovery is enabled
+	/// and it has responded.
+	pub fn device_info(&self, name: &str) -> Option<&DeviceInfo> {
+		self.discovery.as_ref()?.device_info(name)
+	}
+
+	/// Sets how old unmatched telemetry must be for
+	/// [`SmartPlugSwarm::sweep_stale`] to drop it. `None` keeps it until
+	/// matched.
+	pub fn with_stale_after(self, stale_after: Option<time::Duration>) -> Self {
+		let mut s = self;
+		s.stale_after = stale_after;
+		s
+	}
+
+	/// Sets how long a device's LWT must stand before an online/offline
+	/// change is reported. `None` reports every change immediately.
+	pub fn with_lwt_debounce(self, debounce: Option<time::Duration>) -> Self {
+		let mut s = self;
+		s.lwt_debounce = debounce;
+		s
+	}
+
+	/// Sets how each device's `energy_today` is baselined on the first day it
+	/// is seen.
+	pub fn with_today_baseline(self, today_baseline: TodayBaseline) -> Self {
+		let mut s = self;
+		s.today_baseline = today_baseline;
+		s
+	}
+
+	/// Resumes each device's `energy_today` on `date`, such as from the last
+	/// values written before a restart. Devices seen already are unaffected.
+	pub fn seed_energy_today(&mut self, date: Date, energy_today: BTreeMap<String, i64>) {
+		self.today_seeds = energy_today
+			.into_iter()
+			.map(|(name, energy_today)| (name, (date, energy_today)))
+			.collect();
+	}
+
+	/// Drops each device's unmatched telemetry older than the configured
+	/// age, such as sensor telemetry whose state telemetry was lost, so it
+	/// cannot accumulate. Returns the number of readings dropped.
+	pub fn sweep_stale(&mut self) -> usize {
+		let Some(stale_after) = self.stale_after else {
+			return 0;
+		};
+		let before = self.now() - stale_after;
+
+		let mut evicted = 0;
+		for smartplug in self.smartplugs.values_mut() {
+			let count = smartplug.clear_stale(before);
+			if count > 0 {
+				tracing::debug!(
+					"dropped {count} stale unmatched readings for device '{}'",
+					smartplug.name()
+				);
+			}
+			evicted += count;
+		}
+		if evicted > 0 {
+			tracing::info!("dropped {evicted} unmatched readings older than {stale_after}");
+		}
+		evicted
+	}
+
+	/// Fixes the time the swarm treats as now, for replaying captured
+	/// messages deterministically. `None` restores the system clock.
+	pub fn set_clock(&mut self, now: Option<OffsetDateTime>) {
+		self.clock = now;
+	}
+
+	fn now(&self) -> OffsetDateTime {
+		self.clock.unwrap_or_else(OffsetDateTime::now_utc)
+	}
+
+	/// Sets whether sensor telemetry is written without waiting for matching
+	/// state telemetry, omitting the fields which come from it, such as the
+	/// relay state. For devices which only report energy.
+	pub fn with_sensor_only(self, sensor_only: bool) -> Self {
+		let mut s = self;
+		s.sensor_only = sensor_only;
+		s
+	}
+
+	/// Sets whether to derive apparent power and power factor for devices
+	/// which do not report them. Derived values are tagged `derived=true`.
+	pub fn with_derived_power(self, derive_power: bool) -> Self {
+		let mut s = self;
+		s.derive_power = derive_power;
+		s
+	}
+
+	/// Sets whether to write device diagnostics, such as the number of MQTT
+	/// reconnections and WiFi signal strength, with each telemetry point.
+	/// The WiFi network is written as an `ssid` tag.
+	pub fn with_diagnostics(self, diagnostics: bool) -> Self {
+		let mut s = self;
+		s.diagnostics = diagnostics;
+		s
+	}
+
+	/// Sets whether to write energy in kWh, as the float field `energy_kwh`,
+	/// alongside the `energy` field in Wh.
+	pub fn with_energy_kwh(self, energy_kwh: bool) -> Self {
+		let mut s = self;
+		s.energy_kwh = energy_kwh;
+		s
+	}
+
+	/// Sets how often each device's diagnostics are written. Telemetry
+	/// between samples is written without them.
+	pub fn with_diagnostics_sampling(self, sampling: DiagnosticsSampling) -> Self {
+		let mut s = self;
+		s.diagnostics_sampling = sampling;
+		s
+	}
+
+	/// Sets whether to write a `reboot` event when a device announces it
+	/// has booted with `INFO1`.
+	pub fn with_reboot_events(self, reboot_events: bool) -> Self {
+		let mut s = self;
+		s.reboot_events = reboot_events;
+		s
+	}
+
+	/// Sets the tariff used to write each device's `cumulative_cost` for the
+	/// current billing period. Without one, no cost is written.
+	pub fn with_tariff(self, tariff: Option<Tariff>) -> Self {
+		let mut s = self;
+		s.tariff = tariff;
+		s
+	}
+
+	/// Replaces the tariff while running.
+	pub fn set_tariff(&mut self, tariff: Option<Tariff>) {
+		self.tariff = tariff;
+	}
+
+	/// Resumes cumulative costs, by device, such as those saved before a
+	/// restart.
+	pub fn with_costs(self, costs: BTreeMap<String, CumulativeCost>) -> Self {
+		let mut s = self;
+		s.costs = costs;
+		s
+	}
+
+	/// Returns the cumulative cost of each device, for saving.
+	pub fn costs(&self) -> &BTreeMap<String, CumulativeCost> {
+		&self.costs
+	}
+
+	/// Sets the number of decimal places to which named float fields, such
+	/// as `current`, are rounded when written. Other fields are written in
+	/// full.
+	pub fn with_float_precision(self, float_precision: BTreeMap<String, u32>) -> Self {
+		let mut s = self;
+		s.float_precision = float_precision;
+		s
+	}
+
+	/// Writes at most one telemetry point per device per interval,
+	/// aggregating the readings within it. `None` writes every reading.
+	pub fn with_downsample(self, downsample: Option<DownsampleConfig>) -> Self {
+		let mut s = self;
+		s.downsample = downsample;
+		s
+	}
+
+	/// Writes the points for intervals still being downsampled, such as
+	/// before shutting down.
+	pub async fn flush_downsampled(&mut self) -> Result<(), Box<dyn error::Error + 'static>> {
+		let pending: Vec<_> = self
+			.downsamplers
+			.values_mut()
+			.filter_map(Downsampler::flush)
+			.collect();
+		self.write_downsampled(pending).await
+	}
+
+	/// Writes the points for intervals which ended at least an interval ago
+	/// but are still open because their device stopped reporting. Call it
+	/// about once an interval.
+	pub async fn flush_stale_downsampled(&mut self) -> Result<(), Box<dyn error::Error + 'static>> {
+		let now = millis_from_datetime(self.now());
+		let pending: Vec<_> = self
+			.downsamplers
+			.values_mut()
+			.filter_map(|downsampler| downsampler.flush_stale(now))
+			.collect();
+		self.write_downsampled(pending).await
+	}
+
+	async fn write_downsampled(
+		&mut self,
+		pending: Vec<Telemetry>,
+	) -> Result<(), Box<dyn error::Error + 'static>> {
+		let now = self.now();
+		for telemetry in pending {
+			let dt = telemetry.timestamp.map_or(now, datetime_from_millis);
+			self.write_telemetry(telemetry, dt, now).await?;
+		}
+		Ok(())
+	}
+
+	/// Rounds `value` to the configured decimal places for `field`, if any.
+	fn round_field(&self, field: &str, value: f64) -> f64 {
+		match self.float_precision.get(field) {
+			Some(&places) => {
+				let scale = 10f64.powi(places as i32);
+				(value * scale).round() / scale
+			}
+			None => value,
+		}
+	}
+
+	/// Sets when to pause writes for a device whose points InfluxDB keeps
+	/// rejecting.
+	pub fn with_circuit_breaker(self, config: CircuitBreakerConfig) -> Self {
+		let mut s = self;
+		s.breaker_config = config;
+		s
+	}
+
+	/// Sets named groups of smart plugs whose readings are summed and written
+	/// as `group_telemetry` whenever a member reports.
+	pub fn with_groups(self, groups: BTreeMap<String, Vec<String>>) -> Self {
+		let mut s = self;
+		s.groups = groups;
+		s
+	}
+
+	/// Replaces the configured groups while running.
+	pub fn set_groups(&mut self, groups: BTreeMap<String, Vec<String>>) {
+		self.groups = groups;
+	}
+
+	/// Sums the latest readings of the members of a group.
+	///
+	/// Offline members, per their LWT, still contribute their last energy
+	/// reading (it is cumulative) but no power. Members which have not yet
+	/// reported are skipped.
+	pub fn group_totals(&self, members: &[String]) -> GroupTotals {
+		let mut totals = GroupTotals::default();
+		for member in members {
+			let Some(reading) = self.latest.get(member) else {
+				continue;
+			};
+
+			totals.energy += reading.energy;
+
+			let offline = self
+				.smartplugs
+				.get(member)
+				.and_then(|smartplug| smartplug.lwt(self.now()))
+				.is_some_and(|lwt| lwt.eq_ignore_ascii_case("offline"));
+			if !offline {
+				totals.power += reading.power;
+				totals.online += 1;
+			}
+		}
+		totals
+	}
+
+	/// Sets the timestamp strategy used by newly adopted smart plugs.
+	pub fn with_timestamp_strategy(self, strategy: TimestampStrategy) -> Self {
+		let mut s = self;
+		s.timestamp_strategy = strategy;
+		s
+	}
+
+	/// Sets how newly adopted smart plugs handle readings with a duplicate
+	/// timestamp.
+	pub fn with_duplicate_policy(self, policy: DuplicatePolicy) -> Self {
+		let mut s = self;
+		s.duplicate_policy = policy;
+		s
+	}
+
+	/// Limits how many devices are adopted from their telemetry, so a
+	/// misbehaving broker cannot make the swarm grow without bound. Devices
+	/// already adopted are unaffected. `None` adopts every device.
+	pub fn with_max_devices(self, max_devices: Option<usize>) -> Self {
+		let mut s = self;
+		s.max_devices = max_devices;
+		s
+	}
+
+	/// Sets the timezone device clocks are set to, for placing their
+	/// timestamps on the timeline.
+	pub fn with_device_timezone(self, timezone: DeviceTimezone) -> Self {
+		let mut s = self;
+		s.device_timezone = timezone;
+		s
+	}
+
+	/// Sets how monitor uptime is reported in telemetry.
+	pub fn with_monitor_uptime(self, monitor_uptime: MonitorUptime) -> Self {
+		let mut s = self;
+		s.monitor_uptime = monitor_uptime;
+		s
+	}
+
 	pub fn create_new_smartplug(&mut self, name: String) -> Option<SmartPlug<G>> {
-		let smartplug = SmartPlug::new(name);
+		let mut smartplug = SmartPlug::new(name)
+			.with_first_observation(self.now())
+			.with_timestamp_strategy(self.timestamp_strategy)
+			.with_duplicate_policy(self.duplicate_policy)
+			.with_timezone(self.device_timezone)
+			.with_derived_power(self.derive_power)
+			.with_sensor_only(self.sensor_only)
+			.with_lwt_debounce(self.lwt_debounce)
+			.with_today_baseline(self.today_baseline);
+		if let Some((date, energy_today)) = self.today_seeds.remove(smartplug.name()) {
+			smartplug.seed_energy_today(date, energy_today);
+		}
 
 		// Remove any existing smartplug with the same name.
-		let existing_smartplug = self.smartplugs.get(smartplug.name());
-		if let Some(existing_smartplug) = existing_smartplug {
-			self.telemetry_map
-				.remove(&existing_smartplug.sensor_telemetry_topic());
-			self.telemetry_map
-				.remove(&existing_smartplug.state_telemetry_topic());
-			self.telemetry_map.remove(&existing_smartplug.lwt_topic());
-		}
-
-		self.telemetry_map.insert(
-			smartplug.sensor_telemetry_topic(),
-			smartplug.name().to_string(),
-		);
-		self.telemetry_map.insert(
-			smartplug.state_telemetry_topic(),
-			smartplug.name().to_string(),
-		);
-		self.telemetry_map
-			.insert(smartplug.lwt_topic(), smartplug.name().to_string());
+		if self.smartplugs.contains_key(smartplug.name()) {
+			self.unmap_topics(smartplug.name());
+		}
 
+		self.map_topics(smartplug.name());
 		self.smartplugs
 			.insert(smartplug.name().to_string(), smartplug)
 	}
 
+	/// Renames a device, migrating its energy offset, daily baseline and
+	/// unwritten telemetry rather than starting afresh, so its energy
+	/// continues without a discontinuity. Returns false, changing nothing, if
+	/// `old` is unknown or `new` is already in use.
+	pub fn rename(&mut self, old: &str, new: &str) -> bool {
+		if self.smartplugs.contains_key(new) {
+			tracing::warn!("cannot rename device '{old}' to '{new}', which already exists");
+			return false;
+		}
+		let Some(mut smartplug) = self.smartplugs.remove(old) else {
+			tracing::warn!("ignoring rename of unknown device '{old}'");
+			return false;
+		};
+
+		tracing::info!("renaming device '{old}' to '{new}'");
+		self.unmap_topics(old);
+		smartplug.rename(new.to_string());
+		self.map_topics(new);
+		self.smartplugs.insert(new.to_string(), smartplug);
+
+		if let Some(reading) = self.latest.remove(old) {
+			self.latest.insert(new.to_string(), reading);
+		}
+		if let Some(breaker) = self.breakers.remove(old) {
+			self.breakers.insert(new.to_string(), breaker);
+		}
+		if let Some(cost) = self.costs.remove(old) {
+			self.costs.insert(new.to_string(), cost);
+		}
+		if let Some(downsampler) = self.downsamplers.remove(old) {
+			self.downsamplers.insert(new.to_string(), downsampler);
+		}
+		if let Some(sampler) = self.diagnostics_samplers.remove(old) {
+			self.diagnostics_samplers.insert(new.to_string(), sampler);
+		}
+		true
+	}
+
+	fn map_topics(&mut self, name: &str) {
+		for topic in self.topics(name) {
+			self.telemetry_map.insert(topic, name.to_string());
+		}
+	}
+
+	fn unmap_topics(&mut self, name: &str) {
+		for topic in self.topics(name) {
+			self.telemetry_map.remove(&topic);
+		}
+	}
+
+	fn topics(&self, name: &str) -> [String; 6] {
+		let status_response = self.topics.status_response_topic(name);
+		[
+			self.topics.sensor_telemetry_topic(name),
+			self.topics.state_telemetry_topic(name),
+			self.topics.lwt_topic(name),
+			self.topics.info_topic(name),
+			// Tasmota answers `Status 0` on `STATUS0`.
+			format!("{status_response}0"),
+			status_response,
+		]
+	}
+
+	/// Handles a command published to `fizzle/cmnd/{device}/{command}`.
+	/// Returns false if the command or device is not recognised.
+	pub fn handle_control(&mut self, topic: &str) -> bool {
+		let Some(command) = topic.strip_prefix("fizzle/cmnd/") else {
+			return false;
+		};
+
+		match command.strip_suffix("/reset_energy") {
+			Some(name) => self.reset_energy(name),
+			None => {
+				tracing::warn!("unknown control command on topic '{topic}'");
+				false
+			}
+		}
+	}
+
+	/// Resets the energy offset and daily baseline of the named device.
+	/// Returns false, changing nothing, if the device is unknown.
+	pub fn reset_energy(&mut self, name: &str) -> bool {
+		let Some(smartplug) = self.smartplugs.get_mut(name) else {
+			tracing::warn!("ignoring energy reset for unknown device '{name}'");
+			return false;
+		};
+
+		tracing::info!("resetting energy accumulation for device '{name}'");
+		smartplug.reset_energy();
+		true
+	}
+
 	pub async fn handle_telemetry(
 		&mut self,
 		message: Message,
+	) -> Result<(), Box<dyn error::Error + 'static>> {
+		let Message { topic, payload, .. } = message;
+		self.handle_payload(topic.as_str(), payload).await
+	}
+
+	pub async fn handle_payload(
+		&mut self,
+		topic: &str,
+		payload: Bytes,
 	) -> Result<(), Box<dyn error::Error + 'static>> {
 		//
-		let topic = message.topic.as_str();
 		let mut smartplug_name = self.telemetry_map.get(topic).map(|s| s.as_str());
 		if smartplug_name.is_none() {
 			tracing::warn!("handling telemetry from unknown topic: {topic}");
-			if let Some(name) = G::extract_device_name(topic) {
+			if let Some(name) = self.topics.extract_device_name(topic) {
 				tracing::warn!("extracted device name: {name}");
 				smartplug_name = Some(name);
-				if let Some(old_plug) = self.create_new_smartplug(name.to_string()) {
-					tracing::warn!(
-						"created new smartplug: {name}, replacing old smartplug: {old_plug:?}"
-					);
+
+				// Adoption is idempotent; a second topic for a device we have
+				// only just seen must not reset its buffered telemetry.
+				if !self.smartplugs.contains_key(name) {
+					if let Some(max_devices) = self.max_devices {
+						if self.smartplugs.len() >= max_devices {
+							return Err(format!(
+								"not adopting device '{name}', already monitoring the maximum of {max_devices} devices"
+							)
+							.into());
+						}
+					}
+					self.create_new_smartplug(name.to_string());
+					tracing::warn!("created new smartplug: {name}");
 				}
 			}
 		}
@@ -77,6 +615,12 @@ impl<G: TopicGenerator + fmt::Debug> SmartPlugSwarm<G> {
 			tracing::error!("received telemetry for unknown topic: {}", topic);
 			return Err("unknown topic".into());
 		};
+		tracing::Span::current().record("device", smartplug_name);
+
+		let now = self.now();
+		if let Some(discovery) = &mut self.discovery {
+			discovery.poll(&self.topics, smartplug_name, now);
+		}
 
 		let Some(smartplug) = self.smartplugs.get_mut(smartplug_name) else {
 			tracing::error!(
@@ -86,51 +630,284 @@ impl<G: TopicGenerator + fmt::Debug> SmartPlugSwarm<G> {
 			return Ok(());
 		};
 
-		match G::telemetry_type(topic) {
+		match self.topics.telemetry_type(topic) {
 			Some(TelemetryType::Sensor) => {
-				let telemetry = parse_json_payload::<StatusSNS>(message)?;
+				let telemetry = parse_json_bytes::<StatusSNS>(topic, payload)?;
 				smartplug.append_sensor_telemetry(telemetry);
 			}
 			Some(TelemetryType::State) => {
-				let telemetry = parse_json_payload::<StatusSTS>(message)?;
+				let telemetry = parse_json_bytes::<StatusSTS>(topic, payload)?;
 				smartplug.append_state_telemetry(telemetry);
 			}
 			Some(TelemetryType::Lwt) => {
 				// The Tasmota LWT payload is just a string.
-				let lwt = bytes_to_string(message.payload.clone())?;
-				smartplug.set_lwt(lwt);
+				let lwt = bytes_to_string(payload)?;
+				smartplug.set_lwt(lwt, now);
+			}
+			Some(TelemetryType::Status) => {
+				let status = parse_json_bytes::<Status0>(topic, payload)?;
+				if let Some(discovery) = &mut self.discovery {
+					discovery.update(smartplug_name, status);
+				}
+			}
+			Some(TelemetryType::Info) => {
+				// Only the first boot message is needed to mark the reboot.
+				if self.reboot_events && topic.ends_with("/INFO1") {
+					let info = parse_json_bytes::<Info1>(topic, payload)?;
+					tracing::info!(
+						"device '{smartplug_name}' rebooted running {}",
+						info.info.version
+					);
+					self.writer
+						.write_with(|builder| {
+							builder
+								.measurement("reboot")
+								.tag("device", smartplug_name)
+								.field("module", info.info.module.as_str())
+								.field("version", info.info.version.as_str())
+								.timestamp(millis_from_datetime(now))
+								.close_line()
+						})
+						.await?;
+				}
 			}
 			None => {
 				tracing::warn!("unknown telemetry type received for device '{smartplug_name}' on topic '{topic}'");
 			}
 		}
 
-		if let Some((dt, sns, sts)) = smartplug.matched_telemetry() {
+		if let Some((dt, sns, sts)) = smartplug.next_telemetry() {
 			//
-			let telemetry = smartplug.generate_telemetry(dt, sns, sts)?;
+			let telemetry = match sts {
+				Some(sts) => smartplug.generate_telemetry(dt, sns, sts)?,
+				None => smartplug.generate_sensor_telemetry(dt, sns)?,
+			};
+			let state_changes = smartplug.observe_states(dt, &telemetry.states);
+			let name = telemetry.name.clone();
+			let timestamp = telemetry.timestamp;
+
+			// State changes are written as they happen, even while the
+			// reading itself is held back to be downsampled.
+			match self.downsample {
+				Some(config) => {
+					let downsampled = self
+						.downsamplers
+						.entry(name.clone())
+						.or_insert_with(|| Downsampler::new(config))
+						.push(telemetry);
+					if let Some(telemetry) = downsampled {
+						let dt = telemetry.timestamp.map_or(dt, datetime_from_millis);
+						self.write_telemetry(telemetry, dt, now).await?;
+					}
+				}
+				None => self.write_telemetry(telemetry, dt, now).await?,
+			}
+			self.write_state_changes(&name, state_changes, timestamp)
+				.await?;
+		}
+
+		Ok(())
+	}
+
+	/// Writes a telemetry point, accumulating its cost as of `dt`.
+	///
+	/// While the device's circuit breaker is open the point itself is
+	/// dropped, but it still counts towards its groups' totals.
+	async fn write_telemetry(
+		&mut self,
+		telemetry: Telemetry,
+		dt: OffsetDateTime,
+		now: OffsetDateTime,
+	) -> Result<(), Box<dyn error::Error + 'static>> {
+		let energy_today = match self.smartplugs.get_mut(&telemetry.name) {
+			Some(smartplug) => smartplug.energy_today(dt, telemetry.energy),
+			None => Some(0),
+		};
+		let cumulative_cost = self.tariff.as_ref().map(|tariff| {
+			self.costs
+				.entry(telemetry.name.clone())
+				.or_default()
+				.accumulate(tariff, dt, telemetry.energy)
+		});
+
+		let breaker_config = self.breaker_config;
+		let allowed = self
+			.breakers
+			.entry(telemetry.name.clone())
+			.or_insert_with(|| CircuitBreaker::new(breaker_config))
+			.allow(&telemetry.name, Instant::now());
+		if allowed {
+			self.write_telemetry_point(&telemetry, dt, now, energy_today, cumulative_cost)
+				.await?;
+		} else {
+			tracing::debug!(
+				"writes paused for device '{}', dropping telemetry",
+				telemetry.name
+			);
+		}
+
+		self.latest.insert(
+			telemetry.name.clone(),
+			LatestReading {
+				power: telemetry.power,
+				energy: telemetry.energy,
+			},
+		);
+		self.write_group_telemetry(&telemetry.name, telemetry.timestamp)
+			.await?;
+
+		Ok(())
+	}
+
+	/// Writes the `telemetry` point for a reading, tracking its outcome.
+	async fn write_telemetry_point(
+		&mut self,
+		telemetry: &Telemetry,
+		dt: OffsetDateTime,
+		now: OffsetDateTime,
+		energy_today: Option<i64>,
+		cumulative_cost: Option<f64>,
+	) -> Result<(), Box<dyn error::Error + 'static>> {
+		let monitor_field = self.monitor_uptime.field_at(telemetry.monitor_start, now);
+		let diagnostics_sampling = self.diagnostics_sampling;
+		let sample_diagnostics = self.diagnostics
+			&& self
+				.diagnostics_samplers
+				.entry(telemetry.name.clone())
+				.or_insert_with(|| DiagnosticsSampler::new(diagnostics_sampling))
+				.sample(dt);
+
+		let status = self
+			.writer
+			.write_with(|builder| {
+				let builder = builder.measurement("telemetry");
+				let builder = if telemetry.derived {
+					builder.tag("derived", "true")
+				} else {
+					builder
+				};
+				let builder = builder.tag("device", &telemetry.name);
+				let builder = match &telemetry.ssid {
+					Some(ssid) if self.diagnostics => builder.tag("ssid", ssid),
+					_ => builder,
+				};
+				let builder =
+					builder.field("current", self.round_field("current", telemetry.current));
+				let builder = builder.optional_field("device_uptime", telemetry.device_uptime);
+				let builder = builder.field("energy", telemetry.energy);
+				let builder = if self.energy_kwh {
+					// Whole Wh are exact in kWh to well beyond any meter's lifetime.
+					let kwh = telemetry.energy as f64 / 1000.0;
+					builder.field("energy_kwh", self.round_field("energy_kwh", kwh))
+				} else {
+					builder
+				};
+				let builder = builder
+					.optional_field("energy_today", energy_today)
+					.field("device_energy_today", telemetry.device_energy_today)
+					.field("device_energy_yesterday", telemetry.device_energy_yesterday)
+					.field("power", telemetry.power);
+				let builder = telemetry
+					.states
+					.iter()
+					.fold(builder, |builder, (relay, state)| match relay {
+						0 => builder.field("state", state_str(*state)),
+						relay => builder.field(&format!("state{relay}"), state_str(*state)),
+					});
+				let builder = builder
+					.field("total_start_time", telemetry.total_start_time)
+					.field("voltage", telemetry.voltage);
+				let power_factor = telemetry
+					.power_factor
+					.map(|value| self.round_field("power_factor", value));
+				let builder = builder
+					.optional_field("apparent_power", telemetry.apparent_power)
+					.optional_field("power_factor", power_factor)
+					.optional_field("reactive_power", telemetry.reactive_power)
+					.optional_field("cumulative_cost", cumulative_cost)
+					.optional_field(
+						"mqtt_count",
+						telemetry.mqtt_count.filter(|_| sample_diagnostics),
+					);
+				let builder = match (telemetry.rssi, telemetry.wifi_signal, telemetry.link_count) {
+					(Some(rssi), Some(signal), Some(link_count)) if sample_diagnostics => builder
+						.field("rssi", rssi)
+						.field("wifi_signal", signal)
+						.field("link_count", link_count),
+					_ => builder,
+				};
+				let builder = match monitor_field {
+					Some((key, value)) => builder.field(key, value),
+					None => builder,
+				};
+				match telemetry.timestamp {
+					Some(timestamp) => builder.timestamp(timestamp).close_line(),
+					None => builder.close_line(),
+				}
+			})
+			.await?;
+		if let Some(health) = &self.health {
+			health.track(status.clone());
+		}
+		if let Some(breaker) = self.breakers.get_mut(&telemetry.name) {
+			breaker.track(status);
+		}
+
+		Ok(())
+	}
+
+	/// Writes each relay's state transition. Relays other than a device's
+	/// only relay are tagged with their number.
+	async fn write_state_changes(
+		&self,
+		name: &str,
+		state_changes: Vec<(u8, PowerState, PowerState)>,
+		timestamp: Option<i64>,
+	) -> Result<(), Box<dyn error::Error + 'static>> {
+		for (relay, from, to) in state_changes {
 			self.writer
 				.write_with(|builder| {
-					builder
-						.measurement("telemetry")
-						.tag("device", &telemetry.name)
-						.field("apparent_power", telemetry.apparent_power)
-						.field("current", telemetry.current)
-						.field("device_uptime", telemetry.device_uptime)
-						.field("energy", telemetry.energy)
-						.field("monitor_uptime", telemetry.monitor_uptime)
-						.field("power", telemetry.power)
-						.field("power_factor", telemetry.power_factor)
-						.field("reactive_power", telemetry.reactive_power)
-						.field(
-							"state",
-							match telemetry.state {
-								PowerState::On => "on",
-								PowerState::Off => "off",
-							},
-						)
-						.field("voltage", telemetry.voltage)
-						.timestamp(telemetry.timestamp)
-						.close_line()
+					let builder = builder.measurement("state_change").tag("device", name);
+					let builder = match relay {
+						0 => builder,
+						relay => builder.tag("relay", &relay.to_string()),
+					};
+					let builder = builder
+						.field("from", state_str(from))
+						.field("to", state_str(to));
+					match timestamp {
+						Some(timestamp) => builder.timestamp(timestamp).close_line(),
+						None => builder.close_line(),
+					}
+				})
+				.await?;
+		}
+		Ok(())
+	}
+
+	async fn write_group_telemetry(
+		&self,
+		name: &str,
+		timestamp: Option<i64>,
+	) -> Result<(), Box<dyn error::Error + 'static>> {
+		for (group, members) in &self.groups {
+			if !members.iter().any(|member| member == name) {
+				continue;
+			}
+
+			let totals = self.group_totals(members);
+			self.writer
+				.write_with(|builder| {
+					let builder = builder
+						.measurement("group_telemetry")
+						.tag("group", group)
+						.field("energy", totals.energy)
+						.field("online", totals.online)
+						.field("power", totals.power);
+					match timestamp {
+						Some(timestamp) => builder.timestamp(timestamp).close_line(),
+						None => builder.close_line(),
+					}
 				})
 				.await?;
 		}
@@ -138,3 +915,798 @@ impl<G: TopicGenerator + fmt::Debug> SmartPlugSwarm<G> {
 		Ok(())
 	}
 }
+
+fn state_str(state: PowerState) -> &'static str {
+	match state {
+		PowerState::On => "on",
+		PowerState::Off => "off",
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		breaker::CircuitBreakerConfig,
+		diagnostics::DiagnosticsSampling,
+		downsample::{Aggregation, DownsampleConfig},
+		topic::HomeTasmotaTopicScheme,
+		DeviceTimezone, SmartPlugSwarm, TimestampStrategy,
+	};
+	use crate::{
+		capture::{read_capture, replay, Recorder},
+		util::message_span,
+	};
+	use bytes::Bytes;
+	use influxdb::{
+		util::{channel_buffered_client, stdout_buffered_client},
+		Status,
+	};
+	use std::{
+		collections::BTreeMap,
+		fmt,
+		sync::{Arc, Mutex},
+	};
+	use time::{macros::datetime, Duration, UtcOffset};
+	use tokio::sync::{mpsc, watch};
+	use tracing::{
+		field::{Field, Visit},
+		span, Instrument, Subscriber,
+	};
+	use tracing_subscriber::{
+		layer::{Context, SubscriberExt},
+		Layer, Registry,
+	};
+
+	pub(crate) const SENSOR: &str = r#"{"Time":"2023-10-04T12:00:00","ENERGY":{"TotalStartTime":"2023-01-01T00:00:00","Total":12.345,"Yesterday":1.200,"Today":0.500,"Period":3,"Power":120,"ApparentPower":130,"ReactivePower":40,"Factor":0.92,"Voltage":240,"Current":0.540}}"#;
+
+	pub(crate) const STATE: &str = r#"{"Time":"2023-10-04T12:00:10","Uptime":"0T01:00:00","UptimeSec":3600,"Vcc":3.2,"LoadAvg":19,"Sleep":50,"SleepMode":"Dynamic","MqttCount":1,"POWER":"ON","Wifi":{"AP":1,"SSId":"home","BSSId":"AA:BB:CC:DD:EE:FF","Channel":6,"RSSI":80,"Signal":-60,"LinkCount":1,"Downtime":"0T00:00:03"}}"#;
+
+	/// Sensor telemetry at `time` with the given power reading.
+	pub(crate) fn sensor(time: &str, power: u32) -> String {
+		SENSOR
+			.replace("2023-10-04T12:00:00", time)
+			.replace(r#""Power":120"#, &format!(r#""Power":{power}"#))
+	}
+
+	/// State telemetry at `time` with the given relay state.
+	pub(crate) fn state(time: &str, power_state: &str) -> String {
+		STATE
+			.replace("2023-10-04T12:00:10", time)
+			.replace(r#""POWER":"ON""#, &format!(r#""POWER":"{power_state}""#))
+	}
+
+	/// Collects the line protocol written so far.
+	pub(crate) fn written_lines(
+		rx: &mut mpsc::Receiver<(Bytes, watch::Sender<Status>)>,
+	) -> Vec<String> {
+		let mut lines = Vec::new();
+		while let Ok((buffer, _)) = rx.try_recv() {
+			let buffer = String::from_utf8(buffer.to_vec()).unwrap();
+			lines.extend(buffer.lines().map(String::from));
+		}
+		lines
+	}
+
+	/// Collects the lines written so far to `measurement`.
+	pub(crate) fn written_measurement(
+		rx: &mut mpsc::Receiver<(Bytes, watch::Sender<Status>)>,
+		measurement: &str,
+	) -> Vec<String> {
+		written_lines(rx)
+			.into_iter()
+			.filter(|line| line.split([',', ' ']).next() == Some(measurement))
+			.collect()
+	}
+
+	/// A reading from `device`: `sensor` telemetry, then state telemetry at
+	/// `time` with the relay on.
+	pub(crate) fn reading(device: &str, time: &str, sensor: String) -> [(String, String); 2] {
+		[
+			(format!("tasmota/tele/{device}/SENSOR"), sensor),
+			(format!("tasmota/tele/{device}/STATE"), state(time, "ON")),
+		]
+	}
+
+	/// Handles each `(topic, payload)` message in turn, as if received over
+	/// MQTT.
+	pub(crate) async fn handle_messages<T: AsRef<str>>(
+		swarm: &mut SmartPlugSwarm<HomeTasmotaTopicScheme>,
+		messages: impl IntoIterator<Item = (T, String)>,
+	) {
+		for (topic, payload) in messages {
+			swarm
+				.handle_payload(topic.as_ref(), Bytes::from(payload))
+				.await
+				.unwrap();
+		}
+	}
+
+	#[tokio::test]
+	async fn adoption_is_idempotent() {
+		let (writer, _) = stdout_buffered_client();
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer);
+
+		swarm
+			.handle_payload("tasmota/tele/kitchen/kettle/SENSOR", Bytes::from(SENSOR))
+			.await
+			.unwrap();
+		swarm
+			.handle_payload("tasmota/tele/kitchen/kettle/STATE", Bytes::from(STATE))
+			.await
+			.unwrap();
+
+		assert_eq!(swarm.smartplugs.len(), 1);
+		let smartplug = &swarm.smartplugs["kitchen/kettle"];
+		assert_eq!(smartplug.pending_telemetry(), 2);
+
+		// Status responses are matched without adopting the device again.
+		for topic in [
+			"tasmota/stat/kitchen/kettle/STATUS",
+			"tasmota/stat/kitchen/kettle/STATUS0",
+		] {
+			assert_eq!(
+				swarm.telemetry_map.get(topic).map(String::as_str),
+				Some("kitchen/kettle")
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn group_telemetry_is_summed() {
+		let (writer, mut rx) = channel_buffered_client(16);
+		let groups = BTreeMap::from([(
+			String::from("kitchen"),
+			vec![
+				String::from("kitchen/kettle"),
+				String::from("kitchen/toaster"),
+			],
+		)]);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer).with_groups(groups);
+
+		let time = "2023-10-04T12:00:00";
+		let messages = [
+			reading("kitchen/kettle", time, sensor(time, 120)),
+			reading("kitchen/toaster", time, sensor(time, 80)),
+		];
+		handle_messages(&mut swarm, messages.concat()).await;
+
+		let group_lines = written_measurement(&mut rx, "group_telemetry");
+
+		assert_eq!(group_lines.len(), 2);
+		assert!(group_lines[1]
+			.starts_with("group_telemetry,group=kitchen energy=0i,online=2i,power=200i "));
+	}
+
+	#[tokio::test]
+	async fn batched_readings_keep_their_own_timestamps() {
+		let (writer, mut rx) = channel_buffered_client(16);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer)
+			.with_timestamp_strategy(TimestampStrategy::PreferDevice)
+			.with_device_timezone(DeviceTimezone::Fixed(UtcOffset::UTC));
+
+		// Two readings from one device, buffered into the same batch.
+		let later = "2023-10-04T12:00:20";
+		let messages = [
+			("tasmota/tele/kitchen/kettle/SENSOR", String::from(SENSOR)),
+			("tasmota/tele/kitchen/kettle/STATE", String::from(STATE)),
+			("tasmota/tele/kitchen/kettle/SENSOR", sensor(later, 120)),
+			("tasmota/tele/kitchen/kettle/STATE", state(later, "ON")),
+		];
+		handle_messages(&mut swarm, messages).await;
+
+		let timestamps: Vec<_> = written_measurement(&mut rx, "telemetry")
+			.into_iter()
+			.map(|line| line.rsplit_once(' ').unwrap().1.to_string())
+			.collect();
+		assert_eq!(timestamps, vec!["1696420810000", "1696420820000"]);
+	}
+
+	#[tokio::test]
+	async fn energy_today_follows_device_time() {
+		let (writer, mut rx) = channel_buffered_client(16);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer)
+			.with_timestamp_strategy(TimestampStrategy::PreferDevice)
+			.with_device_timezone(DeviceTimezone::Fixed(UtcOffset::UTC));
+
+		// A backlog spanning a day, all received at once.
+		swarm.set_clock(Some(datetime!(2023-10-05 12:00:05 UTC)));
+		for (time, total) in [
+			("2023-10-04T12:00:00", "12.000"),
+			("2023-10-04T13:00:00", "12.100"),
+			("2023-10-05T12:00:00", "12.400"),
+		] {
+			let sensor = sensor(time, 120).replace("12.345", total);
+			handle_messages(&mut swarm, reading("kitchen/kettle", time, sensor)).await;
+		}
+
+		// The last reading starts a new day, though it arrived with the rest.
+		let energy_today: Vec<_> = written_measurement(&mut rx, "telemetry")
+			.into_iter()
+			.map(|line| {
+				line.split([' ', ','])
+					.find_map(|field| field.strip_prefix("energy_today="))
+					.unwrap()
+					.to_string()
+			})
+			.collect();
+		assert_eq!(energy_today, vec!["0i", "100i", "300i"]);
+	}
+
+	#[tokio::test]
+	async fn state_transition_writes_one_event() {
+		let (writer, mut rx) = channel_buffered_client(16);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer);
+
+		let (on, off) = ("2023-10-04T12:00:00", "2023-10-04T12:00:10");
+		let messages = [
+			("tasmota/tele/kitchen/kettle/SENSOR", sensor(on, 120)),
+			("tasmota/tele/kitchen/kettle/STATE", state(on, "ON")),
+			("tasmota/tele/kitchen/kettle/SENSOR", sensor(off, 0)),
+			("tasmota/tele/kitchen/kettle/STATE", state(off, "OFF")),
+			// A duplicate of the previous reading must not count twice.
+			("tasmota/tele/kitchen/kettle/SENSOR", sensor(off, 0)),
+			("tasmota/tele/kitchen/kettle/STATE", state(off, "OFF")),
+		];
+		handle_messages(&mut swarm, messages).await;
+
+		let events = written_measurement(&mut rx, "state_change");
+		assert_eq!(events.len(), 1);
+		assert!(events[0].starts_with(r#"state_change,device=kitchen/kettle from="on",to="off" "#));
+	}
+
+	#[tokio::test]
+	async fn diagnostics_include_mqtt_count() {
+		for diagnostics in [false, true] {
+			let (writer, mut rx) = channel_buffered_client(16);
+			let mut swarm =
+				SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer).with_diagnostics(diagnostics);
+
+			let time = "2023-10-04T12:00:00";
+			handle_messages(
+				&mut swarm,
+				reading("kitchen/kettle", time, sensor(time, 120)),
+			)
+			.await;
+
+			let telemetry = written_measurement(&mut rx, "telemetry");
+			assert_eq!(telemetry[0].contains(",mqtt_count=1u"), diagnostics);
+		}
+	}
+
+	#[tokio::test]
+	async fn stale_telemetry_is_swept() {
+		let (writer, mut rx) = channel_buffered_client(16);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer)
+			.with_timestamp_strategy(TimestampStrategy::PreferDevice)
+			.with_device_timezone(DeviceTimezone::Fixed(UtcOffset::UTC))
+			.with_stale_after(Some(Duration::minutes(10)));
+		swarm.set_clock(Some(datetime!(2023-10-04 13:00 UTC)));
+
+		let messages = ["2023-10-04T11:00:00", "2023-10-04T12:58:00"]
+			.map(|time| ("tasmota/tele/kitchen/kettle/SENSOR", sensor(time, 120)));
+		handle_messages(&mut swarm, messages).await;
+		assert_eq!(swarm.sweep_stale(), 1);
+		assert_eq!(swarm.smartplugs["kitchen/kettle"].pending_telemetry(), 1);
+
+		let state = state("2023-10-04T12:58:00", "ON");
+		handle_messages(&mut swarm, [("tasmota/tele/kitchen/kettle/STATE", state)]).await;
+		let telemetry = written_measurement(&mut rx, "telemetry");
+		assert_eq!(telemetry.len(), 1);
+		assert!(telemetry[0].ends_with(" 1696424280000"));
+	}
+
+	#[tokio::test]
+	async fn diagnostics_are_sampled() {
+		let (writer, mut rx) = channel_buffered_client(64);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer)
+			.with_diagnostics(true)
+			.with_diagnostics_sampling(DiagnosticsSampling {
+				every: Some(3),
+				interval_secs: None,
+			});
+
+		for second in 0..6 {
+			let time = format!("2023-10-04T12:00:{:02}", second * 10);
+			handle_messages(
+				&mut swarm,
+				reading("kitchen/kettle", &time, sensor(&time, 120)),
+			)
+			.await;
+		}
+
+		let telemetry = written_measurement(&mut rx, "telemetry");
+		assert_eq!(telemetry.len(), 6);
+		assert!(telemetry.iter().all(|line| line.contains(",power=120i")));
+		let sampled: Vec<_> = telemetry
+			.iter()
+			.map(|line| line.contains(",mqtt_count=1u") && line.contains(",rssi=80i"))
+			.collect();
+		assert_eq!(sampled, vec![true, false, false, true, false, false]);
+	}
+
+	#[tokio::test]
+	async fn wifi_status_is_optional() {
+		let without_wifi = STATE
+			.split_once(r#","Wifi""#)
+			.map(|(state, _)| format!("{state}}}"))
+			.unwrap();
+
+		for (state, wifi) in [(String::from(STATE), true), (without_wifi, false)] {
+			let (writer, mut rx) = channel_buffered_client(16);
+			let mut swarm =
+				SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer).with_diagnostics(true);
+			let messages = [
+				("tasmota/tele/kitchen/kettle/SENSOR", String::from(SENSOR)),
+				("tasmota/tele/kitchen/kettle/STATE", state),
+			];
+			handle_messages(&mut swarm, messages).await;
+
+			let telemetry = written_measurement(&mut rx, "telemetry").remove(0);
+			assert!(telemetry.contains(",device_energy_today=500i,device_energy_yesterday=1200i,"));
+			assert_eq!(
+				telemetry.starts_with("telemetry,device=kitchen/kettle,ssid=home "),
+				wifi
+			);
+			assert_eq!(
+				telemetry.contains(",rssi=80i,wifi_signal=-60i,link_count=1u"),
+				wifi
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn reset_energy_clears_offset() {
+		let (writer, mut rx) = channel_buffered_client(16);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer);
+
+		let readings = [
+			("2023-10-04T12:00:00", "12.345"),
+			("2023-10-04T12:00:10", "13.345"),
+			("2023-10-04T12:00:20", "13.500"),
+		];
+		for (index, (time, total)) in readings.into_iter().enumerate() {
+			if index == 2 {
+				assert!(!swarm.handle_control("fizzle/cmnd/kitchen/toaster/reset_energy"));
+				assert!(swarm.handle_control("fizzle/cmnd/kitchen/kettle/reset_energy"));
+			}
+
+			let sensor = sensor(time, 120).replace("12.345", total);
+			handle_messages(&mut swarm, reading("kitchen/kettle", time, sensor)).await;
+		}
+
+		let energy: Vec<_> = written_measurement(&mut rx, "telemetry")
+			.into_iter()
+			.map(|line| {
+				let field = line.split(',').find(|field| field.starts_with("energy="));
+				field.unwrap().to_string()
+			})
+			.collect();
+		assert_eq!(energy, vec!["energy=0i", "energy=1000i", "energy=0i"]);
+	}
+
+	#[tokio::test]
+	async fn rename_preserves_offset() {
+		let (writer, mut rx) = channel_buffered_client(16);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer);
+
+		let readings = [
+			("kitchen/kettle", "2023-10-04T12:00:00", "12.345"),
+			("kitchen/jug", "2023-10-04T12:00:10", "13.345"),
+		];
+		for (name, time, total) in readings {
+			if name == "kitchen/jug" {
+				assert!(!swarm.rename("kitchen/toaster", "kitchen/jug"));
+				assert!(swarm.rename("kitchen/kettle", "kitchen/jug"));
+			}
+
+			let sensor = sensor(time, 120).replace("12.345", total);
+			handle_messages(&mut swarm, reading(name, time, sensor)).await;
+		}
+
+		let telemetry = written_measurement(&mut rx, "telemetry");
+		assert_eq!(telemetry.len(), 2);
+		assert!(telemetry[1].contains("device=kitchen/jug"));
+		assert!(telemetry[1].contains("energy=1000i"));
+	}
+
+	#[tokio::test]
+	async fn energy_is_written_in_kwh() {
+		let (writer, mut rx) = channel_buffered_client(16);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer).with_energy_kwh(true);
+
+		let later = "2023-10-04T12:00:20";
+		let messages = [
+			("tasmota/tele/kitchen/kettle/SENSOR", String::from(SENSOR)),
+			("tasmota/tele/kitchen/kettle/STATE", String::from(STATE)),
+			(
+				"tasmota/tele/kitchen/kettle/SENSOR",
+				sensor(later, 120).replace("12.345", "13.579"),
+			),
+			("tasmota/tele/kitchen/kettle/STATE", state(later, "ON")),
+		];
+		handle_messages(&mut swarm, messages).await;
+
+		let telemetry = written_measurement(&mut rx, "telemetry");
+		assert_eq!(telemetry.len(), 2);
+		assert!(telemetry[0].contains(",energy=0i,energy_kwh=0,"));
+		assert!(telemetry[1].contains(",energy=1234i,energy_kwh=1.234,"));
+	}
+
+	#[tokio::test]
+	async fn float_fields_are_rounded() {
+		let (writer, mut rx) = channel_buffered_client(16);
+		let precision = BTreeMap::from([(String::from("current"), 3)]);
+		let mut swarm =
+			SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer).with_float_precision(precision);
+
+		let time = "2023-10-04T12:00:00";
+		let sensor = sensor(time, 120).replace("0.540", "1.23456789");
+		handle_messages(&mut swarm, reading("kitchen/kettle", time, sensor)).await;
+
+		let telemetry = written_measurement(&mut rx, "telemetry");
+		assert!(telemetry[0].contains(" current=1.235,"));
+	}
+
+	#[tokio::test]
+	async fn adoption_is_refused_past_the_cap() {
+		let (writer, _) = stdout_buffered_client();
+		let mut swarm =
+			SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer).with_max_devices(Some(1));
+
+		swarm
+			.handle_payload("tasmota/tele/kitchen/kettle/SENSOR", Bytes::from(SENSOR))
+			.await
+			.unwrap();
+		assert!(swarm
+			.handle_payload("tasmota/tele/kitchen/toaster/SENSOR", Bytes::from(SENSOR))
+			.await
+			.is_err());
+		assert_eq!(swarm.smartplugs.len(), 1);
+
+		// The device already adopted carries on.
+		swarm
+			.handle_payload("tasmota/tele/kitchen/kettle/STATE", Bytes::from(STATE))
+			.await
+			.unwrap();
+	}
+
+	#[tokio::test]
+	async fn sensor_only_writes_without_state() {
+		let (writer, mut rx) = channel_buffered_client(16);
+		let mut swarm =
+			SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer).with_sensor_only(true);
+
+		let later = sensor("2023-10-04T12:00:10", 150).replace("12.345", "13.345");
+		let messages = [
+			("tasmota/tele/kitchen/kettle/SENSOR", String::from(SENSOR)),
+			("tasmota/tele/kitchen/kettle/STATE", String::from(STATE)),
+			("tasmota/tele/kitchen/kettle/SENSOR", later),
+		];
+		handle_messages(&mut swarm, messages).await;
+		assert_eq!(swarm.smartplugs["kitchen/kettle"].pending_telemetry(), 0);
+
+		let telemetry = written_measurement(&mut rx, "telemetry");
+		assert_eq!(telemetry.len(), 2);
+		assert!(telemetry[1].contains(",energy=1000i,"));
+		assert!(telemetry[1].contains(",power=150i,"));
+		assert!(!telemetry[1].contains("state="));
+		assert!(!telemetry[1].contains("device_uptime="));
+	}
+
+	#[tokio::test]
+	async fn readings_are_downsampled_per_minute() {
+		let (writer, mut rx) = channel_buffered_client(64);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer)
+			.with_timestamp_strategy(TimestampStrategy::PreferDevice)
+			.with_downsample(Some(DownsampleConfig {
+				interval_secs: 60,
+				aggregation: Aggregation::Max,
+			}));
+
+		for (second, power) in [
+			(0, 100),
+			(10, 180),
+			(20, 110),
+			(30, 120),
+			(40, 130),
+			(50, 90),
+		] {
+			let time = format!("2023-10-04T12:00:{second:02}");
+			handle_messages(
+				&mut swarm,
+				reading("kitchen/kettle", &time, sensor(&time, power)),
+			)
+			.await;
+		}
+		assert!(written_measurement(&mut rx, "telemetry").is_empty());
+
+		// The first reading of the next minute closes the previous one.
+		let time = "2023-10-04T12:01:00";
+		handle_messages(
+			&mut swarm,
+			reading("kitchen/kettle", time, sensor(time, 70)),
+		)
+		.await;
+		let lines = written_measurement(&mut rx, "telemetry");
+		assert_eq!(lines.len(), 1);
+		assert!(lines[0].contains(",power=180i,"));
+		let first = lines[0].rsplit(' ').next().unwrap().parse::<i64>().unwrap();
+		assert_eq!(first % 60_000, 0);
+
+		swarm.flush_downsampled().await.unwrap();
+		let lines = written_measurement(&mut rx, "telemetry");
+		assert_eq!(lines.len(), 1);
+		assert!(lines[0].contains(",power=70i,"));
+		assert!(lines[0].ends_with(&format!(" {}", first + 60_000)));
+	}
+
+	#[tokio::test]
+	async fn stale_intervals_are_flushed() {
+		let (writer, mut rx) = channel_buffered_client(64);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer)
+			.with_timestamp_strategy(TimestampStrategy::PreferDevice)
+			.with_device_timezone(DeviceTimezone::Fixed(UtcOffset::UTC))
+			.with_downsample(Some(DownsampleConfig {
+				interval_secs: 60,
+				aggregation: Aggregation::Last,
+			}));
+
+		// The device reports once during 12:00, then stops.
+		let time = "2023-10-04T12:00:30";
+		handle_messages(
+			&mut swarm,
+			reading("kitchen/kettle", time, sensor(time, 120)),
+		)
+		.await;
+
+		// Readings for 12:00 may still arrive late during 12:01.
+		swarm.set_clock(Some(datetime!(2023-10-04 12:01:30 UTC)));
+		swarm.flush_stale_downsampled().await.unwrap();
+		assert!(written_measurement(&mut rx, "telemetry").is_empty());
+
+		// The point is stamped at the end of its interval.
+		swarm.set_clock(Some(datetime!(2023-10-04 12:02:00 UTC)));
+		swarm.flush_stale_downsampled().await.unwrap();
+		let lines = written_measurement(&mut rx, "telemetry");
+		assert_eq!(lines.len(), 1);
+		assert!(lines[0].ends_with(" 1696420860000"));
+
+		// A reading for the interval already written is dropped.
+		let time = "2023-10-04T12:00:50";
+		handle_messages(
+			&mut swarm,
+			reading("kitchen/kettle", time, sensor(time, 120)),
+		)
+		.await;
+		swarm.flush_downsampled().await.unwrap();
+		assert!(written_measurement(&mut rx, "telemetry").is_empty());
+	}
+
+	#[tokio::test]
+	async fn info1_writes_reboot_event() {
+		let (writer, mut rx) = channel_buffered_client(16);
+		let mut swarm =
+			SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer).with_reboot_events(true);
+		swarm.set_clock(Some(datetime!(2023-10-04 12:00 UTC)));
+
+		let info1 = r#"{"Info1":{"Module":"Sonoff Basic","Version":"12.1.1(tasmota)","FallbackTopic":"cmnd/DVES_0A1B2C_fb/","GroupTopic":"cmnd/tasmotas/"}}"#;
+		swarm
+			.handle_payload("tasmota/tele/kitchen/kettle/INFO1", Bytes::from(info1))
+			.await
+			.unwrap();
+		assert!(swarm.smartplugs.contains_key("kitchen/kettle"));
+
+		let lines = written_lines(&mut rx);
+		assert_eq!(
+			lines,
+			vec![
+				r#"reboot,device=kitchen/kettle module="Sonoff Basic",version="12.1.1(tasmota)" 1696420800000"#
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn closed_writer_returns_error() {
+		let (writer, rx) = channel_buffered_client(16);
+		drop(rx);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer);
+
+		let time = "2023-10-04T12:00:00";
+		swarm
+			.handle_payload(
+				"tasmota/tele/kitchen/kettle/SENSOR",
+				Bytes::from(sensor(time, 120)),
+			)
+			.await
+			.unwrap();
+		let result = swarm
+			.handle_payload(
+				"tasmota/tele/kitchen/kettle/STATE",
+				Bytes::from(state(time, "ON")),
+			)
+			.await;
+		assert!(result.is_err());
+	}
+
+	/// Captures the fields recorded on every span.
+	#[derive(Clone, Default)]
+	struct SpanFields(Arc<Mutex<BTreeMap<String, String>>>);
+
+	struct FieldVisitor<'a>(&'a Mutex<BTreeMap<String, String>>);
+
+	impl Visit for FieldVisitor<'_> {
+		fn record_str(&mut self, field: &Field, value: &str) {
+			let mut fields = self.0.lock().unwrap();
+			fields.insert(field.name().to_string(), value.to_string());
+		}
+
+		fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+			let mut fields = self.0.lock().unwrap();
+			fields.insert(field.name().to_string(), format!("{value:?}"));
+		}
+	}
+
+	impl<S: Subscriber> Layer<S> for SpanFields {
+		fn on_new_span(&self, attrs: &span::Attributes<'_>, _: &span::Id, _: Context<'_, S>) {
+			attrs.record(&mut FieldVisitor(&self.0));
+		}
+
+		fn on_record(&self, _: &span::Id, values: &span::Record<'_>, _: Context<'_, S>) {
+			values.record(&mut FieldVisitor(&self.0));
+		}
+	}
+
+	#[tokio::test]
+	async fn message_span_records_topic_and_device() {
+		let fields = SpanFields::default();
+		let subscriber = Registry::default().with(fields.clone());
+		let _guard = tracing::subscriber::set_default(subscriber);
+
+		let (writer, _rx) = channel_buffered_client(16);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer);
+		let topic = "tasmota/tele/kitchen/kettle/SENSOR";
+		swarm
+			.handle_payload(topic, Bytes::from_static(SENSOR.as_bytes()))
+			.instrument(message_span(topic))
+			.await
+			.unwrap();
+
+		let fields = fields.0.lock().unwrap();
+		assert_eq!(fields.get("topic").map(String::as_str), Some(topic));
+		assert_eq!(
+			fields.get("device").map(String::as_str),
+			Some("kitchen/kettle")
+		);
+	}
+
+	#[tokio::test]
+	async fn rejected_device_is_tripped() {
+		let (writer, mut rx) = channel_buffered_client(64);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer).with_circuit_breaker(
+			CircuitBreakerConfig {
+				threshold: 2,
+				cooldown_secs: 3600,
+			},
+		);
+
+		let mut written = Vec::new();
+		for second in 0..4 {
+			let time = format!("2023-10-04T12:00:0{second}");
+			for device in ["kitchen/kettle", "kitchen/toaster"] {
+				let sensor = sensor(&time, 100 + second);
+				handle_messages(&mut swarm, reading(device, &time, sensor)).await;
+			}
+
+			// InfluxDB refuses everything from the kettle.
+			while let Ok((buffer, status)) = rx.try_recv() {
+				let line = String::from_utf8(buffer.to_vec()).unwrap();
+				if line.contains("device=kitchen/kettle") {
+					status.send_replace(Status::Rejected);
+				} else {
+					status.send_replace(Status::Accepted);
+				}
+				written.push((second, line));
+			}
+		}
+
+		let telemetry = |device: &str| -> Vec<_> {
+			written
+				.iter()
+				.filter(|(_, line)| {
+					line.starts_with(&format!("telemetry,device=kitchen/{device} "))
+				})
+				.map(|(second, _)| *second)
+				.collect()
+		};
+		assert_eq!(telemetry("kettle"), vec![0, 1]);
+		assert_eq!(telemetry("toaster"), vec![0, 1, 2, 3]);
+		let tripped: Vec<_> = swarm
+			.breakers
+			.iter()
+			.filter(|(_, breaker)| breaker.is_open())
+			.map(|(name, _)| name.as_str())
+			.collect();
+		assert_eq!(tripped, vec!["kitchen/kettle"]);
+
+		// The paused device's readings still reach its groups.
+		assert_eq!(swarm.latest["kitchen/kettle"].power, 103);
+	}
+
+	#[tokio::test]
+	async fn replay_matches_live_handling() {
+		let time = "2023-10-04T12:00:00";
+		let received = datetime!(2023-10-04 12:00:05 UTC);
+		let messages = [
+			("tasmota/tele/kitchen/kettle/SENSOR", sensor(time, 120)),
+			("tasmota/tele/kitchen/kettle/STATE", state(time, "ON")),
+		];
+
+		// Handle the messages live, recording them as we go.
+		let path =
+			std::env::temp_dir().join(format!("fizzle-capture-{}.jsonl", std::process::id()));
+		let mut recorder = Recorder::open(&path).unwrap();
+		let (writer, mut rx) = channel_buffered_client(16);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer);
+		swarm.set_clock(Some(received));
+		for (topic, payload) in &messages {
+			let payload = Bytes::from(payload.clone());
+			recorder.record(received, topic, &payload).unwrap();
+			swarm.handle_payload(topic, payload).await.unwrap();
+		}
+		let live = written_lines(&mut rx);
+
+		// Replay the capture through a fresh swarm.
+		let captured = read_capture(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(captured.len(), 2);
+		assert_eq!(captured[0].topic, "tasmota/tele/kitchen/kettle/SENSOR");
+
+		let (writer, mut rx) = channel_buffered_client(16);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer);
+		assert_eq!(replay(&mut swarm, captured).await, 2);
+		let replayed = written_lines(&mut rx);
+
+		assert!(!live.is_empty());
+		assert_eq!(live, replayed);
+	}
+
+	#[tokio::test]
+	async fn discovery_requests_status_and_records_response() {
+		let (writer, _rx) = channel_buffered_client(16);
+		let mut swarm = SmartPlugSwarm::<HomeTasmotaTopicScheme>::new(writer).with_discovery(true);
+
+		let topic = "tasmota/tele/kitchen/kettle/SENSOR";
+		swarm
+			.handle_payload(topic, Bytes::from_static(SENSOR.as_bytes()))
+			.await
+			.unwrap();
+		assert_eq!(
+			swarm.take_discovery_commands(),
+			vec![(
+				String::from("tasmota/cmnd/kitchen/kettle/Status"),
+				String::from("0")
+			)]
+		);
+
+		// The request is only sent once while awaiting a response.
+		swarm
+			.handle_payload(topic, Bytes::from_static(SENSOR.as_bytes()))
+			.await
+			.unwrap();
+		assert!(swarm.take_discovery_commands().is_empty());
+
+		let response = r#"{"Status":{"Module":1,"DeviceName":"Kettle","FriendlyName":["Kitchen Kettle"],"Topic":"kitchen/kettle","Power":1}}"#;
+		swarm
+			.handle_payload(
+				"tasmota/stat/kitchen/kettle/STATUS",
+				Bytes::from_static(response.as_bytes()),
+			)
+			.await
+			.unwrap();
+
+		let info = swarm.device_info("kitchen/kettle").unwrap();
+		assert_eq!(info.device_name, "Kettle");
+		assert_eq!(info.friendly_name.as_deref(), Some("Kitchen Kettle"));
+		assert_eq!(info.module, 1);
+	}
+}
@@ -1,33 +1,75 @@
-use crate::util::millis_from_datetime;
-use std::{collections::BTreeMap, error, fmt, time::Instant};
-use tasmota::{sns::StatusSNS, PowerState, StatusSTS};
-use time::OffsetDateTime;
+use crate::util::{local_offset_at, millis_from_datetime};
+use std::{collections::BTreeMap, error, fmt};
+use tasmota::{
+	sns::{Energy, StatusSNS},
+	PowerState, StatusSTS,
+};
+use time::{Date, Duration, OffsetDateTime, PrimitiveDateTime, UtcOffset};
 
-use super::topic::{TelemetryType, TopicGenerator};
+use super::{
+	baseline::TodayBaseline,
+	timestamp::{DeviceTimezone, DuplicatePolicy, TimestampStrategy},
+	topic::TopicGenerator,
+};
 
 #[derive(Debug)]
 pub struct SmartPlug<G: TopicGenerator> {
 	name: String,
 
 	lwt: Option<String>,
+	/// A changed LWT, and when it was received, not yet stable for
+	/// `lwt_debounce`.
+	pending_lwt: Option<(String, OffsetDateTime)>,
+	lwt_debounce: Option<Duration>,
 	raw_telemetry: BTreeMap<OffsetDateTime, (Option<StatusSNS>, Option<StatusSTS>)>,
 	last_energy: Option<f32>,
+	last_start_time: Option<PrimitiveDateTime>,
 	energy_offset: f32,
-	first_observation: Instant,
+	first_observation: OffsetDateTime,
+	timestamp_strategy: TimestampStrategy,
+	duplicate_policy: DuplicatePolicy,
+	timezone: DeviceTimezone,
+	derive_power: bool,
+	sensor_only: bool,
+	last_states: BTreeMap<u8, (OffsetDateTime, PowerState)>,
+	today: Option<EnergyBaseline>,
+	today_baseline: TodayBaseline,
+	/// `energy_today` already counted on a date, to resume from.
+	seeded_today: Option<(Date, i64)>,
 
 	_phantom: std::marker::PhantomData<G>,
 }
 
+/// Lifetime energy, in Wh, at the start of the local day.
+#[derive(Clone, Copy, Debug)]
+struct EnergyBaseline {
+	date: Date,
+	baseline: i64,
+	last: i64,
+	/// False if the baseline is the first reading of the day, rather than
+	/// the end of the previous day or a seeded value.
+	complete: bool,
+}
+
+/// No plausible telemetry is timestamped before 2020-01-01.
+const EARLIEST_TIMESTAMP_MS: i64 = 1_577_836_800_000;
+
+/// Telemetry timestamped by the device before its counters started, or
+/// before any plausible reading, such as after its clock jumped backwards.
 #[derive(Debug)]
-pub struct TelemetryNotAvailable(TelemetryType);
+pub struct TimestampBeforeFloor {
+	pub name: String,
+	pub timestamp: i64,
+	pub floor: i64,
+}
 
-impl fmt::Display for TelemetryNotAvailable {
+impl fmt::Display for TimestampBeforeFloor {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		write!(f, "{self:?}")
 	}
 }
 
-impl error::Error for TelemetryNotAvailable {}
+impl error::Error for TimestampBeforeFloor {}
 
 impl<G: TopicGenerator> SmartPlug<G> {
 	/// Creates a new smart plug with the given name.
@@ -35,52 +77,169 @@ impl<G: TopicGenerator> SmartPlug<G> {
 		Self {
 			name,
 			lwt: None,
+			pending_lwt: None,
+			lwt_debounce: None,
 			raw_telemetry: Default::default(),
 			last_energy: None,
+			last_start_time: None,
 			energy_offset: 0f32,
-			first_observation: Instant::now(),
+			first_observation: OffsetDateTime::now_utc(),
+			timestamp_strategy: Default::default(),
+			duplicate_policy: Default::default(),
+			timezone: Default::default(),
+			derive_power: false,
+			sensor_only: false,
+			last_states: BTreeMap::new(),
+			today: None,
+			today_baseline: TodayBaseline::FirstObservation,
+			seeded_today: None,
 			_phantom: std::marker::PhantomData,
 		}
 	}
 
+	/// Sets when fizzle first observed the smart plug.
+	pub fn with_first_observation(self, first_observation: OffsetDateTime) -> Self {
+		let mut s = self;
+		s.first_observation = first_observation;
+		s
+	}
+
+	/// Sets the strategy used to pick telemetry timestamps.
+	pub fn with_timestamp_strategy(self, strategy: TimestampStrategy) -> Self {
+		let mut s = self;
+		s.timestamp_strategy = strategy;
+		s
+	}
+
+	/// Sets how readings with a duplicate timestamp are handled.
+	pub fn with_duplicate_policy(self, policy: DuplicatePolicy) -> Self {
+		let mut s = self;
+		s.duplicate_policy = policy;
+		s
+	}
+
+	/// Sets the timezone the device's clock is set to.
+	pub fn with_timezone(self, timezone: DeviceTimezone) -> Self {
+		let mut s = self;
+		s.timezone = timezone;
+		s
+	}
+
+	/// Sets whether apparent power and power factor are derived from voltage,
+	/// current and power when the device does not report them.
+	pub fn with_derived_power(self, derive_power: bool) -> Self {
+		let mut s = self;
+		s.derive_power = derive_power;
+		s
+	}
+
+	/// Sets whether sensor telemetry is written on its own, without waiting
+	/// for state telemetry with the same timestamp. State telemetry is then
+	/// ignored, for devices which never publish it.
+	pub fn with_sensor_only(self, sensor_only: bool) -> Self {
+		let mut s = self;
+		s.sensor_only = sensor_only;
+		s
+	}
+
+	/// Sets how long a changed LWT must stand before it is reported, so a
+	/// brief `Offline` while WiFi is unstable is ignored. `None` reports
+	/// every change immediately.
+	pub fn with_lwt_debounce(self, debounce: Option<Duration>) -> Self {
+		let mut s = self;
+		s.lwt_debounce = debounce;
+		s
+	}
+
+	/// Sets how `energy_today` is baselined on the first day the device is
+	/// seen.
+	pub fn with_today_baseline(self, today_baseline: TodayBaseline) -> Self {
+		let mut s = self;
+		s.today_baseline = today_baseline;
+		s
+	}
+
+	/// Resumes `energy_today` on `date` from `energy_today` Wh, such as the
+	/// last value written before a restart. Applies to the first reading, if
+	/// it is on `date`.
+	pub fn seed_energy_today(&mut self, date: Date, energy_today: i64) {
+		self.seeded_today = Some((date, energy_today));
+	}
+
 	/// Returns the name of the smart plug.
 	#[inline(always)]
 	pub fn name(&self) -> &str {
 		&self.name
 	}
 
+	/// Renames the smart plug, keeping its offset, baseline and buffered
+	/// telemetry. Its topics follow the new name.
+	pub fn rename(&mut self, name: String) {
+		self.name = name;
+	}
+
 	/// Generates the MQTT topic for the smart plug's sensor telemetry.
-	pub fn sensor_telemetry_topic(&self) -> String {
-		G::sensor_telemetry_topic(&self.name)
+	pub fn sensor_telemetry_topic(&self, topics: &G) -> String {
+		topics.sensor_telemetry_topic(&self.name)
 	}
 
 	/// Generates the MQTT topic for the smart plug's state telemetry.
-	pub fn state_telemetry_topic(&self) -> String {
-		G::state_telemetry_topic(&self.name)
+	pub fn state_telemetry_topic(&self, topics: &G) -> String {
+		topics.state_telemetry_topic(&self.name)
 	}
 
 	/// Generates the MQTT topic for the smart plug's last will and testament.
-	pub fn lwt_topic(&self) -> String {
-		G::lwt_topic(&self.name)
+	pub fn lwt_topic(&self, topics: &G) -> String {
+		topics.lwt_topic(&self.name)
 	}
 
-	/// Returns the last will and testament of the smart plug, if any.
-	#[allow(dead_code)]
-	pub fn lwt(&self) -> Option<&str> {
-		self.lwt.as_deref()
+	/// Returns the last will and testament of the smart plug, if any, as
+	/// reported at `now`: a change is only reported once it has stood for
+	/// the debounce period.
+	pub fn lwt(&self, now: OffsetDateTime) -> Option<&str> {
+		match (&self.pending_lwt, self.lwt_debounce) {
+			(Some((lwt, since)), Some(debounce)) if now - *since >= debounce => Some(lwt),
+			_ => self.lwt.as_deref(),
+		}
 	}
 
-	pub fn set_lwt(&mut self, lwt: String) -> Option<String> {
+	/// Records the last will and testament received at `at`.
+	pub fn set_lwt(&mut self, lwt: String, at: OffsetDateTime) {
 		tracing::trace!(
 			"smartplug '{}', setting last will and testament: '{}'",
 			self.name(),
 			lwt
 		);
-		self.lwt.replace(lwt)
+
+		// Settle a pending change which has stood long enough.
+		if let Some(stable) = self.lwt(at).map(String::from) {
+			if self.lwt.as_ref() != Some(&stable) {
+				self.lwt = Some(stable);
+				self.pending_lwt = None;
+			}
+		}
+
+		match &self.lwt {
+			// The first LWT is reported straight away.
+			Some(_) if self.lwt_debounce.is_some() => {}
+			_ => {
+				self.lwt = Some(lwt);
+				self.pending_lwt = None;
+				return;
+			}
+		}
+
+		if self.lwt.as_ref() == Some(&lwt) {
+			if self.pending_lwt.take().is_some() {
+				tracing::debug!("ignoring brief LWT change for device '{}'", self.name);
+			}
+		} else if self.pending_lwt.as_ref().map(|(pending, _)| pending) != Some(&lwt) {
+			self.pending_lwt = Some((lwt, at));
+		}
 	}
 
 	pub fn append_sensor_telemetry(&mut self, telemetry: StatusSNS) {
-		let timestamp = telemetry.time.assume_utc();
+		let timestamp = self.timezone.resolve(telemetry.time);
 
 		if let Some(last) = self.raw_telemetry.last_entry() {
 			if last.key() > &timestamp {
@@ -91,34 +250,80 @@ impl<G: TopicGenerator> SmartPlug<G> {
 			}
 		}
 
+		// A new TotalStartTime means the device's counters were reset, even
+		// if the total has already climbed past its previous value.
+		let start_time = telemetry.energy.start_time;
+		let restarted = self
+			.last_start_time
+			.is_some_and(|last_start_time| last_start_time != start_time);
+
+		// After a reset the new counter started from zero, so the energy
+		// counted so far is carried forward on top of it.
 		self.energy_offset = self
 			.last_energy
 			.map(|value| {
-				if telemetry.energy.energy_lifetime < value {
+				if restarted {
+					tracing::warn!(
+						"energy counter reset detected for device '{}': TotalStartTime changed to {start_time}",
+						self.name
+					);
+					self.energy_offset - value
+				} else if telemetry.energy.energy_lifetime < value {
 					tracing::warn!("energy counter reset detected for device '{}'", self.name);
-					value
+					self.energy_offset - value
 				} else {
 					self.energy_offset
 				}
 			})
 			.unwrap_or(telemetry.energy.energy_lifetime);
 		self.last_energy = Some(telemetry.energy.energy_lifetime);
+		self.last_start_time = Some(start_time);
 
 		let (sns, _) = self.raw_telemetry.entry(timestamp).or_default();
-		if let Some(old_telemetry) = sns.replace(telemetry.clone()) {
-			tracing::warn!("received SNS telemetry with duplicate timestamp: {old_telemetry:?}");
+		if !self.duplicate_policy.apply(sns, telemetry) {
+			self.raw_telemetry.remove(&timestamp);
 		}
 	}
 
 	pub fn append_state_telemetry(&mut self, telemetry: StatusSTS) {
-		let timestamp = telemetry.time.assume_utc();
+		// It would never be matched, so must not be buffered.
+		if self.sensor_only {
+			return;
+		}
+
+		let timestamp = self.timezone.resolve(telemetry.time);
 
 		let (_, sts) = self.raw_telemetry.entry(timestamp).or_default();
-		if let Some(old_telemetry) = sts.replace(telemetry.clone()) {
-			tracing::warn!("received STS telemetry with duplicate timestamp: {old_telemetry:?}");
+		if !self.duplicate_policy.apply(sts, telemetry) {
+			self.raw_telemetry.remove(&timestamp);
 		}
 	}
 
+	/// Forgets the energy offset and daily baseline, so the next reading
+	/// becomes the new zero. For use after the device's hardware is replaced.
+	pub fn reset_energy(&mut self) {
+		self.energy_offset = 0f32;
+		self.last_energy = None;
+		self.last_start_time = None;
+		self.today = None;
+	}
+
+	/// Returns the number of timestamps with buffered, unmatched telemetry.
+	pub fn pending_telemetry(&self) -> usize {
+		self.raw_telemetry.len()
+	}
+
+	/// Drops unmatched telemetry timestamped before `before`, such as sensor
+	/// telemetry whose state telemetry was lost, returning how many
+	/// timestamps were dropped. Matched telemetry is kept to be written.
+	pub fn clear_stale(&mut self, before: OffsetDateTime) -> usize {
+		let len = self.raw_telemetry.len();
+		self.raw_telemetry.retain(|timestamp, (sns, sts)| {
+			*timestamp >= before || (sns.is_some() && sts.is_some())
+		});
+		len - self.raw_telemetry.len()
+	}
+
 	pub fn first_matched_telemetry(&mut self) -> Option<(OffsetDateTime, StatusSNS, StatusSTS)> {
 		let key = self
 			.raw_telemetry
@@ -138,59 +343,726 @@ impl<G: TopicGenerator> SmartPlug<G> {
 		self.first_matched_telemetry()
 	}
 
+	/// Removes the oldest sensor telemetry, whether or not it was matched.
+	pub fn sensor_telemetry(&mut self) -> Option<(OffsetDateTime, StatusSNS)> {
+		let key = self
+			.raw_telemetry
+			.iter()
+			.find(|(_, (sns, _))| sns.is_some())
+			.map(|(key, _)| *key)?;
+
+		self.raw_telemetry
+			.remove(&key)
+			.and_then(|(sns, _)| Some((key, sns?)))
+	}
+
+	/// Removes the oldest telemetry ready to be written: matched sensor and
+	/// state telemetry or, in sensor-only mode, sensor telemetry alone.
+	pub fn next_telemetry(&mut self) -> Option<(OffsetDateTime, StatusSNS, Option<StatusSTS>)> {
+		if self.sensor_only {
+			self.sensor_telemetry().map(|(key, sns)| (key, sns, None))
+		} else {
+			self.matched_telemetry()
+				.map(|(key, sns, sts)| (key, sns, Some(sts)))
+		}
+	}
+
+	/// Records the state of `relay` reported at `timestamp`, returning the
+	/// `(from, to)` transition if it changed.
+	///
+	/// Readings no newer than the last observed one are ignored, so duplicate
+	/// telemetry cannot produce a second transition.
+	pub fn observe_state(
+		&mut self,
+		timestamp: OffsetDateTime,
+		relay: u8,
+		state: PowerState,
+	) -> Option<(PowerState, PowerState)> {
+		match self.last_states.get(&relay).copied() {
+			Some((last_timestamp, _)) if timestamp <= last_timestamp => None,
+			Some((_, last)) => {
+				self.last_states.insert(relay, (timestamp, state));
+				(last != state).then_some((last, state))
+			}
+			None => {
+				self.last_states.insert(relay, (timestamp, state));
+				None
+			}
+		}
+	}
+
+	/// Records the state of every relay reported at `timestamp`, returning
+	/// the `(relay, from, to)` transitions.
+	pub fn observe_states(
+		&mut self,
+		timestamp: OffsetDateTime,
+		states: &BTreeMap<u8, PowerState>,
+	) -> Vec<(u8, PowerState, PowerState)> {
+		states
+			.iter()
+			.filter_map(|(&relay, &state)| {
+				self.observe_state(timestamp, relay, state)
+					.map(|(from, to)| (relay, from, to))
+			})
+			.collect()
+	}
+
+	/// Returns the energy, in Wh, used since local midnight given the
+	/// offset-corrected lifetime `energy` observed at `at`.
+	///
+	/// Tasmota's own `Today` resets at the device's midnight, which need not
+	/// match ours. The first reading of each local day, or after fizzle starts,
+	/// captures the baseline; at a rollover the last reading of the previous
+	/// day is used, which is the closest available to midnight.
+	///
+	/// Returns `None` while the first day is not being counted in full, per
+	/// [`TodayBaseline::Midnight`].
+	pub fn energy_today(&mut self, at: OffsetDateTime, energy: i64) -> Option<i64> {
+		self.energy_today_with(at, energy, local_offset_at)
+	}
+
+	fn energy_today_with<F>(&mut self, at: OffsetDateTime, energy: i64, offset_at: F) -> Option<i64>
+	where
+		F: Fn(OffsetDateTime) -> UtcOffset,
+	{
+		let date = at.to_offset(offset_at(at)).date();
+		let today = match self.today {
+			Some(today) if today.date == date => EnergyBaseline {
+				last: energy,
+				..today
+			},
+			Some(yesterday) if yesterday.date < date => EnergyBaseline {
+				date,
+				baseline: yesterday.last,
+				last: energy,
+				complete: true,
+			},
+			// Out-of-order readings from an earlier day are not counted.
+			Some(today) => today,
+			None => match self.seeded_today.take() {
+				Some((seeded_date, seeded)) if seeded_date == date => EnergyBaseline {
+					date,
+					baseline: energy - seeded,
+					last: energy,
+					complete: true,
+				},
+				_ => EnergyBaseline {
+					date,
+					baseline: energy,
+					last: energy,
+					complete: false,
+				},
+			},
+		};
+		self.today = Some(today);
+
+		if !today.complete && self.today_baseline == TodayBaseline::Midnight {
+			return None;
+		}
+		Some((today.last - today.baseline).max(0))
+	}
+
 	pub fn generate_telemetry(
 		&self,
 		odt: OffsetDateTime,
 		sensor: StatusSNS,
 		state: StatusSTS,
-	) -> Result<Telemetry, TelemetryNotAvailable> {
-		let monitor_uptime = self.first_observation.elapsed().as_secs();
+	) -> Result<Telemetry, TimestampBeforeFloor> {
+		self.build_telemetry(odt, sensor, Some(state))
+	}
+
+	/// Generates telemetry from sensor telemetry alone, without the fields
+	/// which come from state telemetry.
+	pub fn generate_sensor_telemetry(
+		&self,
+		odt: OffsetDateTime,
+		sensor: StatusSNS,
+	) -> Result<Telemetry, TimestampBeforeFloor> {
+		self.build_telemetry(odt, sensor, None)
+	}
+
+	fn build_telemetry(
+		&self,
+		odt: OffsetDateTime,
+		sensor: StatusSNS,
+		state: Option<StatusSTS>,
+	) -> Result<Telemetry, TimestampBeforeFloor> {
 		let energy = ((sensor.energy.energy_lifetime - self.energy_offset) * 1000.0).round() as i64;
 
 		// Pick the timestamp to use for the telemetry datum.
-		let device_timestamp = millis_from_datetime(state.time.assume_utc());
+		let device_time = state.as_ref().map_or(sensor.time, |state| state.time);
+		let device_timestamp = millis_from_datetime(self.timezone.resolve(device_time));
 		let machine_timestamp = millis_from_datetime(odt);
-		let drift = machine_timestamp.abs_diff(device_timestamp);
-		let timestamp = if drift > 20_000 {
+		let timestamp =
+			self.timestamp_strategy
+				.choose(&self.name, device_timestamp, machine_timestamp);
+
+		// Only the device's clock can glitch backwards.
+		let floor = millis_from_datetime(self.timezone.resolve(sensor.energy.start_time))
+			.max(EARLIEST_TIMESTAMP_MS);
+		if timestamp == Some(device_timestamp) && device_timestamp < floor {
 			tracing::warn!(
-				"timestamp drift for '{}' is {}ms > 20,000ms, using machine time",
-				self.name,
-				drift
+				"rejecting telemetry for '{}' timestamped {device_timestamp}ms, before {floor}ms",
+				self.name
 			);
-			machine_timestamp
-		} else {
-			device_timestamp
-		};
+			return Err(TimestampBeforeFloor {
+				name: self.name.clone(),
+				timestamp: device_timestamp,
+				floor,
+			});
+		}
+
+		let readings = SensorReadings::new(&sensor.energy, self.timezone);
+		let mut apparent_power = readings.apparent_power;
+		let mut power_factor = readings.power_factor;
+		let mut derived = false;
+		if self.derive_power && apparent_power.is_none() {
+			let derived_power = DerivedPower::from_measurements(
+				sensor.energy.voltage,
+				sensor.energy.current,
+				sensor.energy.power,
+			);
+			apparent_power = Some(derived_power.apparent_power);
+			power_factor = power_factor.or(derived_power.power_factor);
+			derived = true;
+		}
+
+		let status = state.map(StateReadings::from);
+		let wifi = status.as_ref().and_then(|status| status.wifi.as_ref());
 
 		Ok(Telemetry {
 			name: self.name.clone(),
-			apparent_power: sensor.energy.apparent_power as i64,
-			current: sensor.energy.current as f64,
-			device_uptime: state.uptime_seconds,
+			apparent_power,
+			current: readings.current,
+			device_energy_today: readings.device_energy_today,
+			device_energy_yesterday: readings.device_energy_yesterday,
+			device_uptime: status.as_ref().map(|status| status.device_uptime),
 			energy,
-			monitor_uptime,
-			power: sensor.energy.power as i64,
-			power_factor: sensor.energy.power_factor as f64,
-			reactive_power: sensor.energy.reactive_power as i64,
-			state: state.power_state,
-			voltage: sensor.energy.voltage as i64,
+			link_count: wifi.map(|wifi| wifi.link_count),
+			monitor_start: self.first_observation,
+			mqtt_count: status.as_ref().map(|status| status.mqtt_count),
+			power: readings.power,
+			power_factor,
+			reactive_power: readings.reactive_power,
+			rssi: wifi.map(|wifi| wifi.rssi),
+			ssid: wifi.map(|wifi| wifi.ssid.clone()),
+			wifi_signal: wifi.map(|wifi| wifi.signal),
+			states: status.map(|status| status.states).unwrap_or_default(),
+			total_start_time: readings.total_start_time,
+			voltage: readings.voltage,
 			timestamp,
+			derived,
 		})
 	}
 }
 
-#[derive(Debug)]
+/// Readings from a device's sensor telemetry, in the types written to
+/// InfluxDB: whole Watts, Volts and Wh as integers, everything else as
+/// floats.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorReadings {
+	pub apparent_power: Option<i64>,
+	pub current: f64,
+	pub device_energy_today: i64,
+	pub device_energy_yesterday: i64,
+	pub power: i64,
+	pub power_factor: Option<f64>,
+	pub reactive_power: Option<i64>,
+	/// When the device's energy counters started accumulating, in Unix
+	/// seconds.
+	pub total_start_time: i64,
+	pub voltage: i64,
+}
+
+impl SensorReadings {
+	/// Converts `energy` from a device whose clock is set to `timezone`.
+	pub fn new(energy: &Energy, timezone: DeviceTimezone) -> Self {
+		Self {
+			apparent_power: energy.apparent_power.map(i64::from),
+			current: energy.current as f64,
+			device_energy_today: (energy.energy_today * 1000.0).round() as i64,
+			device_energy_yesterday: (energy.energy_yesterday * 1000.0).round() as i64,
+			power: energy.power.into(),
+			power_factor: energy.power_factor.map(f64::from),
+			reactive_power: energy.reactive_power.map(i64::from),
+			total_start_time: timezone.resolve(energy.start_time).unix_timestamp(),
+			voltage: energy.voltage.into(),
+		}
+	}
+}
+
+/// Readings from a device's state telemetry, in the types written to
+/// InfluxDB.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateReadings {
+	pub device_uptime: u64,
+	pub mqtt_count: u64,
+	pub states: BTreeMap<u8, PowerState>,
+	/// `None` if the device omits its WiFi status.
+	pub wifi: Option<WiFiReadings>,
+}
+
+/// A device's WiFi status.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WiFiReadings {
+	pub link_count: u64,
+	pub rssi: i64,
+	pub signal: i64,
+	pub ssid: String,
+}
+
+impl From<StatusSTS> for StateReadings {
+	fn from(state: StatusSTS) -> Self {
+		Self {
+			device_uptime: state.uptime_seconds,
+			mqtt_count: state.mqtt_count.into(),
+			states: state.power_states,
+			wifi: state.wifi.map(|wifi| WiFiReadings {
+				link_count: wifi.link_count.into(),
+				rssi: wifi.rssi.into(),
+				signal: wifi.signal.into(),
+				ssid: wifi.ssid,
+			}),
+		}
+	}
+}
+
+#[derive(Debug, PartialEq)]
 pub struct Telemetry {
 	pub name: String,
-	pub apparent_power: i64,
+	pub apparent_power: Option<i64>,
 	pub current: f64,
-	pub device_uptime: u64,
+	/// Energy the device counted today and yesterday, in Wh, by its own
+	/// clock and from its own counters.
+	pub device_energy_today: i64,
+	pub device_energy_yesterday: i64,
+	/// Fields from state telemetry are `None` in sensor-only mode.
+	pub device_uptime: Option<u64>,
 	pub energy: i64,
-	pub monitor_uptime: u64,
+	/// Number of times the device has reconnected to WiFi. WiFi fields are
+	/// `None` if the device omits its WiFi status.
+	pub link_count: Option<u64>,
+	/// When fizzle first observed the smart plug.
+	pub monitor_start: OffsetDateTime,
+	/// How many times the device has (re)connected to the MQTT broker.
+	pub mqtt_count: Option<u64>,
 	pub power: i64,
-	pub power_factor: f64,
-	pub reactive_power: i64,
-	pub state: PowerState,
+	pub power_factor: Option<f64>,
+	pub reactive_power: Option<i64>,
+	/// WiFi signal quality as a percentage.
+	pub rssi: Option<i64>,
+	/// SSID of the WiFi network the device is connected to.
+	pub ssid: Option<String>,
+	/// State of each relay, by number; relay 0 is a device's only relay.
+	pub states: BTreeMap<u8, PowerState>,
+	/// When the device's energy counters started accumulating, in Unix
+	/// seconds.
+	pub total_start_time: i64,
 	pub voltage: i64,
-	pub timestamp: i64,
+	/// WiFi signal strength in dBm.
+	pub wifi_signal: Option<i64>,
+	/// Timestamp in milliseconds, or `None` to let InfluxDB assign one.
+	pub timestamp: Option<i64>,
+	/// True if apparent power or power factor were derived rather than
+	/// reported by the device.
+	pub derived: bool,
+}
+
+/// Apparent power and power factor derived from real measurements.
+#[derive(Debug, PartialEq)]
+pub struct DerivedPower {
+	/// Apparent power in VA, `V × I`.
+	pub apparent_power: i64,
+	/// Power factor, `P / S`, or `None` when there is no apparent power.
+	pub power_factor: Option<f64>,
+}
+
+impl DerivedPower {
+	pub fn from_measurements(voltage: u32, current: f32, power: u32) -> Self {
+		let apparent_power = voltage as f64 * current as f64;
+		let power_factor = (apparent_power > 0.0)
+			.then(|| (power as f64 / apparent_power).min(1.0))
+			.map(|factor| (factor * 100.0).round() / 100.0);
+
+		Self {
+			apparent_power: apparent_power.round() as i64,
+			power_factor,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{DerivedPower, SensorReadings, SmartPlug, Telemetry};
+	use crate::smartplugs::{
+		baseline::TodayBaseline,
+		tests::{sensor, state},
+		timestamp::{DeviceTimezone, DuplicatePolicy, TimestampStrategy},
+		topic::HomeTasmotaTopicScheme,
+	};
+	use std::collections::BTreeMap;
+	use tasmota::{sns::StatusSNS, PowerState, StatusSTS};
+	use time::{macros::datetime, Duration, UtcOffset};
+
+	const MINIMAL_SENSOR: &str = r#"{"Time":"2023-10-04T12:00:00","ENERGY":{"TotalStartTime":"2023-01-01T00:00:00","Total":12.345,"Yesterday":1.200,"Today":0.500,"Period":3,"Power":100,"Voltage":240,"Current":0.500}}"#;
+
+	#[test]
+	fn wrapped_sensor_payload() {
+		let wrapped = format!(r#"{{"kitchen/kettle":{MINIMAL_SENSOR}}}"#);
+		let sensor: StatusSNS = serde_json::from_str(&wrapped).unwrap();
+		assert_eq!(sensor.energy.power, 100);
+		assert_eq!(sensor.time, datetime!(2023-10-04 12:00:00));
+
+		let ambiguous = format!(r#"{{"a":{MINIMAL_SENSOR},"b":{MINIMAL_SENSOR}}}"#);
+		assert!(serde_json::from_str::<StatusSNS>(&ambiguous).is_err());
+	}
+
+	#[test]
+	fn relay_states() {
+		let time = "2023-10-04T12:00:00";
+		let single: StatusSTS = serde_json::from_str(&state(time, "ON")).unwrap();
+		assert_eq!(
+			single.power_states.into_iter().collect::<Vec<_>>(),
+			vec![(0, PowerState::On)]
+		);
+
+		let numbered = state(time, "ON").replace(r#""POWER":"ON""#, r#""POWER1":"ON""#);
+		let numbered: StatusSTS = serde_json::from_str(&numbered).unwrap();
+		assert_eq!(
+			numbered.power_states.into_iter().collect::<Vec<_>>(),
+			vec![(0, PowerState::On)]
+		);
+
+		let multi = state(time, "ON").replace(r#""POWER":"ON""#, r#""POWER1":"ON","POWER2":"OFF""#);
+		let multi: StatusSTS = serde_json::from_str(&multi).unwrap();
+		assert_eq!(multi.power_state(), Some(PowerState::On));
+		assert_eq!(
+			multi.power_states.into_iter().collect::<Vec<_>>(),
+			vec![(1, PowerState::On), (2, PowerState::Off)]
+		);
+	}
+
+	#[test]
+	fn stale_unmatched_telemetry_is_cleared() {
+		let mut smartplug =
+			SmartPlug::<HomeTasmotaTopicScheme>::new(String::from("kitchen/kettle"))
+				.with_timezone(DeviceTimezone::Fixed(UtcOffset::UTC));
+		let sensor = |time| serde_json::from_str::<StatusSNS>(&sensor(time, 100)).unwrap();
+		let state = |time| serde_json::from_str::<StatusSTS>(&state(time, "ON")).unwrap();
+
+		smartplug.append_sensor_telemetry(sensor("2023-10-04T11:00:00"));
+		smartplug.append_sensor_telemetry(sensor("2023-10-04T11:30:00"));
+		smartplug.append_state_telemetry(state("2023-10-04T11:30:00"));
+		smartplug.append_sensor_telemetry(sensor("2023-10-04T12:59:00"));
+		assert_eq!(smartplug.pending_telemetry(), 3);
+
+		assert_eq!(smartplug.clear_stale(datetime!(2023-10-04 12:50 UTC)), 1);
+		assert_eq!(smartplug.pending_telemetry(), 2);
+		let (timestamp, _, _) = smartplug.matched_telemetry().unwrap();
+		assert_eq!(timestamp, datetime!(2023-10-04 11:30 UTC));
+	}
+
+	#[test]
+	fn lwt_blips_are_debounced() {
+		let mut smartplug =
+			SmartPlug::<HomeTasmotaTopicScheme>::new(String::from("kitchen/kettle"))
+				.with_lwt_debounce(Some(Duration::seconds(60)));
+		let at = |seconds| datetime!(2023-10-04 12:00 UTC) + Duration::seconds(seconds);
+
+		smartplug.set_lwt(String::from("Online"), at(0));
+		assert_eq!(smartplug.lwt(at(0)), Some("Online"));
+
+		// Offline for 20 seconds, within the debounce period.
+		smartplug.set_lwt(String::from("Offline"), at(100));
+		assert_eq!(smartplug.lwt(at(110)), Some("Online"));
+		smartplug.set_lwt(String::from("Online"), at(120));
+		assert_eq!(smartplug.lwt(at(200)), Some("Online"));
+
+		// Offline for good.
+		smartplug.set_lwt(String::from("Offline"), at(300));
+		assert_eq!(smartplug.lwt(at(330)), Some("Online"));
+		assert_eq!(smartplug.lwt(at(360)), Some("Offline"));
+		smartplug.set_lwt(String::from("Offline"), at(400));
+		assert_eq!(smartplug.lwt(at(400)), Some("Offline"));
+	}
+
+	#[test]
+	fn minimal_energy_block() {
+		let sensor: StatusSNS = serde_json::from_str(MINIMAL_SENSOR).unwrap();
+		assert_eq!(sensor.energy.power, 100);
+		assert_eq!(sensor.energy.apparent_power, None);
+		assert_eq!(sensor.energy.reactive_power, None);
+		assert_eq!(sensor.energy.power_factor, None);
+	}
+
+	#[test]
+	fn changed_start_time_resets_offset() {
+		let mut smartplug =
+			SmartPlug::<HomeTasmotaTopicScheme>::new(String::from("kitchen/kettle"));
+		let sensor = |time: &str, start: &str, total: &str| -> StatusSNS {
+			let json = MINIMAL_SENSOR
+				.replace("2023-10-04T12:00:00", time)
+				.replace("2023-01-01T00:00:00", start)
+				.replace("12.345", total);
+			serde_json::from_str(&json).unwrap()
+		};
+
+		smartplug.append_sensor_telemetry(sensor(
+			"2023-10-04T12:00:00",
+			"2023-01-01T00:00:00",
+			"12.000",
+		));
+		smartplug.append_sensor_telemetry(sensor(
+			"2023-10-04T12:00:10",
+			"2023-01-01T00:00:00",
+			"13.000",
+		));
+		assert_eq!(smartplug.energy_offset, 12.0);
+
+		// The total still increased, but the counters were reset, so all of
+		// it is new energy.
+		smartplug.append_sensor_telemetry(sensor(
+			"2023-10-04T12:00:20",
+			"2023-10-04T12:00:15",
+			"14.000",
+		));
+		assert_eq!(smartplug.energy_offset, -1.0);
+	}
+
+	#[test]
+	fn counter_reset_carries_energy_forward() {
+		let mut smartplug =
+			SmartPlug::<HomeTasmotaTopicScheme>::new(String::from("kitchen/kettle"));
+		let sensor = |time: &str, start: &str, total: &str| -> StatusSNS {
+			let json = MINIMAL_SENSOR
+				.replace("2023-10-04T12:00:00", time)
+				.replace("2023-01-01T00:00:00", start)
+				.replace("12.345", total);
+			serde_json::from_str(&json).unwrap()
+		};
+		let energy = |smartplug: &SmartPlug<HomeTasmotaTopicScheme>, total: f32| {
+			((total - smartplug.energy_offset) * 1000.0).round() as i64
+		};
+
+		smartplug.append_sensor_telemetry(sensor(
+			"2023-10-04T12:00:00",
+			"2023-01-01T00:00:00",
+			"12.000",
+		));
+		smartplug.append_sensor_telemetry(sensor(
+			"2023-10-04T12:00:10",
+			"2023-01-01T00:00:00",
+			"13.000",
+		));
+		assert_eq!(energy(&smartplug, 13.0), 1_000);
+
+		// Reset to zero with a new TotalStartTime.
+		smartplug.append_sensor_telemetry(sensor(
+			"2023-10-04T12:00:20",
+			"2023-10-04T12:00:15",
+			"0.010",
+		));
+		assert_eq!(energy(&smartplug, 0.010), 1_010);
+
+		// Reset to zero without one.
+		smartplug.append_sensor_telemetry(sensor(
+			"2023-10-04T12:00:30",
+			"2023-10-04T12:00:15",
+			"0.005",
+		));
+		assert_eq!(energy(&smartplug, 0.005), 1_015);
+	}
+
+	#[test]
+	fn derived_power() {
+		assert_eq!(
+			DerivedPower::from_measurements(240, 0.5, 100),
+			DerivedPower {
+				apparent_power: 120,
+				power_factor: Some(0.83),
+			}
+		);
+		assert_eq!(
+			DerivedPower::from_measurements(240, 0.0, 0),
+			DerivedPower {
+				apparent_power: 0,
+				power_factor: None,
+			}
+		);
+	}
+
+	#[test]
+	fn energy_today_resets_at_local_midnight() {
+		let mut smartplug =
+			SmartPlug::<HomeTasmotaTopicScheme>::new(String::from("kitchen/kettle"));
+		let offset = |_| UtcOffset::from_hms(10, 0, 0).unwrap();
+
+		// 23:30 and 23:50 local on the 4th, then 00:10 and 01:00 on the 5th.
+		let readings = [
+			(datetime!(2023-10-04 13:30 UTC), 1_000, 0),
+			(datetime!(2023-10-04 13:50 UTC), 1_040, 40),
+			(datetime!(2023-10-04 14:10 UTC), 1_060, 20),
+			(datetime!(2023-10-04 15:00 UTC), 1_200, 160),
+		];
+		for (at, energy, today) in readings {
+			assert_eq!(smartplug.energy_today_with(at, energy, offset), Some(today));
+		}
+
+		// A late reading from the previous day changes nothing.
+		assert_eq!(
+			smartplug.energy_today_with(datetime!(2023-10-04 13:55 UTC), 1_045, offset),
+			Some(160)
+		);
+	}
+
+	#[test]
+	fn energy_today_baselines() {
+		let offset = |_| UtcOffset::UTC;
+		let smartplug = |today_baseline| {
+			SmartPlug::<HomeTasmotaTopicScheme>::new(String::from("kitchen/kettle"))
+				.with_today_baseline(today_baseline)
+		};
+		let (first, later, tomorrow) = (
+			datetime!(2023-10-04 15:00 UTC),
+			datetime!(2023-10-04 16:00 UTC),
+			datetime!(2023-10-05 01:00 UTC),
+		);
+
+		// Resumes from the seeded value, as queried from InfluxDB.
+		let mut resumed = smartplug(TodayBaseline::Query);
+		resumed.seed_energy_today(first.date(), 750);
+		assert_eq!(resumed.energy_today_with(first, 5_000, offset), Some(750));
+		assert_eq!(resumed.energy_today_with(later, 5_100, offset), Some(850));
+
+		// A seed for another day is ignored.
+		let mut stale = smartplug(TodayBaseline::Query);
+		stale.seed_energy_today(datetime!(2023-10-03 00:00 UTC).date(), 750);
+		assert_eq!(stale.energy_today_with(first, 5_000, offset), Some(0));
+
+		// Nothing is counted until the first full day.
+		let mut waiting = smartplug(TodayBaseline::Midnight);
+		assert_eq!(waiting.energy_today_with(first, 5_000, offset), None);
+		assert_eq!(waiting.energy_today_with(later, 5_100, offset), None);
+		assert_eq!(
+			waiting.energy_today_with(tomorrow, 5_300, offset),
+			Some(200)
+		);
+	}
+
+	#[test]
+	fn duplicate_timestamp_policies() {
+		let time = "2023-10-04T12:00:00";
+		let matched = |policy: DuplicatePolicy| {
+			let mut smartplug =
+				SmartPlug::<HomeTasmotaTopicScheme>::new(String::from("kitchen/kettle"))
+					.with_duplicate_policy(policy);
+			let readings = [(120, "ON"), (80, "OFF")];
+			for (power, power_state) in readings {
+				let sns: StatusSNS = serde_json::from_str(&sensor(time, power)).unwrap();
+				let sts: StatusSTS = serde_json::from_str(&state(time, power_state)).unwrap();
+				smartplug.append_sensor_telemetry(sns);
+				smartplug.append_state_telemetry(sts);
+			}
+			smartplug
+				.matched_telemetry()
+				.map(|(_, sns, sts)| (sns.energy.power, sts.power_state().unwrap()))
+		};
+
+		assert_eq!(
+			matched(DuplicatePolicy::KeepFirst),
+			Some((120, PowerState::On))
+		);
+		assert_eq!(
+			matched(DuplicatePolicy::KeepLast),
+			Some((80, PowerState::Off))
+		);
+		assert_eq!(matched(DuplicatePolicy::Reject), None);
+	}
+
+	#[test]
+	fn total_start_time_uses_device_timezone() {
+		let time = "2023-10-04T12:00:00";
+		let odt = datetime!(2023-10-04 12:00 UTC);
+		let sns: StatusSNS = serde_json::from_str(&sensor(time, 120)).unwrap();
+		let start_time = sns.energy.start_time;
+
+		// Devices are assumed to be on the machine's local time by default.
+		let smartplug = SmartPlug::<HomeTasmotaTopicScheme>::new(String::from("kitchen/kettle"))
+			.with_timestamp_strategy(TimestampStrategy::PreferMachine);
+		let telemetry = smartplug
+			.generate_sensor_telemetry(odt, sns.clone())
+			.unwrap();
+		assert_eq!(
+			telemetry.total_start_time,
+			DeviceTimezone::Local.resolve(start_time).unix_timestamp()
+		);
+
+		let smartplug = smartplug.with_timezone(DeviceTimezone::Fixed(
+			UtcOffset::from_hms(10, 0, 0).unwrap(),
+		));
+		let telemetry = smartplug.generate_sensor_telemetry(odt, sns).unwrap();
+		assert_eq!(
+			telemetry.total_start_time,
+			start_time.assume_utc().unix_timestamp() - 10 * 3600
+		);
+	}
+
+	#[test]
+	fn sensor_only_telemetry_matches_paired() {
+		let smartplug = SmartPlug::<HomeTasmotaTopicScheme>::new(String::from("kitchen/kettle"))
+			.with_timestamp_strategy(TimestampStrategy::PreferMachine);
+
+		let time = "2023-10-04T12:00:00";
+		let odt = datetime!(2023-10-04 12:00 UTC);
+		let sns: StatusSNS = serde_json::from_str(&sensor(time, 120)).unwrap();
+		let sts: StatusSTS = serde_json::from_str(&state(time, "ON")).unwrap();
+		assert_eq!(
+			SensorReadings::new(&sns.energy, DeviceTimezone::Local),
+			SensorReadings {
+				apparent_power: Some(130),
+				current: sns.energy.current as f64,
+				device_energy_today: 500,
+				device_energy_yesterday: 1200,
+				power: 120,
+				power_factor: sns.energy.power_factor.map(f64::from),
+				reactive_power: sns.energy.reactive_power.map(i64::from),
+				total_start_time: DeviceTimezone::Local
+					.resolve(sns.energy.start_time)
+					.unix_timestamp(),
+				voltage: sns.energy.voltage.into(),
+			}
+		);
+
+		let paired = smartplug.generate_telemetry(odt, sns.clone(), sts).unwrap();
+		let sensor_only = smartplug.generate_sensor_telemetry(odt, sns).unwrap();
+		assert_eq!(paired.mqtt_count, Some(1));
+		assert_eq!(paired.rssi, Some(80));
+
+		// Everything but the fields from state telemetry is identical.
+		assert_eq!(
+			Telemetry {
+				device_uptime: None,
+				link_count: None,
+				mqtt_count: None,
+				rssi: None,
+				ssid: None,
+				states: BTreeMap::new(),
+				wifi_signal: None,
+				..paired
+			},
+			sensor_only
+		);
+	}
+
+	#[test]
+	fn timestamp_before_floor_is_rejected() {
+		let smartplug = SmartPlug::<HomeTasmotaTopicScheme>::new(String::from("kitchen/kettle"))
+			.with_timestamp_strategy(TimestampStrategy::PreferDevice);
+
+		let time = "1989-06-01T00:00:00";
+		let sns: StatusSNS = serde_json::from_str(&sensor(time, 120)).unwrap();
+		let sts: StatusSTS = serde_json::from_str(&state(time, "ON")).unwrap();
+		let result = smartplug.generate_telemetry(datetime!(2023-10-04 12:00 UTC), sns, sts);
+		assert!(result.is_err());
+	}
 }
@@ -0,0 +1,237 @@
+use crate::util::local_offset_at;
+use serde::Deserialize;
+use std::fmt;
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+/// Strategy for choosing the timestamp written with smart plug telemetry.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampStrategy {
+	/// Always use the device's clock.
+	PreferDevice,
+	/// Always use the time fizzle received the telemetry.
+	PreferMachine,
+	/// Use the device's clock unless it has drifted from the machine clock by
+	/// more than the threshold.
+	DriftGuarded { threshold_ms: u64 },
+	/// Omit the timestamp and let InfluxDB assign one on write.
+	///
+	/// Only suitable when each point is written on its own: InfluxDB gives
+	/// every point in a request the same time, so points of one series in a
+	/// batch overwrite each other.
+	ServerAssigned,
+}
+
+impl Default for TimestampStrategy {
+	fn default() -> Self {
+		Self::DriftGuarded {
+			threshold_ms: 20_000,
+		}
+	}
+}
+
+impl TimestampStrategy {
+	/// Picks the timestamp, in milliseconds, to write for a telemetry datum.
+	/// Returns `None` when the server should assign the timestamp.
+	pub fn choose(&self, name: &str, device_timestamp: i64, machine_timestamp: i64) -> Option<i64> {
+		match *self {
+			Self::PreferDevice => Some(device_timestamp),
+			Self::PreferMachine => Some(machine_timestamp),
+			Self::DriftGuarded { threshold_ms } => {
+				let drift = machine_timestamp.abs_diff(device_timestamp);
+				if drift > threshold_ms {
+					tracing::warn!(
+						"timestamp drift for '{name}' is {drift}ms > {threshold_ms}ms, using machine time"
+					);
+					Some(machine_timestamp)
+				} else {
+					Some(device_timestamp)
+				}
+			}
+			Self::ServerAssigned => None,
+		}
+	}
+}
+
+/// The timezone device clocks are set to. Tasmota reports times without an
+/// offset, so one must be assumed to place them on the timeline.
+///
+/// Written as `local`, `UTC` or a fixed offset such as `+10:00`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum DeviceTimezone {
+	/// The timezone of the machine fizzle runs on.
+	#[default]
+	Local,
+	/// A fixed offset from UTC.
+	Fixed(UtcOffset),
+}
+
+impl DeviceTimezone {
+	/// Returns the instant at which a device's clock showed `time`.
+	pub fn resolve(&self, time: PrimitiveDateTime) -> OffsetDateTime {
+		match *self {
+			Self::Fixed(offset) => time.assume_offset(offset),
+			Self::Local => {
+				// Guess using the offset in effect were `time` in UTC, then
+				// correct the guess if the offset differs at that instant.
+				let guess = time.assume_offset(local_offset_at(time.assume_utc()));
+				time.assume_offset(local_offset_at(guess))
+			}
+		}
+	}
+}
+
+/// A device timezone which is neither `local`, `UTC` nor an offset.
+#[derive(Debug)]
+pub struct InvalidTimezone(String);
+
+impl fmt::Display for InvalidTimezone {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"invalid device timezone '{}', expected 'local', 'UTC' or an offset such as '+10:00'",
+			self.0
+		)
+	}
+}
+
+impl std::error::Error for InvalidTimezone {}
+
+impl TryFrom<String> for DeviceTimezone {
+	type Error = InvalidTimezone;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		match value.as_str() {
+			"local" => Ok(Self::Local),
+			"UTC" | "utc" | "Z" => Ok(Self::Fixed(UtcOffset::UTC)),
+			offset => parse_offset(offset)
+				.map(Self::Fixed)
+				.ok_or(InvalidTimezone(value)),
+		}
+	}
+}
+
+/// Parses an offset written as `+HH:MM` or `+HH`.
+fn parse_offset(value: &str) -> Option<UtcOffset> {
+	let (sign, rest) = match value.as_bytes().first()? {
+		b'+' => (1, &value[1..]),
+		b'-' => (-1, &value[1..]),
+		_ => return None,
+	};
+	let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+	let hours: i8 = hours.parse().ok()?;
+	let minutes: i8 = minutes.parse().ok()?;
+	UtcOffset::from_hms(sign * hours, sign * minutes, 0).ok()
+}
+
+/// What to do with a reading whose timestamp matches one already buffered.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+	/// Keep the reading already buffered, such as when duplicates are
+	/// re-deliveries of retained messages.
+	KeepFirst,
+	/// Replace the buffered reading with the new one.
+	#[default]
+	KeepLast,
+	/// Discard every reading with that timestamp, as neither can be trusted.
+	Reject,
+}
+
+impl DuplicatePolicy {
+	/// Stores `reading` in `slot`. Returns false if the readings for this
+	/// timestamp should be discarded.
+	pub fn apply<T: fmt::Debug>(self, slot: &mut Option<T>, reading: T) -> bool {
+		let Some(existing) = slot.as_ref() else {
+			*slot = Some(reading);
+			return true;
+		};
+
+		match self {
+			Self::KeepFirst => {
+				tracing::warn!(
+					"received telemetry with duplicate timestamp, ignoring: {reading:?}"
+				);
+				true
+			}
+			Self::KeepLast => {
+				tracing::warn!("received telemetry with duplicate timestamp: {existing:?}");
+				*slot = Some(reading);
+				true
+			}
+			Self::Reject => {
+				tracing::warn!(
+					"received telemetry with duplicate timestamp, discarding both: {existing:?}, {reading:?}"
+				);
+				false
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{DeviceTimezone, DuplicatePolicy, TimestampStrategy};
+	use time::macros::{datetime, offset};
+
+	const DEVICE: i64 = 1_696_420_800_000;
+	const MACHINE: i64 = DEVICE + 30_000;
+
+	#[test]
+	fn prefer_device() {
+		let strategy = TimestampStrategy::PreferDevice;
+		assert_eq!(strategy.choose("test", DEVICE, MACHINE), Some(DEVICE));
+	}
+
+	#[test]
+	fn prefer_machine() {
+		let strategy = TimestampStrategy::PreferMachine;
+		assert_eq!(strategy.choose("test", DEVICE, MACHINE), Some(MACHINE));
+	}
+
+	#[test]
+	fn drift_guarded() {
+		let strategy = TimestampStrategy::default();
+		assert_eq!(strategy.choose("test", DEVICE, MACHINE), Some(MACHINE));
+		assert_eq!(strategy.choose("test", DEVICE, DEVICE + 500), Some(DEVICE));
+
+		let strategy = TimestampStrategy::DriftGuarded {
+			threshold_ms: 60_000,
+		};
+		assert_eq!(strategy.choose("test", DEVICE, MACHINE), Some(DEVICE));
+	}
+
+	#[test]
+	fn server_assigned() {
+		let strategy = TimestampStrategy::ServerAssigned;
+		assert_eq!(strategy.choose("test", DEVICE, MACHINE), None);
+	}
+
+	#[test]
+	fn fixed_device_timezone() {
+		let timezone = DeviceTimezone::try_from(String::from("+10:00")).unwrap();
+		assert_eq!(timezone, DeviceTimezone::Fixed(offset!(+10)));
+		assert_eq!(
+			timezone.resolve(datetime!(2023-10-04 22:00:00)),
+			datetime!(2023-10-04 12:00:00 UTC)
+		);
+
+		let timezone = DeviceTimezone::try_from(String::from("-05:30")).unwrap();
+		assert_eq!(timezone, DeviceTimezone::Fixed(offset!(-5:30)));
+		assert!(DeviceTimezone::try_from(String::from("Australia/Brisbane")).is_err());
+	}
+
+	#[test]
+	fn duplicate_policies() {
+		let resolve = |policy: DuplicatePolicy| {
+			let mut slot = None;
+			assert!(policy.apply(&mut slot, 1));
+			policy.apply(&mut slot, 2).then_some(slot).flatten()
+		};
+
+		assert_eq!(resolve(DuplicatePolicy::KeepFirst), Some(1));
+		assert_eq!(resolve(DuplicatePolicy::KeepLast), Some(2));
+		assert_eq!(resolve(DuplicatePolicy::Reject), None);
+	}
+}
@@ -1,69 +1,266 @@
-#[derive(Debug)]
+use serde::Deserialize;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum TelemetryType {
 	Sensor,
 	State,
 	Lwt,
+	/// A response to the `Status` command.
+	Status,
+	/// One of the `INFO1`, `INFO2` or `INFO3` messages sent on boot.
+	Info,
 }
 
 /// A trait for generating MQTT topics for smartplugs
 ///
 pub trait TopicGenerator {
 	/// Produce the topic string for StatusSNS telemetry messages
-	fn sensor_telemetry_topic(device_name: &str) -> String;
+	fn sensor_telemetry_topic(&self, device_name: &str) -> String;
 
 	/// Produce the topic string for StatusSTS telemetry messages
-	fn state_telemetry_topic(device_name: &str) -> String;
+	fn state_telemetry_topic(&self, device_name: &str) -> String;
 
 	/// Produce the topic string for LWT messages
-	fn lwt_topic(device_name: &str) -> String;
+	fn lwt_topic(&self, device_name: &str) -> String;
+
+	/// Produce the topic string for the first boot message
+	fn info_topic(&self, device_name: &str) -> String;
+
+	/// Produce the topic string to publish `Status` commands to
+	fn status_command_topic(&self, device_name: &str) -> String;
+
+	/// Produce the topic string on which `Status` responses arrive
+	fn status_response_topic(&self, device_name: &str) -> String;
 
 	/// Determine the type of telemetry message from the topic string
-	fn telemetry_type(topic: &str) -> Option<TelemetryType>;
+	fn telemetry_type(&self, topic: &str) -> Option<TelemetryType>;
 
 	/// Extract the device name from the topic string
-	fn extract_device_name(topic: &str) -> Option<&str>;
+	fn extract_device_name<'a>(&self, topic: &'a str) -> Option<&'a str>;
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct HomeTasmotaTopicScheme;
 
 impl TopicGenerator for HomeTasmotaTopicScheme {
-	fn sensor_telemetry_topic(device_name: &str) -> String {
+	fn sensor_telemetry_topic(&self, device_name: &str) -> String {
 		format!("tasmota/tele/{}/SENSOR", device_name)
 	}
 
-	fn state_telemetry_topic(device_name: &str) -> String {
+	fn state_telemetry_topic(&self, device_name: &str) -> String {
 		format!("tasmota/tele/{}/STATE", device_name)
 	}
 
-	fn lwt_topic(device_name: &str) -> String {
+	fn lwt_topic(&self, device_name: &str) -> String {
 		format!("tasmota/tele/{}/LWT", device_name)
 	}
 
-	fn telemetry_type(topic: &str) -> Option<TelemetryType> {
+	fn info_topic(&self, device_name: &str) -> String {
+		format!("tasmota/tele/{}/INFO1", device_name)
+	}
+
+	fn status_command_topic(&self, device_name: &str) -> String {
+		format!("tasmota/cmnd/{}/Status", device_name)
+	}
+
+	fn status_response_topic(&self, device_name: &str) -> String {
+		format!("tasmota/stat/{}/STATUS", device_name)
+	}
+
+	fn telemetry_type(&self, topic: &str) -> Option<TelemetryType> {
 		if topic.ends_with("/SENSOR") {
 			Some(TelemetryType::Sensor)
 		} else if topic.ends_with("/STATE") {
 			Some(TelemetryType::State)
 		} else if topic.ends_with("/LWT") {
 			Some(TelemetryType::Lwt)
+		} else if topic.ends_with("/STATUS") || topic.ends_with("/STATUS0") {
+			Some(TelemetryType::Status)
+		} else if topic.ends_with("/INFO1")
+			|| topic.ends_with("/INFO2")
+			|| topic.ends_with("/INFO3")
+		{
+			Some(TelemetryType::Info)
 		} else {
 			None
 		}
 	}
 
-	fn extract_device_name(topic: &str) -> Option<&str> {
+	fn extract_device_name<'a>(&self, topic: &'a str) -> Option<&'a str> {
 		let topic = topic.trim_start_matches("tasmota/tele/");
+		let topic = topic.trim_start_matches("tasmota/stat/");
 		let topic = topic.trim_end_matches("/SENSOR");
 		let topic = topic.trim_end_matches("/STATE");
 		let topic = topic.trim_end_matches("/LWT");
+		let topic = topic.trim_end_matches("/STATUS0");
+		let topic = topic.trim_end_matches("/STATUS");
+		let topic = topic.trim_end_matches("/INFO1");
+		let topic = topic.trim_end_matches("/INFO2");
+		let topic = topic.trim_end_matches("/INFO3");
 		Some(topic)
 	}
 }
 
+/// Placeholder for the device name in a [`TopicTemplate`], as in
+/// Tasmota's own full topic setting.
+pub const TOPIC_PLACEHOLDER: &str = "%topic%";
+
+/// A topic layout containing [`TOPIC_PLACEHOLDER`] exactly once, such as
+/// `tasmota/tele/%topic%/SENSOR`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct TopicTemplate {
+	prefix: String,
+	suffix: String,
+}
+
+impl TopicTemplate {
+	/// Returns the topic for `device_name`.
+	pub fn topic(&self, device_name: &str) -> String {
+		format!("{}{device_name}{}", self.prefix, self.suffix)
+	}
+
+	/// Returns the device name from a topic following this template.
+	pub fn device_name<'a>(&self, topic: &'a str) -> Option<&'a str> {
+		let name = topic
+			.strip_prefix(&self.prefix)?
+			.strip_suffix(&self.suffix)?;
+		(!name.is_empty()).then_some(name)
+	}
+
+	/// Like [`TopicTemplate::device_name`], but ignoring any number ending
+	/// the topic, for messages Tasmota numbers such as `INFO1` to `INFO3`
+	/// or `STATUS` and `STATUS0`.
+	fn numbered_device_name<'a>(&self, topic: &'a str) -> Option<&'a str> {
+		let is_digit = |c: char| c.is_ascii_digit();
+		let name = topic
+			.strip_prefix(&self.prefix)?
+			.trim_end_matches(is_digit)
+			.strip_suffix(self.suffix.trim_end_matches(is_digit))?;
+		(!name.is_empty()).then_some(name)
+	}
+}
+
+#[derive(Debug)]
+pub struct InvalidTopicTemplate(String);
+
+impl fmt::Display for InvalidTopicTemplate {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"invalid topic template '{}', expected '{TOPIC_PLACEHOLDER}' exactly once",
+			self.0
+		)
+	}
+}
+
+impl std::error::Error for InvalidTopicTemplate {}
+
+impl TryFrom<String> for TopicTemplate {
+	type Error = InvalidTopicTemplate;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		match value.split_once(TOPIC_PLACEHOLDER) {
+			Some((prefix, suffix)) if !suffix.contains(TOPIC_PLACEHOLDER) => Ok(Self {
+				prefix: prefix.to_string(),
+				suffix: suffix.to_string(),
+			}),
+			_ => Err(InvalidTopicTemplate(value)),
+		}
+	}
+}
+
+/// Topics built from configured templates, for Tasmota devices with a
+/// non-default topic or full topic setting. The default templates match
+/// [`HomeTasmotaTopicScheme`].
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct TemplateTopicScheme {
+	pub sensor: TopicTemplate,
+	pub state: TopicTemplate,
+	pub lwt: TopicTemplate,
+	/// The first boot message. `INFO2` and `INFO3` are recognised too.
+	pub info: TopicTemplate,
+	pub status_command: TopicTemplate,
+	/// The `Status` response. `STATUS0` is recognised too.
+	pub status_response: TopicTemplate,
+}
+
+impl Default for TemplateTopicScheme {
+	fn default() -> Self {
+		let template = |value: &str| {
+			TopicTemplate::try_from(value.to_string()).expect("default template should be valid")
+		};
+		Self {
+			sensor: template("tasmota/tele/%topic%/SENSOR"),
+			state: template("tasmota/tele/%topic%/STATE"),
+			lwt: template("tasmota/tele/%topic%/LWT"),
+			info: template("tasmota/tele/%topic%/INFO1"),
+			status_command: template("tasmota/cmnd/%topic%/Status"),
+			status_response: template("tasmota/stat/%topic%/STATUS"),
+		}
+	}
+}
+
+impl TemplateTopicScheme {
+	fn matches<'a>(&self, topic: &'a str) -> Option<(TelemetryType, &'a str)> {
+		if let Some(name) = self.sensor.device_name(topic) {
+			Some((TelemetryType::Sensor, name))
+		} else if let Some(name) = self.state.device_name(topic) {
+			Some((TelemetryType::State, name))
+		} else if let Some(name) = self.lwt.device_name(topic) {
+			Some((TelemetryType::Lwt, name))
+		} else if let Some(name) = self.status_response.numbered_device_name(topic) {
+			Some((TelemetryType::Status, name))
+		} else {
+			self.info
+				.numbered_device_name(topic)
+				.map(|name| (TelemetryType::Info, name))
+		}
+	}
+}
+
+impl TopicGenerator for TemplateTopicScheme {
+	fn sensor_telemetry_topic(&self, device_name: &str) -> String {
+		self.sensor.topic(device_name)
+	}
+
+	fn state_telemetry_topic(&self, device_name: &str) -> String {
+		self.state.topic(device_name)
+	}
+
+	fn lwt_topic(&self, device_name: &str) -> String {
+		self.lwt.topic(device_name)
+	}
+
+	fn info_topic(&self, device_name: &str) -> String {
+		self.info.topic(device_name)
+	}
+
+	fn status_command_topic(&self, device_name: &str) -> String {
+		self.status_command.topic(device_name)
+	}
+
+	fn status_response_topic(&self, device_name: &str) -> String {
+		self.status_response.topic(device_name)
+	}
+
+	fn telemetry_type(&self, topic: &str) -> Option<TelemetryType> {
+		self.matches(topic).map(|(kind, _)| kind)
+	}
+
+	fn extract_device_name<'a>(&self, topic: &'a str) -> Option<&'a str> {
+		self.matches(topic).map(|(_, name)| name)
+	}
+}
+
 #[cfg(test)]
 mod tests {
-	use crate::smartplugs::{topic::HomeTasmotaTopicScheme, SmartPlug};
+	use super::{
+		HomeTasmotaTopicScheme, TelemetryType, TemplateTopicScheme, TopicGenerator, TopicTemplate,
+	};
+	use crate::smartplugs::SmartPlug;
 
 	#[test]
 	fn test_smartplug_new() {
@@ -77,7 +274,7 @@ mod tests {
 		let name = "location/device-name".to_string();
 		let smartplug = SmartPlug::<HomeTasmotaTopicScheme>::new(name.clone());
 		assert_eq!(
-			smartplug.sensor_telemetry_topic(),
+			smartplug.sensor_telemetry_topic(&HomeTasmotaTopicScheme),
 			"tasmota/tele/location/device-name/SENSOR"
 		);
 	}
@@ -87,7 +284,7 @@ mod tests {
 		let name = "location/device-name".to_string();
 		let smartplug = SmartPlug::<HomeTasmotaTopicScheme>::new(name.clone());
 		assert_eq!(
-			smartplug.state_telemetry_topic(),
+			smartplug.state_telemetry_topic(&HomeTasmotaTopicScheme),
 			"tasmota/tele/location/device-name/STATE"
 		);
 	}
@@ -97,8 +294,78 @@ mod tests {
 		let name = "location/device-name".to_string();
 		let smartplug = SmartPlug::<HomeTasmotaTopicScheme>::new(name.clone());
 		assert_eq!(
-			smartplug.lwt_topic(),
+			smartplug.lwt_topic(&HomeTasmotaTopicScheme),
 			"tasmota/tele/location/device-name/LWT"
 		);
 	}
+
+	#[test]
+	fn default_templates_match_home_scheme() {
+		let scheme = TemplateTopicScheme::default();
+		for topic in [
+			"tasmota/tele/kitchen/kettle/SENSOR",
+			"tasmota/tele/kitchen/kettle/STATE",
+			"tasmota/tele/kitchen/kettle/LWT",
+			"tasmota/tele/kitchen/kettle/INFO2",
+			"tasmota/stat/kitchen/kettle/STATUS0",
+		] {
+			assert_eq!(
+				scheme.telemetry_type(topic),
+				HomeTasmotaTopicScheme.telemetry_type(topic)
+			);
+			assert_eq!(
+				scheme.extract_device_name(topic),
+				HomeTasmotaTopicScheme.extract_device_name(topic)
+			);
+		}
+		assert_eq!(
+			scheme.status_command_topic("kitchen/kettle"),
+			HomeTasmotaTopicScheme.status_command_topic("kitchen/kettle")
+		);
+	}
+
+	#[test]
+	fn custom_templates() {
+		let scheme: TemplateTopicScheme = serde_yaml::from_str(
+			r#"
+sensor: "%topic%/tele/SENSOR"
+state: "%topic%/tele/STATE"
+lwt: "%topic%/tele/LWT"
+info: "%topic%/tele/INFO1"
+status_command: "%topic%/cmnd/Status"
+status_response: "%topic%/stat/STATUS"
+"#,
+		)
+		.unwrap();
+
+		for (kind, topic) in [
+			(
+				TelemetryType::Sensor,
+				scheme.sensor_telemetry_topic("kettle"),
+			),
+			(TelemetryType::State, scheme.state_telemetry_topic("kettle")),
+			(TelemetryType::Lwt, scheme.lwt_topic("kettle")),
+			(TelemetryType::Info, scheme.info_topic("kettle")),
+			(
+				TelemetryType::Status,
+				scheme.status_response_topic("kettle"),
+			),
+		] {
+			assert!(topic.starts_with("kettle/"));
+			assert_eq!(scheme.telemetry_type(&topic), Some(kind));
+			assert_eq!(scheme.extract_device_name(&topic), Some("kettle"));
+		}
+		assert_eq!(
+			scheme.telemetry_type("kettle/tele/INFO3"),
+			Some(TelemetryType::Info)
+		);
+		assert_eq!(
+			scheme.extract_device_name("tasmota/tele/kettle/SENSOR"),
+			None
+		);
+		assert_eq!(scheme.status_command_topic("kettle"), "kettle/cmnd/Status");
+
+		assert!(TopicTemplate::try_from(String::from("tasmota/tele/SENSOR")).is_err());
+		assert!(TopicTemplate::try_from(String::from("%topic%/%topic%")).is_err());
+	}
 }
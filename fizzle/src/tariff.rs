@@ -0,0 +1,292 @@
+use crate::util::local_offset_at;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io::ErrorKind, path::Path};
+use time::{Date, Month, OffsetDateTime, UtcOffset, Weekday};
+use yesterday::Record;
+
+/// A time-of-use electricity tariff.
+///
+/// Rates are in currency units per kWh. Windows are matched against the
+/// local wall-clock time in effect at each timestamp, so a window keeps
+/// its meaning across daylight-saving transitions.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Tariff {
+	/// Rate used when no window matches.
+	pub default_rate: f64,
+
+	/// Windows are checked in order; the first match wins.
+	#[serde(default)]
+	pub windows: Vec<TariffWindow>,
+
+	/// Day of the month on which each billing period starts, and cumulative
+	/// costs restart from zero. Days after the 28th are treated as the 28th.
+	#[serde(default = "default_billing_day")]
+	pub billing_day: u8,
+}
+
+fn default_billing_day() -> u8 {
+	1
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TariffWindow {
+	pub rate: f64,
+
+	/// Local hour the window starts at (inclusive).
+	pub start_hour: u8,
+
+	/// Local hour the window ends at (exclusive). Windows with an end hour
+	/// before their start hour wrap past midnight.
+	pub end_hour: u8,
+
+	/// Days the window applies on. An empty list matches every day.
+	#[serde(default)]
+	pub days: Vec<Weekday>,
+}
+
+impl TariffWindow {
+	fn matches(&self, weekday: Weekday, hour: u8) -> bool {
+		if !self.days.is_empty() && !self.days.contains(&weekday) {
+			return false;
+		}
+
+		if self.start_hour <= self.end_hour {
+			(self.start_hour..self.end_hour).contains(&hour)
+		} else {
+			hour >= self.start_hour || hour < self.end_hour
+		}
+	}
+}
+
+impl Tariff {
+	/// Returns the rate in effect at `ts`, using the system's local offset at
+	/// that instant.
+	pub fn rate_at(&self, ts: OffsetDateTime) -> f64 {
+		self.rate_at_with(ts, local_offset_at)
+	}
+
+	/// Computes the cost of a cumulative energy series, in Watt hours, such as
+	/// the one returned by [`yesterday::fetch`].
+	pub fn cost_for(&self, energy_series: &[Record]) -> f64 {
+		self.cost_for_with(energy_series, local_offset_at)
+	}
+
+	/// Returns the first day of the billing period containing `date`.
+	pub fn billing_period_start(&self, date: Date) -> Date {
+		let day = self.billing_day.clamp(1, 28);
+		let (year, month) = if date.day() >= day {
+			(date.year(), date.month())
+		} else if date.month() == Month::January {
+			(date.year() - 1, Month::December)
+		} else {
+			(date.year(), date.month().previous())
+		};
+		Date::from_calendar_date(year, month, day).expect("every month has at least 28 days")
+	}
+
+	fn rate_at_with<F>(&self, ts: OffsetDateTime, offset_at: F) -> f64
+	where
+		F: Fn(OffsetDateTime) -> UtcOffset,
+	{
+		let local = ts.to_offset(offset_at(ts));
+		let (weekday, hour) = (local.weekday(), local.hour());
+
+		self.windows
+			.iter()
+			.find(|window| window.matches(weekday, hour))
+			.map(|window| window.rate)
+			.unwrap_or(self.default_rate)
+	}
+
+	fn cost_for_with<F>(&self, energy_series: &[Record], offset_at: F) -> f64
+	where
+		F: Fn(OffsetDateTime) -> UtcOffset,
+	{
+		let mut previous = 0;
+		let mut cost = 0.0;
+		for Record { ts, value } in energy_series {
+			let energy = value.saturating_sub(previous);
+			cost += energy as f64 / 1000.0 * self.rate_at_with(*ts, &offset_at);
+			previous = *value;
+		}
+		cost
+	}
+}
+
+/// The running cost of a device's energy within the current billing period.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct CumulativeCost {
+	period_start: Option<Date>,
+	/// Energy, in Wh, at the previous reading.
+	last_energy: Option<i64>,
+	total: f64,
+}
+
+impl CumulativeCost {
+	/// Adds the cost of the energy used since the previous reading, at the
+	/// rate in effect at `ts`, and returns the total for the billing period.
+	///
+	/// A reading in a new billing period restarts the total from zero. Energy
+	/// which goes backwards, such as after a counter reset, costs nothing.
+	pub fn accumulate(&mut self, tariff: &Tariff, ts: OffsetDateTime, energy: i64) -> f64 {
+		self.accumulate_with(tariff, ts, energy, local_offset_at)
+	}
+
+	fn accumulate_with<F>(
+		&mut self,
+		tariff: &Tariff,
+		ts: OffsetDateTime,
+		energy: i64,
+		offset_at: F,
+	) -> f64
+	where
+		F: Fn(OffsetDateTime) -> UtcOffset,
+	{
+		let date = ts.to_offset(offset_at(ts)).date();
+		let period_start = tariff.billing_period_start(date);
+		if self.period_start != Some(period_start) {
+			self.period_start = Some(period_start);
+			self.total = 0.0;
+		}
+
+		if let Some(last_energy) = self.last_energy {
+			let used = (energy - last_energy).max(0);
+			self.total += used as f64 / 1000.0 * tariff.rate_at_with(ts, &offset_at);
+		}
+		self.last_energy = Some(energy);
+		self.total
+	}
+}
+
+/// Reads the cumulative costs saved by [`save_costs`]. A missing file means
+/// nothing has been saved yet.
+pub fn load_costs(path: &Path) -> anyhow::Result<BTreeMap<String, CumulativeCost>> {
+	match std::fs::read(path) {
+		Ok(data) => Ok(serde_json::from_slice(&data)?),
+		Err(error) if error.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+		Err(error) => Err(error.into()),
+	}
+}
+
+/// Saves cumulative costs, by device, so they survive a restart.
+///
+/// The file is written under a temporary name then renamed, so a crash part
+/// way through leaves the previous save intact.
+pub fn save_costs(path: &Path, costs: &BTreeMap<String, CumulativeCost>) -> anyhow::Result<()> {
+	let partial = path.with_extension("partial");
+	std::fs::write(&partial, serde_json::to_vec(costs)?)?;
+	std::fs::rename(&partial, path)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{CumulativeCost, Tariff, TariffWindow};
+	use time::{macros::datetime, UtcOffset, Weekday};
+	use yesterday::Record;
+
+	fn tariff() -> Tariff {
+		Tariff {
+			default_rate: 0.10,
+			windows: vec![
+				TariffWindow {
+					rate: 0.05,
+					start_hour: 0,
+					end_hour: 24,
+					days: vec![Weekday::Saturday, Weekday::Sunday],
+				},
+				TariffWindow {
+					rate: 0.30,
+					start_hour: 16,
+					end_hour: 21,
+					days: vec![],
+				},
+				TariffWindow {
+					rate: 0.20,
+					start_hour: 7,
+					end_hour: 16,
+					days: vec![],
+				},
+			],
+			billing_day: 15,
+		}
+	}
+
+	fn utc(_: time::OffsetDateTime) -> UtcOffset {
+		UtcOffset::UTC
+	}
+
+	#[test]
+	fn multi_window_day() {
+		// Wednesday.
+		let series = [
+			Record {
+				ts: datetime!(2023-10-04 03:00 UTC),
+				value: 1000,
+			},
+			Record {
+				ts: datetime!(2023-10-04 12:00 UTC),
+				value: 3000,
+			},
+			Record {
+				ts: datetime!(2023-10-04 18:00 UTC),
+				value: 4000,
+			},
+		];
+
+		let cost = tariff().cost_for_with(&series, utc);
+		assert!((cost - (0.10 + 2.0 * 0.20 + 0.30)).abs() < 1e-9);
+	}
+
+	#[test]
+	fn weekend_rate() {
+		let series = [Record {
+			ts: datetime!(2023-10-07 18:00 UTC),
+			value: 2000,
+		}];
+
+		let cost = tariff().cost_for_with(&series, utc);
+		assert!((cost - 0.10).abs() < 1e-9);
+	}
+
+	#[test]
+	fn window_uses_offset_at_instant() {
+		// 06:30 UTC is 07:30 in a +01:00 summer offset, but 06:30 in winter.
+		let summer = |_| UtcOffset::from_hms(1, 0, 0).unwrap();
+		let ts = datetime!(2023-10-04 06:30 UTC);
+		assert_eq!(tariff().rate_at_with(ts, summer), 0.20);
+		assert_eq!(tariff().rate_at_with(ts, utc), 0.10);
+	}
+
+	#[test]
+	fn cumulative_cost_resets_on_billing_day() {
+		let tariff = tariff();
+		let mut cost = CumulativeCost::default();
+
+		// Weekday readings before 07:00 are at the default rate.
+		let readings = [
+			(datetime!(2023-10-11 03:00 UTC), 1000, 0.0),
+			(datetime!(2023-10-11 03:30 UTC), 3000, 0.20),
+			(datetime!(2023-10-12 03:00 UTC), 4000, 0.30),
+			// A new billing period starts on Sunday the 15th.
+			(datetime!(2023-10-16 03:00 UTC), 5000, 0.10),
+			(datetime!(2023-10-16 03:30 UTC), 6000, 0.20),
+		];
+		for (ts, energy, expected) in readings {
+			let total = cost.accumulate_with(&tariff, ts, energy, utc);
+			assert!(
+				(total - expected).abs() < 1e-9,
+				"{ts}: {total} != {expected}"
+			);
+		}
+
+		let path = std::env::temp_dir().join(format!("fizzle-costs-{}.json", std::process::id()));
+		let costs = [(String::from("kitchen/kettle"), cost)]
+			.into_iter()
+			.collect();
+		super::save_costs(&path, &costs).unwrap();
+		assert_eq!(super::load_costs(&path).unwrap(), costs);
+		assert!(!path.with_extension("partial").exists());
+		std::fs::remove_file(&path).unwrap();
+	}
+}
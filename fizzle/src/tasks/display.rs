@@ -1,24 +1,41 @@
 use crate::config::{Config, DisplayButtonConfig, DisplayConfig};
-use fizzle::util::parse_json_payload;
+use fizzle::util::{local_offset_at, parse_json_payload};
 use influxdb::query::QueryClient;
 use mqtt::{clients::tokio::Client, QoS};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{fmt, future::Future, sync::Arc};
 use time::{Date, Duration, OffsetDateTime};
 use tokio::{
-	sync::{watch, RwLock},
+	sync::{mpsc, watch, RwLock},
 	task::JoinHandle,
 };
 use yesterday::Record;
 
 #[derive(Debug, Deserialize)]
 pub struct MeterReading {
-	pub power: u16,
+	/// Net power in Watts, negative while exporting, such as from solar.
+	pub power: i32,
 	pub energy_today: u32,
 	pub energy_yesterday: u32,
 	pub energy_lifetime: u64,
 }
 
+/// Formats net power to seven characters, marking export with an `E` rather
+/// than a minus sign so it stands out on the display.
+fn format_power(power: i32) -> String {
+	if power < 0 {
+		format!("E{: >5}W", power.unsigned_abs())
+	} else {
+		format!("{: >6}W", power)
+	}
+}
+
+/// Formats yesterday's energy use and cost to the display's sixteen
+/// characters.
+fn format_yesterday_cost(energy: impl fmt::Display, cost: f64, currency_symbol: char) -> String {
+	format!("Yn{energy: >5}Wh {currency_symbol}{cost: >5.2}")
+}
+
 #[derive(Debug, Serialize)]
 struct Page {
 	lines: Vec<String>,
@@ -27,7 +44,7 @@ struct Page {
 pub fn create_task<'c>(
 	client: Client,
 	query_client: QueryClient,
-	config: Arc<Config>,
+	config: watch::Receiver<Arc<Config>>,
 	shutdown: watch::Receiver<bool>,
 ) -> JoinHandle<anyhow::Result<()>> {
 	tokio::spawn(start_task(client, query_client, config, shutdown))
@@ -36,55 +53,113 @@ pub fn create_task<'c>(
 pub async fn start_task(
 	mqtt_client: Client,
 	query_client: QueryClient,
-	config: Arc<Config>,
-	mut shutdown_signal: watch::Receiver<bool>,
+	config_rx: watch::Receiver<Arc<Config>>,
+	shutdown_signal: watch::Receiver<bool>,
 ) -> anyhow::Result<()> {
+	// Only the tariff is reloadable; everything else is fixed at startup.
+	let config = Arc::clone(&config_rx.borrow());
 	let Some(display_config) = config.display.clone() else {
 		tracing::error!("no display configuration. skipping character display task");
 		return Ok(());
 	};
 
 	tokio::spawn(button_task(mqtt_client.clone(), display_config.clone()));
-	let mut impulses = mqtt_client.subscribe(display_config.meter_topic, 8).await?;
+	let mut impulses = mqtt_client
+		.subscribe(
+			display_config.meter_topic.clone(),
+			config.mqtt.subscribe_buffers.display,
+		)
+		.await?;
+
+	// Parsed readings are passed to the page loop over a channel, so the loop
+	// does not depend on the broker.
+	let (readings_tx, readings_rx) = mpsc::channel(1);
+	tokio::spawn(async move {
+		while let Some(message) = impulses.recv().await {
+			let Ok(reading) = parse_json_payload(message) else {
+				continue;
+			};
+			if readings_tx.send(reading).await.is_err() {
+				break;
+			}
+		}
+	});
 
 	let yesterdays_data: Arc<RwLock<Option<(Date, Vec<Record>)>>> = Default::default();
 	tokio::spawn(data_update_task(
 		query_client,
-		config,
+		Arc::clone(&config),
 		Arc::clone(&yesterdays_data),
 		shutdown_signal.clone(),
 	));
 
+	let publish = |page: String, retain| {
+		mqtt_client.publish(display_config.topic.as_str(), page, QoS::AtMostOnce, retain)
+	};
+	page_loop(
+		readings_rx,
+		publish,
+		&display_config,
+		config_rx,
+		yesterdays_data,
+		shutdown_signal,
+	)
+	.await;
+	Ok(())
+}
+
+/// Publishes a page for each meter reading with `publish`, then the shutdown
+/// page once shutdown is signalled.
+///
+/// Failed publishes are retried with `publish_retry`, such as during a broker
+/// reconnect. Once that gives up the page is dropped; the failure is logged
+/// by the retry, and the loop carries on with the next reading.
+async fn page_loop<T, E, P, Fut>(
+	mut readings: mpsc::Receiver<MeterReading>,
+	mut publish: P,
+	display_config: &DisplayConfig,
+	config_rx: watch::Receiver<Arc<Config>>,
+	yesterdays_data: Arc<RwLock<Option<(Date, Vec<Record>)>>>,
+	mut shutdown_signal: watch::Receiver<bool>,
+) where
+	E: fmt::Debug,
+	P: FnMut(String, bool) -> Fut,
+	Fut: Future<Output = Result<T, E>>,
+{
 	loop {
 		#[rustfmt::skip]
-		let message = tokio::select! {
-		  Some(message) = impulses.recv() => message,
+		let payload = tokio::select! {
+		  Some(payload) = readings.recv() => payload,
 		  _ = shutdown_signal.changed() => {
 				tracing::info!("shutting down character display task");
-				mqtt_client.publish(
-					display_config.topic.as_str(),
-					"\n  meter  agent\n    shutdown\n ",
-					QoS::AtMostOnce,
-					display_config.retain
-				).await?;
+				let _ = display_config.publish_retry.retry("publishing the shutdown page", || {
+					publish(
+						String::from("\n  meter  agent\n    shutdown\n "),
+						display_config.status_retain(),
+					)
+				}).await;
 				break;
 		  }
 		};
 
-		let Ok(payload): Result<MeterReading, _> = parse_json_payload(message) else {
-			continue;
-		};
-
 		tracing::debug!("received impulse: {payload:?}");
 
-		let now = OffsetDateTime::now_local().expect("WTF!");
+		let now = OffsetDateTime::now_utc();
+		let now = now.to_offset(local_offset_at(now));
 
 		let yesterday_usage = if let Some((date, data)) = yesterdays_data.read().await.as_ref() {
 			let yesterday = now.checked_sub(Duration::days(1)).unwrap();
 			if &yesterday.date() == date {
 				data.iter()
-					.find(|Record { ts, .. }| ts >= &yesterday)
-					.cloned()
+					.position(|Record { ts, .. }| ts >= &yesterday)
+					.map(|index| {
+						let cost = config_rx
+							.borrow()
+							.tariff
+							.as_ref()
+							.map(|tariff| tariff.cost_for(&data[..=index]));
+						(data[index], cost)
+					})
 			} else {
 				None
 			}
@@ -92,26 +167,27 @@ pub async fn start_task(
 			None
 		};
 
-		let line3 = if let Some(yesterday_usage) = yesterday_usage {
-			let Record { ts, value } = yesterday_usage;
-			format!(
+		let line3 = match yesterday_usage {
+			Some((Record { value, .. }, Some(cost))) => {
+				format_yesterday_cost(value, cost, display_config.currency_symbol)
+			}
+			Some((Record { ts, value }, None)) => format!(
 				"Yn{: >5}Wh @{: >4.0}W",
 				value,
 				(value as f64 * 3600.0
 					/ (ts.hour() as u32 * 3600 + ts.minute() as u32 * 60 + ts.second() as u32)
 						as f64)
 					.round()
-			)
-		} else {
-			String::default()
+			),
+			None => String::default(),
 		};
 
 		let page = format!(
-			"{:02}:{:02}:{:02} {: >6}W\nT {: >5}Wh @{: >4.0}W\n{line3}\nYt{: >5}Wh @{: >4.0}W",
+			"{:02}:{:02}:{:02} {}\nT {: >5}Wh @{: >4.0}W\n{line3}\nYt{: >5}Wh @{: >4.0}W",
 			now.hour(),
 			now.minute(),
 			now.second(),
-			payload.power,
+			format_power(payload.power),
 			payload.energy_today,
 			(payload.energy_today as f64 * 3600.0
 				/ (now.hour() as u32 * 3600 + now.minute() as u32 * 60 + now.second() as u32)
@@ -122,16 +198,13 @@ pub async fn start_task(
 		);
 
 		tracing::debug!("generated page: {page:?}");
-		mqtt_client
-			.publish(
-				display_config.topic.as_str(),
-				page,
-				QoS::AtMostOnce,
-				display_config.retain,
-			)
-			.await?;
+		let _ = display_config
+			.publish_retry
+			.retry("publishing the display page", || {
+				publish(page.clone(), display_config.page_retain())
+			})
+			.await;
 	}
-	Ok(())
 }
 
 async fn fetch_yesterdays_energy_data(
@@ -148,7 +221,7 @@ async fn fetch_yesterdays_energy_data(
 	tracing::info!("fetching {date}'s energy usage data");
 
 	// Fetch yesterdays's energy usage data.
-	if let Ok(data) = yesterday::fetch(
+	match yesterday::fetch(
 		&query_client,
 		date,
 		&config.influxdb.bucket,
@@ -156,7 +229,10 @@ async fn fetch_yesterdays_energy_data(
 	)
 	.await
 	{
-		yesterdays_data.write().await.replace((date, data));
+		Ok(data) => {
+			yesterdays_data.write().await.replace((date, data));
+		}
+		Err(error) => tracing::warn!("failed to fetch {date}'s energy usage data: {error:?}"),
 	}
 }
 
@@ -235,14 +311,120 @@ async fn button_task(mqtt_client: Client, display_config: DisplayConfig) -> anyh
 			None => message.payload.to_vec(),
 		};
 
-		mqtt_client
-			.publish(
-				button_config.output_topic.as_str(),
-				payload,
-				QoS::AtMostOnce,
-				button_config.retain,
-			)
-			.await?;
+		// A failure is logged by the retry; later presses are still forwarded.
+		let _ = display_config
+			.publish_retry
+			.retry("publishing button output", || {
+				mqtt_client.publish(
+					button_config.output_topic.as_str(),
+					payload.clone(),
+					QoS::AtMostOnce,
+					display_config.button_retain(button_config),
+				)
+			})
+			.await;
 	}
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{format_power, format_yesterday_cost, page_loop, MeterReading};
+	use crate::config::Config;
+	use std::{collections::VecDeque, sync::Arc};
+	use tokio::sync::{mpsc, watch};
+
+	const CONFIG: &str = "mqtt:
+  host: localhost
+influxdb:
+  host: http://localhost:8086
+  bucket: energy
+  token: token
+  org: home
+  read_only: true
+display:
+  topic: display
+  meter_topic: meter
+  meter_device: garage/meter
+  publish_retry:
+    initial_delay_ms: 1
+    max_delay_ms: 1
+    max_attempts: 3
+";
+
+	fn reading(power: i32) -> MeterReading {
+		MeterReading {
+			power,
+			energy_today: 1200,
+			energy_yesterday: 9800,
+			energy_lifetime: 123456,
+		}
+	}
+
+	#[test]
+	fn export_is_rendered_distinctly() {
+		let reading: MeterReading = serde_json::from_str(
+			r#"{"power":-450,"energy_today":1200,"energy_yesterday":9800,"energy_lifetime":123456}"#,
+		)
+		.unwrap();
+		assert_eq!(reading.power, -450);
+		assert_eq!(format_power(reading.power), "E  450W");
+		assert_eq!(format_power(1250), "  1250W");
+	}
+
+	#[test]
+	fn yesterday_cost_fits_the_display() {
+		let line = format_yesterday_cost(12345, 4.5, '€');
+		assert_eq!(line, "Yn12345Wh € 4.50");
+		assert_eq!(line.chars().count(), 16);
+	}
+
+	#[tokio::test]
+	async fn failed_publish_does_not_end_the_task() {
+		let config: Config = serde_yaml::from_str(CONFIG).unwrap();
+		let display_config = config.display.clone().unwrap();
+		let (_config_tx, config_rx) = watch::channel(Arc::new(config));
+		let (shutdown_tx, shutdown_rx) = watch::channel(false);
+		let (readings_tx, readings_rx) = mpsc::channel(1);
+
+		// A fake broker which drops the first publish, as during a reconnect,
+		// then every attempt at the second page.
+		let (broker_tx, mut broker_rx) = mpsc::unbounded_channel();
+		let mut outcomes = VecDeque::from([false, true, false, false, false]);
+		let mut attempts = 0;
+		let publish = |page: String, _retain| {
+			attempts += 1;
+			let result = if outcomes.pop_front().unwrap_or(true) {
+				broker_tx.send(page).map_err(|_| "closed")
+			} else {
+				Err("disconnected")
+			};
+			async move { result }
+		};
+
+		let pages = page_loop(
+			readings_rx,
+			publish,
+			&display_config,
+			config_rx,
+			Default::default(),
+			shutdown_rx,
+		);
+		let broker = async {
+			for power in [100, 200, 300] {
+				readings_tx.send(reading(power)).await.unwrap();
+			}
+			let first = broker_rx.recv().await.unwrap();
+			let third = broker_rx.recv().await.unwrap();
+			shutdown_tx.send(true).unwrap();
+			let shutdown = broker_rx.recv().await.unwrap();
+			(first, third, shutdown)
+		};
+		let ((), (first, third, shutdown)) = tokio::join!(pages, broker);
+
+		assert!(first.contains(&format_power(100)));
+		assert!(third.contains(&format_power(300)));
+		assert!(shutdown.contains("shutdown"));
+		assert_eq!(attempts, 7);
+	}
+}
@@ -0,0 +1,74 @@
+use fizzle::health::HealthStats;
+use influxdb::buffered::Client as InfluxDbClient;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+	sync::watch,
+	task::JoinHandle,
+	time::{interval_at, Instant},
+};
+
+pub fn create_task(
+	write_client: InfluxDbClient,
+	health: Arc<HealthStats>,
+	period: Duration,
+	shutdown: watch::Receiver<bool>,
+) -> JoinHandle<anyhow::Result<()>> {
+	tokio::spawn(start_task(write_client, health, period, shutdown))
+}
+
+/// Writes the health counters every `period` until shutdown.
+pub async fn start_task(
+	write_client: InfluxDbClient,
+	health: Arc<HealthStats>,
+	period: Duration,
+	mut shutdown_signal: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+	let mut ticks = interval_at(Instant::now() + period, period);
+
+	loop {
+		tokio::select! {
+			_ = ticks.tick() => {
+				write_client
+					.write_with(health.write_line_protocol_with(write_client.queued()))
+					.await?;
+			}
+			_ = shutdown_signal.changed() => break,
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::create_task;
+	use fizzle::health::HealthStats;
+	use influxdb::util::channel_buffered_client;
+	use std::{sync::Arc, time::Duration};
+	use tokio::{sync::watch, time::Instant};
+
+	#[tokio::test]
+	async fn health_is_written_at_interval() {
+		let (writer, mut rx) = channel_buffered_client(4);
+		let (shutdown_tx, shutdown_rx) = watch::channel(false);
+		let health = Arc::new(HealthStats::default());
+		health.message_processed();
+
+		let period = Duration::from_millis(50);
+		let start = Instant::now();
+		let task = create_task(writer, Arc::clone(&health), period, shutdown_rx);
+
+		let (first, _) = rx.recv().await.unwrap();
+		assert!(start.elapsed() >= period);
+		let (_, _) = rx.recv().await.unwrap();
+		assert!(start.elapsed() >= period * 2);
+
+		let line = String::from_utf8(first.to_vec()).unwrap();
+		assert!(line.starts_with(
+			"fizzle,reason=health buffer_depth=0u,messages_processed=1u,uptime=0u,write_failures=0u"
+		));
+
+		shutdown_tx.send(true).unwrap();
+		task.await.unwrap().unwrap();
+	}
+}
@@ -1,3 +1,7 @@
 pub mod display;
+pub mod health;
+pub mod query_api;
+#[cfg(unix)]
+pub mod reload;
 pub mod smart_meter;
 // pub mod mqtt;
@@ -0,0 +1,402 @@
+use crate::config::{Config, QueryApiConfig};
+use influxdb::query::QueryClient;
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+	net::TcpListener,
+	sync::watch,
+	task::JoinHandle,
+	time::timeout,
+};
+
+/// Largest Flux query body the endpoint will accept.
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// Largest request line and headers, together, the endpoint will read.
+const MAX_HEAD_LEN: u64 = 8 * 1024;
+
+/// Most headers the endpoint will read.
+const MAX_HEADERS: usize = 64;
+
+/// Longest the endpoint waits for a whole request, so a client which sends
+/// nothing, or sends slowly, cannot hold its connection open.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct Request {
+	method: String,
+	path: String,
+	authorization: Option<String>,
+	body: Vec<u8>,
+}
+
+pub fn create_task(
+	query_client: QueryClient,
+	config: Arc<Config>,
+	shutdown: watch::Receiver<bool>,
+) -> JoinHandle<anyhow::Result<()>> {
+	tokio::spawn(start_task(query_client, config, shutdown))
+}
+
+pub async fn start_task(
+	query_client: QueryClient,
+	config: Arc<Config>,
+	shutdown_signal: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+	let Some(QueryApiConfig { bind, token }) = config.query_api.clone() else {
+		tracing::debug!("no query api configuration. skipping query api task");
+		return Ok(());
+	};
+
+	let listener = TcpListener::bind(bind).await?;
+	tracing::warn!("query api listening on {bind}; it can read all data in the organisation");
+
+	serve(listener, query_client, token, shutdown_signal).await
+}
+
+/// Accepts connections on `listener` until the shutdown signal changes.
+pub async fn serve(
+	listener: TcpListener,
+	query_client: QueryClient,
+	token: String,
+	mut shutdown_signal: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+	let token = Arc::new(token);
+
+	loop {
+		let (stream, peer) = tokio::select! {
+			result = listener.accept() => result?,
+			_ = shutdown_signal.changed() => break,
+		};
+
+		let query_client = query_client.clone();
+		let token = Arc::clone(&token);
+		tokio::spawn(async move {
+			if let Err(error) = handle_connection(stream, &query_client, &token).await {
+				tracing::warn!("error handling query api request from {peer}: {error:?}");
+			}
+		});
+	}
+
+	tracing::info!("shutting down query api task");
+	Ok(())
+}
+
+async fn handle_connection<S>(
+	stream: S,
+	query_client: &QueryClient,
+	token: &str,
+) -> anyhow::Result<()>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let mut reader = BufReader::new(stream);
+	let request = read_request_within(&mut reader, REQUEST_TIMEOUT).await?;
+	let mut stream = reader.into_inner();
+
+	let authorized = request
+		.authorization
+		.as_deref()
+		.and_then(|value| {
+			value
+				.strip_prefix("Bearer ")
+				.or_else(|| value.strip_prefix("Token "))
+		})
+		.is_some_and(|value| constant_time_eq(value.as_bytes(), token.as_bytes()));
+
+	if !authorized {
+		return respond(
+			&mut stream,
+			"401 Unauthorized",
+			json!({ "error": "unauthorized" }),
+		)
+		.await;
+	}
+
+	if request.method != "POST" || request.path != "/query" {
+		return respond(
+			&mut stream,
+			"404 Not Found",
+			json!({ "error": "not found" }),
+		)
+		.await;
+	}
+
+	let flux = String::from_utf8(request.body)?;
+	tracing::debug!("running ad-hoc query: {flux}");
+
+	match run_query(query_client, &flux).await {
+		Ok(rows) => respond(&mut stream, "200 OK", json!(rows)).await,
+		Err(error) => {
+			respond(
+				&mut stream,
+				"502 Bad Gateway",
+				json!({ "error": error.to_string() }),
+			)
+			.await
+		}
+	}
+}
+
+async fn run_query(
+	query_client: &QueryClient,
+	flux: &str,
+) -> anyhow::Result<Vec<BTreeMap<String, String>>> {
+	let rows = query_client
+		.query_as::<BTreeMap<String, String>, _, _>(flux, [])
+		.await?;
+
+	// Drop the unnamed annotation column.
+	Ok(rows
+		.into_iter()
+		.map(|mut row| {
+			row.remove("");
+			row
+		})
+		.collect())
+}
+
+/// Compares every byte, rather than stopping at the first difference, so
+/// the time taken does not reveal how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Reads a line of the request head, of which `remaining` bytes may still
+/// be read. Returns an empty string at the end of the stream.
+async fn read_head_line<R: AsyncRead + Unpin>(
+	reader: &mut BufReader<R>,
+	remaining: &mut u64,
+) -> anyhow::Result<String> {
+	let mut line = String::new();
+	let read = (&mut *reader).take(*remaining).read_line(&mut line).await?;
+	*remaining -= read as u64;
+	anyhow::ensure!(
+		read == 0 || line.ends_with('\n'),
+		"request headers longer than {MAX_HEAD_LEN} bytes"
+	);
+	Ok(line)
+}
+
+/// Reads a request, giving up if it has not all arrived within `limit`.
+async fn read_request_within<R: AsyncRead + Unpin>(
+	reader: &mut BufReader<R>,
+	limit: Duration,
+) -> anyhow::Result<Request> {
+	match timeout(limit, read_request(reader)).await {
+		Ok(result) => result,
+		Err(_) => anyhow::bail!("no complete request within {limit:?}"),
+	}
+}
+
+async fn read_request<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> anyhow::Result<Request> {
+	let mut remaining = MAX_HEAD_LEN;
+	let request_line = read_head_line(reader, &mut remaining).await?;
+
+	let mut parts = request_line.split_whitespace();
+	let method = parts.next().unwrap_or_default().to_string();
+	let path = parts.next().unwrap_or_default().to_string();
+
+	let mut content_length = 0;
+	let mut authorization = None;
+	let mut headers = 0;
+	loop {
+		let line = read_head_line(reader, &mut remaining).await?;
+		if line.is_empty() {
+			break;
+		}
+
+		let line = line.trim_end();
+		if line.is_empty() {
+			break;
+		}
+
+		headers += 1;
+		anyhow::ensure!(headers <= MAX_HEADERS, "more than {MAX_HEADERS} headers");
+
+		if let Some((name, value)) = line.split_once(':') {
+			let value = value.trim();
+			match name.to_ascii_lowercase().as_str() {
+				"content-length" => content_length = value.parse()?,
+				"authorization" => authorization = Some(value.to_string()),
+				_ => {}
+			}
+		}
+	}
+
+	anyhow::ensure!(
+		content_length <= MAX_BODY_LEN,
+		"request body too large: {content_length} bytes"
+	);
+
+	let mut body = vec![0; content_length];
+	reader.read_exact(&mut body).await?;
+
+	Ok(Request {
+		method,
+		path,
+		authorization,
+		body,
+	})
+}
+
+async fn respond<W: AsyncWrite + Unpin>(
+	stream: &mut W,
+	status: &str,
+	body: serde_json::Value,
+) -> anyhow::Result<()> {
+	let body = body.to_string();
+	let response = format!(
+		"HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+		body.len()
+	);
+	stream.write_all(response.as_bytes()).await?;
+	stream.shutdown().await?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		constant_time_eq, read_request, read_request_within, serve, MAX_HEADERS, MAX_HEAD_LEN,
+	};
+	use std::{collections::BTreeMap, time::Duration};
+	use tokio::{
+		io::{duplex, AsyncReadExt, AsyncWriteExt, BufReader},
+		net::{TcpListener, TcpStream},
+		sync::watch,
+	};
+
+	const CSV: &str = "#datatype,string,long,dateTime:RFC3339,long\r\n\
+		#group,false,false,false,false\r\n\
+		#default,_result,,,\r\n\
+		,result,table,_time,_value\r\n\
+		,,0,2023-10-04T00:01:00Z,12\r\n\
+		,,0,2023-10-04T00:02:00Z,25\r\n";
+
+	/// Accepts a single query and answers it with canned CSV.
+	async fn mock_influxdb() -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			let mut reader = BufReader::new(stream);
+			let request = read_request(&mut reader).await.unwrap();
+			assert_eq!(request.path, "/api/v2/query");
+
+			let mut stream = reader.into_inner();
+			let response = format!(
+				"HTTP/1.1 200 OK\r\nContent-Type: text/csv; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{CSV}",
+				CSV.len()
+			);
+			stream.write_all(response.as_bytes()).await.unwrap();
+		});
+		format!("http://{address}")
+	}
+
+	#[tokio::test]
+	async fn query_returns_parsed_rows() {
+		let influxdb = mock_influxdb().await;
+		let query_client = influxdb::Client::new(influxdb, "influx-token")
+			.unwrap()
+			.query_client();
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		tokio::spawn(serve(
+			listener,
+			query_client,
+			String::from("secret"),
+			shutdown_rx,
+		));
+
+		let flux = r#"from(bucket: "energy") |> range(start: -1h)"#;
+		let mut stream = TcpStream::connect(address).await.unwrap();
+		let request = format!(
+			"POST /query HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n{flux}",
+			flux.len()
+		);
+		stream.write_all(request.as_bytes()).await.unwrap();
+
+		let mut response = String::new();
+		stream.read_to_string(&mut response).await.unwrap();
+		assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+		let (_, body) = response.split_once("\r\n\r\n").unwrap();
+		let rows: Vec<BTreeMap<String, String>> = serde_json::from_str(body).unwrap();
+		assert_eq!(rows.len(), 2);
+		assert_eq!(rows[0]["_time"], "2023-10-04T00:01:00Z");
+		assert_eq!(rows[1]["_value"], "25");
+		assert!(!rows[0].contains_key(""));
+	}
+
+	#[tokio::test]
+	async fn query_requires_token() {
+		let query_client = influxdb::Client::new("http://127.0.0.1:9", "influx-token")
+			.unwrap()
+			.query_client();
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		tokio::spawn(serve(
+			listener,
+			query_client,
+			String::from("secret"),
+			shutdown_rx,
+		));
+
+		let mut stream = TcpStream::connect(address).await.unwrap();
+		stream
+			.write_all(b"POST /query HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+			.await
+			.unwrap();
+
+		let mut response = String::new();
+		stream.read_to_string(&mut response).await.unwrap();
+		assert!(response.starts_with("HTTP/1.1 401 Unauthorized"));
+	}
+
+	#[test]
+	fn tokens_are_compared_in_full() {
+		assert!(constant_time_eq(b"secret", b"secret"));
+		assert!(!constant_time_eq(b"secret", b"secreT"));
+		assert!(!constant_time_eq(b"secret", b"secret2"));
+		assert!(!constant_time_eq(b"", b"secret"));
+	}
+
+	#[tokio::test]
+	async fn oversized_headers_are_refused() {
+		let long = format!(
+			"POST /query HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+			"a".repeat(MAX_HEAD_LEN as usize)
+		);
+		let mut reader = BufReader::new(long.as_bytes());
+		assert!(read_request(&mut reader).await.is_err());
+
+		let many = format!(
+			"POST /query HTTP/1.1\r\n{}\r\n",
+			"X-Padding: a\r\n".repeat(MAX_HEADERS + 1)
+		);
+		let mut reader = BufReader::new(many.as_bytes());
+		assert!(read_request(&mut reader).await.is_err());
+
+		let request = "POST /query HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}";
+		let mut reader = BufReader::new(request.as_bytes());
+		let request = read_request(&mut reader).await.unwrap();
+		assert_eq!(request.body, b"{}");
+	}
+
+	#[tokio::test]
+	async fn slow_requests_time_out() {
+		// A client which sends part of the request line, then stalls.
+		let (mut client, server) = duplex(64);
+		client.write_all(b"POST /qu").await.unwrap();
+
+		let mut reader = BufReader::new(server);
+		let result = read_request_within(&mut reader, Duration::from_millis(50)).await;
+		assert!(result.is_err());
+	}
+}
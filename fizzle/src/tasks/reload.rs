@@ -0,0 +1,148 @@
+use crate::config::{Config, ConfigChanges};
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+use tokio::{
+	signal::unix::{signal, SignalKind},
+	sync::watch,
+	task::JoinHandle,
+};
+
+/// Installs the SIGHUP handler and spawns a task which re-reads the
+/// configuration file each time the signal arrives.
+///
+/// Sections which can change at runtime are published on `config_tx`;
+/// changes to any other section are logged and otherwise ignored until
+/// fizzle is restarted.
+pub fn create_task(
+	path: PathBuf,
+	config_tx: watch::Sender<Arc<Config>>,
+	shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+	let hangup = signal(SignalKind::hangup())?;
+	Ok(tokio::spawn(start_task(path, config_tx, hangup, shutdown)))
+}
+
+async fn start_task(
+	path: PathBuf,
+	config_tx: watch::Sender<Arc<Config>>,
+	mut hangup: tokio::signal::unix::Signal,
+	mut shutdown_signal: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+	let mut previous = None;
+	loop {
+		tokio::select! {
+			Some(()) = hangup.recv() => {},
+			_ = shutdown_signal.changed() => break,
+		}
+
+		tracing::info!("received SIGHUP, reloading {}", path.display());
+		if let Err(error) = reload(&path, &config_tx, &mut previous) {
+			tracing::error!("failed to reload configuration, keeping current: {error:?}");
+		}
+	}
+
+	Ok(())
+}
+
+/// Re-reads the configuration file at `path`, publishing any changes to the
+/// hot sections on `config_tx`.
+///
+/// `previous` holds the file as last reloaded. Sections which require a
+/// restart are warned about when they are edited, not again on each later
+/// reload while the restart is outstanding. Returns the changes reported.
+fn reload(
+	path: &Path,
+	config_tx: &watch::Sender<Arc<Config>>,
+	previous: &mut Option<Config>,
+) -> anyhow::Result<ConfigChanges> {
+	let new = Config::load(path)?;
+	let current = Arc::clone(&config_tx.borrow());
+	let mut changes = current.changes(&new);
+	if changes.applied.is_empty() && changes.restart_required.is_empty() {
+		tracing::info!("configuration unchanged");
+	}
+
+	if let Some(previous) = previous.as_ref() {
+		let edited = previous.changes(&new).restart_required;
+		changes
+			.restart_required
+			.retain(|section| edited.contains(section));
+	}
+	if !changes.restart_required.is_empty() {
+		tracing::warn!(
+			"changes to {} require a restart to take effect",
+			changes.restart_required.join(", ")
+		);
+	}
+
+	*previous = Some(new.clone());
+	if !changes.applied.is_empty() {
+		tracing::info!("applied changes to {}", changes.applied.join(", "));
+
+		// Only publish the hot sections; everything else stays as started.
+		let Config { tariff, groups, .. } = new;
+		let mut updated = Config::clone(&current);
+		updated.tariff = tariff;
+		updated.groups = groups;
+		config_tx.send_replace(Arc::new(updated));
+	}
+
+	Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::reload;
+	use crate::config::Config;
+	use std::sync::Arc;
+	use tokio::sync::watch;
+
+	const CONFIG: &str = "mqtt:
+  host: localhost
+influxdb:
+  host: http://localhost:8086
+  bucket: energy
+  token: token
+  org: home
+  read_only: true
+tariff:
+  default_rate: 0.25
+";
+
+	#[test]
+	fn tariff_change_applies_on_reload() {
+		let path = std::env::temp_dir().join(format!("fizzle-reload-{}.yaml", std::process::id()));
+		std::fs::write(&path, CONFIG).unwrap();
+
+		let config = Arc::new(Config::load(&path).unwrap());
+		let (config_tx, config_rx) = watch::channel(config);
+		let mut previous = None;
+
+		// The tariff can change at runtime; the MQTT host cannot.
+		let updated = CONFIG
+			.replace("default_rate: 0.25", "default_rate: 0.30")
+			.replace("host: localhost", "host: broker.lan");
+		std::fs::write(&path, &updated).unwrap();
+
+		let changes = reload(&path, &config_tx, &mut previous).unwrap();
+		assert_eq!(changes.applied, vec!["tariff"]);
+		assert_eq!(changes.restart_required, vec!["mqtt"]);
+		let config = Arc::clone(&config_rx.borrow());
+		assert_eq!(config.tariff.as_ref().unwrap().default_rate, 0.30);
+		assert_eq!(config.mqtt.host, "localhost");
+
+		// The outstanding restart is not warned about again...
+		let changes = reload(&path, &config_tx, &mut previous).unwrap();
+		assert!(changes.applied.is_empty());
+		assert!(changes.restart_required.is_empty());
+
+		// ...until the section is edited once more.
+		std::fs::write(&path, updated.replace("broker.lan", "mqtt.lan")).unwrap();
+		let changes = reload(&path, &config_tx, &mut previous).unwrap();
+		assert_eq!(changes.restart_required, vec!["mqtt"]);
+
+		std::fs::remove_file(path).unwrap();
+	}
+}
@@ -1,10 +1,23 @@
-use fizzle::util::{parse_json_payload, timestamp_ms};
+use crate::config::{ImpulseMeterConfig, MeterConfig};
+use fizzle::{
+	health::HealthStats,
+	monitor::MonitorUptime,
+	smartplugs::timestamp::TimestampStrategy,
+	util::{datetime_from_millis, parse_json_payload, timestamp_ms},
+};
 use influxdb::write::buffered::Client as InfluxDbClient;
-use mqtt::{clients::tokio::Client as MqttClient, FilterBuf};
+use mqtt::clients::tokio::Client as MqttClient;
 
 use influxdb::LineBuilder;
 use serde::Deserialize;
-use std::time::Instant;
+use std::{
+	collections::{BTreeMap, VecDeque},
+	future::pending,
+	sync::Arc,
+	time::Duration,
+};
+use time::OffsetDateTime;
+use tokio::time::{sleep_until, Instant};
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Impulse {
@@ -22,46 +35,356 @@ pub struct Impulse {
 pub struct ImpulseContext {
 	pub previous_count: i64,
 	pub offset: i64,
-	pub first_impulse: Instant,
+	/// When the first impulse was received. Monitor uptime is measured from
+	/// here to each line's timestamp, so it does not depend on when the line
+	/// is written.
+	pub first_impulse: OffsetDateTime,
+	pub device: String,
+	pub impulses_per_kwh: u32,
 }
 
 impl ImpulseContext {
 	pub fn with_initial_count(count: i64) -> Self {
+		let meter = ImpulseMeterConfig::default();
 		Self {
 			previous_count: count,
 			offset: count,
-			first_impulse: Instant::now(),
+			first_impulse: OffsetDateTime::now_utc(),
+			device: meter.device,
+			impulses_per_kwh: meter.impulses_per_kwh,
 		}
 	}
 
+	/// Sets when the first impulse was received.
+	pub fn with_first_impulse(self, first_impulse: OffsetDateTime) -> Self {
+		let mut s = self;
+		s.first_impulse = first_impulse;
+		s
+	}
+
+	/// Sets the device tag and meter constant from the meter's configuration.
+	pub fn with_meter(self, meter: &ImpulseMeterConfig) -> Self {
+		let mut s = self;
+		s.device = meter.device.clone();
+		s.impulses_per_kwh = meter.impulses_per_kwh;
+		s
+	}
+
+	/// Returns the energy, in Wh, counted since the first impulse up to
+	/// `count`.
+	fn energy(&self, count: i64) -> i64 {
+		(count - self.offset + 1) * 1000 / self.impulses_per_kwh.max(1) as i64
+	}
+
 	pub fn write_line_protocol_with<'a>(
 		&'a self,
 		impulse: &'a Impulse,
 		timestamp: &'a i64,
+		monitor_uptime: MonitorUptime,
 	) -> impl FnOnce(LineBuilder) -> LineBuilder + 'a {
-		|builder| {
-			builder
+		move |builder| {
+			let builder = builder
 				.measurement("impulse")
-				.tag("device", "garage/meter")
+				.tag("device", &self.device)
 				.field("device_uptime", impulse.clock / 1_000_000)
-				.field("energy", impulse.impulse_count as i64 - self.offset + 1)
-				.field("monitor_uptime", self.first_impulse.elapsed().as_secs())
+				.field("energy", self.energy(impulse.impulse_count as i64));
+			let builder = match monitor_uptime
+				.field_at(self.first_impulse, datetime_from_millis(*timestamp))
+			{
+				Some((key, value)) => builder.field(key, value),
+				None => builder,
+			};
+			builder
 				.field("power", impulse.power.round() as i64)
 				.timestamp(*timestamp)
 				.close_line()
 		}
 	}
+
+	/// Writes an explicit zero power reading at the last known energy count,
+	/// for when the meter has gone quiet.
+	pub fn write_zero_power_with(
+		&self,
+		timestamp: i64,
+		monitor_uptime: MonitorUptime,
+	) -> impl FnOnce(LineBuilder) -> LineBuilder + '_ {
+		move |builder| {
+			let builder = builder
+				.measurement("impulse")
+				.tag("device", &self.device)
+				.field("energy", self.energy(self.previous_count));
+			let builder = match monitor_uptime
+				.field_at(self.first_impulse, datetime_from_millis(timestamp))
+			{
+				Some((key, value)) => builder.field(key, value),
+				None => builder,
+			};
+			builder
+				.field("power", 0i64)
+				.timestamp(timestamp)
+				.close_line()
+		}
+	}
+}
+
+/// Fires after a period without impulses, then again at the same interval
+/// until [`ZeroPowerHeartbeat::reset`] is called.
+///
+/// The meter reports power only when an impulse arrives, so with no
+/// consumption the last reading would otherwise stand indefinitely.
+#[derive(Debug)]
+pub struct ZeroPowerHeartbeat {
+	after: Option<Duration>,
+	deadline: Instant,
+}
+
+impl ZeroPowerHeartbeat {
+	/// Creates a heartbeat firing after `after`, or never if `None`.
+	pub fn new(after: Option<Duration>) -> Self {
+		let mut heartbeat = Self {
+			after,
+			deadline: Instant::now(),
+		};
+		heartbeat.reset();
+		heartbeat
+	}
+
+	/// Restarts the interval, typically because an impulse arrived.
+	pub fn reset(&mut self) {
+		if let Some(after) = self.after {
+			self.deadline = Instant::now() + after;
+		}
+	}
+
+	/// Returns when the heartbeat is next due, if ever.
+	pub fn deadline(&self) -> Option<Instant> {
+		self.after.map(|_| self.deadline)
+	}
+
+	/// Waits until the heartbeat is due. Cancel safe.
+	pub async fn elapsed(&mut self) {
+		match self.after {
+			Some(_) => {
+				sleep_until(self.deadline).await;
+				self.reset();
+			}
+			None => pending().await,
+		}
+	}
+}
+
+/// An impulse meter and the state of its impulse stream.
+#[derive(Debug)]
+struct ImpulseMeter {
+	config: ImpulseMeterConfig,
+	context: Option<ImpulseContext>,
+	heartbeat: ZeroPowerHeartbeat,
+	/// `(impulse_count, clock)` of recent impulses, oldest first.
+	recent: VecDeque<((u32, u64), Instant)>,
+	/// `(clock, timestamp)` pairing the meter's clock with machine time, in
+	/// milliseconds, from which impulse timestamps are derived.
+	clock_sync: Option<(u64, i64)>,
+}
+
+/// Impulse meters by the topic they publish to.
+#[derive(Debug)]
+pub struct ImpulseMeters {
+	meters: BTreeMap<String, ImpulseMeter>,
+	dedup_window: Option<Duration>,
+	device_clock_grace_ms: Option<u64>,
+}
+
+impl ImpulseMeters {
+	pub fn new(configs: Vec<ImpulseMeterConfig>, zero_power_after: Option<Duration>) -> Self {
+		let meters = configs
+			.into_iter()
+			.map(|config| {
+				let meter = ImpulseMeter {
+					config: config.clone(),
+					context: None,
+					heartbeat: ZeroPowerHeartbeat::new(zero_power_after),
+					recent: VecDeque::new(),
+					clock_sync: None,
+				};
+				(config.topic, meter)
+			})
+			.collect();
+		Self {
+			meters,
+			dedup_window: None,
+			device_clock_grace_ms: None,
+		}
+	}
+
+	/// Ignores impulses repeating the `impulse_count` and `clock` of one
+	/// received within `window`, such as retained or redelivered messages.
+	pub fn with_dedup_window(self, window: Option<Duration>) -> Self {
+		let mut s = self;
+		s.dedup_window = window;
+		s
+	}
+
+	/// Timestamps impulses by the meter's clock, anchored to machine time,
+	/// unless it drifts from machine time by more than `grace_ms`. `None`
+	/// always uses machine time.
+	pub fn with_device_clock(self, grace_ms: Option<u64>) -> Self {
+		let mut s = self;
+		s.device_clock_grace_ms = grace_ms;
+		s
+	}
+
+	/// Returns the timestamp, in milliseconds, to write for an impulse on
+	/// `topic` received at `received`.
+	///
+	/// The meter's clock is anchored to machine time at the first impulse.
+	/// It is re-anchored when the clock goes backwards, such as after the
+	/// meter restarts, or when it drifts beyond the grace and machine time is
+	/// used instead.
+	pub fn timestamp(&mut self, topic: &str, impulse: &Impulse, received: i64) -> i64 {
+		let Some(grace_ms) = self.device_clock_grace_ms else {
+			return received;
+		};
+		let Some(meter) = self.meters.get_mut(topic) else {
+			return received;
+		};
+
+		let (clock, synced_at) = match meter.clock_sync {
+			Some((clock, synced_at)) if impulse.clock >= clock => (clock, synced_at),
+			_ => {
+				meter.clock_sync = Some((impulse.clock, received));
+				return received;
+			}
+		};
+
+		let device = synced_at + ((impulse.clock - clock) / 1000) as i64;
+		let strategy = TimestampStrategy::DriftGuarded {
+			threshold_ms: grace_ms,
+		};
+		let timestamp = strategy
+			.choose(&meter.config.device, device, received)
+			.unwrap_or(received);
+		if timestamp != device {
+			meter.clock_sync = Some((impulse.clock, received));
+		}
+		timestamp
+	}
+
+	/// Returns true if the impulse on `topic` exactly repeats one received
+	/// within the deduplication window, remembering it otherwise.
+	pub fn is_duplicate(&mut self, topic: &str, impulse: &Impulse) -> bool {
+		let Some(window) = self.dedup_window else {
+			return false;
+		};
+		let Some(meter) = self.meters.get_mut(topic) else {
+			return false;
+		};
+
+		let now = Instant::now();
+		while let Some((_, received)) = meter.recent.front() {
+			if now.duration_since(*received) <= window {
+				break;
+			}
+			meter.recent.pop_front();
+		}
+
+		let key = (impulse.impulse_count, impulse.clock);
+		if meter.recent.iter().any(|(recent, _)| *recent == key) {
+			return true;
+		}
+		meter.recent.push_back((key, now));
+		false
+	}
+
+	pub fn topics(&self) -> Vec<&str> {
+		self.meters.keys().map(String::as_str).collect()
+	}
+
+	/// Records an impulse received at `received` from the meter on `topic`,
+	/// returning its updated context, or `None` if no meter publishes to
+	/// `topic`.
+	pub fn observe(
+		&mut self,
+		topic: &str,
+		impulse: &Impulse,
+		received: OffsetDateTime,
+	) -> Option<&ImpulseContext> {
+		let meter = self.meters.get_mut(topic)?;
+		meter.heartbeat.reset();
+
+		let count = impulse.impulse_count as i64;
+		let context = meter.context.get_or_insert_with(|| {
+			ImpulseContext::with_initial_count(count)
+				.with_first_impulse(received)
+				.with_meter(&meter.config)
+		});
+
+		if count < context.previous_count {
+			tracing::info!(
+				"impulse counter reset detected for '{}', adjusting offset",
+				context.device
+			);
+			context.offset = context.previous_count;
+		}
+		context.previous_count = count;
+
+		Some(context)
+	}
+
+	/// Waits until a meter has gone quiet for too long, returning its context
+	/// if it has reported before. Cancel safe.
+	pub async fn quiet(&mut self) -> Option<&ImpulseContext> {
+		let meter = self
+			.meters
+			.values_mut()
+			.filter(|meter| meter.heartbeat.deadline().is_some())
+			.min_by_key(|meter| meter.heartbeat.deadline());
+		let Some(meter) = meter else {
+			return pending().await;
+		};
+
+		meter.heartbeat.elapsed().await;
+		meter.context.as_ref()
+	}
 }
 
 pub async fn smart_meter_task(
 	mqtt_client: MqttClient,
 	influxdb_client: InfluxDbClient,
-	topic_filter: FilterBuf,
+	meter: MeterConfig,
+	monitor_uptime: MonitorUptime,
+	subscribe_buffer: usize,
+	health: Arc<HealthStats>,
 ) -> anyhow::Result<()> {
-	let mut impulse_context: Option<ImpulseContext> = None;
+	let zero_power_after = meter.zero_power_after_secs.map(Duration::from_secs);
+	let mut meters = ImpulseMeters::new(meter.meters(), zero_power_after)
+		.with_dedup_window(meter.dedup_window_secs.map(Duration::from_secs))
+		.with_device_clock(meter.device_clock_grace_ms);
+
+	let topics = meters.topics();
+	let mut impulses = mqtt_client
+		.subscribe(topics.as_slice(), subscribe_buffer)
+		.await?;
+	loop {
+		let message = tokio::select! {
+			message = impulses.recv() => match message {
+				Some(message) => message,
+				None => break,
+			},
+			context = meters.quiet() => {
+				// Nothing to report until the first impulse gives us an energy count.
+				if let Some(context) = context {
+					tracing::debug!("no impulses received recently from '{}', writing zero power", context.device);
+					influxdb_client
+						.write_with(context.write_zero_power_with(timestamp_ms(), monitor_uptime))
+						.await?;
+				}
+				continue;
+			}
+		};
+
+		let topic = message.topic.to_string();
+		health.message_received(&topic);
 
-	let mut impulses = mqtt_client.subscribe(topic_filter.as_str(), 8).await?;
-	while let Some(message) = impulses.recv().await {
 		//
 		// Parse the payload as an Impulse object.
 		let payload: Impulse = match parse_json_payload(message) {
@@ -72,22 +395,240 @@ pub async fn smart_meter_task(
 			}
 		};
 
-		let context = impulse_context.get_or_insert_with(|| {
-			ImpulseContext::with_initial_count(payload.impulse_count as i64)
-		});
-
-		if (payload.impulse_count as i64) < context.previous_count {
-			tracing::info!("impulse counter reset detected, adjusting offset");
-			context.offset = context.previous_count;
+		if meters.is_duplicate(&topic, &payload) {
+			tracing::debug!("ignoring duplicate impulse on '{topic}'");
+			continue;
 		}
 
+		let timestamp = meters.timestamp(&topic, &payload, timestamp_ms());
+		let Some(context) = meters.observe(&topic, &payload, datetime_from_millis(timestamp))
+		else {
+			tracing::warn!("received impulse on unexpected topic '{topic}'");
+			continue;
+		};
+
 		influxdb_client
-			.write_with(context.write_line_protocol_with(&payload, &timestamp_ms()))
+			.write_with(context.write_line_protocol_with(&payload, &timestamp, monitor_uptime))
 			.await?;
-
-		// Update the count
-		context.previous_count = payload.impulse_count.into();
 	}
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{Impulse, ImpulseContext, ImpulseMeters, ZeroPowerHeartbeat};
+	use crate::config::ImpulseMeterConfig;
+	use fizzle::monitor::MonitorUptime;
+	use influxdb::util::channel_buffered_client;
+	use std::time::Duration;
+	use time::{macros::datetime, OffsetDateTime};
+	use tokio::time::{timeout, Instant};
+
+	#[tokio::test]
+	async fn zero_power_after_gap() {
+		let mut heartbeat = ZeroPowerHeartbeat::new(Some(Duration::from_millis(50)));
+		let start = Instant::now();
+		heartbeat.elapsed().await;
+		assert!(start.elapsed() >= Duration::from_millis(50));
+
+		let (writer, mut rx) = channel_buffered_client(4);
+		let context = ImpulseContext::with_initial_count(100);
+		writer
+			.write_with(context.write_zero_power_with(1_696_420_800_000, MonitorUptime::Omit))
+			.await
+			.unwrap();
+
+		let (buffer, _) = rx.recv().await.unwrap();
+		assert_eq!(
+			String::from_utf8(buffer.to_vec()).unwrap(),
+			"impulse,device=garage/meter energy=1i,power=0i 1696420800000\n"
+		);
+	}
+
+	#[tokio::test]
+	async fn monitor_uptime_is_measured_from_first_impulse() {
+		let mut meters = ImpulseMeters::new(vec![ImpulseMeterConfig::default()], None);
+		let topic = ImpulseMeterConfig::default().topic;
+		let impulse = |impulse_count| Impulse {
+			impulse_count,
+			clock: 0,
+			interval: 0,
+			power: 0.0,
+		};
+
+		let (writer, mut rx) = channel_buffered_client(8);
+		let first = datetime!(2023-10-04 12:00 UTC);
+		for (count, received) in [(100, first), (101, datetime!(2023-10-04 12:01:30 UTC))] {
+			let impulse = impulse(count);
+			let context = meters.observe(&topic, &impulse, received).unwrap();
+			assert_eq!(context.first_impulse, first);
+
+			let timestamp = (received.unix_timestamp_nanos() / 1_000_000) as i64;
+			writer
+				.write_with(context.write_line_protocol_with(
+					&impulse,
+					&timestamp,
+					MonitorUptime::Uptime,
+				))
+				.await
+				.unwrap();
+		}
+
+		let mut lines = Vec::new();
+		while let Ok((buffer, _)) = rx.try_recv() {
+			lines.push(String::from_utf8(buffer.to_vec()).unwrap());
+		}
+		assert_eq!(
+			lines,
+			vec![
+				"impulse,device=garage/meter device_uptime=0u,energy=1i,monitor_uptime=0u,power=0i 1696420800000\n",
+				"impulse,device=garage/meter device_uptime=0u,energy=2i,monitor_uptime=90u,power=0i 1696420890000\n",
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn disabled_heartbeat_never_fires() {
+		let mut heartbeat = ZeroPowerHeartbeat::new(None);
+		assert!(timeout(Duration::from_millis(50), heartbeat.elapsed())
+			.await
+			.is_err());
+	}
+
+	#[tokio::test]
+	async fn meters_are_independent() {
+		let meter = |topic: &str, device: &str| ImpulseMeterConfig {
+			topic: String::from(topic),
+			device: String::from(device),
+			impulses_per_kwh: 1000,
+		};
+		let mut meters = ImpulseMeters::new(
+			vec![
+				meter("meter-reader/main", "garage/meter"),
+				meter("meter-reader/solar", "roof/solar"),
+			],
+			None,
+		);
+		let impulse = |impulse_count| Impulse {
+			impulse_count,
+			clock: 0,
+			interval: 0,
+			power: 0.0,
+		};
+
+		let (writer, mut rx) = channel_buffered_client(8);
+		for (topic, count) in [
+			("meter-reader/main", 100),
+			("meter-reader/solar", 5000),
+			("meter-reader/main", 150),
+			("meter-reader/solar", 5010),
+		] {
+			let impulse = impulse(count);
+			let context = meters
+				.observe(topic, &impulse, OffsetDateTime::UNIX_EPOCH)
+				.unwrap();
+			writer
+				.write_with(context.write_line_protocol_with(&impulse, &0, MonitorUptime::Omit))
+				.await
+				.unwrap();
+		}
+		assert!(meters
+			.observe(
+				"meter-reader/other",
+				&impulse(1),
+				OffsetDateTime::UNIX_EPOCH
+			)
+			.is_none());
+
+		let mut lines = Vec::new();
+		while let Ok((buffer, _)) = rx.try_recv() {
+			lines.push(String::from_utf8(buffer.to_vec()).unwrap());
+		}
+		assert_eq!(
+			lines,
+			vec![
+				"impulse,device=garage/meter device_uptime=0u,energy=1i,power=0i 0\n",
+				"impulse,device=roof/solar device_uptime=0u,energy=1i,power=0i 0\n",
+				"impulse,device=garage/meter device_uptime=0u,energy=51i,power=0i 0\n",
+				"impulse,device=roof/solar device_uptime=0u,energy=11i,power=0i 0\n",
+			]
+		);
+	}
+
+	#[test]
+	fn impulse_timestamps_follow_device_clock() {
+		let mut meters = ImpulseMeters::new(vec![ImpulseMeterConfig::default()], None)
+			.with_device_clock(Some(1_000));
+		let topic = ImpulseMeterConfig::default().topic;
+		let impulse = |clock| Impulse {
+			impulse_count: 100,
+			clock,
+			interval: 0,
+			power: 0.0,
+		};
+
+		let synced = 1_696_420_800_000;
+		assert_eq!(
+			meters.timestamp(&topic, &impulse(5_000_000), synced),
+			synced
+		);
+
+		// Received 400ms late, within the grace.
+		assert_eq!(
+			meters.timestamp(&topic, &impulse(65_000_000), synced + 60_400),
+			synced + 60_000
+		);
+
+		// Drifted by 5s: machine time is used, and the clock re-anchored.
+		assert_eq!(
+			meters.timestamp(&topic, &impulse(125_000_000), synced + 125_000),
+			synced + 125_000
+		);
+		assert_eq!(
+			meters.timestamp(&topic, &impulse(126_000_000), synced + 126_300),
+			synced + 126_000
+		);
+
+		// Without a grace, machine time is always used.
+		let mut meters = ImpulseMeters::new(vec![ImpulseMeterConfig::default()], None);
+		meters.timestamp(&topic, &impulse(5_000_000), synced);
+		assert_eq!(
+			meters.timestamp(&topic, &impulse(65_000_000), synced + 60_400),
+			synced + 60_400
+		);
+	}
+
+	#[tokio::test]
+	async fn duplicate_impulse_is_written_once() {
+		let mut meters = ImpulseMeters::new(vec![ImpulseMeterConfig::default()], None)
+			.with_dedup_window(Some(Duration::from_secs(60)));
+		let topic = ImpulseMeterConfig::default().topic;
+		let impulse = Impulse {
+			impulse_count: 100,
+			clock: 5_000_000,
+			interval: 0,
+			power: 0.0,
+		};
+
+		let (writer, mut rx) = channel_buffered_client(8);
+		for _ in 0..2 {
+			if meters.is_duplicate(&topic, &impulse) {
+				continue;
+			}
+			let context = meters
+				.observe(&topic, &impulse, OffsetDateTime::UNIX_EPOCH)
+				.unwrap();
+			writer
+				.write_with(context.write_line_protocol_with(&impulse, &0, MonitorUptime::Omit))
+				.await
+				.unwrap();
+		}
+
+		let mut writes = 0;
+		while rx.try_recv().is_ok() {
+			writes += 1;
+		}
+		assert_eq!(writes, 1);
+	}
+}
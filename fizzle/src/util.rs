@@ -1,12 +1,19 @@
 use bytes::{Buf, Bytes};
 use mqtt::clients::tokio::Message;
-use time::OffsetDateTime;
+use time::{OffsetDateTime, UtcOffset};
 
 pub fn parse_json_payload<T: serde::de::DeserializeOwned>(
 	message: Message,
 ) -> serde_json::Result<T> {
-	let topic = message.topic;
-	let reader = message.payload.reader();
+	let Message { topic, payload, .. } = message;
+	parse_json_bytes(topic.as_str(), payload)
+}
+
+pub fn parse_json_bytes<T: serde::de::DeserializeOwned>(
+	topic: &str,
+	payload: Bytes,
+) -> serde_json::Result<T> {
+	let reader = payload.reader();
 	match serde_json::from_reader(reader) {
 		Ok(v) => Ok(v),
 		Err(error) => {
@@ -16,11 +23,24 @@ pub fn parse_json_payload<T: serde::de::DeserializeOwned>(
 	}
 }
 
+/// Creates the span covering the handling of one MQTT message, from receipt
+/// through parsing to the resulting writes. Handlers record the `device`
+/// field once they have resolved it from the topic.
+pub fn message_span(topic: &str) -> tracing::Span {
+	tracing::info_span!("mqtt_message", topic, device = tracing::field::Empty)
+}
+
 #[inline]
 pub fn timestamp_ms() -> i64 {
 	millis_from_datetime(OffsetDateTime::now_utc())
 }
 
+/// Returns the local UTC offset in effect at `ts`, falling back to UTC when
+/// it cannot be determined.
+pub fn local_offset_at(ts: OffsetDateTime) -> UtcOffset {
+	UtcOffset::local_offset_at(ts).unwrap_or(UtcOffset::UTC)
+}
+
 #[inline]
 pub fn millis_from_datetime(dt: OffsetDateTime) -> i64 {
 	let timestamp = dt.unix_timestamp_nanos() / 1_000_000;
@@ -29,6 +49,12 @@ pub fn millis_from_datetime(dt: OffsetDateTime) -> i64 {
 		.expect("timestamp in milliseconds shouldn't overflow an i64")
 }
 
+#[inline]
+pub fn datetime_from_millis(timestamp: i64) -> OffsetDateTime {
+	OffsetDateTime::from_unix_timestamp_nanos(timestamp as i128 * 1_000_000)
+		.expect("timestamp in milliseconds should be in range")
+}
+
 pub fn bytes_to_string(bytes: Bytes) -> Result<String, std::io::Error> {
 	use std::io::Read;
 
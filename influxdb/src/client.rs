@@ -1,8 +1,13 @@
-use crate::{query::QueryClient, write::builder::Builder};
+use crate::{
+	query::{QueryClient, DEFAULT_QUERY_TIMEOUT},
+	write::builder::Builder,
+};
 use reqwest::{
 	header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE},
-	ClientBuilder, IntoUrl,
+	Certificate, IntoUrl, StatusCode,
 };
+use serde::Deserialize;
+use std::{path::PathBuf, time::Duration};
 use url::Url;
 
 #[derive(Debug)]
@@ -11,20 +16,45 @@ pub struct Client {
 	host: Url,
 }
 
-impl Client {
-	/// Creates a new InfluxDB client.
+/// Builder for [`Client`] instances which need non-default TLS settings.
+pub struct ClientBuilder {
+	host: reqwest::Result<Url>,
+	token: String,
+	ca_certificate: Option<PathBuf>,
+	accept_invalid_certs: bool,
+}
+
+impl ClientBuilder {
+	/// Trust the PEM-encoded CA certificate at `path` in addition to the
+	/// system roots. Useful for InfluxDB instances with self-signed
+	/// certificates.
+	pub fn ca_certificate(self, path: impl Into<PathBuf>) -> Self {
+		let mut s = self;
+		s.ca_certificate = Some(path.into());
+		s
+	}
+
+	/// Disable TLS certificate verification entirely.
 	///
-	/// # Arguments
-	/// * `host` - The URL of the InfluxDB host.
-	/// * `token` - The token to use for authentication.
+	/// # Warning
+	/// This makes the connection vulnerable to man-in-the-middle attacks and
+	/// leaks the authorization token to anyone able to intercept traffic. Only
+	/// use this for lab setups.
+	pub fn danger_accept_invalid_certs(self, accept: bool) -> Self {
+		let mut s = self;
+		s.accept_invalid_certs = accept;
+		s
+	}
+
+	/// Builds the client.
 	///
 	/// # Errors
-	/// Returns an error if the URL is invalid, or the token does not serialize
-	/// to a valid header value.
-	///
-	pub fn new(host: impl IntoUrl, token: impl AsRef<str>) -> anyhow::Result<Self> {
-		let host = host.into_url()?;
-		let token = token.as_ref();
+	/// Returns an error if the URL is invalid, the token does not serialize
+	/// to a valid header value, or the CA certificate cannot be read or
+	/// parsed.
+	pub fn build(self) -> anyhow::Result<Client> {
+		let host = self.host?;
+		let token = self.token;
 
 		// Create the default header set.
 		//
@@ -41,12 +71,57 @@ impl Client {
 
 		// Build the HTTP client. This will be reused for all requests.
 		//
-		let client = ClientBuilder::new()
+		let mut builder = reqwest::ClientBuilder::new()
 			.gzip(true)
-			.default_headers(default_headers)
-			.build()?;
+			.default_headers(default_headers);
+
+		if let Some(path) = self.ca_certificate {
+			let pem = std::fs::read(&path).map_err(|error| {
+				anyhow::anyhow!(
+					"failed to read CA certificate '{}': {error}",
+					path.display()
+				)
+			})?;
+			builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+		}
 
-		Ok(Self { host, client })
+		if self.accept_invalid_certs {
+			tracing::warn!(
+				"TLS certificate verification is DISABLED for InfluxDB host '{host}'; \
+				 the connection and token are not protected"
+			);
+			builder = builder.danger_accept_invalid_certs(true);
+		}
+
+		let client = builder.build()?;
+
+		Ok(Client { host, client })
+	}
+}
+
+impl Client {
+	/// Creates a new InfluxDB client.
+	///
+	/// # Arguments
+	/// * `host` - The URL of the InfluxDB host.
+	/// * `token` - The token to use for authentication.
+	///
+	/// # Errors
+	/// Returns an error if the URL is invalid, or the token does not serialize
+	/// to a valid header value.
+	///
+	pub fn new(host: impl IntoUrl, token: impl AsRef<str>) -> anyhow::Result<Self> {
+		Self::builder(host, token).build()
+	}
+
+	/// Creates a builder for a client with custom TLS settings.
+	pub fn builder(host: impl IntoUrl, token: impl AsRef<str>) -> ClientBuilder {
+		ClientBuilder {
+			host: host.into_url(),
+			token: token.as_ref().to_string(),
+			ca_certificate: None,
+			accept_invalid_certs: false,
+		}
 	}
 
 	/// Creates a write client builder for the given bucket.
@@ -61,7 +136,112 @@ impl Client {
 		QueryClient {
 			client: self.client.clone(),
 			url,
+			timeout: DEFAULT_QUERY_TIMEOUT,
+		}
+	}
+
+	/// Checks that the InfluxDB host is reachable, returning the version it
+	/// reports, if any.
+	///
+	/// # Errors
+	/// Returns an error if the request fails or the host does not respond
+	/// with a success status.
+	pub async fn ping(&self) -> anyhow::Result<Option<String>> {
+		let mut url = self.host.clone();
+		url.set_path("/ping");
+
+		let response = self.client.get(url).send().await?.error_for_status()?;
+		let version = response
+			.headers()
+			.get("x-influxdb-version")
+			.and_then(|value| value.to_str().ok())
+			.map(String::from);
+
+		Ok(version)
+	}
+
+	/// Returns true if the organization has a bucket called `name`.
+	///
+	/// # Errors
+	/// Returns an error if the request fails or the response cannot be
+	/// parsed.
+	pub async fn bucket_exists(&self, org: &str, name: &str) -> anyhow::Result<bool> {
+		#[derive(Deserialize)]
+		struct Buckets {
+			buckets: Vec<serde_json::Value>,
 		}
+
+		let mut url = self.host.clone();
+		url.set_path("/api/v2/buckets");
+		url.query_pairs_mut()
+			.append_pair("org", org)
+			.append_pair("name", name);
+
+		let response = self.client.get(url).send().await?;
+		if response.status() == StatusCode::NOT_FOUND {
+			return Ok(false);
+		}
+		let body = response.error_for_status()?.bytes().await?;
+		let Buckets { buckets } = serde_json::from_slice(&body)?;
+		Ok(!buckets.is_empty())
+	}
+
+	/// Returns the retention period of the organization's bucket called
+	/// `name`, or `None` if it keeps data forever.
+	///
+	/// # Errors
+	/// Returns an error if the request fails, the response cannot be parsed
+	/// or there is no such bucket.
+	pub async fn bucket_retention(
+		&self,
+		org: &str,
+		name: &str,
+	) -> anyhow::Result<Option<Duration>> {
+		#[derive(Deserialize)]
+		#[serde(rename_all = "camelCase")]
+		struct RetentionRule {
+			every_seconds: u64,
+		}
+
+		#[derive(Deserialize)]
+		#[serde(rename_all = "camelCase")]
+		struct Bucket {
+			#[serde(default)]
+			retention_rules: Vec<RetentionRule>,
+		}
+
+		#[derive(Deserialize)]
+		struct Buckets {
+			buckets: Vec<Bucket>,
+		}
+
+		let mut url = self.host.clone();
+		url.set_path("/api/v2/buckets");
+		url.query_pairs_mut()
+			.append_pair("org", org)
+			.append_pair("name", name);
+
+		let body = self
+			.client
+			.get(url)
+			.send()
+			.await?
+			.error_for_status()?
+			.bytes()
+			.await?;
+		let Buckets { buckets } = serde_json::from_slice(&body)?;
+		let Some(bucket) = buckets.into_iter().next() else {
+			anyhow::bail!("InfluxDB bucket '{name}' does not exist");
+		};
+
+		// A period of zero means the data never expires.
+		Ok(bucket
+			.retention_rules
+			.iter()
+			.map(|rule| rule.every_seconds)
+			.filter(|&seconds| seconds > 0)
+			.min()
+			.map(Duration::from_secs))
 	}
 
 	/// Returns the URL of the InfluxDB host.
@@ -69,3 +249,96 @@ impl Client {
 		&self.host
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::Client;
+	use crate::{
+		mock::{self, MockResponse},
+		Precision,
+	};
+	use std::time::Duration;
+
+	#[test]
+	fn missing_ca_certificate_errors() {
+		let result = Client::builder("http://localhost:8086", "token")
+			.ca_certificate("/nonexistent/ca.pem")
+			.build();
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn endpoint_urls() {
+		let client = Client::new("http://localhost:8086", "token").unwrap();
+
+		let writer = client
+			.write_to_bucket("fizzle")
+			.org("home")
+			.precision(Precision::Milliseconds)
+			.build();
+		assert_eq!(
+			writer.write_url().as_str(),
+			"http://localhost:8086/api/v2/write?bucket=fizzle&precision=ms&org=home"
+		);
+
+		let query_client = client.query_client().org("home");
+		assert_eq!(
+			query_client.url().as_str(),
+			"http://localhost:8086/api/v2/query?org=home"
+		);
+	}
+
+	#[test]
+	fn insecure_builder_succeeds() {
+		let client = Client::builder("http://localhost:8086", "token")
+			.danger_accept_invalid_certs(true)
+			.build()
+			.unwrap();
+		assert_eq!(client.host().as_str(), "http://localhost:8086/");
+	}
+
+	#[tokio::test]
+	async fn bucket_retention_period() {
+		let (url, server) = mock::serve(vec![
+			MockResponse::new(
+				200,
+				r#"{"buckets":[{"id":"1","name":"fizzle","retentionRules":[{"type":"expire","everySeconds":2592000,"shardGroupDurationSeconds":86400}]}]}"#,
+			),
+			MockResponse::new(
+				200,
+				r#"{"buckets":[{"id":"2","name":"archive","retentionRules":[{"type":"expire","everySeconds":0}]}]}"#,
+			),
+			MockResponse::new(200, r#"{"buckets":[]}"#),
+		])
+		.await;
+
+		let client = Client::new(url, "token").unwrap();
+		assert_eq!(
+			client.bucket_retention("home", "fizzle").await.unwrap(),
+			Some(Duration::from_secs(30 * 24 * 60 * 60))
+		);
+		assert_eq!(
+			client.bucket_retention("home", "archive").await.unwrap(),
+			None
+		);
+		assert!(client.bucket_retention("home", "missing").await.is_err());
+
+		let requests = server.await.unwrap();
+		assert_eq!(requests[0].path, "/api/v2/buckets?org=home&name=fizzle");
+	}
+
+	#[tokio::test]
+	async fn ping_returns_version() {
+		let (url, server) = mock::serve(vec![
+			MockResponse::new(204, "").header("X-Influxdb-Version", "v2.7.1")
+		])
+		.await;
+
+		let client = Client::new(url, "token").unwrap();
+		assert_eq!(client.ping().await.unwrap().as_deref(), Some("v2.7.1"));
+
+		let requests = server.await.unwrap();
+		assert_eq!(requests[0].method, "GET");
+		assert_eq!(requests[0].path, "/ping");
+	}
+}
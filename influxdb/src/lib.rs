@@ -1,13 +1,20 @@
 mod client;
+#[cfg(test)]
+mod mock;
 pub mod query;
 pub mod util;
 pub mod write;
 
 pub use write::precision::Precision;
 
-pub use client::Client;
+pub use client::{Client, ClientBuilder};
 
 pub use write::buffered;
+pub use write::files::RotatingFiles;
 pub use write::immediate;
+pub use write::multi::{AcceptancePolicy, MultiSink};
+pub use write::router::MeasurementRouter;
+pub use write::sink::LineProtocolSink;
 pub use write::LineBuilder;
+pub use write::OptionalField;
 pub use write::Status;
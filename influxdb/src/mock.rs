@@ -0,0 +1,130 @@
+//! A minimal HTTP server for exercising clients against canned responses.
+
+use std::time::Duration;
+use tokio::{
+	io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+	net::TcpListener,
+	task::JoinHandle,
+};
+use url::Url;
+
+#[derive(Clone, Debug)]
+pub(crate) struct MockResponse {
+	status: u16,
+	headers: Vec<(&'static str, String)>,
+	body: String,
+	delay: Option<Duration>,
+}
+
+impl MockResponse {
+	pub(crate) fn new(status: u16, body: impl Into<String>) -> Self {
+		Self {
+			status,
+			headers: Vec::new(),
+			body: body.into(),
+			delay: None,
+		}
+	}
+
+	pub(crate) fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+		self.headers.push((name, value.into()));
+		self
+	}
+
+	/// Waits before responding, once the request has been read.
+	pub(crate) fn delay(mut self, delay: Duration) -> Self {
+		self.delay = Some(delay);
+		self
+	}
+}
+
+#[derive(Debug)]
+pub(crate) struct RecordedRequest {
+	pub(crate) method: String,
+	pub(crate) path: String,
+	pub(crate) headers: Vec<(String, String)>,
+	pub(crate) body: Vec<u8>,
+}
+
+impl RecordedRequest {
+	pub(crate) fn header(&self, name: &str) -> Option<&str> {
+		self.headers
+			.iter()
+			.find(|(key, _)| key.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value.as_str())
+	}
+}
+
+/// Serves each response, in order, to one connection apiece. The handle
+/// resolves to the requests received once every response has been sent.
+pub(crate) async fn serve(responses: Vec<MockResponse>) -> (Url, JoinHandle<Vec<RecordedRequest>>) {
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+
+	let handle = tokio::spawn(async move {
+		let mut requests = Vec::new();
+		for response in responses {
+			let (stream, _) = listener.accept().await.unwrap();
+			let mut reader = BufReader::new(stream);
+
+			let mut request_line = String::new();
+			reader.read_line(&mut request_line).await.unwrap();
+			let mut parts = request_line.split_whitespace();
+			let method = parts.next().unwrap_or_default().to_string();
+			let path = parts.next().unwrap_or_default().to_string();
+
+			let mut headers = Vec::new();
+			loop {
+				let mut line = String::new();
+				if reader.read_line(&mut line).await.unwrap() == 0 {
+					break;
+				}
+				let line = line.trim_end();
+				if line.is_empty() {
+					break;
+				}
+				if let Some((name, value)) = line.split_once(':') {
+					headers.push((name.trim().to_string(), value.trim().to_string()));
+				}
+			}
+
+			let content_length = headers
+				.iter()
+				.find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+				.map(|(_, value)| value.parse().unwrap())
+				.unwrap_or(0);
+			let mut body = vec![0; content_length];
+			reader.read_exact(&mut body).await.unwrap();
+
+			if let Some(delay) = response.delay {
+				tokio::time::sleep(delay).await;
+			}
+
+			let mut stream = reader.into_inner();
+			let mut head = format!(
+				"HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+				response.status,
+				response.body.len()
+			);
+			for (name, value) in &response.headers {
+				head.push_str(&format!("{name}: {value}\r\n"));
+			}
+			head.push_str("\r\n");
+
+			// The client may have given up waiting; that is fine.
+			let _ = stream.write_all(head.as_bytes()).await;
+			let _ = stream.write_all(response.body.as_bytes()).await;
+			let _ = stream.shutdown().await;
+
+			requests.push(RecordedRequest {
+				method,
+				path,
+				headers,
+				body,
+			});
+		}
+		requests
+	});
+
+	(url, handle)
+}
@@ -0,0 +1,297 @@
+//! A builder for the Flux queries fizzle runs, so they are composed rather
+//! than edited as strings.
+//!
+//! ```ignore
+//! let flux = Flux::from_bucket(Expr::param("bucket"))
+//!     .range(Expr::time_param("start"), Expr::time_param("stop"))
+//!     .filter_measurement("impulse")
+//!     .filter_tag("device", Expr::param("device"))
+//!     .aggregate_window(Duration::from_secs(60), Aggregate::Last, false)
+//!     .yield_as("mean")
+//!     .build();
+//! ```
+
+use std::{fmt, time::Duration};
+
+/// A Flux expression used as an argument, such as a string literal or a
+/// reference to a query parameter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expr(String);
+
+impl Expr {
+	/// A string literal, escaped as Flux requires.
+	pub fn string(value: &str) -> Self {
+		let mut escaped = String::with_capacity(value.len() + 2);
+		escaped.push('"');
+		let mut chars = value.chars().peekable();
+		while let Some(c) = chars.next() {
+			match c {
+				'"' | '\\' => {
+					escaped.push('\\');
+					escaped.push(c);
+				}
+				// `${` would otherwise start string interpolation.
+				'$' if chars.peek() == Some(&'{') => escaped.push_str("\\$"),
+				_ => escaped.push(c),
+			}
+		}
+		escaped.push('"');
+		Self(escaped)
+	}
+
+	/// A reference to the query parameter `name`, as `params.{name}`.
+	pub fn param(name: &str) -> Self {
+		Self(format!("params.{name}"))
+	}
+
+	/// A query parameter holding an RFC3339 time, converted with `time()`.
+	pub fn time_param(name: &str) -> Self {
+		Self(format!("time(v: params.{name})"))
+	}
+
+	/// A duration literal, such as `1m`, in the largest unit which
+	/// represents it exactly.
+	pub fn duration(duration: Duration) -> Self {
+		Self(format_duration(duration))
+	}
+
+	/// A negative duration, such as `-1h`, for a range relative to now.
+	pub fn ago(duration: Duration) -> Self {
+		Self(format!("-{}", format_duration(duration)))
+	}
+}
+
+impl From<&str> for Expr {
+	fn from(value: &str) -> Self {
+		Self::string(value)
+	}
+}
+
+impl fmt::Display for Expr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+fn format_duration(duration: Duration) -> String {
+	let millis = duration.as_millis();
+	for (unit, len) in [
+		("d", 86_400_000),
+		("h", 3_600_000),
+		("m", 60_000),
+		("s", 1_000),
+	] {
+		if millis > 0 && millis % len == 0 {
+			return format!("{}{unit}", millis / len);
+		}
+	}
+	format!("{millis}ms")
+}
+
+/// The function `aggregateWindow` applies to each window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregate {
+	Count,
+	First,
+	Last,
+	Max,
+	Mean,
+	Min,
+	Sum,
+}
+
+impl Aggregate {
+	fn as_str(&self) -> &'static str {
+		match self {
+			Self::Count => "count",
+			Self::First => "first",
+			Self::Last => "last",
+			Self::Max => "max",
+			Self::Mean => "mean",
+			Self::Min => "min",
+			Self::Sum => "sum",
+		}
+	}
+}
+
+/// A Flux query: a `from()` source followed by piped stages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Flux {
+	source: String,
+	stages: Vec<String>,
+}
+
+impl Flux {
+	/// Starts a query reading from `bucket`.
+	pub fn from_bucket(bucket: impl Into<Expr>) -> Self {
+		Self {
+			source: format!("from(bucket: {})", bucket.into()),
+			stages: Vec::new(),
+		}
+	}
+
+	fn pipe(self, stage: String) -> Self {
+		let mut s = self;
+		s.stages.push(stage);
+		s
+	}
+
+	/// Keeps rows with times in `[start, stop)`.
+	pub fn range(self, start: impl Into<Expr>, stop: impl Into<Expr>) -> Self {
+		self.pipe(format!(
+			"range(start: {}, stop: {})",
+			start.into(),
+			stop.into()
+		))
+	}
+
+	/// Keeps rows with times from `start` onwards.
+	pub fn range_from(self, start: impl Into<Expr>) -> Self {
+		self.pipe(format!("range(start: {})", start.into()))
+	}
+
+	/// Keeps rows whose `column` equals `value`.
+	pub fn filter(self, column: &str, value: impl Into<Expr>) -> Self {
+		self.pipe(format!(
+			"filter(fn: (r) => r[{}] == {})",
+			Expr::string(column),
+			value.into()
+		))
+	}
+
+	/// Keeps rows from `measurement`.
+	pub fn filter_measurement(self, measurement: impl Into<Expr>) -> Self {
+		self.filter("_measurement", measurement)
+	}
+
+	/// Keeps rows for `field`.
+	pub fn filter_field(self, field: impl Into<Expr>) -> Self {
+		self.filter("_field", field)
+	}
+
+	/// Keeps rows whose tag `key` equals `value`.
+	pub fn filter_tag(self, key: &str, value: impl Into<Expr>) -> Self {
+		self.filter(key, value)
+	}
+
+	/// Replaces each value with its increase since the first row, ignoring
+	/// counter resets.
+	pub fn increase(self) -> Self {
+		self.pipe(String::from("increase()"))
+	}
+
+	/// Regroups rows into one table per distinct combination of `columns`.
+	pub fn group(self, columns: &[&str]) -> Self {
+		self.pipe(format!("group(columns: {})", string_array(columns)))
+	}
+
+	/// Sorts the rows of each table by `columns`, ascending.
+	pub fn sort(self, columns: &[&str]) -> Self {
+		self.pipe(format!("sort(columns: {})", string_array(columns)))
+	}
+
+	/// Keeps the last row of each table.
+	pub fn last(self) -> Self {
+		self.pipe(String::from("last()"))
+	}
+
+	/// Combines the rows in each window of length `every` with `aggregate`.
+	/// Empty windows are only output if `create_empty` is set.
+	pub fn aggregate_window(
+		self,
+		every: Duration,
+		aggregate: Aggregate,
+		create_empty: bool,
+	) -> Self {
+		self.pipe(format!(
+			"aggregateWindow(every: {}, fn: {}, createEmpty: {create_empty})",
+			format_duration(every),
+			aggregate.as_str()
+		))
+	}
+
+	/// Names the query's result.
+	pub fn yield_as(self, name: &str) -> Self {
+		self.pipe(format!("yield(name: {})", Expr::string(name)))
+	}
+
+	/// Returns the Flux source.
+	pub fn build(&self) -> String {
+		self.to_string()
+	}
+}
+
+/// Formats `values` as a Flux array of string literals.
+fn string_array(values: &[&str]) -> String {
+	let values: Vec<_> = values
+		.iter()
+		.map(|value| Expr::string(value).to_string())
+		.collect();
+	format!("[{}]", values.join(", "))
+}
+
+impl fmt::Display for Flux {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.source)?;
+		for stage in &self.stages {
+			write!(f, "\n  |> {stage}")?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Aggregate, Expr, Flux};
+	use std::time::Duration;
+
+	#[test]
+	fn matches_hand_written_query() {
+		let expected = r#"from(bucket: params.bucket)
+  |> range(start: time(v: params.dayStart), stop: time(v: params.dayStop))
+  |> filter(fn: (r) => r["_measurement"] == "impulse")
+  |> filter(fn: (r) => r["_field"] == "energy")
+  |> filter(fn: (r) => r["device"] == params.device)
+  |> increase()
+  |> aggregateWindow(every: 1m, fn: last, createEmpty: false)
+  |> yield(name: "mean")"#;
+
+		let flux = Flux::from_bucket(Expr::param("bucket"))
+			.range(Expr::time_param("dayStart"), Expr::time_param("dayStop"))
+			.filter_measurement("impulse")
+			.filter_field("energy")
+			.filter_tag("device", Expr::param("device"))
+			.increase()
+			.aggregate_window(Duration::from_secs(60), Aggregate::Last, false)
+			.yield_as("mean");
+		assert_eq!(flux.build(), expected);
+	}
+
+	#[test]
+	fn regrouped_tables_are_sorted() {
+		let flux = Flux::from_bucket("fizzle")
+			.group(&["device", "relay"])
+			.sort(&["_time"])
+			.last();
+		assert_eq!(
+			flux.build(),
+			r#"from(bucket: "fizzle")
+  |> group(columns: ["device", "relay"])
+  |> sort(columns: ["_time"])
+  |> last()"#
+		);
+	}
+
+	#[test]
+	fn literals_are_escaped() {
+		let flux = Flux::from_bucket("fizzle")
+			.range_from(Expr::ago(Duration::from_secs(90)))
+			.filter_tag("device", r#"say "hi" ${x}\"#);
+		assert_eq!(
+			flux.build(),
+			r#"from(bucket: "fizzle")
+  |> range(start: -90s)
+  |> filter(fn: (r) => r["device"] == "say \"hi\" \${x}\\")"#
+		);
+	}
+}
@@ -0,0 +1,60 @@
+//! Deserializers tolerant of how older data was written.
+
+use serde::de::{self, Deserializer, Unexpected, Visitor};
+use std::fmt;
+
+/// Deserializes an integer which may have been stored as a float with no
+/// fractional part, e.g. `123.0`.
+///
+/// ```ignore
+/// #[derive(Deserialize)]
+/// struct Record {
+///     #[serde(deserialize_with = "influxdb::query::lenient::integer")]
+///     value: u32,
+/// }
+/// ```
+pub fn integer<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+	D: Deserializer<'de>,
+	T: TryFrom<i128>,
+{
+	let value = deserializer.deserialize_any(IntegerVisitor)?;
+	T::try_from(value).map_err(|_| de::Error::custom(format!("integer {value} is out of range")))
+}
+
+struct IntegerVisitor;
+
+impl<'de> Visitor<'de> for IntegerVisitor {
+	type Value = i128;
+
+	fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("an integer, or a float with no fractional part")
+	}
+
+	fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+		Ok(v.into())
+	}
+
+	fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+		Ok(v.into())
+	}
+
+	fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+		if v.is_finite() && v.fract() == 0.0 {
+			Ok(v as i128)
+		} else {
+			Err(E::invalid_value(Unexpected::Float(v), &self))
+		}
+	}
+
+	fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+		if let Ok(value) = v.trim().parse::<i128>() {
+			return Ok(value);
+		}
+
+		match v.trim().parse::<f64>() {
+			Ok(value) => self.visit_f64(value),
+			Err(_) => Err(E::invalid_value(Unexpected::Str(v), &self)),
+		}
+	}
+}
@@ -1,11 +1,20 @@
-use std::{borrow::Cow, collections::BTreeMap, str::from_utf8};
+pub mod flux;
+pub mod lenient;
+
+pub use flux::Flux;
+
+use std::{collections::BTreeMap, fmt, time::Duration};
 
 use reqwest::{
 	header::{HeaderValue, ACCEPT, CONTENT_TYPE},
-	Method, Response, Url,
+	Method, Response, StatusCode, Url,
 };
-use serde::Serialize;
-use time::OffsetDateTime;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime, UtcOffset};
+
+/// How long a query may take, including reading the response body, unless
+/// overridden with [`QueryClient::timeout`].
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Serialize)]
 struct QueryPayload<'a> {
@@ -20,8 +29,54 @@ struct QueryPayload<'a> {
 
 	#[serde(borrow, rename = "type")]
 	ty: &'a str,
-	// #[serde(borrow)]
-	// params: BTreeMap<&'a str, &'a str>,
+
+	#[serde(skip_serializing_if = "BTreeMap::is_empty")]
+	params: BTreeMap<&'a str, serde_json::Value>,
+}
+
+/// A value bound to a `params.*` reference in a Flux query.
+///
+/// Times are passed as RFC3339 strings, so the query converts them with
+/// `time(v: params.start)`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryParam {
+	String(String),
+	Integer(i64),
+	Time(OffsetDateTime),
+}
+
+impl QueryParam {
+	fn into_value(self) -> anyhow::Result<serde_json::Value> {
+		Ok(match self {
+			Self::String(value) => value.into(),
+			Self::Integer(value) => value.into(),
+			Self::Time(value) => value.to_offset(UtcOffset::UTC).format(&Rfc3339)?.into(),
+		})
+	}
+}
+
+impl From<&str> for QueryParam {
+	fn from(value: &str) -> Self {
+		Self::String(value.to_string())
+	}
+}
+
+impl From<String> for QueryParam {
+	fn from(value: String) -> Self {
+		Self::String(value)
+	}
+}
+
+impl From<i64> for QueryParam {
+	fn from(value: i64) -> Self {
+		Self::Integer(value)
+	}
+}
+
+impl From<OffsetDateTime> for QueryParam {
+	fn from(value: OffsetDateTime) -> Self {
+		Self::Time(value)
+	}
 }
 
 #[derive(Serialize)]
@@ -36,6 +91,7 @@ struct Dialect<'a> {
 pub struct QueryClient {
 	pub(crate) client: reqwest::Client,
 	pub(crate) url: Url,
+	pub(crate) timeout: Duration,
 }
 
 impl QueryClient {
@@ -51,17 +107,32 @@ impl QueryClient {
 		self
 	}
 
-	pub async fn query<'a, T: AsRef<str>, P: Into<BTreeMap<&'a str, &'a str>>>(
-		&self,
-		flux: T,
-		params: P,
-	) -> anyhow::Result<Response> {
-		//
-		let mut query = Cow::Borrowed(flux.as_ref());
-		for (k, v) in params.into().into_iter() {
-			let search = format!("params.{k}");
-			query = Cow::Owned(query.replace(&search, v));
-		}
+	/// Returns the query endpoint with its query parameters. The token is
+	/// sent in a header, never in the URL.
+	pub fn url(&self) -> &Url {
+		&self.url
+	}
+
+	/// Sets the maximum time a query may take, from sending the request until
+	/// the response body has been read. Exceeding it fails with a
+	/// [`reqwest::Error`] for which `is_timeout()` is true.
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Runs a Flux query. Each of `params` is sent alongside the query and
+	/// referenced from it as `params.{name}`, so values are never spliced
+	/// into the Flux itself.
+	pub async fn query<'a, T, P>(&self, flux: T, params: P) -> anyhow::Result<Response>
+	where
+		T: AsRef<str>,
+		P: IntoIterator<Item = (&'a str, QueryParam)>,
+	{
+		let params = params
+			.into_iter()
+			.map(|(name, value)| Ok((name, value.into_value()?)))
+			.collect::<anyhow::Result<_>>()?;
 
 		let payload = QueryPayload {
 			dialect: Some(Dialect {
@@ -69,12 +140,13 @@ impl QueryClient {
 				header: true,
 			}),
 			now: OffsetDateTime::now_utc(),
-			query: &query,
+			query: flux.as_ref(),
 			ty: "flux",
+			params,
 		};
 
 		let body = serde_json::to_vec(&payload)?;
-		eprintln!("{}", from_utf8(&body)?);
+		tracing::trace!("sending query: {}", String::from_utf8_lossy(&body));
 
 		let response = self
 			.client
@@ -82,9 +154,194 @@ impl QueryClient {
 			.header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
 			.header(ACCEPT, HeaderValue::from_static("application/csv"))
 			.body(body)
+			.timeout(self.timeout)
 			.send()
 			.await?;
 
 		Ok(response)
 	}
+
+	/// Runs a Flux query and deserializes each row of the results into `T`.
+	/// A failed query is returned as a [`QueryError`] carrying InfluxDB's
+	/// message.
+	pub async fn query_as<'a, T, F, P>(&self, flux: F, params: P) -> anyhow::Result<Vec<T>>
+	where
+		T: DeserializeOwned,
+		F: AsRef<str>,
+		P: IntoIterator<Item = (&'a str, QueryParam)>,
+	{
+		let response = self.query(flux, params).await?;
+		let data = QueryError::check(response).await?.text().await?;
+		from_csv(&data)
+	}
+}
+
+/// Deserializes the rows of InfluxDB's annotated CSV into `T`.
+///
+/// Annotation rows are skipped, and each table's header row names the
+/// columns of the rows which follow it.
+pub fn from_csv<T: DeserializeOwned>(data: &str) -> anyhow::Result<Vec<T>> {
+	let mut reader = csv::ReaderBuilder::new()
+		.has_headers(false)
+		.flexible(true)
+		.comment(Some(b'#'))
+		.from_reader(data.as_bytes());
+
+	let mut header: Option<csv::StringRecord> = None;
+	let mut rows = Vec::new();
+	for record in reader.records() {
+		let record = record?;
+
+		// Each table in the response starts with its own header row.
+		if header.is_none() || record.get(1) == Some("result") {
+			header = Some(record);
+			continue;
+		}
+
+		rows.push(record.deserialize(header.as_ref())?);
+	}
+
+	Ok(rows)
+}
+
+/// An error returned by InfluxDB in place of CSV query results.
+///
+/// Malformed Flux is rejected with a JSON body such as
+/// `{"code":"invalid","message":"compilation failed: ..."}`.
+#[derive(Debug, Deserialize)]
+pub struct QueryError {
+	#[serde(skip)]
+	pub status: Option<StatusCode>,
+	pub code: String,
+	pub message: String,
+}
+
+impl QueryError {
+	/// Parses an error response body. Bodies which are not InfluxDB's JSON
+	/// error format are preserved verbatim in `message`.
+	pub fn from_body(status: Option<StatusCode>, body: &str) -> Self {
+		match serde_json::from_str::<QueryError>(body) {
+			Ok(error) => Self { status, ..error },
+			Err(_) => Self {
+				status,
+				code: String::from("unknown"),
+				message: body.trim().to_string(),
+			},
+		}
+	}
+
+	/// Checks a query response, converting error statuses and JSON bodies
+	/// into a [`QueryError`].
+	pub async fn check(response: Response) -> Result<Response, QueryError> {
+		let status = response.status();
+		let is_json = response
+			.headers()
+			.get(CONTENT_TYPE)
+			.and_then(|value| value.to_str().ok())
+			.is_some_and(|value| value.starts_with("application/json"));
+
+		if status.is_success() && !is_json {
+			return Ok(response);
+		}
+
+		let body = response.text().await.unwrap_or_default();
+		Err(Self::from_body(Some(status), &body))
+	}
+}
+
+impl fmt::Display for QueryError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.status {
+			Some(status) => write!(
+				f,
+				"query failed ({status}, {}): {}",
+				self.code, self.message
+			),
+			None => write!(f, "query failed ({}): {}", self.code, self.message),
+		}
+	}
+}
+
+impl std::error::Error for QueryError {}
+
+#[cfg(test)]
+mod tests {
+	use super::{QueryError, QueryParam};
+	use crate::mock::{self, MockResponse};
+	use reqwest::StatusCode;
+	use std::{collections::BTreeMap, time::Duration};
+	use time::{OffsetDateTime, UtcOffset};
+
+	#[test]
+	fn parse_flux_error() {
+		let body = r#"{"code":"invalid","message":"compilation failed: error @4:6-4:51: column \"_vlue\" not found"}"#;
+		let error = QueryError::from_body(Some(StatusCode::BAD_REQUEST), body);
+		assert_eq!(error.code, "invalid");
+		assert_eq!(
+			error.message,
+			r#"compilation failed: error @4:6-4:51: column "_vlue" not found"#
+		);
+		assert_eq!(error.status, Some(StatusCode::BAD_REQUEST));
+	}
+
+	#[test]
+	fn parse_non_json_error() {
+		let error = QueryError::from_body(None, "bad gateway\n");
+		assert_eq!(error.code, "unknown");
+		assert_eq!(error.message, "bad gateway");
+	}
+
+	#[tokio::test]
+	async fn slow_query_times_out() {
+		let response = MockResponse::new(200, "").delay(Duration::from_secs(5));
+		let (url, _server) = mock::serve(vec![response]).await;
+
+		let query_client = crate::Client::new(url, "token")
+			.unwrap()
+			.query_client()
+			.timeout(Duration::from_millis(100));
+
+		let error = query_client
+			.query("buckets()", BTreeMap::new())
+			.await
+			.unwrap_err();
+		let error = error.downcast_ref::<reqwest::Error>().unwrap();
+		assert!(error.is_timeout());
+	}
+
+	#[tokio::test]
+	async fn params_are_sent_separately() {
+		let (url, server) = mock::serve(vec![MockResponse::new(200, "")]).await;
+		let query_client = crate::Client::new(url, "token").unwrap().query_client();
+
+		let flux = r#"from(bucket: params.bucket)
+  |> range(start: time(v: params.start))
+  |> filter(fn: (r) => r["device"] == params.device)
+  |> limit(n: params.limit)"#;
+		let device = r#"kitchen/"kettle" \ params.device"#;
+		// 2023-10-04T00:00:00+01:00
+		let start = OffsetDateTime::from_unix_timestamp(1_696_374_000)
+			.unwrap()
+			.to_offset(UtcOffset::from_hms(1, 0, 0).unwrap());
+		let params = [
+			("bucket", QueryParam::from("fizzle")),
+			("device", device.into()),
+			("limit", 10i64.into()),
+			("start", start.into()),
+		];
+		query_client.query(flux, params).await.unwrap();
+
+		let requests = server.await.unwrap();
+		let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+		assert_eq!(body["query"], flux);
+		assert_eq!(
+			body["params"],
+			serde_json::json!({
+				"bucket": "fizzle",
+				"device": device,
+				"limit": 10,
+				"start": "2023-10-03T23:00:00Z",
+			})
+		);
+	}
 }
@@ -8,6 +8,18 @@ use tokio::{
 	time::interval,
 };
 
+/// Creates a buffered client whose writes are delivered to the returned
+/// receiver instead of an InfluxDB instance.
+pub fn channel_buffered_client(
+	channel_len: usize,
+) -> (
+	buffered::Client,
+	mpsc::Receiver<(Bytes, watch::Sender<Status>)>,
+) {
+	let (tx, rx) = mpsc::channel(channel_len);
+	(buffered::Client::new(tx), rx)
+}
+
 pub fn stdout_buffered_client() -> (buffered::Client, JoinHandle<anyhow::Result<()>>) {
 	let (tx, mut rx) = mpsc::channel::<(Bytes, watch::Sender<Status>)>(64);
 
@@ -1,10 +1,19 @@
-use super::{immediate, LineBuilder, Status, LINE_PROTOCOL_BUFFER_LEN};
-use bytes::{Bytes, BytesMut};
+use super::{
+	immediate, normalize_lines, precision::Precision, sort_tags, wal::Wal, LineBuilder, Status,
+	LINE_PROTOCOL_BUFFER_LEN,
+};
+use bytes::{BufMut, Bytes, BytesMut};
 use core::fmt;
-use std::{collections::VecDeque, time::Duration};
+use std::{
+	collections::VecDeque,
+	fs::{self, OpenOptions},
+	io::Write,
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
 use tokio::{
 	sync::{mpsc, watch},
-	time::interval,
+	time::{interval, sleep_until, Instant},
 };
 
 const DEFAULT_LINE_LIMIT: usize = 5000;
@@ -19,6 +28,41 @@ pub struct Options {
 	pub channel_len: usize,
 	pub max_timeout: Duration,
 	pub max_lines: usize,
+	/// Maximum number of batches to submit per second.
+	///
+	/// After an outage the backlog is drained one batch at a time at no more
+	/// than this rate, rather than as fast as InfluxDB will accept them.
+	/// `None` drains without limit.
+	pub max_flush_rate: Option<f64>,
+	/// Submit entries strictly in the order they were written.
+	///
+	/// When InfluxDB rejects a batch without writing any of it, its entries
+	/// are resent one at a time so that only those it refuses are dropped.
+	/// By default an entry which then fails transiently is retried later,
+	/// while the entries after it carry on, so they can be accepted first. In strict mode the entries
+	/// after it wait to be retried with it, which costs throughput during
+	/// an outage but settles each entry's [`Status`] in submission order.
+	pub strict_order: bool,
+	/// Times a batch is resent immediately after a transient failure, such
+	/// as a connection error or 5xx, before it is left for the next flush.
+	pub write_retries: u32,
+	/// Wait before the first resend, doubling after each one.
+	pub retry_base_delay: Duration,
+	/// Directory in which each write is logged before it is buffered, and
+	/// from which writes InfluxDB has not yet settled are replayed when the
+	/// task starts. `None` buffers in memory only.
+	pub wal_directory: Option<PathBuf>,
+	/// Sync each logged write to disk. Without it, writes survive the
+	/// process being killed but may be lost if the machine loses power.
+	pub wal_fsync: bool,
+	/// File to which line protocol InfluxDB refuses is appended, for later
+	/// inspection or replay. `None` drops it with only a log message.
+	pub dead_letter_path: Option<PathBuf>,
+	/// Points older than this are dropped when writes are replayed from the
+	/// write-ahead log, as InfluxDB would discard them. Typically the
+	/// bucket's retention period, from [`crate::Client::bucket_retention`].
+	/// `None` replays every point.
+	pub max_point_age: Option<Duration>,
 }
 
 impl Default for Options {
@@ -27,6 +71,14 @@ impl Default for Options {
 			channel_len: 64,
 			max_timeout: Duration::from_secs(60),
 			max_lines: DEFAULT_LINE_LIMIT,
+			max_flush_rate: None,
+			strict_order: false,
+			write_retries: 2,
+			retry_base_delay: Duration::from_millis(200),
+			wal_directory: None,
+			wal_fsync: false,
+			dead_letter_path: None,
+			max_point_age: None,
 		}
 	}
 }
@@ -47,6 +99,17 @@ impl Client {
 		Self { channel }
 	}
 
+	/// Returns true if the write task has stopped and no longer accepts
+	/// writes, for example after InfluxDB rejected the authorization token.
+	pub fn is_closed(&self) -> bool {
+		self.channel.is_closed()
+	}
+
+	/// Returns the number of writes waiting to be picked up by the write task.
+	pub fn queued(&self) -> usize {
+		self.channel.max_capacity() - self.channel.capacity()
+	}
+
 	pub async fn write_with<F>(&self, f: F) -> Result<watch::Receiver<Status>, BufferedWriteError>
 	where
 		F: FnOnce(LineBuilder) -> LineBuilder,
@@ -54,6 +117,29 @@ impl Client {
 		let buf = BytesMut::with_capacity(LINE_PROTOCOL_BUFFER_LEN);
 		let builder = LineBuilder::new_with(buf);
 		let buf = f(builder).build().freeze();
+		self.write_raw(buf).await
+	}
+
+	/// Like [`Client::write_with`], for closures which can fail. Nothing is
+	/// written if the closure returns an error, which is passed on.
+	pub async fn try_write_with<F, E>(&self, f: F) -> Result<watch::Receiver<Status>, E>
+	where
+		F: FnOnce(LineBuilder) -> Result<LineBuilder, E>,
+		E: From<BufferedWriteError>,
+	{
+		let buf = BytesMut::with_capacity(LINE_PROTOCOL_BUFFER_LEN);
+		let builder = LineBuilder::new_with(buf);
+		let buf = f(builder)?.build().freeze();
+		Ok(self.write_raw(buf).await?)
+	}
+
+	/// Queues pre-formatted line protocol. Line endings are normalized so
+	/// each line ends with a single `\n`, and tags are sorted by key.
+	pub async fn write_raw(
+		&self,
+		buf: Bytes,
+	) -> Result<watch::Receiver<Status>, BufferedWriteError> {
+		let buf = sort_tags(normalize_lines(buf));
 
 		let (tx, rx) = watch::channel(Status::Init);
 		self.channel
@@ -71,6 +157,151 @@ impl Drop for Client {
 	}
 }
 
+/// A buffered write, with its id in the write-ahead log if one is kept.
+struct Entry {
+	buffer: Bytes,
+	status: watch::Sender<Status>,
+	wal_id: Option<u64>,
+}
+
+impl Entry {
+	fn lines(&self) -> usize {
+		self.buffer.iter().filter(|&&x| x == b'\n').count()
+	}
+
+	/// Reports the outcome of the write, which no longer needs replaying.
+	fn settle(self, status: Status, wal: &mut Option<Wal>) {
+		if let (Some(wal), Some(id)) = (wal.as_mut(), self.wal_id) {
+			wal.remove(id);
+		}
+		self.status.send_replace(status);
+	}
+}
+
+/// Returns the lines of `buffer` whose timestamps are within `max_age` of
+/// now. Lines without a timestamp are kept.
+fn drop_expired(buffer: Bytes, precision: &Precision, max_age: Duration) -> Bytes {
+	let now = SystemTime::now()
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.unwrap_or_default();
+	let cutoff = now.saturating_sub(max_age).as_nanos() as i128;
+
+	let mut kept = BytesMut::with_capacity(buffer.len());
+	let mut dropped = 0;
+	for line in buffer.split_inclusive(|&x| x == b'\n') {
+		match line_timestamp(line) {
+			Some(timestamp) if precision.to_nanos(timestamp) < cutoff => dropped += 1,
+			_ => kept.extend_from_slice(line),
+		}
+	}
+	if dropped > 0 {
+		tracing::warn!("dropping {dropped} replayed points older than {max_age:?}");
+	}
+	kept.freeze()
+}
+
+/// Returns the timestamp of a line of line protocol, if it has one.
+fn line_timestamp(line: &[u8]) -> Option<i64> {
+	let line = std::str::from_utf8(line).ok()?.trim_end();
+	let (_, timestamp) = line.rsplit_once(' ')?;
+	timestamp.parse().ok()
+}
+
+/// Gives every line of `buffer` without a timestamp the current time, so a
+/// replayed write keeps the time it was made rather than the time it was
+/// replayed.
+fn stamp_lines(buffer: Bytes, precision: &Precision) -> Bytes {
+	let lines = buffer.split_inclusive(|&x| x == b'\n');
+	if lines.clone().all(|line| line_timestamp(line).is_some()) {
+		return buffer;
+	}
+
+	let now = precision.now().to_string();
+	let mut stamped = BytesMut::with_capacity(buffer.len() + now.len() + 2);
+	for line in lines {
+		match line_timestamp(line) {
+			Some(_) => stamped.extend_from_slice(line),
+			None => {
+				stamped.extend_from_slice(line.strip_suffix(b"\n").unwrap_or(line));
+				stamped.put_u8(b' ');
+				stamped.extend_from_slice(now.as_bytes());
+				stamped.put_u8(b'\n');
+			}
+		}
+	}
+	stamped.freeze()
+}
+
+/// Adds a write to the buffers, returning its number of lines.
+///
+/// Logged writes are stamped with the current time first, see
+/// [`stamp_lines`].
+fn push_buffer(
+	buffers: &mut VecDeque<Entry>,
+	wal: &mut Option<Wal>,
+	precision: &Precision,
+	buffer: Bytes,
+	status: watch::Sender<Status>,
+) -> usize {
+	let buffer = match wal {
+		Some(_) => stamp_lines(buffer, precision),
+		None => buffer,
+	};
+	let wal_id = match wal.as_mut().map(|wal| wal.append(&buffer)) {
+		Some(Ok(id)) => Some(id),
+		Some(Err(error)) => {
+			tracing::error!("failed to log buffered write, keeping it in memory only: {error:?}");
+			None
+		}
+		None => None,
+	};
+	let entry = Entry {
+		buffer,
+		status,
+		wal_id,
+	};
+	let new_lines = entry.lines();
+	let len = entry.buffer.len();
+	entry.status.send_replace(Status::Buffered);
+	buffers.push_back(entry);
+
+	tracing::trace!(
+		"buffering {new_lines} lines, {len} bytes of line-protocol; {} entries in buffers",
+		buffers.len()
+	);
+	new_lines
+}
+
+/// Appends line protocol InfluxDB refused to the dead-letter file, if one is
+/// configured.
+fn dead_letter(path: Option<&Path>, buffer: &[u8]) {
+	let Some(path) = path else {
+		return;
+	};
+	let result = path
+		.parent()
+		.map_or(Ok(()), fs::create_dir_all)
+		.and_then(|_| OpenOptions::new().create(true).append(true).open(path))
+		.and_then(|mut file| file.write_all(buffer));
+	if let Err(error) = result {
+		tracing::error!(
+			"failed to append rejected line protocol to '{}': {error:?}",
+			path.display()
+		);
+	}
+}
+
+/// Batches writes and submits them to InfluxDB.
+///
+/// Once `shutdown_signal` becomes true, or every client is dropped, no more
+/// writes are accepted and everything already buffered is flushed, in
+/// batches of at most `max_lines`, before the task returns.
+///
+/// With [`Options::wal_directory`] set, writes left in the log by a previous
+/// run are buffered ahead of any new ones, and writes InfluxDB is unavailable
+/// to take at shutdown are settled as [`Status::Deferred`] rather than
+/// [`Status::Rejected`]. Nobody is waiting on their
+/// [`Status`], so it is only kept for bookkeeping.
 pub async fn buffered_write_task(
 	client: immediate::Client,
 	mut channel: mpsc::Receiver<(Bytes, watch::Sender<Status>)>,
@@ -82,71 +313,148 @@ pub async fn buffered_write_task(
 	let mut lines = 0;
 	let mut buffers = VecDeque::new();
 
-	let mut flush_interval = interval(options.max_timeout);
+	let mut wal = match &options.wal_directory {
+		Some(directory) => {
+			let (wal, replayed) = match Wal::open(directory, options.wal_fsync) {
+				Ok(opened) => opened,
+				Err(error) => {
+					tracing::error!(
+						"failed to open write-ahead log '{}': {error:?}",
+						directory.display()
+					);
+					channel.close();
+					return Err(error.into());
+				}
+			};
+			if !replayed.is_empty() {
+				tracing::info!(
+					"replaying {} buffered entries from '{}'",
+					replayed.len(),
+					directory.display()
+				);
+			}
+			for (id, buffer) in replayed {
+				let buffer = match options.max_point_age {
+					Some(max_age) => drop_expired(buffer, client.precision(), max_age),
+					None => buffer,
+				};
+				if buffer.is_empty() {
+					wal.remove(id);
+					continue;
+				}
+				let (status, _) = watch::channel(Status::Buffered);
+				let entry = Entry {
+					buffer,
+					status,
+					wal_id: Some(id),
+				};
+				lines += entry.lines();
+				buffers.push_back(entry);
+			}
+			Some(wal)
+		}
+		None => None,
+	};
 
-	while !shutdown {
-		let flush = tokio::select! {
-			biased;
+	let mut flush_interval = interval(options.max_timeout);
 
-			message = channel.recv() => {
-				match message {
-					Some((buffer, status)) => {
-						// Calculate how many lines we've received.
-						let new_lines = buffer.iter().filter(|&&x| x == b'\n').count();
-						lines += new_lines;
+	let flush_spacing = options
+		.max_flush_rate
+		.filter(|rate| *rate > 0.0)
+		.map(|rate| Duration::from_secs_f64(1.0 / rate));
+	let mut next_flush = Instant::now();
+	let mut backlog = false;
 
-						let len = buffer.len();
-						status.send_replace(Status::Buffered);
-						buffers.push_back((buffer, status));
+	loop {
+		let flush = if shutdown {
+			// Pick up whatever was written before the channel closed, then
+			// flush batch by batch until nothing is left.
+			while let Ok((buffer, status)) = channel.try_recv() {
+				lines += push_buffer(&mut buffers, &mut wal, client.precision(), buffer, status);
+			}
+			if buffers.is_empty() {
+				break;
+			}
+			true
+		} else {
+			tokio::select! {
+				biased;
 
-						tracing::trace!(
-							"buffering {new_lines} lines, {len} bytes of line-protocol; {} entries in buffers, {lines} lines",
-							buffers.len()
-						);
+				message = channel.recv() => {
+					match message {
+						Some((buffer, status)) => {
+							lines += push_buffer(&mut buffers, &mut wal, client.precision(), buffer, status);
 
-						// Flush the buffers immediately if we've already reached the limit.
-						lines >= options.max_lines
+							// Flush the buffers immediately if we've already reached the limit.
+							lines >= options.max_lines
+						}
+						None => {
+							tracing::debug!("channel closed, shutting down task");
+							shutdown = true;
+							false
+						}
 					}
-					None => {
-						tracing::debug!("channel closed, shutting down task");
-						shutdown = true;
-						true
+				}
+				result = shutdown_signal.changed() => {
+					// A dropped sender also means shutdown.
+					if result.is_ok() && !*shutdown_signal.borrow() {
+						continue;
 					}
+					tracing::debug!("shutdown signalled, flushing {} buffered entries", buffers.len());
+					shutdown = true;
+					channel.close();
+					false
+				}
+				_ = sleep_until(next_flush), if backlog => {
+					!buffers.is_empty()
+				}
+				_ = flush_interval.tick() => {
+					!buffers.is_empty()
+				}
+				else => {
+					shutdown = true;
+					!buffers.is_empty()
 				}
-			}
-			_ = shutdown_signal.changed() => {
-				//
-				shutdown = true;
-				true
-			}
-			_ = flush_interval.tick() => {
-				!buffers.is_empty()
-			}
-			else => {
-				shutdown = true;
-				!buffers.is_empty()
 			}
 		};
 
+		// Hold off until the rate limit allows another batch. Shutdown is never
+		// delayed.
+		if flush && !shutdown && Instant::now() < next_flush {
+			backlog = true;
+			continue;
+		}
+
 		if flush {
 			tracing::debug!("will send buffered line-protocol to InfluxDB instance");
+			backlog = false;
+			if let Some(spacing) = flush_spacing {
+				next_flush = Instant::now() + spacing;
+			}
 
+			let mut retry = false;
 			let mut in_progress = VecDeque::new();
 			let mut body_buffer = BytesMut::new();
 			let mut total_lines = 0;
 
-			while let Some((buffer, status)) = buffers.pop_front() {
-				let new_lines = buffer.iter().filter(|&&x| x == b'\n').count();
-				total_lines += new_lines;
+			while let Some(entry) = buffers.pop_front() {
+				total_lines += entry.lines();
 
-				body_buffer.extend_from_slice(&buffer);
-				in_progress.push_back((buffer, status));
+				body_buffer.extend_from_slice(&entry.buffer);
+				in_progress.push_back(entry);
 				if total_lines >= options.max_lines {
 					break;
 				}
 			}
 
-			match client.write(body_buffer.freeze()).await {
+			let result = client
+				.write_with_retry(
+					body_buffer.freeze(),
+					options.write_retries,
+					options.retry_base_delay,
+				)
+				.await;
+			match result {
 				Ok(_) => {
 					tracing::debug!(
 						"wrote {} lines to bucket '{}'",
@@ -154,17 +462,135 @@ pub async fn buffered_write_task(
 						client.bucket()
 					);
 					lines -= total_lines;
-					for (_, status) in in_progress {
-						status.send_replace(Status::Accepted);
+					for entry in in_progress {
+						entry.settle(Status::Accepted, &mut wal);
+					}
+
+					// Keep draining while a full batch is still waiting.
+					backlog = lines >= options.max_lines;
+				}
+				Err(error) if error.is_auth_failure() => {
+					// Retrying cannot succeed, and buffering would grow without
+					// bound. Stop the task so writers see the failure.
+					tracing::error!(
+						"InfluxDB rejected the authorization token ({:?}); no longer writing to bucket '{}', {} buffered entries dropped",
+						error.status(),
+						client.bucket(),
+						buffers.len() + in_progress.len()
+					);
+					channel.close();
+					return Err(error.into());
+				}
+				Err(error) if error.partial_write().is_some() => {
+					// InfluxDB has already written the points it accepted, so
+					// none of the batch is resent. Entries it does not say
+					// which lines were refused from are all treated as refused.
+					tracing::warn!(
+						"InfluxDB partially wrote a batch of {} entries: {error}",
+						in_progress.len()
+					);
+
+					let refused = error.partial_write().unwrap_or_default();
+					let mut first_line = 1;
+					for entry in in_progress {
+						let entry_lines = first_line..first_line + entry.lines();
+						first_line = entry_lines.end;
+						lines -= entry.lines();
+
+						if refused.is_empty()
+							|| refused.iter().any(|line| entry_lines.contains(line))
+						{
+							tracing::error!(
+								"dropping line protocol rejected by InfluxDB: {:?}",
+								String::from_utf8_lossy(&entry.buffer)
+							);
+							dead_letter(options.dead_letter_path.as_deref(), &entry.buffer);
+							entry.settle(Status::Rejected, &mut wal);
+						} else {
+							entry.settle(Status::Accepted, &mut wal);
+						}
+					}
+				}
+				Err(error) if error.is_rejected() => {
+					// One bad entry must not hold back the rest of the batch.
+					// Resend entries one at a time so only those InfluxDB
+					// refuses are dropped.
+					tracing::warn!(
+						"InfluxDB rejected a batch of {} entries, resending individually: {error}",
+						in_progress.len()
+					);
+
+					let single = in_progress.len() == 1;
+					let mut requeue = Vec::new();
+					for entry in in_progress {
+						// Nothing may overtake an entry waiting to be retried.
+						if options.strict_order && !requeue.is_empty() {
+							requeue.push(entry);
+							continue;
+						}
+
+						// A lone entry has already been rejected on its own.
+						let result = if single {
+							Err(true)
+						} else {
+							client
+								.write(entry.buffer.clone())
+								.await
+								.map_err(|error| error.is_rejected())
+						};
+
+						match result {
+							Ok(()) => {
+								lines -= entry.lines();
+								entry.settle(Status::Accepted, &mut wal);
+							}
+							Err(true) => {
+								tracing::error!(
+									"dropping line protocol rejected by InfluxDB: {:?}",
+									String::from_utf8_lossy(&entry.buffer)
+								);
+								dead_letter(options.dead_letter_path.as_deref(), &entry.buffer);
+								lines -= entry.lines();
+								entry.settle(Status::Rejected, &mut wal);
+							}
+							Err(false) => requeue.push(entry),
+						}
+					}
+
+					retry = !requeue.is_empty();
+					for value in requeue.into_iter().rev() {
+						buffers.push_front(value);
 					}
 				}
 				Err(error) => {
 					tracing::error!("error submitting line protocol: {error:?}");
-					for value in in_progress {
+					retry = true;
+					for value in in_progress.into_iter().rev() {
 						buffers.push_front(value);
 					}
 				}
 			}
+
+			// Retrying could hold up shutdown indefinitely. Logged entries are
+			// left in the write-ahead log to be replayed on the next start.
+			if shutdown && retry {
+				tracing::error!(
+					"InfluxDB is unavailable at shutdown, {} buffered entries {}",
+					buffers.len(),
+					if wal.is_some() {
+						"kept for replay"
+					} else {
+						"dropped"
+					}
+				);
+				for entry in buffers.drain(..) {
+					entry.status.send_replace(match entry.wal_id {
+						Some(_) => Status::Deferred,
+						None => Status::Rejected,
+					});
+				}
+				break;
+			}
 		}
 	}
 
@@ -175,3 +601,480 @@ pub async fn buffered_write_task(
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::Options;
+	use crate::{
+		mock::{self, MockResponse},
+		util::channel_buffered_client,
+		Precision, Status,
+	};
+	use std::time::{Duration, Instant};
+	use tokio::sync::watch;
+
+	#[tokio::test]
+	async fn unauthorized_stops_writes() {
+		let (url, server) = mock::serve(vec![MockResponse::new(
+			401,
+			r#"{"code":"unauthorized","message":"unauthorized access"}"#,
+		)])
+		.await;
+
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let options = Options {
+			max_lines: 1,
+			..Default::default()
+		};
+		let (client, handle) = crate::Client::new(url, "revoked")
+			.unwrap()
+			.write_to_bucket("test")
+			.build()
+			.buffered_with(shutdown_rx, options);
+
+		client
+			.write_with(|builder| builder.measurement("m").field("f", 1i64).close_line())
+			.await
+			.unwrap();
+
+		assert!(handle.await.unwrap().is_err());
+		assert!(client.is_closed());
+		assert!(client
+			.write_with(|builder| builder.measurement("m").field("f", 2i64).close_line())
+			.await
+			.is_err());
+
+		let requests = server.await.unwrap();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].method, "POST");
+		assert_eq!(requests[0].path, "/api/v2/write?bucket=test&precision=ns");
+		assert_eq!(requests[0].header("authorization"), Some("Token revoked"));
+		assert_eq!(requests[0].body, b"m f=1i\n");
+	}
+
+	#[tokio::test]
+	async fn backlog_drains_at_configured_rate() {
+		let responses = vec![MockResponse::new(204, ""); 5];
+		let (url, server) = mock::serve(responses).await;
+
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let options = Options {
+			max_lines: 1,
+			max_flush_rate: Some(20.0),
+			..Default::default()
+		};
+		let (client, _handle) = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build()
+			.buffered_with(shutdown_rx, options);
+
+		let start = Instant::now();
+		for value in 0..5i64 {
+			client
+				.write_with(|builder| builder.measurement("m").field("f", value).close_line())
+				.await
+				.unwrap();
+		}
+
+		// Five batches at 20 per second need at least four 50ms gaps.
+		let requests = server.await.unwrap();
+		assert_eq!(requests.len(), 5);
+		assert!(start.elapsed() >= Duration::from_millis(200));
+	}
+
+	#[tokio::test]
+	async fn rejected_entry_is_isolated() {
+		let (url, server) = mock::serve(vec![
+			MockResponse::new(
+				400,
+				r#"{"code":"invalid","message":"unable to parse 'm f=\"one\"'"}"#,
+			),
+			MockResponse::new(204, ""),
+			MockResponse::new(400, r#"{"code":"invalid","message":"field type conflict"}"#),
+		])
+		.await;
+
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let options = Options {
+			max_lines: 2,
+			..Default::default()
+		};
+		let (client, _handle) = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build()
+			.buffered_with(shutdown_rx, options);
+
+		let mut good = client
+			.write_with(|builder| builder.measurement("m").field("f", 1i64).close_line())
+			.await
+			.unwrap();
+		let mut bad = client
+			.write_with(|builder| builder.measurement("m").field("f", "one").close_line())
+			.await
+			.unwrap();
+
+		let settled = |status: &Status| matches!(status, Status::Accepted | Status::Rejected);
+		assert_eq!(*good.wait_for(settled).await.unwrap(), Status::Accepted);
+		assert_eq!(*bad.wait_for(settled).await.unwrap(), Status::Rejected);
+
+		let requests = server.await.unwrap();
+		assert_eq!(requests[0].body, b"m f=1i\nm f=\"one\"\n");
+		assert_eq!(requests[1].body, b"m f=1i\n");
+		assert_eq!(requests[2].body, b"m f=\"one\"\n");
+	}
+
+	#[tokio::test]
+	async fn partial_write_is_not_resent() {
+		let (url, server) = mock::serve(vec![MockResponse::new(
+			400,
+			r#"{"code":"invalid","message":"partial write has occurred, errors encountered on line(s): line 2: invalid field format"}"#,
+		)])
+		.await;
+
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let options = Options {
+			max_lines: 2,
+			..Default::default()
+		};
+		let (client, _handle) = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build()
+			.buffered_with(shutdown_rx, options);
+
+		let mut good = client
+			.write_with(|builder| builder.measurement("m").field("f", 1i64).close_line())
+			.await
+			.unwrap();
+		let mut bad = client
+			.write_with(|builder| builder.measurement("m").field("f", "one").close_line())
+			.await
+			.unwrap();
+
+		let settled = |status: &Status| matches!(status, Status::Accepted | Status::Rejected);
+		assert_eq!(*good.wait_for(settled).await.unwrap(), Status::Accepted);
+		assert_eq!(*bad.wait_for(settled).await.unwrap(), Status::Rejected);
+
+		// The accepted line has already been written, so nothing is resent.
+		let requests = server.await.unwrap();
+		assert_eq!(requests.len(), 1);
+	}
+
+	#[tokio::test]
+	async fn rejected_entry_is_dead_lettered() {
+		let path =
+			std::env::temp_dir().join(format!("influxdb-dead-letter-{}.lp", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let (url, _server) = mock::serve(vec![MockResponse::new(
+			400,
+			r#"{"code":"invalid","message":"unable to parse"}"#,
+		)])
+		.await;
+
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let options = Options {
+			max_lines: 1,
+			dead_letter_path: Some(path.clone()),
+			..Default::default()
+		};
+		let (client, _handle) = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build()
+			.buffered_with(shutdown_rx, options);
+
+		let mut bad = client
+			.write_with(|builder| builder.measurement("m").field("f", "one").close_line())
+			.await
+			.unwrap();
+		let settled = |status: &Status| matches!(status, Status::Accepted | Status::Rejected);
+		assert_eq!(*bad.wait_for(settled).await.unwrap(), Status::Rejected);
+
+		assert_eq!(std::fs::read(&path).unwrap(), b"m f=\"one\"\n");
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn failed_closure_writes_nothing() {
+		let (client, mut rx) = channel_buffered_client(4);
+
+		let result = client
+			.try_write_with(|builder| {
+				let label: Option<&str> = None;
+				let label = label.ok_or_else(|| anyhow::anyhow!("missing label"))?;
+				Ok::<_, anyhow::Error>(
+					builder
+						.measurement("m")
+						.tag("label", label)
+						.field("f", 1i64)
+						.close_line(),
+				)
+			})
+			.await;
+		assert!(result.is_err());
+		assert!(rx.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn strict_order_survives_transient_failure() {
+		let (url, server) = mock::serve(vec![
+			MockResponse::new(503, ""),
+			MockResponse::new(
+				400,
+				r#"{"code":"invalid","message":"unable to parse 'm f=1i'"}"#,
+			),
+			MockResponse::new(503, ""),
+			MockResponse::new(204, ""),
+		])
+		.await;
+
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let options = Options {
+			max_lines: 3,
+			max_timeout: Duration::from_millis(50),
+			strict_order: true,
+			write_retries: 0,
+			..Default::default()
+		};
+		let (client, _handle) = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build()
+			.buffered_with(shutdown_rx, options);
+
+		let mut statuses = Vec::new();
+		for value in 1..=3i64 {
+			let status = client
+				.write_with(|builder| builder.measurement("m").field("f", value).close_line())
+				.await
+				.unwrap();
+			statuses.push(status);
+		}
+
+		let settled = |status: &Status| matches!(status, Status::Accepted | Status::Rejected);
+		for mut status in statuses {
+			assert_eq!(*status.wait_for(settled).await.unwrap(), Status::Accepted);
+		}
+
+		// The retried entry is never overtaken by those written after it.
+		let requests = server.await.unwrap();
+		let bodies: Vec<_> = requests.iter().map(|request| &request.body[..]).collect();
+		assert_eq!(
+			bodies,
+			vec![
+				&b"m f=1i\nm f=2i\nm f=3i\n"[..],
+				&b"m f=1i\nm f=2i\nm f=3i\n"[..],
+				&b"m f=1i\n"[..],
+				&b"m f=1i\nm f=2i\nm f=3i\n"[..],
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn unsettled_writes_are_replayed_after_a_crash() {
+		let directory = std::env::temp_dir().join(format!("influxdb-wal-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&directory);
+		let options = || Options {
+			wal_directory: Some(directory.clone()),
+			..Default::default()
+		};
+
+		// Nothing is flushed before the task is killed.
+		let before = Precision::Nanoseconds.now();
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let (client, handle) = crate::Client::new("http://127.0.0.1:9", "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build()
+			.buffered_with(shutdown_rx, options());
+		for value in 1..=2i64 {
+			let mut status = client
+				.write_with(|builder| builder.measurement("m").field("f", value).close_line())
+				.await
+				.unwrap();
+			status
+				.wait_for(|status| *status == Status::Buffered)
+				.await
+				.unwrap();
+		}
+		handle.abort();
+		assert!(handle.await.unwrap_err().is_cancelled());
+		drop(client);
+		assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 2);
+
+		let (url, server) = mock::serve(vec![MockResponse::new(204, "")]).await;
+		let (shutdown_tx, shutdown_rx) = watch::channel(false);
+		let (_client, handle) = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build()
+			.buffered_with(shutdown_rx, options());
+
+		// Replayed lines keep the time they were written, not replayed.
+		let requests = server.await.unwrap();
+		let body = String::from_utf8(requests[0].body.clone()).unwrap();
+		let lines: Vec<_> = body
+			.lines()
+			.map(|line| line.rsplit_once(' ').unwrap())
+			.collect();
+		assert_eq!(lines.len(), 2);
+		for (index, (line, timestamp)) in lines.into_iter().enumerate() {
+			assert_eq!(line, format!("m f={}i", index + 1));
+			let timestamp: i64 = timestamp.parse().unwrap();
+			assert!((before..=Precision::Nanoseconds.now()).contains(&timestamp));
+		}
+
+		shutdown_tx.send(true).unwrap();
+		handle.await.unwrap().unwrap();
+		assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[tokio::test]
+	async fn expired_points_are_not_replayed() {
+		let directory =
+			std::env::temp_dir().join(format!("influxdb-wal-expired-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&directory);
+		let options = |max_point_age| Options {
+			wal_directory: Some(directory.clone()),
+			max_point_age,
+			..Default::default()
+		};
+		let now = time::OffsetDateTime::now_utc();
+		let millis = |at: time::OffsetDateTime| (at.unix_timestamp_nanos() / 1_000_000) as i64;
+
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let (client, handle) = crate::Client::new("http://127.0.0.1:9", "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.precision(Precision::Milliseconds)
+			.build()
+			.buffered_with(shutdown_rx, options(None));
+		let points = [
+			(1i64, now - time::Duration::days(40)),
+			(2, now - time::Duration::days(1)),
+		];
+		for (value, at) in points {
+			let mut status = client
+				.write_with(|builder| {
+					builder
+						.measurement("m")
+						.field("f", value)
+						.timestamp(millis(at))
+						.close_line()
+				})
+				.await
+				.unwrap();
+			status
+				.wait_for(|status| *status == Status::Buffered)
+				.await
+				.unwrap();
+		}
+		handle.abort();
+		assert!(handle.await.unwrap_err().is_cancelled());
+		drop(client);
+
+		// With a 30 day retention period, only the recent point is replayed.
+		let (url, server) = mock::serve(vec![MockResponse::new(204, "")]).await;
+		let (shutdown_tx, shutdown_rx) = watch::channel(false);
+		let (_client, handle) = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.precision(Precision::Milliseconds)
+			.build()
+			.buffered_with(
+				shutdown_rx,
+				options(Some(Duration::from_secs(30 * 24 * 60 * 60))),
+			);
+
+		let requests = server.await.unwrap();
+		assert_eq!(
+			requests[0].body,
+			format!("m f=2i {}\n", millis(points[1].1)).into_bytes()
+		);
+
+		shutdown_tx.send(true).unwrap();
+		handle.await.unwrap().unwrap();
+		assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[tokio::test]
+	async fn logged_writes_are_deferred_at_shutdown() {
+		let directory =
+			std::env::temp_dir().join(format!("influxdb-wal-deferred-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&directory);
+		let options = Options {
+			wal_directory: Some(directory.clone()),
+			..Default::default()
+		};
+
+		// Nothing is listening, so InfluxDB is unavailable at shutdown.
+		let (shutdown_tx, shutdown_rx) = watch::channel(false);
+		let (client, handle) = crate::Client::new("http://127.0.0.1:9", "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build()
+			.buffered_with(shutdown_rx, options);
+		let status = client
+			.write_with(|builder| builder.measurement("m").field("f", 1i64).close_line())
+			.await
+			.unwrap();
+		shutdown_tx.send(true).unwrap();
+		handle.await.unwrap().unwrap();
+
+		assert_eq!(*status.borrow(), Status::Deferred);
+		assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[tokio::test]
+	async fn shutdown_flushes_buffered_lines() {
+		let responses = vec![MockResponse::new(204, ""); 3];
+		let (url, server) = mock::serve(responses).await;
+
+		let (shutdown_tx, shutdown_rx) = watch::channel(false);
+		let options = Options {
+			max_lines: 2,
+			..Default::default()
+		};
+		let (client, handle) = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build()
+			.buffered_with(shutdown_rx, options);
+
+		let mut statuses = Vec::new();
+		for value in 1..=5i64 {
+			let status = client
+				.write_with(|builder| builder.measurement("m").field("f", value).close_line())
+				.await
+				.unwrap();
+			statuses.push(status);
+		}
+		shutdown_tx.send(true).unwrap();
+
+		// Well before the 60 second flush interval.
+		tokio::time::timeout(Duration::from_secs(5), handle)
+			.await
+			.expect("task exits after flushing")
+			.unwrap()
+			.unwrap();
+		for status in statuses {
+			assert_eq!(*status.borrow(), Status::Accepted);
+		}
+
+		let requests = server.await.unwrap();
+		let bodies: Vec<_> = requests.iter().map(|request| &request.body[..]).collect();
+		assert_eq!(
+			bodies,
+			vec![
+				&b"m f=1i\nm f=2i\n"[..],
+				&b"m f=3i\nm f=4i\n"[..],
+				&b"m f=5i\n"[..],
+			]
+		);
+	}
+}
@@ -9,6 +9,8 @@ pub struct Builder {
 	org_id: Option<String>,
 	org_name: Option<String>,
 	precision: Precision,
+	gzip: bool,
+	gzip_min_len: usize,
 }
 
 impl Builder {
@@ -20,6 +22,8 @@ impl Builder {
 			org_id: Default::default(),
 			org_name: Default::default(),
 			precision: Default::default(),
+			gzip: true,
+			gzip_min_len: immediate::DEFAULT_GZIP_MIN_LEN,
 		}
 	}
 
@@ -49,6 +53,21 @@ impl Builder {
 		s
 	}
 
+	/// Compress request bodies with gzip. Enabled by default.
+	pub fn gzip(self, gzip: bool) -> Self {
+		let mut s = self;
+		s.gzip = gzip;
+		s
+	}
+
+	/// Send bodies shorter than `len` bytes uncompressed even when gzip is
+	/// enabled. Defaults to 1KiB.
+	pub fn gzip_min_len(self, len: usize) -> Self {
+		let mut s = self;
+		s.gzip_min_len = len;
+		s
+	}
+
 	pub fn build(self) -> immediate::Client {
 		let client = self.client;
 
@@ -68,6 +87,13 @@ impl Builder {
 			};
 		}
 
-		immediate::Client::new(client, url)
+		immediate::Client::new(
+			client,
+			url,
+			self.bucket,
+			self.precision,
+			self.gzip,
+			self.gzip_min_len,
+		)
 	}
 }
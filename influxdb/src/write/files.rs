@@ -0,0 +1,237 @@
+use super::{buffered, Status};
+use bytes::{Bytes, BytesMut};
+use serde::Deserialize;
+use std::{
+	fs::File,
+	io::Write,
+	path::PathBuf,
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+	sync::{mpsc, watch},
+	task::JoinHandle,
+	time::interval,
+};
+
+const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_AGE_SECS: u64 = 3600;
+
+/// Writes line protocol to a directory of files, starting a new file once the
+/// current one reaches a size or age limit.
+///
+/// Each file is valid line protocol which can later be loaded with
+/// `influx write`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct RotatingFiles {
+	pub directory: PathBuf,
+
+	/// Largest size of a file, in bytes, unless a single batch is larger.
+	#[serde(default = "default_max_bytes")]
+	pub max_bytes: u64,
+
+	/// Seconds after which a new file is started.
+	#[serde(default = "default_max_age_secs")]
+	pub max_age_secs: u64,
+}
+
+fn default_max_bytes() -> u64 {
+	DEFAULT_MAX_BYTES
+}
+
+fn default_max_age_secs() -> u64 {
+	DEFAULT_MAX_AGE_SECS
+}
+
+impl RotatingFiles {
+	pub fn new(directory: impl Into<PathBuf>) -> Self {
+		Self {
+			directory: directory.into(),
+			max_bytes: DEFAULT_MAX_BYTES,
+			max_age_secs: DEFAULT_MAX_AGE_SECS,
+		}
+	}
+
+	pub fn buffered(
+		self,
+		shutdown_signal: watch::Receiver<bool>,
+	) -> (buffered::Client, JoinHandle<anyhow::Result<()>>) {
+		self.buffered_with(shutdown_signal, Default::default())
+	}
+
+	/// Creates a buffered client whose writes are batched as for InfluxDB,
+	/// using `options.max_lines` and `options.max_timeout`, then appended to
+	/// the current file.
+	pub fn buffered_with(
+		self,
+		shutdown_signal: watch::Receiver<bool>,
+		options: buffered::Options,
+	) -> (buffered::Client, JoinHandle<anyhow::Result<()>>) {
+		let (tx, rx) = mpsc::channel(options.channel_len);
+		let handle = tokio::spawn(files_task(self, rx, shutdown_signal, options));
+		(buffered::Client::new(tx), handle)
+	}
+}
+
+struct CurrentFile {
+	file: File,
+	opened: Instant,
+	written: u64,
+}
+
+async fn files_task(
+	files: RotatingFiles,
+	mut channel: mpsc::Receiver<(Bytes, watch::Sender<Status>)>,
+	mut shutdown_signal: watch::Receiver<bool>,
+	options: buffered::Options,
+) -> anyhow::Result<()> {
+	std::fs::create_dir_all(&files.directory)?;
+
+	let mut current: Option<CurrentFile> = None;
+	let mut sequence = 0;
+	let mut batch = BytesMut::new();
+	let mut statuses = Vec::new();
+	let mut lines = 0;
+	let mut flush_interval = interval(options.max_timeout);
+
+	let mut shutdown = false;
+	loop {
+		let (flush, closed) = tokio::select! {
+			biased;
+
+			message = channel.recv() => match message {
+				Some((buffer, status)) => {
+					lines += buffer.iter().filter(|&&x| x == b'\n').count();
+					batch.extend_from_slice(&buffer);
+					status.send_replace(Status::Buffered);
+					statuses.push(status);
+					(lines >= options.max_lines, false)
+				}
+				None => (true, true),
+			},
+			_ = shutdown_signal.changed(), if !shutdown => {
+				// Stop accepting writes, but keep whatever is already queued.
+				shutdown = true;
+				channel.close();
+				continue;
+			}
+			_ = flush_interval.tick() => (true, false),
+		};
+
+		if flush && !batch.is_empty() {
+			let result = write_batch(&files, &mut current, &mut sequence, &batch);
+			let status = match result {
+				Ok(()) => Status::Accepted,
+				Err(error) => {
+					tracing::error!(
+						"error writing {} bytes of line protocol to '{}': {error:?}",
+						batch.len(),
+						files.directory.display()
+					);
+					Status::Rejected
+				}
+			};
+			for sender in statuses.drain(..) {
+				sender.send_replace(status.clone());
+			}
+			batch.clear();
+			lines = 0;
+		}
+
+		if closed {
+			break;
+		}
+	}
+
+	tracing::debug!(
+		"line protocol file task for '{}' stopped",
+		files.directory.display()
+	);
+	Ok(())
+}
+
+fn write_batch(
+	files: &RotatingFiles,
+	current: &mut Option<CurrentFile>,
+	sequence: &mut u32,
+	batch: &[u8],
+) -> std::io::Result<()> {
+	let rotate = match current {
+		Some(current) => {
+			(current.written > 0 && current.written + batch.len() as u64 > files.max_bytes)
+				|| current.opened.elapsed() >= Duration::from_secs(files.max_age_secs)
+		}
+		None => true,
+	};
+
+	if rotate {
+		let started = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_millis();
+		let path = files.directory.join(format!("{started}-{sequence:04}.lp"));
+		*sequence += 1;
+
+		tracing::debug!("writing line protocol to '{}'", path.display());
+		*current = Some(CurrentFile {
+			file: File::create(path)?,
+			opened: Instant::now(),
+			written: 0,
+		});
+	}
+
+	let current = current.as_mut().expect("a file is open after rotating");
+	current.file.write_all(batch)?;
+	current.file.flush()?;
+	current.written += batch.len() as u64;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::RotatingFiles;
+	use crate::{buffered, Status};
+	use tokio::sync::watch;
+
+	#[tokio::test]
+	async fn files_rotate_at_size_limit() {
+		let directory = std::env::temp_dir().join(format!("influxdb-files-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&directory);
+
+		let files = RotatingFiles {
+			max_bytes: 16,
+			..RotatingFiles::new(&directory)
+		};
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let options = buffered::Options {
+			max_lines: 1,
+			..Default::default()
+		};
+		let (client, handle) = files.buffered_with(shutdown_rx, options);
+
+		for value in 1..=3i64 {
+			let mut status = client
+				.write_with(|builder| builder.measurement("m").field("f", value).close_line())
+				.await
+				.unwrap();
+			let settled = |status: &Status| matches!(status, Status::Accepted | Status::Rejected);
+			assert_eq!(*status.wait_for(settled).await.unwrap(), Status::Accepted);
+		}
+		drop(client);
+		handle.await.unwrap().unwrap();
+
+		let mut paths: Vec<_> = std::fs::read_dir(&directory)
+			.unwrap()
+			.map(|entry| entry.unwrap().path())
+			.collect();
+		paths.sort();
+
+		// Each 7 byte line fits twice within 16 bytes.
+		let contents: Vec<_> = paths
+			.iter()
+			.map(|path| std::fs::read_to_string(path).unwrap())
+			.collect();
+		assert_eq!(contents, vec!["m f=1i\nm f=2i\n", "m f=3i\n"]);
+
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+}
@@ -1,49 +1,114 @@
-use std::{borrow, fmt};
+use std::{fmt, io::Write, time::Duration};
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
+use flate2::{write::GzEncoder, Compression};
+use reqwest::{header::CONTENT_ENCODING, StatusCode};
 use tokio::{
 	sync::{mpsc, watch},
 	task::JoinHandle,
 };
 
-use super::{buffered, LineBuilder, LINE_PROTOCOL_BUFFER_LEN};
+use super::{buffered, precision::Precision, LineBuilder, LINE_PROTOCOL_BUFFER_LEN};
+
+/// Bodies shorter than this are sent uncompressed by default, as gzip would
+/// save little and may even make them larger.
+pub const DEFAULT_GZIP_MIN_LEN: usize = 1024;
 
 #[derive(Debug)]
 pub struct Client {
 	client: reqwest::Client,
 	url: url::Url,
+	bucket: String,
+	precision: Precision,
+	gzip: bool,
+	gzip_min_len: usize,
 }
 
 impl Client {
-	pub(crate) fn new(client: reqwest::Client, url: url::Url) -> Self {
-		Self { client, url }
+	pub(crate) fn new(
+		client: reqwest::Client,
+		url: url::Url,
+		bucket: String,
+		precision: Precision,
+		gzip: bool,
+		gzip_min_len: usize,
+	) -> Self {
+		Self {
+			client,
+			url,
+			bucket,
+			precision,
+			gzip,
+			gzip_min_len,
+		}
 	}
 
-	pub async fn write<B: bytes::Buf>(&self, line_protocol: B) -> Result<(), WriteError>
-	where
-		B: Into<reqwest::Body>,
-	{
-		let response = match self
-			.client
-			.post(self.url.clone())
-			.body(line_protocol)
-			.send()
-			.await
-		{
+	pub async fn write<B: bytes::Buf>(&self, line_protocol: B) -> Result<(), WriteError> {
+		let mut line_protocol = line_protocol;
+		let body = line_protocol.copy_to_bytes(line_protocol.remaining());
+
+		let request = self.client.post(self.url.clone());
+		let request = match self.compress(&body) {
+			Some(compressed) => request.header(CONTENT_ENCODING, "gzip").body(compressed),
+			None => request.body(body),
+		};
+		let response = match request.send().await {
 			Ok(response) => response,
 			Err(error) => {
 				tracing::error!("error sending data to InfluxDB: {error:?}");
-				return Err(WriteError);
+				return Err(WriteError::Request(error));
 			}
 		};
 
 		let status = response.status();
-		if status == 204 {
+		if status == StatusCode::NO_CONTENT {
 			Ok(())
 		} else {
-			let body = response.text().await.unwrap();
+			let body = response.text().await.unwrap_or_default();
 			tracing::error!("influxdb response: {body}");
-			Err(WriteError)
+			Err(WriteError::from_response(status, body))
+		}
+	}
+
+	/// Returns the gzip-compressed body, if it should be compressed.
+	fn compress(&self, body: &[u8]) -> Option<Vec<u8>> {
+		if !self.gzip || body.len() < self.gzip_min_len {
+			return None;
+		}
+		match gzip(body) {
+			Ok(compressed) => Some(compressed),
+			Err(error) => {
+				tracing::warn!("failed to compress line protocol, sending uncompressed: {error:?}");
+				None
+			}
+		}
+	}
+
+	/// Writes `line_protocol`, retrying up to `retries` times while the
+	/// failure is transient, such as a connection error, 429 or 5xx. The wait
+	/// starts at `base_delay` and doubles after each attempt. Permanent
+	/// failures, such as rejected line protocol, are returned immediately.
+	pub async fn write_with_retry(
+		&self,
+		line_protocol: Bytes,
+		retries: u32,
+		base_delay: Duration,
+	) -> Result<(), WriteError> {
+		let mut delay = base_delay;
+		let mut attempt = 0;
+		loop {
+			match self.write(line_protocol.clone()).await {
+				Err(error) if error.is_retryable() && attempt < retries => {
+					attempt += 1;
+					tracing::warn!(
+						"retrying write to bucket '{}' in {delay:?} ({attempt} of {retries})",
+						self.bucket()
+					);
+					tokio::time::sleep(delay).await;
+					delay *= 2;
+				}
+				result => return result,
+			}
 		}
 	}
 
@@ -59,14 +124,19 @@ impl Client {
 	}
 
 	/// Returns the name of the bucket data is written to.
-	pub fn bucket(&self) -> borrow::Cow<'_, str> {
-		let (_, bucket) = self
-			.url
-			.query_pairs()
-			.find(|(key, _)| key == "bucket")
-			.expect("bucket query parameter should be set");
+	pub fn bucket(&self) -> &str {
+		&self.bucket
+	}
 
-		bucket
+	/// Returns the precision of the timestamps written.
+	pub fn precision(&self) -> &Precision {
+		&self.precision
+	}
+
+	/// Returns the write endpoint with its query parameters. The token is
+	/// sent in a header, never in the URL.
+	pub fn write_url(&self) -> &url::Url {
+		&self.url
 	}
 
 	/// Creates a buffered client with the default options.
@@ -96,13 +166,288 @@ impl Client {
 	}
 }
 
+fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+	let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), Compression::fast());
+	encoder.write_all(body)?;
+	encoder.finish()
+}
+
+/// Why a write to InfluxDB failed.
 #[derive(Debug)]
-pub struct WriteError;
+pub enum WriteError {
+	/// The request could not be sent, or no response was received.
+	Request(reqwest::Error),
+	/// InfluxDB did not accept the authorization token.
+	Unauthorized,
+	/// InfluxDB could not parse the line protocol.
+	BadRequest { body: String },
+	/// InfluxDB parsed the line protocol but refused some of it, such as for
+	/// a field type conflict.
+	UnprocessableEntity { body: String },
+	/// Any other unsuccessful response.
+	Status { code: StatusCode, body: String },
+}
+
+impl WriteError {
+	fn from_response(code: StatusCode, body: String) -> Self {
+		match code {
+			StatusCode::UNAUTHORIZED => Self::Unauthorized,
+			StatusCode::BAD_REQUEST => Self::BadRequest { body },
+			StatusCode::UNPROCESSABLE_ENTITY => Self::UnprocessableEntity { body },
+			code => Self::Status { code, body },
+		}
+	}
+
+	/// Returns the HTTP status returned by InfluxDB, if a response was
+	/// received.
+	pub fn status(&self) -> Option<StatusCode> {
+		match self {
+			Self::Request(error) => error.status(),
+			Self::Unauthorized => Some(StatusCode::UNAUTHORIZED),
+			Self::BadRequest { .. } => Some(StatusCode::BAD_REQUEST),
+			Self::UnprocessableEntity { .. } => Some(StatusCode::UNPROCESSABLE_ENTITY),
+			Self::Status { code, .. } => Some(*code),
+		}
+	}
+
+	/// Returns the body of InfluxDB's response, which explains the failure.
+	pub fn body(&self) -> Option<&str> {
+		match self {
+			Self::BadRequest { body }
+			| Self::UnprocessableEntity { body }
+			| Self::Status { body, .. } => Some(body),
+			Self::Request(_) | Self::Unauthorized => None,
+		}
+	}
+
+	/// Returns true if InfluxDB rejected the authorization token. Retrying
+	/// will not succeed until the token is replaced.
+	pub fn is_auth_failure(&self) -> bool {
+		matches!(
+			self,
+			Self::Unauthorized
+				| Self::Status {
+					code: StatusCode::FORBIDDEN,
+					..
+				}
+		)
+	}
+
+	/// Returns true if InfluxDB refused the line protocol itself, for example
+	/// a malformed line or a field type conflict. Resending it cannot succeed.
+	pub fn is_rejected(&self) -> bool {
+		matches!(
+			self,
+			Self::BadRequest { .. } | Self::UnprocessableEntity { .. }
+		)
+	}
+
+	/// Returns the lines, numbered from 1, which InfluxDB reported refusing
+	/// if it wrote the rest of the line protocol, or `None` if it wrote
+	/// none of it. The list is empty when InfluxDB did not say which lines
+	/// it refused.
+	///
+	/// Resending any of a partial write would write its accepted points
+	/// again.
+	pub fn partial_write(&self) -> Option<Vec<usize>> {
+		let body = self.body().filter(|_| self.is_rejected())?;
+		if !body.contains("partial write") {
+			return None;
+		}
+		let lines = body
+			.match_indices("line ")
+			.filter_map(|(index, pattern)| {
+				let (number, _) = body[index + pattern.len()..].split_once(':')?;
+				number.parse().ok()
+			})
+			.collect();
+		Some(lines)
+	}
+
+	/// Returns true if the write may succeed if sent again shortly: InfluxDB
+	/// could not be reached, was rate limiting or had a server error.
+	pub fn is_retryable(&self) -> bool {
+		match self {
+			Self::Request(_) => true,
+			Self::Status { code, .. } => {
+				*code == StatusCode::TOO_MANY_REQUESTS || code.is_server_error()
+			}
+			_ => false,
+		}
+	}
+}
 
 impl fmt::Display for WriteError {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		write!(f, "{self:?}")
+		match self {
+			Self::Request(error) => write!(f, "failed to send line protocol to InfluxDB: {error}"),
+			Self::Unauthorized => write!(f, "InfluxDB did not accept the authorization token"),
+			Self::BadRequest { body } => {
+				write!(f, "InfluxDB could not parse the line protocol: {body}")
+			}
+			Self::UnprocessableEntity { body } => {
+				write!(f, "InfluxDB refused the line protocol: {body}")
+			}
+			Self::Status { code, body } => write!(f, "InfluxDB responded {code}: {body}"),
+		}
+	}
+}
+
+impl std::error::Error for WriteError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::Request(error) => Some(error),
+			_ => None,
+		}
 	}
 }
 
-impl std::error::Error for WriteError {}
+#[cfg(test)]
+mod tests {
+	use super::WriteError;
+	use crate::mock::{self, MockResponse};
+	use bytes::Bytes;
+	use flate2::read::GzDecoder;
+	use std::{io::Read, time::Duration};
+
+	#[tokio::test]
+	async fn error_responses_are_mapped() {
+		let body = r#"{"code":"unprocessable entity","message":"failure writing points to database: partial write: field type conflict"}"#;
+		let (url, _server) = mock::serve(vec![
+			MockResponse::new(
+				401,
+				r#"{"code":"unauthorized","message":"unauthorized access"}"#,
+			),
+			MockResponse::new(422, body),
+		])
+		.await;
+		let client = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build();
+
+		let error = client
+			.write(Bytes::from_static(b"m f=1i\n"))
+			.await
+			.unwrap_err();
+		assert!(matches!(error, WriteError::Unauthorized));
+		assert!(error.is_auth_failure());
+
+		let error = client
+			.write(Bytes::from_static(b"m f=\"one\"\n"))
+			.await
+			.unwrap_err();
+		match &error {
+			WriteError::UnprocessableEntity { body: received } => assert_eq!(received, body),
+			error => panic!("expected UnprocessableEntity, got {error:?}"),
+		}
+		assert!(error.is_rejected());
+		assert_eq!(error.partial_write(), Some(vec![]));
+	}
+
+	#[test]
+	fn partial_write_lines_are_parsed() {
+		let error = WriteError::BadRequest {
+			body: r#"{"code":"invalid","message":"partial write has occurred, errors encountered on line(s): line 2: invalid field format; line 5: missing tag value"}"#.into(),
+		};
+		assert_eq!(error.partial_write(), Some(vec![2, 5]));
+
+		let error = WriteError::BadRequest {
+			body: r#"{"code":"invalid","message":"unable to parse 'm f=': missing field value"}"#
+				.into(),
+		};
+		assert_eq!(error.partial_write(), None);
+	}
+
+	#[tokio::test]
+	async fn small_writes_are_not_compressed() {
+		let responses = vec![MockResponse::new(204, ""); 2];
+		let (url, server) = mock::serve(responses).await;
+		let client = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.gzip_min_len(64)
+			.build();
+
+		let small = "fizzle,reason=started running=true\n";
+		let large = small.repeat(4);
+		client.write(Bytes::from(small)).await.unwrap();
+		client.write(Bytes::from(large.clone())).await.unwrap();
+
+		let requests = server.await.unwrap();
+		assert_eq!(requests[0].header("content-encoding"), None);
+		assert_eq!(requests[0].body, small.as_bytes());
+		assert_eq!(requests[1].header("content-encoding"), Some("gzip"));
+		let mut decoded = String::new();
+		GzDecoder::new(requests[1].body.as_slice())
+			.read_to_string(&mut decoded)
+			.unwrap();
+		assert_eq!(decoded, large);
+	}
+
+	#[tokio::test]
+	async fn large_writes_are_compressed() {
+		let (url, server) = mock::serve(vec![MockResponse::new(204, "")]).await;
+		let client = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build();
+
+		let line_protocol: String = (0..100)
+			.map(|i| format!("telemetry,device=kitchen/kettle power={i}i {i}\n"))
+			.collect();
+		client
+			.write(Bytes::from(line_protocol.clone()))
+			.await
+			.unwrap();
+
+		let requests = server.await.unwrap();
+		assert_eq!(requests[0].header("content-encoding"), Some("gzip"));
+		let mut decoded = String::new();
+		GzDecoder::new(requests[0].body.as_slice())
+			.read_to_string(&mut decoded)
+			.unwrap();
+		assert_eq!(decoded, line_protocol);
+	}
+
+	#[tokio::test]
+	async fn transient_failures_are_retried() {
+		let (url, server) = mock::serve(vec![
+			MockResponse::new(503, ""),
+			MockResponse::new(503, ""),
+			MockResponse::new(204, ""),
+		])
+		.await;
+		let client = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build();
+
+		client
+			.write_with_retry(Bytes::from_static(b"m f=1i\n"), 3, Duration::from_millis(1))
+			.await
+			.unwrap();
+		assert_eq!(server.await.unwrap().len(), 3);
+	}
+
+	#[tokio::test]
+	async fn rejected_write_is_not_retried() {
+		let (url, server) = mock::serve(vec![MockResponse::new(
+			400,
+			r#"{"code":"invalid","message":"unable to parse 'm f=': missing field value"}"#,
+		)])
+		.await;
+		let client = crate::Client::new(url, "token")
+			.unwrap()
+			.write_to_bucket("test")
+			.build();
+
+		let error = client
+			.write_with_retry(Bytes::from_static(b"m f=\n"), 3, Duration::from_millis(1))
+			.await
+			.unwrap_err();
+		assert!(error.is_rejected());
+		assert!(!error.is_retryable());
+		assert_eq!(server.await.unwrap().len(), 1);
+	}
+}
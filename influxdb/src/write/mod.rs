@@ -1,19 +1,208 @@
-use bytes::BytesMut;
-use influxdb_line_protocol::{builder::BeforeMeasurement, LineProtocolBuilder};
+use bytes::{BufMut, Bytes, BytesMut};
+use influxdb_line_protocol::{
+	builder::{AfterField, BeforeMeasurement, FieldValue},
+	LineProtocolBuilder,
+};
 
 pub mod buffered;
 pub mod builder;
+pub mod files;
 pub mod immediate;
+pub mod multi;
 pub mod precision;
+pub mod router;
+pub mod sink;
+mod wal;
 
 pub type LineBuilder = LineProtocolBuilder<BytesMut, BeforeMeasurement>;
 
+/// Adds fields which are only written when present.
+pub trait OptionalField: Sized {
+	/// Adds the `key` field if `value` is `Some`.
+	fn optional_field<F: FieldValue>(self, key: &str, value: Option<F>) -> Self;
+}
+
+impl<B: BufMut> OptionalField for LineProtocolBuilder<B, AfterField> {
+	fn optional_field<F: FieldValue>(self, key: &str, value: Option<F>) -> Self {
+		match value {
+			Some(value) => self.field(key, value),
+			None => self,
+		}
+	}
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Status {
 	Init,
 	Buffered,
 	Accepted,
+	/// InfluxDB refused the line protocol as invalid. It will not be retried.
+	Rejected,
+	/// InfluxDB was unavailable at shutdown. The write is kept in the
+	/// write-ahead log and will be replayed on the next start.
+	Deferred,
 }
 
 /// Initial size of the buffer to use with LineProtocolBuilder instances.
 pub const LINE_PROTOCOL_BUFFER_LEN: usize = 1024;
+
+/// Ensures every line in `buffer` ends with exactly one `\n`.
+///
+/// Carriage returns before a newline are stripped, blank lines are dropped
+/// and a missing final newline is added, so that counting `\n` bytes counts
+/// lines. Well-formed buffers are returned without copying.
+pub fn normalize_lines(buffer: Bytes) -> Bytes {
+	let well_formed = !buffer.contains(&b'\r')
+		&& (buffer.is_empty()
+			|| (buffer.ends_with(b"\n")
+				&& !buffer.starts_with(b"\n")
+				&& !buffer.windows(2).any(|pair| pair == b"\n\n")));
+	if well_formed {
+		return buffer;
+	}
+
+	let mut normalized = BytesMut::with_capacity(buffer.len() + 1);
+	for line in buffer.split(|&byte| byte == b'\n') {
+		let end = line
+			.iter()
+			.rposition(|&byte| byte != b'\r')
+			.map_or(0, |index| index + 1);
+		if end == 0 {
+			continue;
+		}
+		normalized.extend_from_slice(&line[..end]);
+		normalized.put_u8(b'\n');
+	}
+	normalized.freeze()
+}
+
+/// Sorts the tags of every line lexically by key, as InfluxDB parses
+/// sorted tags fastest. Already sorted buffers are returned without copying.
+pub fn sort_tags(buffer: Bytes) -> Bytes {
+	let mut sorted = BytesMut::with_capacity(buffer.len());
+	let mut changed = false;
+
+	for line in buffer.split_inclusive(|&byte| byte == b'\n') {
+		// The series key, measurement and tags, ends at the first unescaped
+		// space.
+		let key_end = find_unescaped(line, b' ').unwrap_or(line.len());
+		let (series, rest) = line.split_at(key_end);
+		let mut parts = split_unescaped(series, b',');
+		let tags = &mut parts[1..];
+
+		if tags
+			.windows(2)
+			.all(|pair| tag_key(pair[0]) <= tag_key(pair[1]))
+		{
+			sorted.extend_from_slice(line);
+			continue;
+		}
+
+		changed = true;
+		tags.sort_by(|a, b| tag_key(a).cmp(tag_key(b)));
+		sorted.extend_from_slice(parts[0]);
+		for tag in &parts[1..] {
+			sorted.put_u8(b',');
+			sorted.extend_from_slice(tag);
+		}
+		sorted.extend_from_slice(rest);
+	}
+
+	if changed {
+		sorted.freeze()
+	} else {
+		buffer
+	}
+}
+
+fn find_unescaped(bytes: &[u8], needle: u8) -> Option<usize> {
+	let mut escaped = false;
+	for (index, &byte) in bytes.iter().enumerate() {
+		if escaped {
+			escaped = false;
+		} else if byte == b'\\' {
+			escaped = true;
+		} else if byte == needle {
+			return Some(index);
+		}
+	}
+	None
+}
+
+fn split_unescaped(mut bytes: &[u8], separator: u8) -> Vec<&[u8]> {
+	let mut parts = Vec::new();
+	while let Some(index) = find_unescaped(bytes, separator) {
+		parts.push(&bytes[..index]);
+		bytes = &bytes[index + 1..];
+	}
+	parts.push(bytes);
+	parts
+}
+
+fn tag_key(tag: &[u8]) -> &[u8] {
+	&tag[..find_unescaped(tag, b'=').unwrap_or(tag.len())]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{normalize_lines, sort_tags, OptionalField};
+	use crate::LineBuilder;
+	use bytes::{Bytes, BytesMut};
+
+	#[test]
+	fn crlf_is_normalized() {
+		let buffer = Bytes::from_static(b"a f=1i\r\nb f=2i\r\n\r\nc f=3i");
+		let normalized = normalize_lines(buffer);
+		assert_eq!(&normalized[..], b"a f=1i\nb f=2i\nc f=3i\n");
+		assert_eq!(normalized.iter().filter(|&&byte| byte == b'\n').count(), 3);
+	}
+
+	#[test]
+	fn well_formed_is_unchanged() {
+		let buffer = Bytes::from_static(b"a f=1i\nb f=2i\n");
+		assert_eq!(normalize_lines(buffer.clone()), buffer);
+		assert_eq!(normalize_lines(Bytes::new()), Bytes::new());
+	}
+
+	#[test]
+	fn tags_are_sorted() {
+		let buffer = LineBuilder::new_with(BytesMut::new())
+			.measurement("telemetry")
+			.tag("room", "kitchen")
+			.tag("device", "kettle")
+			.tag("a=b", "escaped")
+			.field("power", 120i64)
+			.close_line()
+			.measurement("telemetry")
+			.tag("device", "toaster")
+			.tag("room", "kitchen")
+			.field("power", 80i64)
+			.close_line()
+			.build()
+			.freeze();
+
+		assert_eq!(
+			&sort_tags(buffer)[..],
+			b"telemetry,a\\=b=escaped,device=kettle,room=kitchen power=120i\ntelemetry,device=toaster,room=kitchen power=80i\n"
+		);
+	}
+
+	#[test]
+	fn absent_fields_are_omitted() {
+		let buffer = LineBuilder::new_with(BytesMut::new())
+			.measurement("telemetry")
+			.field("power", 120i64)
+			.optional_field("voltage", Some(240i64))
+			.optional_field::<f64>("power_factor", None)
+			.close_line()
+			.build();
+
+		assert_eq!(&buffer[..], b"telemetry power=120i,voltage=240i\n");
+	}
+
+	#[test]
+	fn sorted_tags_are_unchanged() {
+		let buffer = Bytes::from_static(b"m,a=1,b=2 f=1i\nm f=2i\n");
+		assert_eq!(sort_tags(buffer.clone()), buffer);
+	}
+}
@@ -0,0 +1,210 @@
+use super::{buffered, Status};
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio::{
+	sync::{mpsc, oneshot, watch},
+	task::JoinHandle,
+};
+
+/// When a write to several sinks counts as accepted.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AcceptancePolicy {
+	/// Every sink must accept the write.
+	#[default]
+	All,
+	/// At least one sink must accept the write.
+	Any,
+}
+
+impl AcceptancePolicy {
+	fn outcome(self, accepted: usize, total: usize) -> Status {
+		let accepted = match self {
+			Self::All => accepted == total,
+			Self::Any => accepted > 0,
+		};
+		if accepted {
+			Status::Accepted
+		} else {
+			Status::Rejected
+		}
+	}
+}
+
+type Pending = oneshot::Sender<watch::Receiver<Status>>;
+
+/// Copies every write to several buffered clients.
+///
+/// Each sink is fed from its own queue, so a sink which fails or falls
+/// behind does not hold up the others. A write whose queue is full is
+/// counted as failed for that sink.
+#[derive(Debug)]
+pub struct MultiSink {
+	policy: AcceptancePolicy,
+	sinks: Vec<(buffered::Client, JoinHandle<anyhow::Result<()>>)>,
+}
+
+impl MultiSink {
+	pub fn new(policy: AcceptancePolicy) -> Self {
+		Self {
+			policy,
+			sinks: Vec::new(),
+		}
+	}
+
+	/// Adds a sink, along with the task driving it.
+	pub fn with_sink(self, client: buffered::Client, task: JoinHandle<anyhow::Result<()>>) -> Self {
+		let mut s = self;
+		s.sinks.push((client, task));
+		s
+	}
+
+	/// Creates a buffered client whose writes are copied to every sink. The
+	/// status of each write settles once every sink has settled it.
+	pub fn buffered(
+		self,
+		shutdown_signal: watch::Receiver<bool>,
+	) -> (buffered::Client, JoinHandle<anyhow::Result<()>>) {
+		let (tx, rx) = mpsc::channel(buffered::Options::default().channel_len);
+		let handle = tokio::spawn(multi_sink_task(self, rx, shutdown_signal));
+		(buffered::Client::new(tx), handle)
+	}
+}
+
+async fn multi_sink_task(
+	multi_sink: MultiSink,
+	mut channel: mpsc::Receiver<(Bytes, watch::Sender<Status>)>,
+	mut shutdown_signal: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+	let MultiSink { policy, sinks } = multi_sink;
+
+	let mut queues = Vec::new();
+	let mut forwarders = Vec::new();
+	let mut tasks = Vec::new();
+	for (client, task) in sinks {
+		let (tx, rx) = mpsc::channel(buffered::Options::default().channel_len);
+		queues.push(tx);
+		forwarders.push(tokio::spawn(forward_task(client, rx)));
+		tasks.push(task);
+	}
+
+	let mut shutdown = false;
+	loop {
+		let message = tokio::select! {
+			biased;
+
+			message = channel.recv() => message,
+			_ = shutdown_signal.changed(), if !shutdown => {
+				// Stop accepting writes, but pass on whatever is already queued.
+				shutdown = true;
+				channel.close();
+				continue;
+			}
+		};
+
+		let Some((buffer, status)) = message else {
+			break;
+		};
+
+		let mut results = Vec::with_capacity(queues.len());
+		for queue in &queues {
+			let (tx, rx) = oneshot::channel();
+			if queue.try_send((buffer.clone(), tx)).is_err() {
+				tracing::warn!("sink queue is full or closed, write not copied to it");
+			}
+			results.push(rx);
+		}
+
+		status.send_replace(Status::Buffered);
+		tokio::spawn(settle(policy, results, status));
+	}
+
+	// Let each sink finish independently; one failing does not fail the rest.
+	drop(queues);
+	for forwarder in forwarders {
+		forwarder.await?;
+	}
+	for task in tasks {
+		match task.await {
+			Ok(Ok(())) => {}
+			Ok(Err(error)) => tracing::error!("sink task failed: {error:?}"),
+			Err(error) => tracing::error!("sink task panicked: {error:?}"),
+		}
+	}
+
+	Ok(())
+}
+
+async fn forward_task(client: buffered::Client, mut queue: mpsc::Receiver<(Bytes, Pending)>) {
+	while let Some((buffer, pending)) = queue.recv().await {
+		// A closed sink drops `pending`, which counts as a failure.
+		if let Ok(status) = client.write_raw(buffer).await {
+			let _ = pending.send(status);
+		}
+	}
+}
+
+async fn settle(
+	policy: AcceptancePolicy,
+	results: Vec<oneshot::Receiver<watch::Receiver<Status>>>,
+	status: watch::Sender<Status>,
+) {
+	let total = results.len();
+	let mut accepted = 0;
+	for result in results {
+		let Ok(mut sink_status) = result.await else {
+			continue;
+		};
+		let settled = sink_status
+			.wait_for(|status| matches!(status, Status::Accepted | Status::Rejected))
+			.await
+			.map(|status| *status == Status::Accepted);
+		if let Ok(true) = settled {
+			accepted += 1;
+		}
+	}
+
+	if accepted < total {
+		tracing::warn!("{} of {total} sinks failed a write", total - accepted);
+	}
+	status.send_replace(policy.outcome(accepted, total));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{AcceptancePolicy, MultiSink};
+	use crate::{util::channel_buffered_client, Status};
+	use tokio::sync::watch;
+
+	async fn outcome(policy: AcceptancePolicy) -> Status {
+		let (influxdb, mut influxdb_rx) = channel_buffered_client(4);
+		// A file sink which has failed and stopped accepting writes.
+		let (file, file_rx) = channel_buffered_client(4);
+		drop(file_rx);
+
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let (client, _handle) = MultiSink::new(policy)
+			.with_sink(influxdb, tokio::spawn(async { Ok(()) }))
+			.with_sink(file, tokio::spawn(async { Ok(()) }))
+			.buffered(shutdown_rx);
+
+		let mut status = client
+			.write_with(|builder| builder.measurement("m").field("f", 1i64).close_line())
+			.await
+			.unwrap();
+
+		let (buffer, influxdb_status) = influxdb_rx.recv().await.unwrap();
+		assert_eq!(&buffer[..], b"m f=1i\n");
+		influxdb_status.send_replace(Status::Accepted);
+
+		let settled = |status: &Status| matches!(status, Status::Accepted | Status::Rejected);
+		let outcome = status.wait_for(settled).await.map(|status| status.clone());
+		outcome.unwrap()
+	}
+
+	#[tokio::test]
+	async fn failed_file_sink_is_isolated() {
+		assert_eq!(outcome(AcceptancePolicy::Any).await, Status::Accepted);
+		assert_eq!(outcome(AcceptancePolicy::All).await, Status::Rejected);
+	}
+}
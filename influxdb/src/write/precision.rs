@@ -1,3 +1,5 @@
+use std::time::SystemTime;
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Precision {
 	Nanoseconds,
@@ -15,6 +17,26 @@ impl Precision {
 			Self::Seconds => "s",
 		}
 	}
+
+	/// Converts a timestamp at this precision to nanoseconds.
+	pub fn to_nanos(&self, timestamp: i64) -> i128 {
+		let scale = match self {
+			Self::Nanoseconds => 1,
+			Self::Microseconds => 1_000,
+			Self::Milliseconds => 1_000_000,
+			Self::Seconds => 1_000_000_000,
+		};
+		timestamp as i128 * scale
+	}
+
+	/// Returns the current time as a timestamp at this precision.
+	pub fn now(&self) -> i64 {
+		let nanos = SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_nanos() as i128;
+		(nanos / self.to_nanos(1)) as i64
+	}
 }
 
 impl ToString for Precision {
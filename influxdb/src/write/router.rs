@@ -0,0 +1,147 @@
+use super::{buffered, find_unescaped, Status};
+use bytes::{Bytes, BytesMut};
+use std::collections::BTreeMap;
+use tokio::{
+	sync::{mpsc, watch},
+	task::JoinHandle,
+};
+
+/// Sends each line to a buffered client chosen by its measurement, such as
+/// one per bucket.
+#[derive(Debug)]
+pub struct MeasurementRouter {
+	clients: Vec<(buffered::Client, JoinHandle<anyhow::Result<()>>)>,
+	routes: BTreeMap<String, usize>,
+}
+
+impl MeasurementRouter {
+	/// Creates a router sending lines without a route to `client`.
+	pub fn new(client: buffered::Client, task: JoinHandle<anyhow::Result<()>>) -> Self {
+		Self {
+			clients: vec![(client, task)],
+			routes: BTreeMap::new(),
+		}
+	}
+
+	/// Sends lines for any of `measurements` to `client`.
+	pub fn with_route<I, S>(
+		self,
+		measurements: I,
+		client: buffered::Client,
+		task: JoinHandle<anyhow::Result<()>>,
+	) -> Self
+	where
+		I: IntoIterator<Item = S>,
+		S: Into<String>,
+	{
+		let mut s = self;
+		let index = s.clients.len();
+		s.clients.push((client, task));
+		for measurement in measurements {
+			s.routes.insert(measurement.into(), index);
+		}
+		s
+	}
+
+	/// Creates a buffered client whose lines are routed by measurement. A
+	/// write is accepted once every client it was routed to accepts it.
+	pub fn buffered(
+		self,
+		shutdown_signal: watch::Receiver<bool>,
+	) -> (buffered::Client, JoinHandle<anyhow::Result<()>>) {
+		let (tx, rx) = mpsc::channel(buffered::Options::default().channel_len);
+		let handle = tokio::spawn(router_task(self, rx, shutdown_signal));
+		(buffered::Client::new(tx), handle)
+	}
+
+	/// Splits `buffer` into one buffer per client, keeping line order.
+	fn split(&self, buffer: &[u8]) -> Vec<BytesMut> {
+		let mut buffers = vec![BytesMut::new(); self.clients.len()];
+		for line in buffer.split_inclusive(|&byte| byte == b'\n') {
+			let comma = find_unescaped(line, b',').unwrap_or(line.len());
+			let space = find_unescaped(line, b' ').unwrap_or(line.len());
+			let end = comma.min(space);
+			let index = std::str::from_utf8(&line[..end])
+				.ok()
+				.and_then(|measurement| self.routes.get(measurement))
+				.copied()
+				.unwrap_or(0);
+			buffers[index].extend_from_slice(line);
+		}
+		buffers
+	}
+}
+
+async fn router_task(
+	router: MeasurementRouter,
+	mut channel: mpsc::Receiver<(Bytes, watch::Sender<Status>)>,
+	mut shutdown_signal: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+	let mut shutdown = false;
+	loop {
+		let message = tokio::select! {
+			biased;
+
+			message = channel.recv() => message,
+			_ = shutdown_signal.changed(), if !shutdown => {
+				// Stop accepting writes, but route whatever is already queued.
+				shutdown = true;
+				channel.close();
+				continue;
+			}
+		};
+
+		let Some((buffer, status)) = message else {
+			break;
+		};
+
+		let mut results = Vec::new();
+		let mut failed = false;
+		for (routed, (client, _)) in router.split(&buffer).into_iter().zip(&router.clients) {
+			if routed.is_empty() {
+				continue;
+			}
+			match client.write_raw(routed.freeze()).await {
+				Ok(result) => results.push(result),
+				Err(_) => failed = true,
+			}
+		}
+
+		status.send_replace(Status::Buffered);
+		tokio::spawn(async move {
+			let settled = |status: &Status| {
+				matches!(
+					status,
+					Status::Accepted | Status::Rejected | Status::Deferred
+				)
+			};
+			let mut deferred = false;
+			for mut result in results {
+				match result.wait_for(settled).await.map(|status| status.clone()) {
+					Ok(Status::Accepted) => {}
+					Ok(Status::Deferred) => deferred = true,
+					_ => failed = true,
+				}
+			}
+			status.send_replace(if failed {
+				Status::Rejected
+			} else if deferred {
+				Status::Deferred
+			} else {
+				Status::Accepted
+			});
+		});
+	}
+
+	let MeasurementRouter { clients, .. } = router;
+	for (client, task) in clients {
+		drop(client);
+		match task.await {
+			Ok(Ok(())) => {}
+			Ok(Err(error)) => tracing::error!("routed write task failed: {error:?}"),
+			Err(error) => tracing::error!("routed write task panicked: {error:?}"),
+		}
+	}
+
+	Ok(())
+}
@@ -0,0 +1,253 @@
+use super::{buffered, Status};
+use bytes::{Bytes, BytesMut};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tokio::{
+	io::AsyncWriteExt,
+	net::{TcpStream, UdpSocket},
+	sync::{mpsc, watch},
+	task::JoinHandle,
+};
+
+/// Largest UDP datagram sent. Batches are split at line boundaries to stay
+/// within a typical Ethernet MTU; a single longer line is sent on its own.
+const MAX_DATAGRAM_LEN: usize = 1400;
+
+/// A socket which accepts line protocol directly, such as a Telegraf socket
+/// listener, bypassing the InfluxDB HTTP API and its authentication.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LineProtocolSink {
+	Udp(SocketAddr),
+	Tcp(SocketAddr),
+}
+
+impl LineProtocolSink {
+	/// Creates a buffered client whose writes are sent to the socket as they
+	/// arrive.
+	///
+	/// Like the other sinks, it is written to through a [`buffered::Client`],
+	/// so it can stand in for, or be combined with, the InfluxDB HTTP client.
+	///
+	/// Neither transport confirms receipt, so writes are marked
+	/// [`Status::Accepted`] once they have been sent. A batch which cannot be
+	/// sent is resent [`buffered::Options::write_retries`] times, waiting
+	/// [`buffered::Options::retry_base_delay`] and doubling between each, and
+	/// then marked [`Status::Rejected`].
+	pub fn buffered(
+		self,
+		shutdown_signal: watch::Receiver<bool>,
+	) -> (buffered::Client, JoinHandle<anyhow::Result<()>>) {
+		let (tx, rx) = mpsc::channel(buffered::Options::default().channel_len);
+		let handle = tokio::spawn(sink_task(self, rx, shutdown_signal));
+		(buffered::Client::new(tx), handle)
+	}
+}
+
+enum Transport {
+	Udp(UdpSocket),
+	Tcp(SocketAddr, Option<TcpStream>),
+}
+
+impl Transport {
+	async fn send(&mut self, batch: &[u8]) -> std::io::Result<()> {
+		match self {
+			Self::Udp(socket) => send_datagrams(socket, batch).await,
+			Self::Tcp(addr, stream) => send_stream(stream, *addr, batch).await,
+		}
+	}
+}
+
+async fn sink_task(
+	sink: LineProtocolSink,
+	mut channel: mpsc::Receiver<(Bytes, watch::Sender<Status>)>,
+	mut shutdown_signal: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+	let options = buffered::Options::default();
+	let mut transport = match sink {
+		LineProtocolSink::Udp(addr) => {
+			let bind: SocketAddr = match addr {
+				SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+				SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+			};
+			let socket = UdpSocket::bind(bind).await?;
+			socket.connect(addr).await?;
+			Transport::Udp(socket)
+		}
+		LineProtocolSink::Tcp(addr) => Transport::Tcp(addr, None),
+	};
+
+	let mut shutdown = false;
+	loop {
+		let message = tokio::select! {
+			biased;
+
+			message = channel.recv() => message,
+			_ = shutdown_signal.changed(), if !shutdown => {
+				// Stop accepting writes, but send whatever is already queued.
+				shutdown = true;
+				channel.close();
+				continue;
+			}
+		};
+
+		let Some(first) = message else {
+			break;
+		};
+
+		// Send everything already waiting as one batch.
+		let mut batch = BytesMut::new();
+		let mut statuses = Vec::new();
+		let mut next = Some(first);
+		while let Some((buffer, status)) = next {
+			status.send_replace(Status::Buffered);
+			batch.extend_from_slice(&buffer);
+			statuses.push(status);
+			next = channel.try_recv().ok();
+		}
+
+		let mut result = transport.send(&batch).await;
+		let mut delay = options.retry_base_delay;
+		for attempt in 1..=options.write_retries {
+			let Err(error) = &result else {
+				break;
+			};
+			tracing::warn!(
+				"error sending line protocol to {sink:?}, retrying in {delay:?} ({attempt} of {}): {error:?}",
+				options.write_retries
+			);
+			tokio::time::sleep(delay).await;
+			delay *= 2;
+			result = transport.send(&batch).await;
+		}
+
+		let status = match result {
+			Ok(()) => Status::Accepted,
+			Err(error) => {
+				tracing::error!(
+					"error sending {} bytes of line protocol to {sink:?}, dropping it: {error:?}",
+					batch.len()
+				);
+				Status::Rejected
+			}
+		};
+		for entry in statuses {
+			entry.send_replace(status.clone());
+		}
+	}
+
+	tracing::debug!("line protocol sink task for {sink:?} stopped");
+	Ok(())
+}
+
+async fn send_datagrams(socket: &UdpSocket, batch: &[u8]) -> std::io::Result<()> {
+	for datagram in split_lines(batch, MAX_DATAGRAM_LEN) {
+		if datagram.len() > MAX_DATAGRAM_LEN {
+			tracing::warn!(
+				"line of {} bytes exceeds the {MAX_DATAGRAM_LEN} byte datagram limit",
+				datagram.len()
+			);
+		}
+		socket.send(datagram).await?;
+	}
+	Ok(())
+}
+
+async fn send_stream(
+	stream: &mut Option<TcpStream>,
+	addr: SocketAddr,
+	batch: &[u8],
+) -> std::io::Result<()> {
+	let connection = match stream.take() {
+		Some(connection) => connection,
+		None => TcpStream::connect(addr).await?,
+	};
+	let connection = stream.insert(connection);
+
+	let result = connection.write_all(batch).await;
+
+	// Reconnect on the next batch rather than writing to a broken stream.
+	if result.is_err() {
+		*stream = None;
+	}
+	result
+}
+
+/// Splits line protocol into chunks of whole lines no longer than `max_len`,
+/// except where a single line is itself longer.
+fn split_lines(buffer: &[u8], max_len: usize) -> Vec<&[u8]> {
+	let mut chunks = Vec::new();
+	let mut start = 0;
+	let mut end = 0;
+
+	for line in buffer.split_inclusive(|&byte| byte == b'\n') {
+		if end > start && end - start + line.len() > max_len {
+			chunks.push(&buffer[start..end]);
+			start = end;
+		}
+		end += line.len();
+	}
+	if end > start {
+		chunks.push(&buffer[start..end]);
+	}
+
+	chunks
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{split_lines, LineProtocolSink};
+	use crate::Status;
+	use std::net::TcpListener;
+	use tokio::{net::UdpSocket, sync::watch};
+
+	#[test]
+	fn split_at_line_boundaries() {
+		let buffer = b"m f=1i\nm f=2i\nm f=3i\nlong_measurement f=4i\n";
+		assert_eq!(
+			split_lines(buffer, 14),
+			vec![
+				&b"m f=1i\nm f=2i\n"[..],
+				&b"m f=3i\n"[..],
+				&b"long_measurement f=4i\n"[..],
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn udp_sink_delivers_lines() {
+		let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let (client, _handle) = LineProtocolSink::Udp(addr).buffered(shutdown_rx);
+		client
+			.write_with(|builder| builder.measurement("m").field("f", 1i64).close_line())
+			.await
+			.unwrap();
+
+		let mut datagram = [0; 1500];
+		let len = listener.recv(&mut datagram).await.unwrap();
+		assert_eq!(&datagram[..len], b"m f=1i\n");
+	}
+
+	#[tokio::test]
+	async fn unsendable_writes_are_rejected() {
+		// Bind then drop a listener, leaving an address which refuses
+		// connections.
+		let addr = TcpListener::bind("127.0.0.1:0")
+			.unwrap()
+			.local_addr()
+			.unwrap();
+
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let (client, _handle) = LineProtocolSink::Tcp(addr).buffered(shutdown_rx);
+		let mut status = client
+			.write_with(|builder| builder.measurement("m").field("f", 1i64).close_line())
+			.await
+			.unwrap();
+
+		let settled = |status: &Status| matches!(status, Status::Accepted | Status::Rejected);
+		assert_eq!(*status.wait_for(settled).await.unwrap(), Status::Rejected);
+	}
+}
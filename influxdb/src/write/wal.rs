@@ -0,0 +1,181 @@
+use bytes::Bytes;
+use std::{
+	fs::{self, File},
+	io::{self, Write},
+	path::{Path, PathBuf},
+};
+
+const EXTENSION: &str = "lp";
+const PARTIAL_EXTENSION: &str = "partial";
+const CORRUPT_EXTENSION: &str = "corrupt";
+
+/// Keeps each buffered write in its own file until InfluxDB has settled it,
+/// so writes still buffered when the process dies are replayed on the next
+/// start.
+///
+/// Files are named by an increasing id, so replaying them in name order
+/// preserves the order they were written.
+#[derive(Debug)]
+pub(crate) struct Wal {
+	directory: PathBuf,
+	fsync: bool,
+	next_id: u64,
+}
+
+impl Wal {
+	/// Opens the log in `directory`, creating it if needed, and returns the
+	/// writes left in it, oldest first.
+	///
+	/// Files left part way through being written are deleted. Files which
+	/// cannot be read, or do not end with a complete line, are renamed aside
+	/// with a `.corrupt` extension for inspection rather than replayed.
+	pub(crate) fn open(directory: &Path, fsync: bool) -> io::Result<(Self, Vec<(u64, Bytes)>)> {
+		fs::create_dir_all(directory)?;
+
+		let mut entries = Vec::new();
+		let mut next_id = 0;
+		for entry in fs::read_dir(directory)? {
+			let path = entry?.path();
+			let Some(id) = path
+				.file_stem()
+				.and_then(|stem| stem.to_str())
+				.and_then(|stem| stem.parse::<u64>().ok())
+			else {
+				continue;
+			};
+
+			match path.extension().and_then(|ext| ext.to_str()) {
+				Some(EXTENSION) => {}
+				Some(PARTIAL_EXTENSION) => {
+					tracing::warn!(
+						"removing incomplete write-ahead log file '{}'",
+						path.display()
+					);
+					if let Err(error) = fs::remove_file(&path) {
+						tracing::warn!("failed to remove '{}': {error:?}", path.display());
+					}
+					continue;
+				}
+				Some(CORRUPT_EXTENSION) => {
+					next_id = next_id.max(id + 1);
+					continue;
+				}
+				_ => continue,
+			}
+			next_id = next_id.max(id + 1);
+
+			match read_segment(&path) {
+				Ok(buffer) => entries.push((id, buffer)),
+				Err(error) => {
+					let corrupt = path.with_extension(CORRUPT_EXTENSION);
+					tracing::error!(
+						"quarantining corrupt write-ahead log file '{}' as '{}': {error}",
+						path.display(),
+						corrupt.display()
+					);
+					if let Err(error) = fs::rename(&path, &corrupt) {
+						tracing::error!("failed to quarantine '{}': {error:?}", path.display());
+					}
+				}
+			}
+		}
+		entries.sort_by_key(|(id, _)| *id);
+
+		let wal = Self {
+			directory: directory.to_path_buf(),
+			fsync,
+			next_id,
+		};
+		Ok((wal, entries))
+	}
+
+	/// Logs a write, returning its id.
+	///
+	/// The file is written under a temporary name then renamed, so a crash
+	/// part way through never leaves a truncated write to be replayed. With
+	/// `fsync` set, the data and then the rename are also flushed to disk,
+	/// which survives power loss as well as a crash at the cost of two syncs
+	/// per write.
+	pub(crate) fn append(&mut self, buffer: &[u8]) -> io::Result<u64> {
+		let id = self.next_id;
+		let path = self.path(id);
+		let partial = path.with_extension(PARTIAL_EXTENSION);
+
+		let mut file = File::create(&partial)?;
+		file.write_all(buffer)?;
+		if self.fsync {
+			file.sync_data()?;
+		}
+		fs::rename(&partial, &path)?;
+		if self.fsync {
+			File::open(&self.directory)?.sync_all()?;
+		}
+
+		self.next_id += 1;
+		Ok(id)
+	}
+
+	/// Removes a write which InfluxDB has settled.
+	pub(crate) fn remove(&mut self, id: u64) {
+		let path = self.path(id);
+		if let Err(error) = fs::remove_file(&path) {
+			tracing::warn!(
+				"failed to remove '{}' from the write-ahead log: {error:?}",
+				path.display()
+			);
+		}
+	}
+
+	fn path(&self, id: u64) -> PathBuf {
+		self.directory.join(format!("{id:020}.{EXTENSION}"))
+	}
+}
+
+/// Reads a logged write, which is complete only if it ends with a newline.
+fn read_segment(path: &Path) -> io::Result<Bytes> {
+	let buffer = fs::read(path)?;
+	if buffer.last() != Some(&b'\n') {
+		return Err(io::Error::new(
+			io::ErrorKind::UnexpectedEof,
+			"truncated write",
+		));
+	}
+	Ok(Bytes::from(buffer))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Wal;
+	use std::fs;
+
+	#[test]
+	fn corrupt_segments_are_quarantined() {
+		let directory =
+			std::env::temp_dir().join(format!("influxdb-wal-corrupt-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&directory);
+
+		let (mut wal, replayed) = Wal::open(&directory, false).unwrap();
+		assert!(replayed.is_empty());
+		for buffer in [&b"m f=1i\n"[..], b"m f=2i\n", b"m f=3i\n"] {
+			wal.append(buffer).unwrap();
+		}
+		drop(wal);
+
+		// Truncate the middle write, and leave a write part way through.
+		let segment = |id: u64, extension: &str| directory.join(format!("{id:020}.{extension}"));
+		fs::write(segment(1, "lp"), b"m f=").unwrap();
+		fs::write(segment(3, "partial"), b"m f=4i\n").unwrap();
+
+		let (mut wal, replayed) = Wal::open(&directory, false).unwrap();
+		assert_eq!(
+			replayed,
+			vec![(0, b"m f=1i\n"[..].into()), (2, b"m f=3i\n"[..].into())]
+		);
+		assert!(segment(1, "corrupt").exists());
+		assert!(!segment(3, "partial").exists());
+
+		// Ids are not reused.
+		assert_eq!(wal.append(b"m f=5i\n").unwrap(), 3);
+		fs::remove_dir_all(&directory).unwrap();
+	}
+}
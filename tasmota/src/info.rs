@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+/// The first of the messages a device publishes to `tele/.../INFO1` as it
+/// boots.
+//  {"Info1":{"Module":"Sonoff Basic","Version":"12.1.1(tasmota)","FallbackTopic":"cmnd/DVES_0A1B2C_fb/","GroupTopic":"cmnd/tasmotas/"}}
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Info1 {
+	#[serde(rename = "Info1")]
+	pub info: Info1Inner,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Info1Inner {
+	#[serde(rename = "Module")]
+	pub module: String,
+	#[serde(rename = "Version")]
+	pub version: String,
+}
@@ -1,3 +1,8 @@
+// Boot messages
+//
+pub mod info;
+pub use info::Info1;
+
 mod powerstate;
 pub use powerstate::PowerState;
 
@@ -6,7 +11,8 @@ pub use powerstate::PowerState;
 pub mod sns;
 pub use sns::StatusSNS;
 
-mod status0;
+pub mod status0;
+pub use status0::Status0;
 
 // Status telemetry messages
 //
@@ -1,7 +1,11 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use time::PrimitiveDateTime;
 
+/// Sensor telemetry. Payloads wrapped in one outer object, such as one keyed
+/// by the device or sensor name, are unwrapped.
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "MaybeWrapped")]
 pub struct StatusSNS {
 	#[serde(rename = "Time", with = "crate::datetime")]
 	pub time: PrimitiveDateTime,
@@ -9,6 +13,42 @@ pub struct StatusSNS {
 	pub energy: Energy,
 }
 
+#[derive(Deserialize)]
+struct FlatSNS {
+	#[serde(rename = "Time", with = "crate::datetime")]
+	time: PrimitiveDateTime,
+	#[serde(rename = "ENERGY")]
+	energy: Energy,
+}
+
+//  {"kitchen/kettle":{"Time":"2023-10-04T12:00:00","ENERGY":{...}}}
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MaybeWrapped {
+	Flat(FlatSNS),
+	Wrapped(BTreeMap<String, FlatSNS>),
+}
+
+impl TryFrom<MaybeWrapped> for StatusSNS {
+	type Error = String;
+
+	fn try_from(value: MaybeWrapped) -> Result<Self, Self::Error> {
+		let FlatSNS { time, energy } = match value {
+			MaybeWrapped::Flat(flat) => flat,
+			MaybeWrapped::Wrapped(wrapped) => {
+				if wrapped.len() != 1 {
+					return Err(format!(
+						"expected one wrapped sensor payload, found {}",
+						wrapped.len()
+					));
+				}
+				wrapped.into_values().next().expect("one wrapped payload")
+			}
+		};
+		Ok(Self { time, energy })
+	}
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Energy {
 	/// Date and time from which device totals started accumulating.
@@ -29,15 +69,15 @@ pub struct Energy {
 	/// Current power usage in Watts.
 	#[serde(rename = "Power")]
 	pub power: u32,
-	/// Apparent Power in VA.
+	/// Apparent Power in VA. Not reported by some older energy monitors.
 	#[serde(rename = "ApparentPower")]
-	pub apparent_power: u32,
-	/// Reactive Power in VAr.
+	pub apparent_power: Option<u32>,
+	/// Reactive Power in VAr. Not reported by some older energy monitors.
 	#[serde(rename = "ReactivePower")]
-	pub reactive_power: u32,
-	/// Power Factor.
+	pub reactive_power: Option<u32>,
+	/// Power Factor. Not reported by some older energy monitors.
 	#[serde(rename = "Factor")]
-	pub power_factor: f32,
+	pub power_factor: Option<f32>,
 	/// Voltage in Volts.
 	#[serde(rename = "Voltage")]
 	pub voltage: u32,
@@ -13,6 +13,8 @@ pub struct Status0Inner {
 	pub module: u32,
 	#[serde(rename = "DeviceName")]
 	pub device_name: String,
+	#[serde(rename = "FriendlyName", default)]
+	pub friendly_name: Vec<String>,
 	#[serde(rename = "Topic")]
 	pub topic: String,
 }
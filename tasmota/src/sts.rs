@@ -1,5 +1,6 @@
 use crate::PowerState;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use time::PrimitiveDateTime;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -7,8 +8,11 @@ pub struct StatusSTS {
 	/// Clock time of the device in the local timezone.
 	#[serde(rename = "Time", with = "crate::datetime")]
 	pub time: PrimitiveDateTime,
-	#[serde(rename = "POWER")]
-	pub power_state: PowerState,
+	/// State of each relay, by number. Single-relay devices report `POWER`,
+	/// or with some firmware `POWER1`, kept as relay 0; others report
+	/// `POWER1`, `POWER2` and so on.
+	#[serde(flatten, with = "relays")]
+	pub power_states: BTreeMap<u8, PowerState>,
 	/// Length of time the device has been running.
 	#[serde(rename = "Uptime")]
 	pub uptime: String,
@@ -24,8 +28,85 @@ pub struct StatusSTS {
 	pub sleep_mode: String,
 	#[serde(rename = "MqttCount")]
 	pub mqtt_count: u32,
-	#[serde(rename = "Wifi")]
-	pub wifi: WiFi,
+	/// Omitted by some firmware variants, such as under heavy sleep.
+	#[serde(rename = "Wifi", default)]
+	pub wifi: Option<WiFi>,
+}
+
+impl StatusSTS {
+	/// Returns the state of the first relay, if the device has any.
+	pub fn power_state(&self) -> Option<PowerState> {
+		self.power_states.values().next().copied()
+	}
+}
+
+/// (De)serializes the `POWER` and `POWERn` keys of a flattened message.
+mod relays {
+	use crate::PowerState;
+	use serde::{
+		de::{IgnoredAny, MapAccess, Visitor},
+		Deserializer, Serializer,
+	};
+	use std::{collections::BTreeMap, fmt};
+
+	/// Returns the relay number for a `POWER` or `POWERn` key.
+	fn relay(key: &str) -> Option<u8> {
+		match key.strip_prefix("POWER")? {
+			"" => Some(0),
+			number => number.parse().ok(),
+		}
+	}
+
+	pub fn serialize<S: Serializer>(
+		states: &BTreeMap<u8, PowerState>,
+		serializer: S,
+	) -> Result<S::Ok, S::Error> {
+		serializer.collect_map(states.iter().map(|(relay, state)| {
+			let key = match relay {
+				0 => String::from("POWER"),
+				relay => format!("POWER{relay}"),
+			};
+			(key, state)
+		}))
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(
+		deserializer: D,
+	) -> Result<BTreeMap<u8, PowerState>, D::Error> {
+		deserializer.deserialize_map(RelayVisitor)
+	}
+
+	struct RelayVisitor;
+
+	impl<'de> Visitor<'de> for RelayVisitor {
+		type Value = BTreeMap<u8, PowerState>;
+
+		fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+			f.write_str("a map of POWER or POWERn keys to relay states")
+		}
+
+		fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+			let mut states = BTreeMap::new();
+			while let Some(key) = map.next_key::<String>()? {
+				match relay(&key) {
+					Some(relay) => {
+						states.insert(relay, map.next_value()?);
+					}
+					None => {
+						map.next_value::<IgnoredAny>()?;
+					}
+				}
+			}
+
+			// A lone `POWER1` is a single-relay device, the same as `POWER`.
+			if states.len() == 1 {
+				if let Some(state) = states.remove(&1) {
+					states.insert(0, state);
+				}
+			}
+			Ok(states)
+		}
+	}
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -1,21 +1,23 @@
-use influxdb::query::QueryClient;
-use serde::Deserialize;
-use time::{
-	format_description::well_known::Rfc3339,
-	macros::{offset, time},
-	Date, OffsetDateTime,
+use influxdb::query::{
+	flux::{Aggregate, Expr},
+	Flux, QueryClient,
 };
+use serde::Deserialize;
+use std::time::Duration;
+use time::{Date, OffsetDateTime, UtcOffset};
 
-const QUERY: &str = r#"
-	from(bucket: "params.bucket")
-	  |> range(start: params.dayStart, stop: params.dayStop)
-	  |> filter(fn: (r) => r["_measurement"] == "impulse")
-	  |> filter(fn: (r) => r["_field"] == "energy")
-	  |> filter(fn: (r) => r["device"] == "params.device")
-	  |> increase()
-	  |> aggregateWindow(every: 1m, fn: last, createEmpty: false)
-	  |> yield(name: "mean")
-"#;
+/// Per-minute energy use for `params.device` between `params.dayStart` and
+/// `params.dayStop`.
+fn query() -> Flux {
+	Flux::from_bucket(Expr::param("bucket"))
+		.range(Expr::time_param("dayStart"), Expr::time_param("dayStop"))
+		.filter_measurement("impulse")
+		.filter_field("energy")
+		.filter_tag("device", Expr::param("device"))
+		.increase()
+		.aggregate_window(Duration::from_secs(60), Aggregate::Last, false)
+		.yield_as("mean")
+}
 
 pub async fn fetch(
 	client: &QueryClient,
@@ -24,49 +26,56 @@ pub async fn fetch(
 	device: &str,
 ) -> anyhow::Result<Vec<Record>> {
 	//
-	let offset = OffsetDateTime::now_local().unwrap().offset();
-	let start = date.with_time(time!(00:00:00)).assume_offset(offset);
-	let end = date
-		.next_day()
-		.unwrap()
-		.with_time(time!(00:00:00))
-		.assume_offset(offset);
+	let (start, end) = day_bounds(date)?;
 
-	let response = client
-		.query(
-			QUERY,
+	client
+		.query_as(
+			query().build(),
 			[
-				("bucket", bucket),
-				("device", device),
-				(
-					"dayStart",
-					start.to_offset(offset!(+0)).format(&Rfc3339)?.as_str(),
-				),
-				(
-					"dayStop",
-					end.to_offset(offset!(+0)).format(&Rfc3339)?.as_str(),
-				),
+				("bucket", bucket.into()),
+				("device", device.into()),
+				("dayStart", start.into()),
+				("dayStop", end.into()),
 			],
 		)
-		.await?;
+		.await
+}
 
-	let data = if response.status().is_success() {
-		response.text().await?
-	} else {
-		panic!("{:?}", response.text().await?);
-	};
+/// Returns the instants at which the local day `date` starts and ends.
+///
+/// Each boundary uses the UTC offset in effect at that instant, so days on
+/// which daylight-saving time starts or ends are 23 or 25 hours long.
+pub fn day_bounds(date: Date) -> anyhow::Result<(OffsetDateTime, OffsetDateTime)> {
+	day_bounds_with(date, |instant| UtcOffset::local_offset_at(instant).ok())
+}
 
-	let mut result = Vec::new();
-	let mut rdr = csv::ReaderBuilder::new()
-		.has_headers(true)
-		.comment(Some(b'#'))
-		.from_reader(data.as_bytes());
-	for res in rdr.deserialize() {
-		let rec: Record = res?;
-		result.push(rec);
-	}
+fn day_bounds_with<F>(date: Date, offset_at: F) -> anyhow::Result<(OffsetDateTime, OffsetDateTime)>
+where
+	F: Fn(OffsetDateTime) -> Option<UtcOffset>,
+{
+	let next = date
+		.next_day()
+		.ok_or_else(|| anyhow::anyhow!("no day after {date}"))?;
+	Ok((
+		local_midnight(date, &offset_at)?,
+		local_midnight(next, &offset_at)?,
+	))
+}
+
+fn local_midnight<F>(date: Date, offset_at: F) -> anyhow::Result<OffsetDateTime>
+where
+	F: Fn(OffsetDateTime) -> Option<UtcOffset>,
+{
+	let midnight = date.midnight();
+	let unknown_offset = || anyhow::anyhow!("unable to determine local offset for {date}");
 
-	Ok(result)
+	// Guess using the offset in effect at midnight UTC, then correct the guess
+	// if the offset at the resulting instant differs.
+	let offset = offset_at(midnight.assume_utc()).ok_or_else(unknown_offset)?;
+	let instant = midnight.assume_offset(offset);
+	let corrected = offset_at(instant).ok_or_else(unknown_offset)?;
+
+	Ok(midnight.assume_offset(corrected))
 }
 
 #[derive(Clone, Copy, Debug, Deserialize)]
@@ -74,6 +83,116 @@ pub struct Record {
 	#[serde(rename = "_time", with = "time::serde::rfc3339")]
 	pub ts: OffsetDateTime,
 
-	#[serde(rename = "_value")]
+	/// Energy in Watt hours. Older data may store this as a float.
+	#[serde(
+		rename = "_value",
+		deserialize_with = "influxdb::query::lenient::integer"
+	)]
 	pub value: u32,
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{day_bounds_with, query, Record};
+	use influxdb::query::from_csv;
+	use time::{
+		macros::{date, datetime, offset},
+		Duration, OffsetDateTime, UtcOffset,
+	};
+
+	/// Europe/London in 2023: BST (+01:00) from 01:00 UTC on 26 March.
+	fn london(instant: OffsetDateTime) -> Option<UtcOffset> {
+		if instant >= datetime!(2023-03-26 01:00 UTC) {
+			Some(offset!(+1))
+		} else {
+			Some(offset!(UTC))
+		}
+	}
+
+	#[test]
+	fn spring_forward_day_is_23_hours() {
+		let (start, end) = day_bounds_with(date!(2023 - 03 - 26), london).unwrap();
+		assert_eq!(start, datetime!(2023-03-26 00:00 UTC));
+		assert_eq!(end, datetime!(2023-03-26 23:00 UTC));
+		assert_eq!(end - start, Duration::hours(23));
+	}
+
+	#[test]
+	fn ordinary_day_is_24_hours() {
+		let (start, end) = day_bounds_with(date!(2023 - 06 - 01), london).unwrap();
+		assert_eq!(start, datetime!(2023-05-31 23:00 UTC));
+		assert_eq!(end - start, Duration::hours(24));
+	}
+
+	#[test]
+	fn query_reads_the_meter_energy() {
+		let flux = query().build();
+
+		// Every parameter `fetch` supplies is used.
+		for param in ["bucket", "device", "dayStart", "dayStop"] {
+			assert!(flux.contains(&format!("params.{param}")), "{param} unused");
+		}
+		assert!(flux.contains(r#"r["_measurement"] == "impulse""#));
+		assert!(flux.contains(r#"r["_field"] == "energy""#));
+	}
+
+	#[test]
+	fn deserialize_integer_and_float_values() {
+		let data = "\
+			#datatype,string,long,dateTime:RFC3339,double\n\
+			,result,table,_time,_value\n\
+			,,0,2023-10-04T00:01:00Z,123\n\
+			,,0,2023-10-04T00:02:00Z,123.0\n";
+
+		let records: Vec<Record> = csv::ReaderBuilder::new()
+			.comment(Some(b'#'))
+			.from_reader(data.as_bytes())
+			.deserialize()
+			.collect::<Result<_, _>>()
+			.unwrap();
+
+		assert_eq!(records.len(), 2);
+		assert!(records.iter().all(|record| record.value == 123));
+	}
+
+	#[test]
+	fn decode_annotated_tables() {
+		let data = "\
+			#group,false,false,true,false,false\n\
+			#datatype,string,long,string,dateTime:RFC3339,long\n\
+			#default,mean,,,,\n\
+			,result,table,device,_time,_value\n\
+			,,0,garage/meter,2023-10-04T00:01:00Z,120\n\
+			,,0,garage/meter,2023-10-04T00:02:00Z,135\n\
+			\n\
+			#group,false,false,true,false,false\n\
+			#datatype,string,long,string,dateTime:RFC3339,double\n\
+			#default,mean,,,,\n\
+			,result,table,device,_time,_value\n\
+			,,1,garage/meter,2023-10-04T00:03:00Z,150.0\n";
+
+		let records: Vec<Record> = from_csv(data).unwrap();
+		let records: Vec<_> = records
+			.iter()
+			.map(|record| (record.ts, record.value))
+			.collect();
+		assert_eq!(
+			records,
+			vec![
+				(datetime!(2023-10-04 00:01 UTC), 120),
+				(datetime!(2023-10-04 00:02 UTC), 135),
+				(datetime!(2023-10-04 00:03 UTC), 150),
+			]
+		);
+	}
+
+	#[test]
+	fn reject_fractional_values() {
+		let data = ",result,table,_time,_value\n,,0,2023-10-04T00:01:00Z,123.5\n";
+		let result = csv::Reader::from_reader(data.as_bytes())
+			.deserialize::<Record>()
+			.next()
+			.unwrap();
+		assert!(result.is_err());
+	}
+}